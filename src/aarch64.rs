@@ -0,0 +1,975 @@
+use crate::{CodeGenerator, codegen::{self, CodeEmitter, LinkTarget, Relocation, OffsetMap}, ir::{Ir, IrReg, IrReg::*, IrCp::*, IrOperand::*, IrCond, IrCond::*, IrLabel, IrSignature}};
+
+// AArch64 code generator
+//
+// Uses the same memory segment map and the same function frame layout as the x86-64 code
+// generator. The real stack pointer must always be 16-byte aligned on AArch64, so the Wasm
+// value stack is kept in a separate register (x27) and grows down in an area reserved on the
+// native stack by the entry trampoline.
+//
+// Register assignment:
+//
+//   x0, x1, x2    Sra, Src, Srd
+//   x9-x12        Scratch for the complex operations
+//   x16, x17      Scratch for address and constant computations
+//   x19           Function frame pointer (locals)
+//   x27           Value stack pointer
+//   x28           Memory base
+//   x29           Block pointer
+//   x30           Link register
+//
+// Values patched by relocations are kept in inline literal pools (`ldr xN, #8 ; b #12 ; .quad`),
+// so all the relocations are plain 64-bit absolute values, like on x86-64.
+
+pub struct Aarch64Compiler {
+	map_sra: u32,
+	map_src: u32,
+	map_srd: u32,
+}
+
+impl Aarch64Compiler {
+	pub fn new() -> Self {
+		Self { map_sra: X0, map_src: X1, map_srd: X2 }
+	}
+
+	fn reg(&self, r: &IrReg) -> u32 {
+		match r {
+			Sra => self.map_sra,
+			Src => self.map_src,
+			Srd => self.map_srd,
+		}
+	}
+}
+
+impl Default for Aarch64Compiler {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+const X0: u32 = 0;
+const X1: u32 = 1;
+const X2: u32 = 2;
+const X9: u32 = 9;
+const X10: u32 = 10;
+const X11: u32 = 11;
+const X12: u32 = 12;
+const X16: u32 = 16;
+const X17: u32 = 17;
+const X19: u32 = 19;
+const X27: u32 = 27;
+const X28: u32 = 28;
+const X29: u32 = 29;
+const X30: u32 = 30;
+const XZR: u32 = 31;
+const SP: u32 = 31;
+const V16: u32 = 16;
+
+const ABI_PARAM_REGS: usize = 8;
+
+// Native stack space reserved for the value stack by the entry trampoline. Its floor is the
+// native stack pointer, which the generated code only moves below temporarily to call imports.
+const VALUE_STACK_SIZE: u32 = 0x40000;
+
+// Value stack space every function keeps free for its operands on top of its frame
+const VALUE_STACK_RED_ZONE: i64 = 0x1000;
+
+// Return address, saved x19 and saved x29 between the parameters and the locals
+const FRAME_SLOTS: i32 = 3;
+
+const fn native_cond(cond: &IrCond) -> u32 {
+	match cond {
+		Zero => 0x0,
+		NotZero => 0x1,
+		Equal => 0x0,
+		NotEqual => 0x1,
+		LessSigned => 0xb,
+		LessUnsigned => 0x3,
+		GreaterSigned => 0xc,
+		GreaterUnsigned => 0x8,
+		LessOrEqualSigned => 0xd,
+		LessOrEqualUnsigned => 0x9,
+		GreaterOrEqualSigned => 0xa,
+		GreaterOrEqualUnsigned => 0x2,
+	}
+}
+
+const COND_NE: u32 = 0x1;
+const COND_HI: u32 = 0x8;
+const COND_VC: u32 = 0x7;
+const COND_HS: u32 = 0x2;
+
+// Instruction encodings. `sf` selects the 64-bit variant.
+
+const fn sf(is64: bool) -> u32 {
+	if is64 { 1 << 31 } else { 0 }
+}
+
+pub(crate) const fn add(is64: bool, rd: u32, rn: u32, rm: u32) -> u32 {
+	0x0b000000 | sf(is64) | rm << 16 | rn << 5 | rd
+}
+
+pub(crate) const fn add_lsl(rd: u32, rn: u32, rm: u32, shift: u32) -> u32 {
+	0x8b000000 | rm << 16 | shift << 10 | rn << 5 | rd
+}
+
+// add <xd|sp>, <xn|sp>, <wm>, uxtw #<shift>
+pub(crate) const fn add_uxtw(rd: u32, rn: u32, rm: u32) -> u32 {
+	0x8b204000 | rm << 16 | rn << 5 | rd
+}
+
+// add <xd|sp>, <xn|sp>, <xm> ; Extended register form, which can address sp
+pub(crate) const fn add_sp(rd: u32, rn: u32, rm: u32) -> u32 {
+	0x8b206000 | rm << 16 | rn << 5 | rd
+}
+
+pub(crate) const fn add_imm(is64: bool, rd: u32, rn: u32, imm12: u32) -> u32 {
+	debug_assert!(imm12 < 0x1000, "Immediate out of range");
+	0x11000000 | sf(is64) | imm12 << 10 | rn << 5 | rd
+}
+
+pub(crate) const fn sub_imm(is64: bool, rd: u32, rn: u32, imm12: u32) -> u32 {
+	debug_assert!(imm12 < 0x1000, "Immediate out of range");
+	0x51000000 | sf(is64) | imm12 << 10 | rn << 5 | rd
+}
+
+// Immediate shifted left by 12 bits
+pub(crate) const fn add_imm_lsl12(rd: u32, rn: u32, imm12: u32) -> u32 {
+	0x91400000 | imm12 << 10 | rn << 5 | rd
+}
+
+pub(crate) const fn sub_imm_lsl12(rd: u32, rn: u32, imm12: u32) -> u32 {
+	0xd1400000 | imm12 << 10 | rn << 5 | rd
+}
+
+pub(crate) const fn sub(is64: bool, rd: u32, rn: u32, rm: u32) -> u32 {
+	0x4b000000 | sf(is64) | rm << 16 | rn << 5 | rd
+}
+
+pub(crate) const fn cmp(is64: bool, rn: u32, rm: u32) -> u32 {
+	0x6b000000 | sf(is64) | rm << 16 | rn << 5 | XZR
+}
+
+pub(crate) const fn cmp_imm(is64: bool, rn: u32, imm12: u32) -> u32 {
+	0x71000000 | sf(is64) | imm12 << 10 | rn << 5 | XZR
+}
+
+pub(crate) const fn cmn_imm(is64: bool, rn: u32, imm12: u32) -> u32 {
+	0x31000000 | sf(is64) | imm12 << 10 | rn << 5 | XZR
+}
+
+pub(crate) const fn ands(is64: bool, rd: u32, rn: u32, rm: u32) -> u32 {
+	0x6a000000 | sf(is64) | rm << 16 | rn << 5 | rd
+}
+
+pub(crate) const fn orr(is64: bool, rd: u32, rn: u32, rm: u32) -> u32 {
+	0x2a000000 | sf(is64) | rm << 16 | rn << 5 | rd
+}
+
+pub(crate) const fn eor(is64: bool, rd: u32, rn: u32, rm: u32) -> u32 {
+	0x4a000000 | sf(is64) | rm << 16 | rn << 5 | rd
+}
+
+pub(crate) const fn mov(is64: bool, rd: u32, rm: u32) -> u32 {
+	orr(is64, rd, XZR, rm)
+}
+
+pub(crate) const fn neg(is64: bool, rd: u32, rm: u32) -> u32 {
+	sub(is64, rd, XZR, rm)
+}
+
+pub(crate) const fn mul(is64: bool, rd: u32, rn: u32, rm: u32) -> u32 {
+	0x1b000000 | sf(is64) | rm << 16 | XZR << 10 | rn << 5 | rd
+}
+
+// rd = ra - rn * rm
+pub(crate) const fn msub(is64: bool, rd: u32, rn: u32, rm: u32, ra: u32) -> u32 {
+	0x1b008000 | sf(is64) | rm << 16 | ra << 10 | rn << 5 | rd
+}
+
+pub(crate) const fn udiv(is64: bool, rd: u32, rn: u32, rm: u32) -> u32 {
+	0x1ac00800 | sf(is64) | rm << 16 | rn << 5 | rd
+}
+
+pub(crate) const fn sdiv(is64: bool, rd: u32, rn: u32, rm: u32) -> u32 {
+	0x1ac00c00 | sf(is64) | rm << 16 | rn << 5 | rd
+}
+
+pub(crate) const fn lslv(is64: bool, rd: u32, rn: u32, rm: u32) -> u32 {
+	0x1ac02000 | sf(is64) | rm << 16 | rn << 5 | rd
+}
+
+pub(crate) const fn lsrv(is64: bool, rd: u32, rn: u32, rm: u32) -> u32 {
+	0x1ac02400 | sf(is64) | rm << 16 | rn << 5 | rd
+}
+
+pub(crate) const fn asrv(is64: bool, rd: u32, rn: u32, rm: u32) -> u32 {
+	0x1ac02800 | sf(is64) | rm << 16 | rn << 5 | rd
+}
+
+pub(crate) const fn rorv(is64: bool, rd: u32, rn: u32, rm: u32) -> u32 {
+	0x1ac02c00 | sf(is64) | rm << 16 | rn << 5 | rd
+}
+
+pub(crate) const fn clz(is64: bool, rd: u32, rn: u32) -> u32 {
+	0x5ac01000 | sf(is64) | rn << 5 | rd
+}
+
+pub(crate) const fn rbit(is64: bool, rd: u32, rn: u32) -> u32 {
+	0x5ac00000 | sf(is64) | rn << 5 | rd
+}
+
+// rd = cond ? rn : rm
+pub(crate) const fn csel(is64: bool, rd: u32, rn: u32, rm: u32, cond: u32) -> u32 {
+	0x1a800000 | sf(is64) | rm << 16 | cond << 12 | rn << 5 | rd
+}
+
+pub(crate) const fn cset(rd: u32, cond: u32) -> u32 {
+	// csinc <wd>, wzr, wzr, <!cond>
+	0x1a800400 | XZR << 16 | (cond ^ 1) << 12 | XZR << 5 | rd
+}
+
+pub(crate) const fn sxtb(rd: u32, rn: u32) -> u32 {
+	0x93401c00 | rn << 5 | rd
+}
+
+pub(crate) const fn sxth(rd: u32, rn: u32) -> u32 {
+	0x93403c00 | rn << 5 | rd
+}
+
+pub(crate) const fn sxtw(rd: u32, rn: u32) -> u32 {
+	0x93407c00 | rn << 5 | rd
+}
+
+pub(crate) const fn uxtb(rd: u32, rn: u32) -> u32 {
+	0x53001c00 | rn << 5 | rd
+}
+
+pub(crate) const fn uxth(rd: u32, rn: u32) -> u32 {
+	0x53003c00 | rn << 5 | rd
+}
+
+pub(crate) const fn movz(is64: bool, rd: u32, imm16: u32, hw: u32) -> u32 {
+	0x52800000 | sf(is64) | hw << 21 | imm16 << 5 | rd
+}
+
+pub(crate) const fn movk(is64: bool, rd: u32, imm16: u32, hw: u32) -> u32 {
+	0x72800000 | sf(is64) | hw << 21 | imm16 << 5 | rd
+}
+
+pub(crate) const fn movn(is64: bool, rd: u32, imm16: u32, hw: u32) -> u32 {
+	0x12800000 | sf(is64) | hw << 21 | imm16 << 5 | rd
+}
+
+// Memory access sizes, as encoded in the `size` field of the load/store instructions
+pub(crate) const SIZE8: u32 = 0;
+pub(crate) const SIZE16: u32 = 1;
+pub(crate) const SIZE32: u32 = 2;
+pub(crate) const SIZE64: u32 = 3;
+
+// ldr/str <rt>, [<rn>, #<imm>] ; Unsigned offset scaled by the access size
+pub(crate) const fn ldr_imm(size: u32, rt: u32, rn: u32, offset: u32) -> u32 {
+	0x39400000 | size << 30 | (offset >> size) << 10 | rn << 5 | rt
+}
+
+pub(crate) const fn str_imm(size: u32, rt: u32, rn: u32, offset: u32) -> u32 {
+	0x39000000 | size << 30 | (offset >> size) << 10 | rn << 5 | rt
+}
+
+// ldur/stur <xt>, [<rn>, #<simm9>]
+pub(crate) const fn ldur(rt: u32, rn: u32, offset: i32) -> u32 {
+	0xf8400000 | (offset as u32 & 0x1ff) << 12 | rn << 5 | rt
+}
+
+pub(crate) const fn stur(rt: u32, rn: u32, offset: i32) -> u32 {
+	0xf8000000 | (offset as u32 & 0x1ff) << 12 | rn << 5 | rt
+}
+
+// ldr/str <rt>, [<rn>, <xm>]
+pub(crate) const fn ldr_reg(size: u32, rt: u32, rn: u32, rm: u32) -> u32 {
+	0x38606800 | size << 30 | rm << 16 | rn << 5 | rt
+}
+
+pub(crate) const fn str_reg(size: u32, rt: u32, rn: u32, rm: u32) -> u32 {
+	0x38206800 | size << 30 | rm << 16 | rn << 5 | rt
+}
+
+// ldr <xt>, [<xn>, <wm>, uxtw #3]
+pub(crate) const fn ldr_uxtw3(rt: u32, rn: u32, rm: u32) -> u32 {
+	0xf8605800 | rm << 16 | rn << 5 | rt
+}
+
+// str <rt>, [<rn>, #<simm9>]!
+pub(crate) const fn str_pre(size: u32, rt: u32, rn: u32, offset: i32) -> u32 {
+	0x38000c00 | size << 30 | (offset as u32 & 0x1ff) << 12 | rn << 5 | rt
+}
+
+// ldr/str <rt>, [<rn>], #<simm9>
+pub(crate) const fn ldr_post(size: u32, rt: u32, rn: u32, offset: i32) -> u32 {
+	0x38400400 | size << 30 | (offset as u32 & 0x1ff) << 12 | rn << 5 | rt
+}
+
+pub(crate) const fn str_post(size: u32, rt: u32, rn: u32, offset: i32) -> u32 {
+	0x38000400 | size << 30 | (offset as u32 & 0x1ff) << 12 | rn << 5 | rt
+}
+
+// ldr <xt>, <pc+offset>
+pub(crate) const fn ldr_literal(rt: u32, offset: i32) -> u32 {
+	0x58000000 | ((offset >> 2) as u32 & 0x7ffff) << 5 | rt
+}
+
+// stp <xt1>, <xt2>, [<xn>, #<simm7*8>]!
+pub(crate) const fn stp_pre(rt1: u32, rt2: u32, rn: u32, offset: i32) -> u32 {
+	0xa9800000 | ((offset >> 3) as u32 & 0x7f) << 15 | rt2 << 10 | rn << 5 | rt1
+}
+
+pub(crate) const fn stp(rt1: u32, rt2: u32, rn: u32, offset: i32) -> u32 {
+	0xa9000000 | ((offset >> 3) as u32 & 0x7f) << 15 | rt2 << 10 | rn << 5 | rt1
+}
+
+// ldp <xt1>, <xt2>, [<xn>], #<simm7*8>
+pub(crate) const fn ldp_post(rt1: u32, rt2: u32, rn: u32, offset: i32) -> u32 {
+	0xa8c00000 | ((offset >> 3) as u32 & 0x7f) << 15 | rt2 << 10 | rn << 5 | rt1
+}
+
+pub(crate) const fn ldp(rt1: u32, rt2: u32, rn: u32, offset: i32) -> u32 {
+	0xa9400000 | ((offset >> 3) as u32 & 0x7f) << 15 | rt2 << 10 | rn << 5 | rt1
+}
+
+pub(crate) const fn b(offset: i32) -> u32 {
+	0x14000000 | ((offset >> 2) as u32 & 0x3ffffff)
+}
+
+pub(crate) const fn bl(offset: i32) -> u32 {
+	0x94000000 | ((offset >> 2) as u32 & 0x3ffffff)
+}
+
+pub(crate) const fn b_cond(cond: u32, offset: i32) -> u32 {
+	0x54000000 | ((offset >> 2) as u32 & 0x7ffff) << 5 | cond
+}
+
+pub(crate) const fn cbz(is64: bool, rt: u32, offset: i32) -> u32 {
+	0x34000000 | sf(is64) | ((offset >> 2) as u32 & 0x7ffff) << 5 | rt
+}
+
+pub(crate) const fn cbnz(is64: bool, rt: u32, offset: i32) -> u32 {
+	0x35000000 | sf(is64) | ((offset >> 2) as u32 & 0x7ffff) << 5 | rt
+}
+
+pub(crate) const fn br(rn: u32) -> u32 {
+	0xd61f0000 | rn << 5
+}
+
+pub(crate) const fn blr(rn: u32) -> u32 {
+	0xd63f0000 | rn << 5
+}
+
+pub(crate) const fn ret() -> u32 {
+	0xd65f0000 | X30 << 5
+}
+
+pub(crate) const fn adr(rd: u32, offset: i32) -> u32 {
+	0x10000000 | (offset as u32 & 0x3) << 29 | ((offset >> 2) as u32 & 0x7ffff) << 5 | rd
+}
+
+pub(crate) const fn nop() -> u32 {
+	0xd503201f
+}
+
+pub(crate) const fn udf() -> u32 {
+	0x00000000
+}
+
+// fmov <dd>, <xn> / fmov <sd>, <wn>
+pub(crate) const fn fmov_to_simd(is64: bool, rd: u32, rn: u32) -> u32 {
+	if is64 { 0x9e670000 | rn << 5 | rd } else { 0x1e270000 | rn << 5 | rd }
+}
+
+// fmov <wd>, <sn>
+pub(crate) const fn fmov_from_simd(rd: u32, rn: u32) -> u32 {
+	0x1e260000 | rn << 5 | rd
+}
+
+// cnt <vd>.8b, <vn>.8b
+pub(crate) const fn cnt8b(rd: u32, rn: u32) -> u32 {
+	0x0e205800 | rn << 5 | rd
+}
+
+// addv <bd>, <vn>.8b
+pub(crate) const fn addv8b(rd: u32, rn: u32) -> u32 {
+	0x0e31b800 | rn << 5 | rd
+}
+
+fn is_branch_cond(insn: u32) -> bool {
+	insn & 0xff000010 == 0x54000000
+}
+
+impl CodeGenerator for Aarch64Compiler {
+	fn target_arch(&self) -> &'static str {
+		"aarch64"
+	}
+
+	fn compile_func(&self, code: &mut CodeEmitter, index: u32, body: Ir, signatures: &Vec<Option<IrSignature>>, offset_map: &OffsetMap) {
+		macro_rules! emit {
+			($($e:expr),*) => { { $(code.begin_insn(); code.emit_imm32_le($e as i32));* } }
+		}
+
+		// Loads an arbitrary constant with the shortest movz/movn + movk sequence
+		macro_rules! emit_load_imm {
+			($is64:expr, $rd:expr, $imm:expr) => {
+				{
+					let imm: u64 = $imm;
+					let n_halves = if $is64 { 4 } else { 2 };
+					let halves = (0..n_halves).map(|hw| (imm >> (hw * 16)) as u32 & 0xffff).collect::<Vec<_>>();
+					let inverted = halves.iter().filter(|h| **h == 0xffff).count() > halves.iter().filter(|h| **h == 0).count();
+					let fill = if inverted { 0xffff } else { 0 };
+					let mut first = true;
+					for (hw, half) in halves.iter().enumerate() {
+						if *half == fill && !(first && hw == n_halves - 1) {
+							continue;
+						}
+						if first {
+							if inverted {
+								emit!(movn($is64, $rd, !half & 0xffff, hw as u32));
+							} else {
+								emit!(movz($is64, $rd, *half, hw as u32));
+							}
+							first = false;
+						} else {
+							emit!(movk($is64, $rd, *half, hw as u32));
+						}
+					}
+				}
+			}
+		}
+
+		macro_rules! emit_push {
+			($rt:expr) => { emit!(str_pre(SIZE64, $rt, X27, -8)) } // str <xt>, [x27, #-8]!
+		}
+
+		macro_rules! emit_pop {
+			($rt:expr) => { emit!(ldr_post(SIZE64, $rt, X27, 8)) } // ldr <xt>, [x27], #8
+		}
+
+		// Adds or subtracts a constant to a register, any of them may be sp
+		macro_rules! emit_add_const {
+			($rd:expr, $rn:expr, $imm:expr) => {
+				{
+					let imm: i64 = $imm;
+					let abs = imm.unsigned_abs();
+					if abs < 0x1000 {
+						emit!(if imm < 0 { sub_imm(true, $rd, $rn, abs as u32) } else { add_imm(true, $rd, $rn, abs as u32) });
+					} else if abs & 0xfff == 0 && abs < 0x1000000 {
+						emit!(if imm < 0 { sub_imm_lsl12($rd, $rn, (abs >> 12) as u32) } else { add_imm_lsl12($rd, $rn, (abs >> 12) as u32) });
+					} else {
+						emit_load_imm!(true, X16, imm as u64);
+						emit!(add_sp($rd, $rn, X16)); // add <xd|sp>, <xn|sp>, x16
+					}
+				}
+			}
+		}
+
+		// Literal pool holding a single 64-bit value: ldr <xt>, #8 ; b #12 ; .quad <value>
+		macro_rules! emit_literal {
+			($rt:expr) => {
+				{
+					emit!(ldr_literal($rt, 8));
+					emit!(b(12));
+					code.pc()
+				}
+			}
+		}
+
+		let self_signature = signatures[index as usize].as_ref().expect("Self signature available");
+		let n_params = self_signature.params;
+		let local_offset = |index: u32| {
+			if index < n_params {
+				-(index as i32 + 1) * 8
+			} else {
+				-(index as i32 + 1 + FRAME_SLOTS) * 8
+			}
+		};
+
+		macro_rules! emit_frame_access {
+			($load:expr, $rt:expr, $index:expr) => {
+				{
+					let offset = local_offset($index);
+					if offset >= -256 {
+						emit!(if $load { ldur($rt, X19, offset) } else { stur($rt, X19, offset) });
+					} else {
+						emit_add_const!(X17, X19, offset as i64);
+						emit!(if $load { ldr_imm(SIZE64, $rt, X17, 0) } else { str_imm(SIZE64, $rt, X17, 0) });
+					}
+				}
+			}
+		}
+
+		// Linear memory access, the address register holds a 32-bit value
+		macro_rules! emit_memory_access {
+			($load:expr, $size:expr, $rt:expr, $offset:expr, $raddr:expr) => {
+				{
+					emit!(add_uxtw(X16, X28, $raddr)); // add x16, x28, <waddr>, uxtw
+					if $offset == 0 {
+						emit!(if $load { ldr_imm($size, $rt, X16, 0) } else { str_imm($size, $rt, X16, 0) });
+					} else {
+						emit_load_imm!(false, X17, $offset as u32 as u64);
+						emit!(if $load { ldr_reg($size, $rt, X16, X17) } else { str_reg($size, $rt, X16, X17) });
+					}
+				}
+			}
+		}
+
+		// Access to the memory below the memory base (globals, VM data)
+		macro_rules! emit_segment_access {
+			($load:expr, $rt:expr, $offset:expr) => {
+				{
+					emit_load_imm!(true, X17, $offset as i64 as u64);
+					emit!(if $load { ldr_reg(SIZE64, $rt, X28, X17) } else { str_reg(SIZE64, $rt, X28, X17) });
+				}
+			}
+		}
+
+		for insn in body.code() {
+			code.ir_insn(insn);
+			match insn {
+				Label(label) => {
+					if let IrLabel::ExportedFunc(findex, _) = label {
+						code.label(label.clone());
+						// AAPCS64 entry trampoline. Saves the callee-saved registers used by the
+						// generated code, reserves the value stack, pins the memory base passed as
+						// the first argument to x28 and pushes the rest of the arguments to the
+						// value stack as the internal calling convention requires.
+						emit!(stp_pre(X29, X30, SP, -48)); // stp x29, x30, [sp, #-48]!
+						emit!(stp(X27, X28, SP, 16)); // stp x27, x28, [sp, #16]
+						emit!(str_imm(SIZE64, X19, SP, 32)); // str x19, [sp, #32]
+						emit!(add_imm(true, X9, SP, 48)); // add x9, sp, #48 ; On-stack arguments
+						emit!(add_imm(true, X27, SP, 0)); // mov x27, sp
+						emit!(sub_imm_lsl12(SP, SP, VALUE_STACK_SIZE >> 12)); // sub sp, sp, #VALUE_STACK_SIZE
+
+						emit!(mov(true, X28, X0)); // mov x28, x0
+
+						for i in 0..n_params {
+							let abi_index = i + 1;
+							if (abi_index as usize) < ABI_PARAM_REGS {
+								emit_push!(X0 + abi_index);
+							} else {
+								emit!(ldr_imm(SIZE64, X16, X9, (abi_index - ABI_PARAM_REGS as u32) * 8)); // ldr x16, [x9, #<off>]
+								emit_push!(X16);
+							}
+						}
+
+						emit!(bl(0)); // bl <body> (no address yet)
+						code.call_targets.push(LinkTarget { offset: code.pc() - 4, func_index: *findex });
+
+						emit!(add_imm_lsl12(SP, SP, VALUE_STACK_SIZE >> 12)); // add sp, sp, #VALUE_STACK_SIZE
+						emit!(ldr_imm(SIZE64, X19, SP, 32)); // ldr x19, [sp, #32]
+						emit!(ldp(X27, X28, SP, 16)); // ldp x27, x28, [sp, #16]
+						emit!(ldp_post(X29, X30, SP, 48)); // ldp x29, x30, [sp], #48
+						emit!(ret());
+
+						code.label(IrLabel::AnonymousFunc(*findex));
+					} else {
+						code.label(label.clone());
+					}
+				},
+				EnterFunction(n_locals) => {
+					// Same frame layout as on x86-64, with the link register in place of the
+					// return address
+					// Traps instead of running into the native frames below the value stack
+					emit_add_const!(X16, X27, -((FRAME_SLOTS as i64 + *n_locals as i64) * 8 + VALUE_STACK_RED_ZONE));
+					emit!(add_imm(true, X17, SP, 0)); // mov x17, sp
+					emit!(cmp(true, X16, X17)); // cmp x16, x17
+					emit!(b_cond(COND_HS, 8)); // b.hs #8
+					emit!(udf());
+					emit_push!(X30);
+					emit_push!(X19);
+					emit_push!(X29);
+					emit_add_const!(X19, X27, (n_params as i64 + FRAME_SLOTS as i64) * 8); // add x19, x27, #<frame_off>
+					emit!(mov(true, X29, X27)); // mov x29, x27
+					for _ in 0..*n_locals {
+						emit_push!(XZR);
+					}
+				},
+				LeaveFunction => {
+					emit_add_const!(X27, X19, -(n_params as i64 + FRAME_SLOTS as i64) * 8); // sub x27, x19, #<frame_off>
+					emit_pop!(X29);
+					emit_pop!(X19);
+				},
+				EnterBlock => {
+					emit_push!(X29);
+					emit!(mov(true, X29, X27)); // mov x29, x27
+				},
+				LeaveBlock => {
+					emit!(mov(true, X27, X29)); // mov x27, x29
+					emit_pop!(X29);
+				},
+				EnterInlinedFunction(frame_local) => {
+					emit_push!(X29);
+					emit!(mov(true, X29, X27)); // mov x29, x27
+					emit_frame_access!(false, X27, *frame_local);
+				},
+				LeaveInlinedFunction(frame_local) => {
+					emit_frame_access!(true, X27, *frame_local);
+					emit_pop!(X29);
+				},
+				Push(op) => {
+					match op {
+						Reg(r) => emit_push!(self.reg(r)),
+						_ => unreachable!()
+					}
+				},
+				Pop(op) => {
+					match op {
+						Reg(r) => emit_pop!(self.reg(r)),
+						_ => unreachable!()
+					}
+				},
+				Move(dest, src) => {
+					match (dest, src) {
+						(Reg(rdest), Reg(rsrc)) => emit!(mov(true, self.reg(rdest), self.reg(rsrc))),
+						(Reg(rdest), Imm32(imm)) | (Reg32(rdest), Imm32(imm)) => emit_load_imm!(false, self.reg(rdest), *imm as u32 as u64),
+						(Reg(rdest), Imm64(imm)) => emit_load_imm!(true, self.reg(rdest), *imm as u64),
+						(Reg(rdest), Local(index)) => emit_frame_access!(true, self.reg(rdest), *index),
+						(Local(index), Reg(rsrc)) => emit_frame_access!(false, self.reg(rsrc), *index),
+						(Reg(rdest), Global(index)) => emit_segment_access!(true, self.reg(rdest), offset_map.globals() + *index as i32 * 8),
+						(Global(index), Reg(rsrc)) => emit_segment_access!(false, self.reg(rsrc), offset_map.globals() + *index as i32 * 8),
+						(Memory8(offset, raddr), Reg8(rsrc)) => emit_memory_access!(false, SIZE8, self.reg(rsrc), *offset, self.reg(raddr)),
+						(Memory16(offset, raddr), Reg16(rsrc)) => emit_memory_access!(false, SIZE16, self.reg(rsrc), *offset, self.reg(raddr)),
+						(Memory32(offset, raddr), Reg32(rsrc)) => emit_memory_access!(false, SIZE32, self.reg(rsrc), *offset, self.reg(raddr)),
+						(Memory64(offset, raddr), Reg(rsrc)) => emit_memory_access!(false, SIZE64, self.reg(rsrc), *offset, self.reg(raddr)),
+						(Reg8(rdest), Memory8(offset, raddr)) => emit_memory_access!(true, SIZE8, self.reg(rdest), *offset, self.reg(raddr)),
+						(Reg16(rdest), Memory16(offset, raddr)) => emit_memory_access!(true, SIZE16, self.reg(rdest), *offset, self.reg(raddr)),
+						(Reg32(rdest), Memory32(offset, raddr)) => emit_memory_access!(true, SIZE32, self.reg(rdest), *offset, self.reg(raddr)),
+						(Reg(rdest), Memory64(offset, raddr)) => emit_memory_access!(true, SIZE64, self.reg(rdest), *offset, self.reg(raddr)),
+						unk => todo!("ir Mov {:?}", unk),
+					}
+				},
+				MoveIf(cond, dest, src) => {
+					match (dest, src) {
+						(Reg(rdest), Reg(rsrc)) | (Reg32(rdest), Reg32(rsrc)) => {
+							emit!(csel(matches!(dest, Reg(_)), self.reg(rdest), self.reg(rsrc), self.reg(rdest), native_cond(cond)));
+						},
+						_ => todo!()
+					}
+				},
+				ZeroExtend(src) => {
+					match src {
+						Reg8(rsrc) => emit!(uxtb(self.reg(rsrc), self.reg(rsrc))),
+						Reg16(rsrc) => emit!(uxth(self.reg(rsrc), self.reg(rsrc))),
+						Reg32(rsrc) => emit!(mov(false, self.reg(rsrc), self.reg(rsrc))), // Writing a w register clears the upper half
+						_ => unreachable!(),
+					}
+				},
+				SignExtend(src) => {
+					// Extends to 64 bits, like on x86-64
+					match src {
+						Reg8(rsrc) => emit!(sxtb(self.reg(rsrc), self.reg(rsrc))),
+						Reg16(rsrc) => emit!(sxth(self.reg(rsrc), self.reg(rsrc))),
+						Reg32(rsrc) => emit!(sxtw(self.reg(rsrc), self.reg(rsrc))),
+						_ => unreachable!(),
+					}
+				},
+				Compare(dest, src) => {
+					match (dest, src) {
+						(Reg32(rdest), Reg32(rsrc)) | (Reg(rdest), Reg(rsrc)) => emit!(cmp(matches!(dest, Reg(_)), self.reg(rdest), self.reg(rsrc))),
+						_ => unreachable!()
+					}
+				},
+				SetIf(cond, dest) => {
+					match dest {
+						Reg(rdest) | Reg32(rdest) => emit!(cset(self.reg(rdest), native_cond(cond))),
+						_ => unreachable!()
+					}
+				},
+				Add(dest, src) | Subtract(dest, src) | Multiply(dest, src) | And(dest, src) | Or(dest, src) | Xor(dest, src) |
+				ShiftLeft(dest, src) | ShiftRightUnsigned(dest, src) | ShiftRightSigned(dest, src) | RotateRight(dest, src) => {
+					match (dest, src) {
+						(Reg32(rdest), Reg32(rsrc)) | (Reg(rdest), Reg(rsrc)) => {
+							let is64 = matches!(dest, Reg(_));
+							let (rd, rs) = (self.reg(rdest), self.reg(rsrc));
+							emit!(match insn {
+								Add(_, _) => add(is64, rd, rd, rs),
+								Subtract(_, _) => sub(is64, rd, rd, rs),
+								Multiply(_, _) => mul(is64, rd, rd, rs),
+								// Sets the flags, the zero test is done with `and`
+								And(_, _) => ands(is64, rd, rd, rs),
+								Or(_, _) => orr(is64, rd, rd, rs),
+								Xor(_, _) => eor(is64, rd, rd, rs),
+								ShiftLeft(_, _) => lslv(is64, rd, rd, rs),
+								ShiftRightUnsigned(_, _) => lsrv(is64, rd, rd, rs),
+								ShiftRightSigned(_, _) => asrv(is64, rd, rd, rs),
+								RotateRight(_, _) => rorv(is64, rd, rd, rs),
+								_ => unreachable!()
+							});
+						},
+						_ => todo!()
+					}
+				},
+				RotateLeft(dest, src) => {
+					match (dest, src) {
+						(Reg32(rdest), Reg32(rsrc)) | (Reg(rdest), Reg(rsrc)) => {
+							let is64 = matches!(dest, Reg(_));
+							emit!(neg(is64, X16, self.reg(rsrc))); // neg x16, <rsrc>
+							emit!(rorv(is64, self.reg(rdest), self.reg(rdest), X16)); // ror <rdest>, <rdest>, x16
+						},
+						_ => todo!()
+					}
+				},
+				DivideUnsigned(dest, src) | DivideSigned(dest, src) | RemainderUnsigned(dest, src) | RemainderSigned(dest, src) => {
+					match (dest, src) {
+						(Reg32(rdest), Reg32(rsrc)) | (Reg(rdest), Reg(rsrc)) => {
+							let is64 = matches!(dest, Reg(_));
+							let (rd, rs) = (self.reg(rdest), self.reg(rsrc));
+							// Division by zero does not trap on AArch64
+							emit!(cbnz(is64, rs, 8)); // cbnz <rsrc>, #8
+							emit!(udf());
+							if matches!(insn, DivideSigned(_, _)) {
+								// Neither does the overflow. The dividend is the minimum value
+								// exactly when subtracting one overflows.
+								emit!(cmn_imm(is64, rs, 1)); // cmn <rsrc>, #1
+								emit!(b_cond(COND_NE, 16)); // b.ne #16
+								emit!(cmp_imm(is64, rd, 1)); // cmp <rdest>, #1
+								emit!(b_cond(COND_VC, 8)); // b.vc #8
+								emit!(udf());
+							}
+							match insn {
+								DivideUnsigned(_, _) => emit!(udiv(is64, rd, rd, rs)),
+								DivideSigned(_, _) => emit!(sdiv(is64, rd, rd, rs)),
+								RemainderUnsigned(_, _) => {
+									emit!(udiv(is64, X16, rd, rs)); // udiv x16, <rdest>, <rsrc>
+									emit!(msub(is64, rd, X16, rs, rd)); // msub <rdest>, x16, <rsrc>, <rdest>
+								},
+								RemainderSigned(_, _) => {
+									emit!(sdiv(is64, X16, rd, rs)); // sdiv x16, <rdest>, <rsrc>
+									emit!(msub(is64, rd, X16, rs, rd)); // msub <rdest>, x16, <rsrc>, <rdest>
+								},
+								_ => unreachable!()
+							}
+						},
+						_ => todo!(),
+					}
+				},
+				LeadingZeroes(src) => {
+					match src {
+						Reg32(rsrc) | Reg(rsrc) => emit!(clz(matches!(src, Reg(_)), self.reg(rsrc), self.reg(rsrc))),
+						_ => unreachable!()
+					}
+				},
+				TrailingZeroes(src) => {
+					match src {
+						Reg32(rsrc) | Reg(rsrc) => {
+							let is64 = matches!(src, Reg(_));
+							emit!(rbit(is64, self.reg(rsrc), self.reg(rsrc)));
+							emit!(clz(is64, self.reg(rsrc), self.reg(rsrc)));
+						},
+						_ => unreachable!()
+					}
+				},
+				BitPopulationCount(src) => {
+					match src {
+						Reg32(rsrc) | Reg(rsrc) => {
+							// There is no scalar population count, SIMD is used instead
+							emit!(fmov_to_simd(matches!(src, Reg(_)), V16, self.reg(rsrc))); // fmov {d16|s16}, <rsrc>
+							emit!(cnt8b(V16, V16)); // cnt v16.8b, v16.8b
+							emit!(addv8b(V16, V16)); // addv b16, v16.8b
+							emit!(fmov_from_simd(self.reg(rsrc), V16)); // fmov <wsrc>, s16
+						},
+						_ => unreachable!(),
+					}
+				},
+				Jump(label) => {
+					emit!(b(0)); // b <label> (no address just yet)
+					code.branch(label.clone());
+				},
+				JumpIf(cond, label) => {
+					emit!(b_cond(native_cond(cond), 0)); // b.<cond> <label> (no address just yet)
+					code.branch(label.clone());
+				},
+				JumpTable(index, targets) => {
+					match index {
+						Reg32(rindex) => {
+							// The table of absolute addresses follows the code, aligned to 8 bytes
+							let padding = (code.pc() as i32 + 12) % 8;
+							emit!(adr(X16, 12 + padding)); // adr x16, <table>
+							emit!(ldr_uxtw3(X16, X16, self.reg(rindex))); // ldr x16, [x16, <windex>, uxtw #3]
+							emit!(br(X16)); // br x16
+							if padding > 0 {
+								emit!(nop());
+							}
+							for target in targets {
+								code.reloc(Relocation::LabelAbsoluteAddress(target.clone()));
+								code.emit_imm64_le(0);
+							}
+						},
+						_ => todo!()
+					}
+				},
+				Call(label) => {
+					match label {
+						IrLabel::AnonymousFunc(idx) | IrLabel::ExportedFunc(idx, _) => {
+							// Arguments are already in place and are removed by the callee
+							emit!(bl(0)); // bl <func> (no address yet)
+							code.call_targets.push(LinkTarget { offset: code.pc() - 4, func_index: *idx });
+							if signatures[*idx as usize].as_ref().expect("Callee signature available").results > 0 {
+								emit_push!(X0);
+							}
+						},
+						IrLabel::Indirect(table_index, op, signature) => {
+							match op {
+								Reg32(op_reg) => {
+									emit_load_imm!(true, X17, offset_map.table(*table_index) as i64 as u64);
+									emit!(add(true, X17, X28, X17)); // add x17, x28, x17
+									emit!(ldr_uxtw3(X16, X17, self.reg(op_reg))); // ldr x16, [x17, <wop>, uxtw #3]
+									emit!(blr(X16)); // blr x16
+								},
+								_ => todo!()
+							}
+							if signature.results > 0 {
+								emit_push!(X0);
+							}
+						},
+						IrLabel::ImportedFunc(idx, addr) => {
+							// AAPCS64 call. The native stack pointer is below the value stack, so
+							// the callee cannot overwrite it.
+							let signature = signatures[*idx as usize].as_ref().expect("Import signature available");
+							let n_params = signature.params;
+							let n_stack_params = (n_params as usize).saturating_sub(ABI_PARAM_REGS) as u32;
+							let stack_size = (n_stack_params * 8 + 15) & !15;
+							for i in 0..n_params {
+								let offset = (n_params - i - 1) * 8;
+								if (i as usize) < ABI_PARAM_REGS {
+									emit!(ldr_imm(SIZE64, X0 + i, X27, offset)); // ldr <abi_reg>, [x27, #<off>]
+								} else {
+									if i as usize == ABI_PARAM_REGS {
+										emit_add_const!(SP, SP, -(stack_size as i64));
+									}
+									emit!(ldr_imm(SIZE64, X16, X27, offset)); // ldr x16, [x27, #<off>]
+									emit!(str_imm(SIZE64, X16, SP, (i - ABI_PARAM_REGS as u32) * 8)); // str x16, [sp, #<off>]
+								}
+							}
+							emit_load_imm!(true, X16, *addr as u64);
+							emit!(blr(X16)); // blr x16
+							if n_stack_params > 0 {
+								emit_add_const!(SP, SP, stack_size as i64);
+							}
+							if n_params > 0 {
+								emit_add_const!(X27, X27, n_params as i64 * 8);
+							}
+							if signature.results > 0 {
+								emit_push!(X0);
+							}
+						},
+						_ => unreachable!(),
+					}
+				},
+				Return => {
+					emit_pop!(X30);
+					if n_params > 0 {
+						emit_add_const!(X27, X27, n_params as i64 * 8);
+					}
+					emit!(ret());
+				},
+				Trap => {
+					emit!(udf());
+				},
+				SourceLoc(_, _) => (),
+				InitTablePreamble(offset) => {
+					match offset {
+						Reg(offset_reg) => {
+							emit_load_imm!(true, X17, offset_map.table(0) as i64 as u64); // FIXME
+							emit!(add(true, X9, X28, X17)); // add x9, x28, x17
+							emit!(add_lsl(X9, X9, self.reg(offset_reg), 3)); // add x9, x9, <roffset>, lsl #3
+						},
+						_ => todo!()
+					}
+				},
+				InitTableElement(func_index_op) => {
+					match func_index_op {
+						Imm32(func_index) => {
+							let pos = emit_literal!(X16); // ldr x16, =<func_address>
+							code.abs_off_targets.push(LinkTarget { offset: pos, func_index: *func_index as u32 });
+							code.reloc(Relocation::FunctionAbsoluteAddress);
+							code.emit_imm64_le(0);
+							emit!(str_post(SIZE64, X16, X9, 8)); // str x16, [x9], #8
+						},
+						_ => todo!()
+					}
+				},
+				InitTablePostamble => (),
+				InitMemoryFromChunk(chunk_idx, chunk_len, offset) => {
+					match offset {
+						Reg(offset_reg) => emit!(add(true, X9, X28, self.reg(offset_reg))), // add x9, x28, <roffset>
+						_ => todo!()
+					}
+					emit_load_imm!(true, X17, offset_map.data_chunk(*chunk_idx) as i64 as u64);
+					emit!(add(true, X10, X28, X17)); // add x10, x28, x17
+					emit_load_imm!(false, X11, *chunk_len as u64);
+					// loop:
+					emit!(cbz(true, X11, 20)); // cbz x11, end
+					emit!(ldr_post(SIZE8, X12, X10, 1)); // ldrb w12, [x10], #1
+					emit!(str_post(SIZE8, X12, X9, 1)); // strb w12, [x9], #1
+					emit!(sub_imm(true, X11, X11, 1)); // sub x11, x11, #1
+					emit!(b(-16)); // b loop
+					// end:
+				},
+				MemoryGrow(pages) => {
+					match pages {
+						Reg32(rpages) => {
+							emit_load_imm!(true, X17, (offset_map.vm_data() + codegen::VM_DATA_MEM_ALLOC) as i64 as u64);
+							emit!(add(true, X17, X28, X17)); // add x17, x28, x17
+							emit!(ldr_imm(SIZE64, X16, X17, 0)); // ldr x16, [x17] ; Allocated pages
+							emit!(add_uxtw(X10, X16, self.reg(rpages))); // add x10, x16, <wpages>, uxtw
+							emit!(ldr_imm(SIZE64, X11, X17, (codegen::VM_DATA_MEM_TOTAL - codegen::VM_DATA_MEM_ALLOC) as u32)); // ldr x11, [x17, #8] ; Total pages
+							emit!(cmp(true, X10, X11)); // cmp x10, x11
+							emit!(b_cond(COND_HI, 16)); // b.hi fail
+							emit!(str_imm(SIZE64, X10, X17, 0)); // str x10, [x17]
+							emit!(mov(false, self.reg(rpages), X16)); // mov <wpages>, w16
+							emit!(b(8)); // b end
+							// fail:
+							emit!(movn(false, self.reg(rpages), 0, 0)); // mov <wpages>, #-1
+							// end:
+						},
+						_ => unreachable!()
+					}
+				},
+				MemorySize(dest) => {
+					match dest {
+						Reg32(rdest) => emit_segment_access!(true, self.reg(rdest), offset_map.vm_data() + codegen::VM_DATA_MEM_ALLOC),
+						_ => unreachable!()
+					}
+				},
+			}
+		}
+	}
+
+	fn finish_func(&self, code: &mut CodeEmitter) {
+		for (offset, label) in std::mem::take(&mut code.branches) {
+			let target = *code.labels.get(&label).unwrap_or_else(|| panic!("Unresolved label: {:?}", label));
+			let disp = target as i32 - offset as i32;
+			let insn = u32::from_le_bytes(code.code[offset..offset + 4].try_into().expect("Four bytes"));
+			let insn = if is_branch_cond(insn) { b_cond(insn & 0xf, disp) } else { b(disp) };
+			code.patch32_le(offset, insn as i32);
+		}
+	}
+
+	fn link(&self, code: &mut CodeEmitter) {
+
+		let mut func_offsets: Vec<usize> = Vec::new();
+		for (label, offset) in code.labels_iter() {
+			if let IrLabel::AnonymousFunc(index) = label {
+				if *index as usize >= func_offsets.len() {
+					func_offsets.resize(*index as usize + 1, 0);
+				}
+				func_offsets[*index as usize] = *offset;
+			}
+		}
+		for target in std::mem::take(&mut code.call_targets) {
+			let disp = func_offsets[target.func_index as usize] as i32 - target.offset as i32;
+			code.patch32_le(target.offset, bl(disp) as i32);
+		}
+		for target in std::mem::take(&mut code.abs_off_targets) {
+			let func_address = func_offsets[target.func_index as usize];
+			code.patch64_le(target.offset, func_address as i64);
+		}
+	}
+}
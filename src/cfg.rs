@@ -0,0 +1,408 @@
+use std::collections::HashMap;
+use crate::ir::{Ir, IrCp, IrLabel, IrOperand};
+
+// Control-flow graph of a single function body
+//
+// The function `Ir` is split into basic blocks at labels (block leaders) and after control
+// transfer instructions (`Jump`, `JumpIf`, `JumpTable`, `Return`, `Trap`). Block 0 is always
+// the function entry. Blocks own their code, so passes may freely rewrite them; the edges,
+// dominator tree and loop information are recomputed by `rebuild()` afterwards.
+
+#[derive(Debug, Clone)]
+pub struct BasicBlock {
+	pub(crate) code: Vec<IrCp>,
+	preds: Vec<usize>,
+	succs: Vec<usize>,
+}
+
+impl BasicBlock {
+	fn new() -> Self {
+		Self { code: Vec::new(), preds: Vec::new(), succs: Vec::new() }
+	}
+
+	pub fn code(&self) -> &[IrCp] {
+		&self.code
+	}
+
+	pub fn code_mut(&mut self) -> &mut Vec<IrCp> {
+		&mut self.code
+	}
+
+	pub fn predecessors(&self) -> &[usize] {
+		&self.preds
+	}
+
+	pub fn successors(&self) -> &[usize] {
+		&self.succs
+	}
+
+	/// Labels defined at the beginning of the block
+	pub fn labels(&self) -> impl Iterator<Item = &IrLabel> {
+		self.code.iter().map_while(|cp| if let IrCp::Label(label) = cp { Some(label) } else { None })
+	}
+
+	fn terminator(&self) -> Option<&IrCp> {
+		self.code.last().filter(|cp| is_terminator(cp))
+	}
+}
+
+#[derive(Debug, Clone)]
+pub struct Loop {
+	header: usize,
+	latches: Vec<usize>,
+	body: Vec<usize>,
+}
+
+impl Loop {
+	pub fn header(&self) -> usize {
+		self.header
+	}
+
+	/// Blocks having a back edge to the header
+	pub fn latches(&self) -> &[usize] {
+		&self.latches
+	}
+
+	/// All the blocks of the loop, including the header, in ascending order
+	pub fn body(&self) -> &[usize] {
+		&self.body
+	}
+
+	pub fn contains(&self, block: usize) -> bool {
+		self.body.binary_search(&block).is_ok()
+	}
+}
+
+fn is_terminator(cp: &IrCp) -> bool {
+	matches!(cp, IrCp::Jump(_) | IrCp::JumpIf(_, _) | IrCp::JumpTable(_, _) | IrCp::Return | IrCp::Trap)
+}
+
+#[derive(Debug, Clone)]
+pub struct Cfg {
+	blocks: Vec<BasicBlock>,
+	idom: Vec<Option<usize>>,
+	loops: Vec<Loop>,
+}
+
+impl Cfg {
+	pub fn build(ir: Ir) -> Self {
+		let mut cfg = Self { blocks: split_blocks(ir.into_code()), idom: Vec::new(), loops: Vec::new() };
+		cfg.analyze();
+		cfg
+	}
+
+	/// Flattens the blocks back into a linear `Ir` in block order
+	pub fn into_ir(self) -> Ir {
+		Ir::from_code(self.blocks.into_iter().flat_map(|b| b.code).collect())
+	}
+
+	/// Re-splits the code and recomputes all the analyses. Must be called after the code of the
+	/// blocks has been changed in a way that may affect control flow.
+	pub fn rebuild(&mut self) {
+		let code = std::mem::take(&mut self.blocks).into_iter().flat_map(|b| b.code).collect();
+		self.blocks = split_blocks(code);
+		self.analyze();
+	}
+
+	pub fn blocks(&self) -> &[BasicBlock] {
+		&self.blocks
+	}
+
+	pub fn blocks_mut(&mut self) -> &mut [BasicBlock] {
+		&mut self.blocks
+	}
+
+	pub fn block(&self, index: usize) -> &BasicBlock {
+		&self.blocks[index]
+	}
+
+	/// Index of the block where `label` is defined
+	pub fn block_of(&self, label: &IrLabel) -> Option<usize> {
+		self.blocks.iter().position(|b| b.labels().any(|l| l == label))
+	}
+
+	pub fn is_reachable(&self, block: usize) -> bool {
+		block == 0 || self.idom[block].is_some()
+	}
+
+	/// Immediate dominator of the block. `None` for the entry block and unreachable blocks
+	pub fn immediate_dominator(&self, block: usize) -> Option<usize> {
+		if block == 0 { None } else { self.idom[block] }
+	}
+
+	/// Returns `true` if every path from the entry to `b` goes through `a`
+	pub fn dominates(&self, a: usize, b: usize) -> bool {
+		if !self.is_reachable(b) {
+			return false;
+		}
+		let mut cur = b;
+		loop {
+			if cur == a {
+				return true;
+			}
+			match self.immediate_dominator(cur) {
+				Some(dom) => cur = dom,
+				None => return false,
+			}
+		}
+	}
+
+	pub fn loops(&self) -> &[Loop] {
+		&self.loops
+	}
+
+	fn analyze(&mut self) {
+		self.compute_edges();
+		self.compute_dominators();
+		self.compute_loops();
+	}
+
+	fn compute_edges(&mut self) {
+		let mut label_map = HashMap::new();
+		for (i, block) in self.blocks.iter().enumerate() {
+			for label in block.labels() {
+				label_map.insert(label.clone(), i);
+			}
+		}
+		let target = |label: &IrLabel| *label_map.get(label).unwrap_or_else(|| panic!("Unresolved label: {:?}", label));
+
+		let n_blocks = self.blocks.len();
+		let mut succs = Vec::with_capacity(n_blocks);
+		for (i, block) in self.blocks.iter().enumerate() {
+			let mut s = match block.terminator() {
+				Some(IrCp::Jump(label)) => vec![target(label)],
+				Some(IrCp::JumpIf(_, label)) => vec![target(label), i + 1],
+				Some(IrCp::JumpTable(_, labels)) => labels.iter().map(target).collect(),
+				Some(IrCp::Return) | Some(IrCp::Trap) => vec![],
+				_ => vec![i + 1],
+			};
+			s.retain(|b| *b < n_blocks);
+			s.sort_unstable();
+			s.dedup();
+			succs.push(s);
+		}
+
+		for block in self.blocks.iter_mut() {
+			block.preds.clear();
+		}
+		for (i, s) in succs.into_iter().enumerate() {
+			for succ in s.iter() {
+				self.blocks[*succ].preds.push(i);
+			}
+			self.blocks[i].succs = s;
+		}
+	}
+
+	fn reverse_postorder(&self) -> Vec<usize> {
+		let mut order = Vec::new();
+		if self.blocks.is_empty() {
+			return order;
+		}
+		let mut visited = vec![false; self.blocks.len()];
+		let mut stack = vec![(0usize, 0usize)];
+		visited[0] = true;
+		while let Some((block, next)) = stack.pop() {
+			if let Some(succ) = self.blocks[block].succs.get(next) {
+				stack.push((block, next + 1));
+				if !visited[*succ] {
+					visited[*succ] = true;
+					stack.push((*succ, 0));
+				}
+			} else {
+				order.push(block);
+			}
+		}
+		order.reverse();
+		order
+	}
+
+	// Cooper, Harvey, Kennedy. "A Simple, Fast Dominance Algorithm"
+	fn compute_dominators(&mut self) {
+		let rpo = self.reverse_postorder();
+		let mut rpo_index = vec![usize::MAX; self.blocks.len()];
+		for (i, block) in rpo.iter().enumerate() {
+			rpo_index[*block] = i;
+		}
+
+		let mut idom: Vec<Option<usize>> = vec![None; self.blocks.len()];
+		if let Some(entry) = rpo.first() {
+			idom[*entry] = Some(*entry);
+		}
+
+		let intersect = |idom: &Vec<Option<usize>>, mut a: usize, mut b: usize| {
+			while a != b {
+				while rpo_index[a] > rpo_index[b] {
+					a = idom[a].expect("Processed block has a dominator");
+				}
+				while rpo_index[b] > rpo_index[a] {
+					b = idom[b].expect("Processed block has a dominator");
+				}
+			}
+			a
+		};
+
+		let mut changed = true;
+		while changed {
+			changed = false;
+			for block in rpo.iter().skip(1) {
+				let mut new_idom = None;
+				for pred in self.blocks[*block].preds.iter() {
+					if idom[*pred].is_none() {
+						continue;
+					}
+					new_idom = Some(match new_idom {
+						None => *pred,
+						Some(cur) => intersect(&idom, *pred, cur),
+					});
+				}
+				if new_idom.is_some() && idom[*block] != new_idom {
+					idom[*block] = new_idom;
+					changed = true;
+				}
+			}
+		}
+
+		self.idom = idom;
+	}
+
+	fn compute_loops(&mut self) {
+		let mut loops: Vec<Loop> = Vec::new();
+		for (latch, block) in self.blocks.iter().enumerate() {
+			for header in block.succs.iter() {
+				if !self.dominates(*header, latch) {
+					continue;
+				}
+				// Back edge found, collect the natural loop body by walking predecessors
+				let mut body = vec![*header];
+				let mut stack = vec![latch];
+				while let Some(b) = stack.pop() {
+					if body.contains(&b) {
+						continue;
+					}
+					body.push(b);
+					stack.extend(self.blocks[b].preds.iter().filter(|p| self.is_reachable(**p)));
+				}
+
+				if let Some(l) = loops.iter_mut().find(|l| l.header == *header) {
+					l.latches.push(latch);
+					l.body.extend(body);
+					l.body.sort_unstable();
+					l.body.dedup();
+				} else {
+					body.sort_unstable();
+					loops.push(Loop { header: *header, latches: vec![latch], body });
+				}
+			}
+		}
+		self.loops = loops;
+	}
+}
+
+fn split_blocks(code: Vec<IrCp>) -> Vec<BasicBlock> {
+	let mut blocks = Vec::new();
+	let mut cur = BasicBlock::new();
+
+	for cp in code {
+		let starts_block = matches!(cp, IrCp::Label(_)) && cur.code.iter().any(|cp| !matches!(cp, IrCp::Label(_)));
+		if starts_block || cur.terminator().is_some() {
+			blocks.push(std::mem::replace(&mut cur, BasicBlock::new()));
+		}
+		cur.code.push(cp);
+	}
+	if !cur.code.is_empty() || blocks.is_empty() {
+		blocks.push(cur);
+	}
+
+	blocks
+}
+
+pub trait IrPass {
+	fn name(&self) -> &'static str;
+	fn run(&mut self, cfg: &mut Cfg);
+}
+
+/// Runs a sequence of passes over every function body
+pub struct PassManager {
+	passes: Vec<Box<dyn IrPass>>,
+}
+
+impl PassManager {
+	pub fn new() -> Self {
+		Self { passes: Vec::new() }
+	}
+
+	pub fn add_pass<P: IrPass + 'static>(&mut self, pass: P) {
+		self.passes.push(Box::new(pass));
+	}
+
+	pub fn run(&mut self, ir: Ir) -> Ir {
+		let mut cfg = Cfg::build(ir);
+		for pass in self.passes.iter_mut() {
+			pass.run(&mut cfg);
+			cfg.rebuild();
+		}
+		cfg.into_ir()
+	}
+}
+
+impl Default for PassManager {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+/// Replaces `push reg1` immediately followed by `pop reg2` with a register move. Source
+/// locations in between are kept after the move.
+pub struct PushPopFolding;
+
+impl IrPass for PushPopFolding {
+	fn name(&self) -> &'static str {
+		"push-pop-folding"
+	}
+
+	fn run(&mut self, cfg: &mut Cfg) {
+		for block in cfg.blocks_mut() {
+			let code = std::mem::take(&mut block.code);
+			let mut opt = Vec::with_capacity(code.len());
+			let mut iter = code.into_iter().peekable();
+			while let Some(cp) = iter.next() {
+				if let IrCp::Push(IrOperand::Reg(push_reg)) = cp {
+					let mut locs = Vec::new();
+					while let Some(IrCp::SourceLoc(_, _)) = iter.peek() {
+						locs.push(iter.next().unwrap());
+					}
+					if let Some(IrCp::Pop(IrOperand::Reg(pop_reg))) = iter.peek() {
+						if push_reg != *pop_reg {
+							opt.push(IrCp::Move(IrOperand::Reg(*pop_reg), IrOperand::Reg(push_reg)));
+						}
+						iter.next();
+					} else {
+						opt.push(cp);
+					}
+					opt.append(&mut locs);
+					continue;
+				}
+				opt.push(cp);
+			}
+			block.code = opt;
+		}
+	}
+}
+
+/// Removes the blocks not reachable from the function entry
+pub struct DeadCodeElimination;
+
+impl IrPass for DeadCodeElimination {
+	fn name(&self) -> &'static str {
+		"dead-code-elimination"
+	}
+
+	fn run(&mut self, cfg: &mut Cfg) {
+		let reachable = (0..cfg.blocks.len()).map(|b| cfg.is_reachable(b)).collect::<Vec<_>>();
+		let mut index = 0;
+		cfg.blocks.retain(|_| {
+			index += 1;
+			reachable[index - 1]
+		});
+	}
+}
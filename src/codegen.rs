@@ -1,25 +1,87 @@
 use std::collections::HashMap;
-use crate::ir::{Ir, IrLabel, IrSignature, IrTable, IrDataChunk};
+use crate::ir::{Ir, IrCp, IrLabel, IrSignature, IrTable, IrDataChunk};
 
-pub(crate) const VM_DATA_TMP_0: i32 = 0x0000;
 pub(crate) const VM_DATA_MEM_ALLOC: i32 = 0x0100;
 pub(crate) const VM_DATA_MEM_TOTAL: i32 = 0x0108;
+// Stack pointer of the innermost entry trampoline, right after it saved the callee-saved registers
+pub(crate) const VM_DATA_ENTRY_SP: i32 = 0x0110;
 
+#[derive(Debug)]
 pub enum Relocation {
-	MemoryAbsolute64,
 	FunctionAbsoluteAddress,
 	LabelAbsoluteAddress(IrLabel),
 }
 
+/// Code location referring to a function, resolved at link time
+#[derive(Debug)]
+pub(crate) struct LinkTarget {
+	pub(crate) offset: usize,
+	pub(crate) func_index: u32,
+}
+
 pub struct CodeEmitter {
 	pub(crate) code: Vec<u8>,
 	pub(crate) labels: HashMap<IrLabel, usize>,
 	pub(crate) relocs: Vec<(Relocation, usize)>,
+	// Start offsets of the emitted machine instructions, in ascending order
+	pub(crate) insns: Vec<usize>,
+	// Instructions jumping to a label, with the displacement still to be resolved
+	pub(crate) branches: Vec<(usize, IrLabel)>,
+	// Offsets of the code generated for each IR instruction, used for the disassembly
+	pub(crate) ir_map: Vec<(usize, IrCp)>,
+	// Native offset, function index and Wasm bytecode offset, in ascending native offset order
+	pub(crate) source_map: Vec<(usize, u32, u32)>,
+	// Native offset from which the code is inlined at the call with the given function index and
+	// Wasm bytecode offset, or is not inlined, in ascending native offset order
+	pub(crate) call_sites: Vec<(usize, Option<(u32, u32)>)>,
+	// Calls to functions and function addresses, resolved by the code generator when linking
+	pub(crate) call_targets: Vec<LinkTarget>,
+	pub(crate) abs_off_targets: Vec<LinkTarget>,
 }
 
 impl CodeEmitter {
 	pub(crate) fn new() -> Self {
-		Self { code: Vec::new(), labels: HashMap::new(), relocs: Vec::new() }
+		Self { code: Vec::new(), labels: HashMap::new(), relocs: Vec::new(), insns: Vec::new(), branches: Vec::new(), ir_map: Vec::new(), source_map: Vec::new(), call_sites: Vec::new(), call_targets: Vec::new(), abs_off_targets: Vec::new() }
+	}
+
+	/// Marks the beginning of the next machine instruction
+	pub(crate) fn begin_insn(&mut self) {
+		if self.insns.last() != Some(&self.code.len()) {
+			self.insns.push(self.code.len());
+		}
+	}
+
+	/// Records the current instruction as a branch to `label`. The displacement is resolved by
+	/// the code generator when the code is finalized.
+	pub(crate) fn branch(&mut self, label: IrLabel) {
+		let insn = *self.insns.last().expect("Branch instruction has been started");
+		self.branches.push((insn, label));
+	}
+
+	/// Marks the beginning of the code generated for an IR instruction
+	pub(crate) fn ir_insn(&mut self, cp: &IrCp) {
+		match cp {
+			IrCp::Label(_) => (),
+			IrCp::SourceLoc(func_index, offset) => {
+				// Inlined functions make no calls, so a location in the caller ends the inlined code
+				if self.call_sites.last().is_some_and(|(_, site)| site.is_some_and(|(caller, _)| caller == *func_index)) {
+					self.call_sites.push((self.code.len(), None));
+				}
+				// Only the last location is kept if no code has been emitted since the previous one
+				if self.source_map.last().is_some_and(|(pc, _, _)| *pc == self.code.len()) {
+					self.source_map.pop();
+				}
+				self.source_map.push((self.code.len(), *func_index, *offset));
+			},
+			_ => {
+				if let IrCp::EnterInlinedFunction(_) = cp {
+					// The last location is the one of the call
+					let site = self.source_map.last().map(|(_, func_index, offset)| (*func_index, *offset));
+					self.call_sites.push((self.code.len(), site));
+				}
+				self.ir_map.push((self.code.len(), cp.clone()))
+			},
+		}
 	}
 
 	pub(crate) fn emit(&mut self, b: u8) {
@@ -54,12 +116,38 @@ impl CodeEmitter {
 		self.code.len()
 	}
 
+	/// Appends the code of a finished function. All the branches of the function must be
+	/// resolved.
+	pub(crate) fn append(&mut self, func: CodeEmitter) {
+		assert!(func.branches.is_empty(), "Function branches are resolved");
+		let base = self.code.len();
+		self.code.extend_from_slice(&func.code);
+		self.labels.extend(func.labels.into_iter().map(|(label, offset)| (label, base + offset)));
+		self.relocs.extend(func.relocs.into_iter().map(|(reloc, offset)| (reloc, base + offset)));
+		self.insns.extend(func.insns.into_iter().map(|offset| base + offset));
+		self.ir_map.extend(func.ir_map.into_iter().map(|(offset, cp)| (base + offset, cp)));
+		self.source_map.extend(func.source_map.into_iter().map(|(offset, func_index, wasm_offset)| (base + offset, func_index, wasm_offset)));
+		self.call_sites.extend(func.call_sites.into_iter().map(|(offset, site)| (base + offset, site)));
+		for (targets, func_targets) in [(&mut self.call_targets, func.call_targets), (&mut self.abs_off_targets, func.abs_off_targets)] {
+			targets.extend(func_targets.into_iter().map(|t| LinkTarget { offset: base + t.offset, func_index: t.func_index }));
+		}
+	}
+
 	pub(crate) fn labels_iter(&self) -> std::collections::hash_map::Iter<'_, IrLabel, usize> {
 		self.labels.iter()
 	}
 }
 
-pub trait CodeGenerator {
+/// Decodes the instruction at the beginning of the code, given its offset. Returns the length and
+/// the text of the instruction.
+pub type InsnDecoder = fn(&[u8], usize) -> Option<(usize, String)>;
+
+/// Code generator for a target architecture. Functions are compiled independently of each
+/// other, possibly on several threads at once, each into its own `CodeEmitter`, which is then
+/// appended to the code of the module.
+pub trait CodeGenerator: Sync {
+	/// Architecture the code runs on, as in `std::env::consts::ARCH`
+	fn target_arch(&self) -> &'static str;
 	fn build_offset_map(&self, ir_tables: &Vec<IrTable>, ir_chunks: &Vec<IrDataChunk>) -> OffsetMap {
 		let mut map = OffsetMap::new();
 		for table in ir_tables {
@@ -83,8 +171,15 @@ pub trait CodeGenerator {
 		}
 		map
 	}
-	fn compile_func(&mut self, code: &mut CodeEmitter, index: u32, body: Ir, signatures: &Vec<Option<IrSignature>>, offset_map: &OffsetMap);
-	fn link(&mut self, code: &mut CodeEmitter);
+	fn compile_func(&self, code: &mut CodeEmitter, index: u32, body: Ir, signatures: &Vec<Option<IrSignature>>, offset_map: &OffsetMap);
+	/// Finalizes the code of a single function, resolving the branches to its labels
+	fn finish_func(&self, code: &mut CodeEmitter);
+	/// Resolves the references between the functions of the module
+	fn link(&self, code: &mut CodeEmitter);
+	/// Instruction decoder for the generated code, if any
+	fn decoder(&self) -> Option<InsnDecoder> {
+		None
+	}
 	// fn apply_relocs(&m)
 }
 
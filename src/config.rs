@@ -0,0 +1,54 @@
+use crate::PvfError;
+
+/// Resource limits the PVF is checked against on translation and instantiation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExecutorConfig {
+	/// Linear memory pages the PVF may grow to. Memory declared without a maximum may grow up
+	/// to this limit, and a larger declared maximum is lowered to it.
+	pub max_memory_pages: u32,
+	/// Elements of every table
+	pub max_table_elements: u32,
+	/// Functions, including the imported ones
+	pub max_functions: u32,
+	/// Parameters and locals of every function
+	pub max_locals: u32,
+	/// Size of the code section in bytes
+	pub max_code_size: usize,
+	/// Total size of the data segments in bytes
+	pub max_data_bytes: usize,
+}
+
+impl Default for ExecutorConfig {
+	fn default() -> Self {
+		Self {
+			max_memory_pages: 2080,
+			max_table_elements: 65536,
+			max_functions: 100_000,
+			max_locals: 50_000,
+			max_code_size: 64 << 20,
+			max_data_bytes: 64 << 20,
+		}
+	}
+}
+
+/// Limit of [`ExecutorConfig`] a PVF exceeds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecutorLimit {
+	MemoryPages,
+	TableElements,
+	Functions,
+	Locals,
+	CodeSize,
+	DataBytes,
+}
+
+impl ExecutorLimit {
+	// Fails if `value` is above `max`
+	pub(crate) fn check(self, value: u64, max: u64) -> Result<(), PvfError> {
+		if value > max {
+			Err(PvfError::LimitExceeded { limit: self, value, max })
+		} else {
+			Ok(())
+		}
+	}
+}
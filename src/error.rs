@@ -1,13 +1,27 @@
 use wasmparser::BinaryReaderError;
 use std::error::Error;
+use crate::{trap::Backtrace, ExecutorLimit};
 
 #[derive(Debug)]
 pub enum PvfError {
 	FilesystemError(std::io::Error),
+	/// The PVF is compiled for the given architecture, not the host one
+	TargetMismatch(&'static str),
+	/// Creating or mapping the code or the memory of an instance failed
+	MemoryMapError(std::io::Error),
 	ParseError(BinaryReaderError),
 	ValidationError(String),
 	ExportNotFound,
 	UnresolvedImport(String),
+	Trap(Backtrace),
+	/// Host access to `len` byte(s) at `offset` outside of the `size` bytes of the linear memory
+	MemoryOutOfBounds { offset: usize, len: usize, size: usize },
+	/// Host write to an immutable global
+	ImmutableGlobal(String),
+	/// Host access to the element `index` outside of the `size` elements of a table
+	TableOutOfBounds { index: u32, size: u32 },
+	/// The PVF needs `value` of a resource limited to `max` by the executor configuration
+	LimitExceeded { limit: ExecutorLimit, value: u64, max: u64 },
 }
 
 impl From<BinaryReaderError> for PvfError {
@@ -18,7 +32,10 @@ impl From<BinaryReaderError> for PvfError {
 
 impl std::fmt::Display for PvfError {
 	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-		write!(f, "PVF Error: {:?}", self)
+		match self {
+			PvfError::Trap(backtrace) => write!(f, "PVF Error: Trap{}", backtrace),
+			_ => write!(f, "PVF Error: {:?}", self),
+		}
 	}
 }
 
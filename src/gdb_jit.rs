@@ -0,0 +1,594 @@
+use std::{cell::UnsafeCell, sync::Mutex};
+use crate::{PreparedPvf, ir::{IrCp, IrLabel}};
+
+// GDB JIT interface
+//
+// GDB learns about JIT code from an in-memory object file linked into the list anchored at
+// `__jit_debug_descriptor`, and sets a breakpoint on `__jit_debug_register_code` to notice the
+// list changes. The object built here is a minimal ELF with no code of its own: the `.text`
+// section has no contents and is placed at the address of the code segment, so GDB reads the
+// code from the process memory. It carries
+//
+// - a function symbol per function body and entry trampoline,
+// - the call frame information in `.debug_frame`, describing the internal function frames
+//   (see `EnterFunction` in the x86-64 code generator) and the trampoline frames, so GDB can
+//   unwind through the generated code,
+// - a line table mapping the code to the Wasm bytecode offsets, which are used as line numbers
+//   of a `pvf.wasm` source file.
+//
+// The call frame information is only generated for the x86-64 code.
+//
+// GDB looks the interface up by symbol name, and so does every other JIT in the process, e.g.
+// wasmtime or LLVM ORC, which define the same strong symbols. Two definitions fail to link, so
+// the symbols are only exported with the `gdb-jit` feature, off by default. Without it the
+// objects are still built and kept on the list, but GDB doesn't find them.
+
+const JIT_NOACTION: u32 = 0;
+const JIT_REGISTER_FN: u32 = 1;
+const JIT_UNREGISTER_FN: u32 = 2;
+
+#[repr(C)]
+struct JitCodeEntry {
+	next_entry: *mut JitCodeEntry,
+	prev_entry: *mut JitCodeEntry,
+	symfile_addr: *const u8,
+	symfile_size: u64,
+}
+
+#[repr(C)]
+struct JitDescriptor {
+	version: u32,
+	action_flag: u32,
+	relevant_entry: *mut JitCodeEntry,
+	first_entry: *mut JitCodeEntry,
+}
+
+#[repr(transparent)]
+struct JitDescriptorCell(UnsafeCell<JitDescriptor>);
+
+// SAFETY: The descriptor is only accessed with `REGISTRATION_LOCK` held
+unsafe impl Sync for JitDescriptorCell {}
+
+#[cfg_attr(feature = "gdb-jit", no_mangle)]
+#[allow(non_upper_case_globals)]
+static __jit_debug_descriptor: JitDescriptorCell = JitDescriptorCell(UnsafeCell::new(JitDescriptor {
+	version: 1, action_flag: JIT_NOACTION, relevant_entry: std::ptr::null_mut(), first_entry: std::ptr::null_mut(),
+}));
+
+static REGISTRATION_LOCK: Mutex<()> = Mutex::new(());
+
+#[cfg_attr(feature = "gdb-jit", no_mangle)]
+#[inline(never)]
+extern "C" fn __jit_debug_register_code() {
+	// GDB breaks here, the call must not be optimized out
+	unsafe { std::arch::asm!("", options(nomem, nostack, preserves_flags)) };
+}
+
+/// Object file registered with GDB, unregistered when dropped
+pub(crate) struct GdbJitRegistration {
+	entry: *mut JitCodeEntry,
+	_object: Vec<u8>,
+}
+
+// SAFETY: The entry is only accessed with `REGISTRATION_LOCK` held
+unsafe impl Send for GdbJitRegistration {}
+unsafe impl Sync for GdbJitRegistration {}
+
+impl GdbJitRegistration {
+	pub(crate) fn register(object: Vec<u8>) -> Self {
+		let _lock = REGISTRATION_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+		unsafe {
+			let descriptor = __jit_debug_descriptor.0.get();
+			let entry = Box::into_raw(Box::new(JitCodeEntry {
+				next_entry: (*descriptor).first_entry,
+				prev_entry: std::ptr::null_mut(),
+				symfile_addr: object.as_ptr(),
+				symfile_size: object.len() as u64,
+			}));
+			if !(*entry).next_entry.is_null() {
+				(*(*entry).next_entry).prev_entry = entry;
+			}
+			(*descriptor).first_entry = entry;
+			(*descriptor).relevant_entry = entry;
+			(*descriptor).action_flag = JIT_REGISTER_FN;
+			__jit_debug_register_code();
+			Self { entry, _object: object }
+		}
+	}
+
+	/// The object file as GDB sees it
+	pub(crate) fn object(&self) -> &[u8] {
+		unsafe { std::slice::from_raw_parts((*self.entry).symfile_addr, (*self.entry).symfile_size as usize) }
+	}
+}
+
+impl Drop for GdbJitRegistration {
+	fn drop(&mut self) {
+		let _lock = REGISTRATION_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+		unsafe {
+			let descriptor = __jit_debug_descriptor.0.get();
+			let entry = &mut *self.entry;
+			if entry.prev_entry.is_null() {
+				(*descriptor).first_entry = entry.next_entry;
+			} else {
+				(*entry.prev_entry).next_entry = entry.next_entry;
+			}
+			if !entry.next_entry.is_null() {
+				(*entry.next_entry).prev_entry = entry.prev_entry;
+			}
+			(*descriptor).relevant_entry = self.entry;
+			(*descriptor).action_flag = JIT_UNREGISTER_FN;
+			__jit_debug_register_code();
+			(*descriptor).relevant_entry = std::ptr::null_mut();
+			(*descriptor).action_flag = JIT_NOACTION;
+			drop(Box::from_raw(self.entry));
+		}
+	}
+}
+
+/// Whether the object of the code at `code_addr` is registered with GDB
+#[cfg(test)]
+pub(crate) fn is_registered(code_addr: usize) -> bool {
+	let _lock = REGISTRATION_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+	let mut entry = unsafe { (*__jit_debug_descriptor.0.get()).first_entry };
+	while !entry.is_null() {
+		let object = unsafe { std::slice::from_raw_parts((*entry).symfile_addr, (*entry).symfile_size as usize) };
+		if text_addr(object) == Some(code_addr) {
+			return true;
+		}
+		entry = unsafe { (*entry).next_entry };
+	}
+	false
+}
+
+// Address of the `.text` section, the second section of the objects built here
+#[cfg(test)]
+pub(crate) fn text_addr(object: &[u8]) -> Option<usize> {
+	let shoff = u64::from_le_bytes(object.get(0x28..0x30)?.try_into().ok()?) as usize;
+	let text = shoff + SHDR_SIZE;
+	Some(u64::from_le_bytes(object.get(text + 0x10..text + 0x18)?.try_into().ok()?) as usize)
+}
+
+// DWARF register numbers of x86-64
+const DW_REG_RBX: u8 = 3;
+const DW_REG_RBP: u8 = 6;
+const DW_REG_RSP: u8 = 7;
+const DW_REG_R12: u8 = 12;
+const DW_REG_R15: u8 = 15;
+const DW_REG_RA: u8 = 16;
+
+const DW_CFA_ADVANCE_LOC1: u8 = 0x02;
+const DW_CFA_ADVANCE_LOC2: u8 = 0x03;
+const DW_CFA_ADVANCE_LOC4: u8 = 0x04;
+const DW_CFA_REMEMBER_STATE: u8 = 0x0a;
+const DW_CFA_RESTORE_STATE: u8 = 0x0b;
+const DW_CFA_DEF_CFA: u8 = 0x0c;
+const DW_CFA_DEF_CFA_OFFSET: u8 = 0x0e;
+const DW_CFA_DEF_CFA_SF: u8 = 0x12;
+const DW_CFA_ADVANCE_LOC: u8 = 0x40;
+const DW_CFA_OFFSET: u8 = 0x80;
+const DW_CFA_NOP: u8 = 0x00;
+
+const DATA_ALIGNMENT: i64 = -8;
+
+fn uleb128(out: &mut Vec<u8>, mut value: u64) {
+	loop {
+		let byte = (value & 0x7f) as u8;
+		value >>= 7;
+		if value == 0 {
+			out.push(byte);
+			return;
+		}
+		out.push(byte | 0x80);
+	}
+}
+
+fn sleb128(out: &mut Vec<u8>, mut value: i64) {
+	loop {
+		let byte = (value & 0x7f) as u8;
+		value >>= 7;
+		if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+			out.push(byte);
+			return;
+		}
+		out.push(byte | 0x80);
+	}
+}
+
+// Call frame instructions of a frame description entry
+struct CfaProgram {
+	insns: Vec<u8>,
+	loc: usize,
+}
+
+impl CfaProgram {
+	fn new(start: usize) -> Self {
+		Self { insns: Vec::new(), loc: start }
+	}
+
+	fn advance(&mut self, loc: usize) -> &mut Self {
+		let delta = loc - self.loc;
+		match delta {
+			0 => (),
+			1..=0x3f => self.insns.push(DW_CFA_ADVANCE_LOC | delta as u8),
+			0x40..=0xff => self.insns.extend_from_slice(&[DW_CFA_ADVANCE_LOC1, delta as u8]),
+			0x100..=0xffff => {
+				self.insns.push(DW_CFA_ADVANCE_LOC2);
+				self.insns.extend_from_slice(&(delta as u16).to_le_bytes());
+			},
+			_ => {
+				self.insns.push(DW_CFA_ADVANCE_LOC4);
+				self.insns.extend_from_slice(&(delta as u32).to_le_bytes());
+			},
+		}
+		self.loc = loc;
+		self
+	}
+
+	// CFA = `reg` + `offset`
+	fn def_cfa(&mut self, reg: u8, offset: i64) -> &mut Self {
+		if offset >= 0 {
+			self.insns.push(DW_CFA_DEF_CFA);
+			uleb128(&mut self.insns, reg as u64);
+			uleb128(&mut self.insns, offset as u64);
+		} else {
+			self.insns.push(DW_CFA_DEF_CFA_SF);
+			uleb128(&mut self.insns, reg as u64);
+			sleb128(&mut self.insns, offset / DATA_ALIGNMENT);
+		}
+		self
+	}
+
+	fn def_cfa_offset(&mut self, offset: i64) -> &mut Self {
+		self.insns.push(DW_CFA_DEF_CFA_OFFSET);
+		uleb128(&mut self.insns, offset as u64);
+		self
+	}
+
+	// `reg` is saved at CFA + `offset`
+	fn offset(&mut self, reg: u8, offset: i64) -> &mut Self {
+		self.insns.push(DW_CFA_OFFSET | reg);
+		uleb128(&mut self.insns, (offset / DATA_ALIGNMENT) as u64);
+		self
+	}
+
+	fn remember_state(&mut self) -> &mut Self {
+		self.insns.push(DW_CFA_REMEMBER_STATE);
+		self
+	}
+
+	fn restore_state(&mut self) -> &mut Self {
+		self.insns.push(DW_CFA_RESTORE_STATE);
+		self
+	}
+}
+
+fn pad(out: &mut Vec<u8>, start: usize) {
+	while !(out.len() - start).is_multiple_of(8) {
+		out.push(DW_CFA_NOP);
+	}
+}
+
+// Builds the call frame information of the function bodies and the entry trampolines
+fn debug_frame(pvf: &PreparedPvf, code_addr: usize) -> Vec<u8> {
+	let mut out = Vec::new();
+
+	// Common information entry, the state on the function entry
+	out.extend_from_slice(&0u32.to_le_bytes()); // Length, patched
+	out.extend_from_slice(&0xffff_ffffu32.to_le_bytes()); // CIE id
+	out.push(3); // Version
+	out.push(0); // Augmentation
+	uleb128(&mut out, 1); // Code alignment factor
+	sleb128(&mut out, DATA_ALIGNMENT);
+	uleb128(&mut out, DW_REG_RA as u64);
+	let mut initial = CfaProgram::new(0);
+	initial.def_cfa(DW_REG_RSP, 8).offset(DW_REG_RA, -8);
+	out.extend_from_slice(&initial.insns);
+	pad(&mut out, 0);
+	let length = out.len() as u32 - 4;
+	out[0..4].copy_from_slice(&length.to_le_bytes());
+
+	let Some(decode) = pvf.decoder.filter(|_| cfg!(target_arch = "x86_64")) else { return out };
+	let insn_len = |pc: usize, mnemonic: &str| decode(&pvf.code[pc..], pc).filter(|(_, text)| text.starts_with(mnemonic)).map(|(len, _)| len);
+
+	let params = pvf.params();
+	for (start, size, _) in pvf.symbols() {
+		let end = start + size;
+		let func = pvf.labels.iter()
+			.find(|(label, offset)| **offset == start && matches!(label, IrLabel::AnonymousFunc(_) | IrLabel::ExportedFunc(_, _)))
+			.map(|(label, _)| label.clone());
+		let program = match func {
+			Some(IrLabel::AnonymousFunc(index)) => body_frame(pvf, start, end, params[index as usize], &insn_len),
+			Some(IrLabel::ExportedFunc(index, _)) => trampoline_frame(pvf, start, end, params[index as usize], decode),
+			_ => None,
+		};
+		let Some(program) = program else { continue };
+
+		let fde = out.len();
+		out.extend_from_slice(&0u32.to_le_bytes()); // Length, patched
+		out.extend_from_slice(&0u32.to_le_bytes()); // CIE offset
+		out.extend_from_slice(&((code_addr + start) as u64).to_le_bytes());
+		out.extend_from_slice(&(size as u64).to_le_bytes());
+		out.extend_from_slice(&program.insns);
+		pad(&mut out, fde);
+		let length = (out.len() - fde) as u32 - 4;
+		out[fde..fde + 4].copy_from_slice(&length.to_le_bytes());
+	}
+	out
+}
+
+// Function bodies: the prologue pushes rbx and rbp and points rbx right above the parameters,
+// where it stays until the epilogue restores it right before the return. The CFA is defined
+// relative to rbx in between, as rsp moves with the blocks and the value stack.
+fn body_frame(pvf: &PreparedPvf, start: usize, end: usize, n_params: u32, insn_len: &dyn Fn(usize, &str) -> Option<usize>) -> Option<CfaProgram> {
+	let cps = pvf.ir_map.iter().filter(|(offset, _)| (start..end).contains(offset)).collect::<Vec<_>>();
+	let frame = n_params as i64 * 8;
+	let mut program = CfaProgram::new(start);
+
+	let (enter, _) = cps.iter().find(|(_, cp)| matches!(cp, IrCp::EnterFunction(_)))?;
+	let mut pc = *enter + insn_len(*enter, "push rbx")?;
+	program.advance(pc).def_cfa_offset(16).offset(DW_REG_RBX, -16);
+	pc += insn_len(pc, "push rbp")?;
+	program.advance(pc).def_cfa_offset(24).offset(DW_REG_RBP, -24);
+	pc += insn_len(pc, "lea rbx")?;
+	program.advance(pc).def_cfa(DW_REG_RBX, -frame);
+
+	for (i, (leave, _)) in cps.iter().enumerate().filter(|(_, (_, cp))| matches!(cp, IrCp::LeaveFunction)) {
+		let mut pc = *leave + insn_len(*leave, "lea rsp")?;
+		pc += insn_len(pc, "pop rbp")?;
+		pc += insn_len(pc, "pop rbx")?;
+		program.advance(pc).remember_state().def_cfa(DW_REG_RSP, 8);
+		let (ret, _) = cps[i..].iter().find(|(_, cp)| matches!(cp, IrCp::Return))?;
+		program.advance(*ret + insn_len(*ret, "ret")?).restore_state();
+	}
+	Some(program)
+}
+
+// Entry trampolines: the callee-saved registers are pushed, then the arguments, which the
+// callee removes from the stack on return
+fn trampoline_frame(pvf: &PreparedPvf, start: usize, end: usize, n_params: u32, decode: crate::codegen::InsnDecoder) -> Option<CfaProgram> {
+	let mut program = CfaProgram::new(start);
+	let mut depth = 8;
+	let mut pc = start;
+	while pc < end {
+		let (len, text) = decode(&pvf.code[pc..], pc)?;
+		pc += len;
+		if let Some(reg) = text.strip_prefix("push ") {
+			depth += 8;
+			program.advance(pc).def_cfa_offset(depth);
+			let saved = match reg {
+				"r12" => Some(DW_REG_R12),
+				"r15" => Some(DW_REG_R15),
+				"rbx" => Some(DW_REG_RBX),
+				"rbp" => Some(DW_REG_RBP),
+				_ => None,
+			};
+			if let Some(reg) = saved.filter(|_| depth <= 40) {
+				program.offset(reg, -depth);
+			}
+		} else if text.starts_with("pop ") {
+			depth -= 8;
+			program.advance(pc).def_cfa_offset(depth);
+		} else if text.starts_with("call ") {
+			depth -= n_params as i64 * 8;
+			program.advance(pc).def_cfa_offset(depth);
+		} else if text.starts_with("ret") {
+			break;
+		}
+	}
+	Some(program)
+}
+
+const LINE_BASE: i8 = -5;
+const LINE_RANGE: u8 = 14;
+const OPCODE_BASE: u8 = 13;
+const DW_LNS_COPY: u8 = 0x01;
+const DW_LNS_ADVANCE_LINE: u8 = 0x03;
+const DW_LNE_END_SEQUENCE: u8 = 0x01;
+const DW_LNE_SET_ADDRESS: u8 = 0x02;
+
+const SOURCE_NAME: &str = "pvf.wasm";
+
+// Builds the line table, with the Wasm bytecode offsets as line numbers
+fn debug_line(pvf: &PreparedPvf, code_addr: usize) -> Vec<u8> {
+	let mut header = vec![
+		1, // Minimum instruction length
+		1, // Maximum operations per instruction
+		1, // Default is_stmt
+		LINE_BASE as u8,
+		LINE_RANGE,
+		OPCODE_BASE,
+	];
+	header.extend_from_slice(&[0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1]); // Standard opcode lengths
+	header.push(0); // No include directories
+	header.extend_from_slice(SOURCE_NAME.as_bytes());
+	header.extend_from_slice(&[0, 0, 0, 0]); // Terminator, directory, time, size
+	header.push(0); // File names end
+
+	let mut program = Vec::new();
+	let set_address = |program: &mut Vec<u8>, addr: usize| {
+		program.extend_from_slice(&[0, 9, DW_LNE_SET_ADDRESS]);
+		program.extend_from_slice(&(addr as u64).to_le_bytes());
+	};
+	let mut line = 1i64;
+	for (offset, _, wasm_offset) in &pvf.source_map {
+		set_address(&mut program, code_addr + offset);
+		program.push(DW_LNS_ADVANCE_LINE);
+		sleb128(&mut program, *wasm_offset as i64 - line);
+		line = *wasm_offset as i64;
+		program.push(DW_LNS_COPY);
+	}
+	set_address(&mut program, code_addr + pvf.code.len());
+	program.extend_from_slice(&[0, 1, DW_LNE_END_SEQUENCE]);
+
+	let mut out = Vec::new();
+	out.extend_from_slice(&((2 + 4 + header.len() + program.len()) as u32).to_le_bytes());
+	out.extend_from_slice(&4u16.to_le_bytes()); // Version
+	out.extend_from_slice(&(header.len() as u32).to_le_bytes());
+	out.extend_from_slice(&header);
+	out.extend_from_slice(&program);
+	out
+}
+
+const DW_TAG_COMPILE_UNIT: u8 = 0x11;
+const DW_AT_NAME: u8 = 0x03;
+const DW_AT_STMT_LIST: u8 = 0x10;
+const DW_AT_LOW_PC: u8 = 0x11;
+const DW_AT_HIGH_PC: u8 = 0x12;
+const DW_FORM_ADDR: u8 = 0x01;
+const DW_FORM_DATA8: u8 = 0x07;
+const DW_FORM_STRING: u8 = 0x08;
+const DW_FORM_SEC_OFFSET: u8 = 0x17;
+
+// A single compilation unit covering the code, referring to the line table
+fn debug_abbrev() -> Vec<u8> {
+	vec![
+		1, DW_TAG_COMPILE_UNIT, 0, // Abbreviation 1, no children
+		DW_AT_NAME, DW_FORM_STRING,
+		DW_AT_STMT_LIST, DW_FORM_SEC_OFFSET,
+		DW_AT_LOW_PC, DW_FORM_ADDR,
+		DW_AT_HIGH_PC, DW_FORM_DATA8,
+		0, 0, // Attributes end
+		0, // Abbreviations end
+	]
+}
+
+fn debug_info(code_addr: usize, code_len: usize) -> Vec<u8> {
+	let mut die = vec![1];
+	die.extend_from_slice(SOURCE_NAME.as_bytes());
+	die.push(0);
+	die.extend_from_slice(&0u32.to_le_bytes()); // Line table offset
+	die.extend_from_slice(&(code_addr as u64).to_le_bytes());
+	die.extend_from_slice(&(code_len as u64).to_le_bytes());
+
+	let mut out = Vec::new();
+	out.extend_from_slice(&((2 + 4 + 1 + die.len()) as u32).to_le_bytes());
+	out.extend_from_slice(&4u16.to_le_bytes()); // Version
+	out.extend_from_slice(&0u32.to_le_bytes()); // Abbreviations offset
+	out.push(8); // Address size
+	out.extend_from_slice(&die);
+	out
+}
+
+const EHDR_SIZE: usize = 64;
+const SHDR_SIZE: usize = 64;
+const SYM_SIZE: usize = 24;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_NOBITS: u32 = 8;
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+const STB_GLOBAL_STT_FUNC: u8 = 0x12;
+
+#[cfg(target_arch = "x86_64")]
+const ELF_MACHINE: u16 = 62; // EM_X86_64
+#[cfg(target_arch = "aarch64")]
+const ELF_MACHINE: u16 = 183; // EM_AARCH64
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const ELF_MACHINE: u16 = 0;
+
+struct Section {
+	name: &'static str,
+	kind: u32,
+	flags: u64,
+	addr: u64,
+	size: u64,
+	data: Vec<u8>,
+	link: u32,
+	info: u32,
+	entsize: u64,
+}
+
+impl Section {
+	fn data(name: &'static str, kind: u32, data: Vec<u8>) -> Self {
+		Self { name, kind, flags: 0, addr: 0, size: data.len() as u64, data, link: 0, info: 0, entsize: 0 }
+	}
+}
+
+/// Builds the object file describing the code of `pvf` loaded at `code_addr`
+pub(crate) fn build_object(pvf: &PreparedPvf, code_addr: usize) -> Vec<u8> {
+	let mut strtab = vec![0];
+	let mut symtab = vec![0; SYM_SIZE];
+	for (offset, size, name) in pvf.symbols() {
+		symtab.extend_from_slice(&(strtab.len() as u32).to_le_bytes());
+		symtab.push(STB_GLOBAL_STT_FUNC);
+		symtab.push(0); // Default visibility
+		symtab.extend_from_slice(&1u16.to_le_bytes()); // .text
+		symtab.extend_from_slice(&((code_addr + offset) as u64).to_le_bytes());
+		symtab.extend_from_slice(&(size as u64).to_le_bytes());
+		strtab.extend_from_slice(name.as_bytes());
+		strtab.push(0);
+	}
+
+	let sections = [
+		Section { name: ".text", kind: SHT_NOBITS, flags: SHF_ALLOC | SHF_EXECINSTR, addr: code_addr as u64, size: pvf.code.len() as u64, data: Vec::new(), link: 0, info: 0, entsize: 0 },
+		// All the symbols are global, the first one after the null symbol
+		Section { link: 3, info: 1, entsize: SYM_SIZE as u64, ..Section::data(".symtab", SHT_SYMTAB, symtab) },
+		Section::data(".strtab", SHT_STRTAB, strtab),
+		Section::data(".debug_frame", SHT_PROGBITS, debug_frame(pvf, code_addr)),
+		Section::data(".debug_line", SHT_PROGBITS, debug_line(pvf, code_addr)),
+		Section::data(".debug_abbrev", SHT_PROGBITS, debug_abbrev()),
+		Section::data(".debug_info", SHT_PROGBITS, debug_info(code_addr, pvf.code.len())),
+	];
+
+	let mut shstrtab = vec![0];
+	let mut names = Vec::new();
+	for section in sections.iter().map(|s| s.name).chain(std::iter::once(".shstrtab")) {
+		names.push(shstrtab.len() as u32);
+		shstrtab.extend_from_slice(section.as_bytes());
+		shstrtab.push(0);
+	}
+	let shstrtab = Section::data(".shstrtab", SHT_STRTAB, shstrtab);
+	let sections = sections.into_iter().chain(std::iter::once(shstrtab)).collect::<Vec<_>>();
+
+	// Header, section contents, section headers
+	let mut out = vec![0; EHDR_SIZE];
+	let mut offsets = Vec::new();
+	for section in &sections {
+		while !out.len().is_multiple_of(8) {
+			out.push(0);
+		}
+		offsets.push(out.len());
+		out.extend_from_slice(&section.data);
+	}
+	while !out.len().is_multiple_of(8) {
+		out.push(0);
+	}
+	let shoff = out.len();
+
+	out.extend_from_slice(&[0; SHDR_SIZE]);
+	for ((section, name), offset) in sections.iter().zip(names).zip(offsets) {
+		out.extend_from_slice(&name.to_le_bytes());
+		out.extend_from_slice(&section.kind.to_le_bytes());
+		out.extend_from_slice(&section.flags.to_le_bytes());
+		out.extend_from_slice(&section.addr.to_le_bytes());
+		out.extend_from_slice(&(offset as u64).to_le_bytes());
+		out.extend_from_slice(&section.size.to_le_bytes());
+		out.extend_from_slice(&section.link.to_le_bytes());
+		out.extend_from_slice(&section.info.to_le_bytes());
+		out.extend_from_slice(&(if section.kind == SHT_NOBITS { 16u64 } else { 1u64 }).to_le_bytes()); // Alignment
+		out.extend_from_slice(&section.entsize.to_le_bytes());
+	}
+
+	let mut ehdr = Vec::with_capacity(EHDR_SIZE);
+	ehdr.extend_from_slice(b"\x7fELF");
+	ehdr.extend_from_slice(&[2, 1, 1, 0]); // 64-bit, little endian, version 1, System V ABI
+	ehdr.extend_from_slice(&[0; 8]);
+	ehdr.extend_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+	ehdr.extend_from_slice(&ELF_MACHINE.to_le_bytes());
+	ehdr.extend_from_slice(&1u32.to_le_bytes()); // Version
+	ehdr.extend_from_slice(&0u64.to_le_bytes()); // Entry point
+	ehdr.extend_from_slice(&0u64.to_le_bytes()); // No program headers
+	ehdr.extend_from_slice(&(shoff as u64).to_le_bytes());
+	ehdr.extend_from_slice(&0u32.to_le_bytes()); // Flags
+	ehdr.extend_from_slice(&(EHDR_SIZE as u16).to_le_bytes());
+	ehdr.extend_from_slice(&0u16.to_le_bytes()); // Program header size
+	ehdr.extend_from_slice(&0u16.to_le_bytes()); // Program header count
+	ehdr.extend_from_slice(&(SHDR_SIZE as u16).to_le_bytes());
+	ehdr.extend_from_slice(&(sections.len() as u16 + 1).to_le_bytes());
+	ehdr.extend_from_slice(&(sections.len() as u16).to_le_bytes()); // .shstrtab is the last one
+	out[..EHDR_SIZE].copy_from_slice(&ehdr);
+	out
+}
@@ -1,35 +1,59 @@
-use std::collections::HashMap;
-use memmap::{MmapMut, Mmap};
-use crate::{PreparedPvf, PvfError, codegen::{self, Relocation}};
+use std::{marker::PhantomData, ops::Range, os::fd::AsRawFd, sync::Arc};
+use memmap::MmapMut;
+use crate::{PreparedPvf, LoadedPvf, ExecutorConfig, ExecutorLimit, PvfError, Value, ValueType, MemoryValue, codegen, trap, perf::ProfilingConfig};
 
-trait WasmType: Send {}
-impl WasmType for i32 {}
-impl WasmType for u32 {}
-impl WasmType for i64 {}
-impl WasmType for u64 {}
+trait WasmType: Send {
+	const TYPE: ValueType;
+}
+impl WasmType for i32 { const TYPE: ValueType = ValueType::I32; }
+impl WasmType for u32 { const TYPE: ValueType = ValueType::I32; }
+impl WasmType for i64 { const TYPE: ValueType = ValueType::I64; }
+impl WasmType for u64 { const TYPE: ValueType = ValueType::I64; }
 
-pub trait WasmResultType {}
-impl<T: WasmType> WasmResultType for T {}
-impl WasmResultType for () {}
+pub trait WasmResultType {
+	/// Wasm types of the results
+	fn value_types() -> Vec<ValueType>;
+}
+impl<T: WasmType> WasmResultType for T {
+	fn value_types() -> Vec<ValueType> {
+		vec![T::TYPE]
+	}
+}
+impl WasmResultType for () {
+	fn value_types() -> Vec<ValueType> {
+		vec![]
+	}
+}
 
 pub unsafe trait WasmParams: Send {
-	unsafe fn invoke<R: WasmResultType>(func: *const u8, args: Self) -> R;
+	/// Wasm types of the parameters
+	fn value_types() -> Vec<ValueType>;
+	/// Calls the entry trampoline `func` on the memory at `membase`
+	unsafe fn invoke<R: WasmResultType>(func: *const u8, membase: usize, args: Self) -> R;
 }
 
 unsafe impl<T: WasmType> WasmParams for T {
-	unsafe fn invoke<R: WasmResultType>(func: *const u8, args: Self) -> R {
-		<(T,) as WasmParams>::invoke::<R>(func, (args,))
+	fn value_types() -> Vec<ValueType> {
+		vec![T::TYPE]
+	}
+
+	unsafe fn invoke<R: WasmResultType>(func: *const u8, membase: usize, args: Self) -> R {
+		<(T,) as WasmParams>::invoke::<R>(func, membase, (args,))
 	}
 }
 
 macro_rules! impl_wasm_params {
     ($($t:ident)*) => {
         unsafe impl<$($t: WasmType,)*> WasmParams for ($($t,)*) {
+        	fn value_types() -> Vec<ValueType> {
+        		vec![$($t::TYPE),*]
+        	}
+
         	#[allow(non_snake_case)]
-            unsafe fn invoke<R: WasmResultType>(func: *const u8, args: Self) -> R {
-                let fnptr: unsafe extern "C" fn($($t,)*) -> R = std::mem::transmute(func);
+            unsafe fn invoke<R: WasmResultType>(func: *const u8, membase: usize, args: Self) -> R {
+                let fnptr: unsafe extern "C" fn(usize, $($t,)*) -> R = std::mem::transmute(func);
                 let ($($t,)*) = args;
-               	fnptr($($t,)*)
+               	fnptr(membase, $($t,)*)
             }
         }
     };
@@ -49,101 +73,404 @@ impl_wasm_params!(A0 A1 A2 A3 A4 A5 A6 A7 A8 A9);
 impl_wasm_params!(A0 A1 A2 A3 A4 A5 A6 A7 A8 A9 A10);
 impl_wasm_params!(A0 A1 A2 A3 A4 A5 A6 A7 A8 A9 A10 A11);
 
-fn offset_by(base: usize, offset: i32) -> usize {
-	if offset.is_negative() {
-		base - offset.abs() as usize
-	} else {
-		base + offset as usize
+// Calls a native function with the C calling convention, all the arguments are passed as
+// 64-bit integers. Fails without calling if there are more than 13 arguments.
+pub(crate) unsafe fn invoke_raw(func: *const u8, args: &[u64]) -> Result<u64, PvfError> {
+	macro_rules! call_with {
+		(@ty $i:tt) => { u64 };
+		($($i:tt)*) => {
+			{
+				let fnptr: unsafe extern "C" fn($(call_with!(@ty $i)),*) -> u64 = std::mem::transmute(func);
+				Ok(fnptr($(args[$i]),*))
+			}
+		};
+	}
+
+	match args.len() {
+		0 => call_with!(),
+		1 => call_with!(0),
+		2 => call_with!(0 1),
+		3 => call_with!(0 1 2),
+		4 => call_with!(0 1 2 3),
+		5 => call_with!(0 1 2 3 4),
+		6 => call_with!(0 1 2 3 4 5),
+		7 => call_with!(0 1 2 3 4 5 6),
+		8 => call_with!(0 1 2 3 4 5 6 7),
+		9 => call_with!(0 1 2 3 4 5 6 7 8),
+		10 => call_with!(0 1 2 3 4 5 6 7 8 9),
+		11 => call_with!(0 1 2 3 4 5 6 7 8 9 10),
+		12 => call_with!(0 1 2 3 4 5 6 7 8 9 10 11),
+		// Entry trampolines take the memory base along with the arguments
+		13 => call_with!(0 1 2 3 4 5 6 7 8 9 10 11 12),
+		n => Err(PvfError::ValidationError(format!("Calls with {} arguments are not supported", n))),
+	}
+}
+
+// Appends the runs of non-zero pages in the page-aligned `range` of `mem` to `runs`
+fn take_snapshot(runs: &mut Vec<(usize, Vec<u8>)>, mem: &[u8], range: Range<usize>) {
+	let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+	for (index, page) in mem[range.clone()].chunks(page_size).enumerate() {
+		if page.iter().all(|b| *b == 0) {
+			continue;
+		}
+		let offset = range.start + index * page_size;
+		match runs.last_mut() {
+			Some((start, bytes)) if *start + bytes.len() == offset => bytes.extend_from_slice(page),
+			_ => runs.push((offset, page.to_vec())),
+		}
+	}
+}
+
+/// Handle to an exported function whose signature was checked against the Rust types `P` and
+/// `R` when it was obtained with [`PvfInstance::get_typed_func`]. It's not tied to the instance,
+/// it can call the function on any instance of the same loaded code.
+pub struct TypedFunc<P, R> {
+	code: Arc<LoadedPvf>,
+	offset: usize,
+	_signature: PhantomData<fn(P) -> R>,
+}
+
+impl<P: WasmParams, R: WasmResultType> TypedFunc<P, R> {
+	/// Calls the function on `instance`, which is borrowed mutably because the code writes to
+	/// its memory
+	pub fn call(&self, instance: &mut PvfInstance, params: P) -> Result<R, PvfError> {
+		assert!(Arc::ptr_eq(&self.code, &instance.code), "Function is called on an instance of another PVF");
+		let (func_ptr, membase) = (self.code.func_ptr(self.offset), instance.membase);
+		// SAFETY: The parameter and result types match the signature of the function
+		unsafe { instance.catch_traps(|| P::invoke::<R>(func_ptr, membase, params)) }
+	}
+}
+
+/// View of an exported table of function references
+pub struct TableRef<'a> {
+	instance: &'a PvfInstance,
+	offset: usize,
+	size: u32,
+}
+
+impl<'a> TableRef<'a> {
+	pub fn size(&self) -> u32 {
+		self.size
+	}
+
+	/// Index of the function referenced by the element `index`, if it's not null
+	pub fn get(&self, index: u32) -> Result<Option<u32>, PvfError> {
+		if index >= self.size {
+			return Err(PvfError::TableOutOfBounds { index, size: self.size });
+		}
+		let slot = self.offset + index as usize * 8;
+		// Elements are the absolute addresses of the function bodies
+		let addr = u64::from_le_bytes(self.instance.memseg[slot..slot + 8].try_into().expect("Length is constant")) as usize;
+		if addr == 0 {
+			return Ok(None);
+		}
+		let offset = addr - self.instance.code.codeseg.as_ptr() as usize;
+		Ok(Some(*self.instance.code.func_offsets.get(&offset).expect("Table element refers to a function body")))
 	}
 }
 
 pub struct PvfInstance {
-	codeseg: Mmap,
+	code: Arc<LoadedPvf>,
 	memseg: MmapMut,
-	entry_points: HashMap<String, usize>
+	membase: usize,
+	entry_sp_slot: usize,
+	// Runs of non-zero pages of the memory segment right after the initialization, by offset.
+	// The rest of the segment is zero.
+	snapshot: Vec<(usize, Vec<u8>)>,
 }
 
 impl PvfInstance {
-	pub fn instantiate(pvf: &PreparedPvf) -> Self {
-		let mut memsize = 2 + pvf.tables_pages + pvf.data_segments_pages();
+	pub fn instantiate(pvf: &PreparedPvf) -> Result<Self, PvfError> {
+		Self::new(Arc::new(LoadedPvf::load(pvf)?))
+	}
+
+	/// Instantiates the PVF, making the code symbols available to the profilers enabled in
+	/// `profiling`
+	pub fn instantiate_with_profiling(pvf: &PreparedPvf, profiling: ProfilingConfig) -> Result<Self, PvfError> {
+		Self::new(Arc::new(LoadedPvf::load_with_profiling(pvf, profiling)?))
+	}
+
+	/// Instantiates the PVF, checking it against the resource limits of `config`, which may be
+	/// tighter than the ones it was translated with
+	pub fn instantiate_with_config(pvf: &PreparedPvf, config: &ExecutorConfig) -> Result<Self, PvfError> {
+		Self::new_with_config(Arc::new(LoadedPvf::load(pvf)?), config)
+	}
+
+	/// Creates an instance running the loaded code on its own memory
+	pub fn new(code: Arc<LoadedPvf>) -> Result<Self, PvfError> {
+		let max_pages = code.memory.1;
+		Self::create(code, max_pages)
+	}
 
-		if pvf.memory.1 > 0 {
-			memsize += pvf.memory.1;
+	/// Creates an instance running the loaded code on its own memory, checked against the
+	/// memory and table limits of `config`. The declared initial sizes are checked, as on
+	/// translation, and the memory cannot grow above the limit. The tables keep the size they
+	/// were translated with, which the generated code is compiled for. The limits on the module
+	/// contents are checked on translation.
+	pub fn new_with_config(code: Arc<LoadedPvf>, config: &ExecutorConfig) -> Result<Self, PvfError> {
+		ExecutorLimit::MemoryPages.check(code.memory.0 as u64, config.max_memory_pages as u64)?;
+		for (_, initial, _) in &code.tables {
+			ExecutorLimit::TableElements.check(*initial as u64, config.max_table_elements as u64)?;
 		}
 
-		let mut memseg_mmap = MmapMut::map_anon(memsize as usize * 0x10000).expect("Memory mmap do not fail to create");
-		let memaddr = (memseg_mmap[..]).as_ptr() as usize;
-		let membase = memaddr + (2 + pvf.tables_pages as usize + pvf.data_segments_pages() as usize) * 0x10000;
+		let max_pages = code.memory.1.min(config.max_memory_pages);
+		Self::create(code, max_pages)
+	}
+
+	fn create(code: Arc<LoadedPvf>, max_pages: u32) -> Result<Self, PvfError> {
+		let mut memseg_mmap = MmapMut::map_anon(code.membase_offset + max_pages as usize * 0x10000).map_err(PvfError::MemoryMapError)?;
+		let memaddr = memseg_mmap.as_ptr() as usize;
+		let membase = memaddr + code.membase_offset;
 
-		let vm_data_offset = offset_by(membase - memaddr, pvf.offset_map.vm_data());
-		println!("Setting PVF memory, initial {} page(s), max. {} page(s)", pvf.memory.0, pvf.memory.1);
-		(&mut memseg_mmap[vm_data_offset + codegen::VM_DATA_MEM_ALLOC as usize..vm_data_offset + codegen::VM_DATA_MEM_ALLOC as usize + 8]).copy_from_slice(&(pvf.memory.0 as u64).to_le_bytes()[..]);
-		(&mut memseg_mmap[vm_data_offset + codegen::VM_DATA_MEM_TOTAL as usize..vm_data_offset + codegen::VM_DATA_MEM_TOTAL as usize + 8]).copy_from_slice(&(pvf.memory.1 as u64).to_le_bytes()[..]);
+		println!("Setting PVF memory, initial {} page(s), max. {} page(s)", code.memory.0, max_pages);
+		let mem_alloc = code.vm_data_slot(codegen::VM_DATA_MEM_ALLOC);
+		memseg_mmap[mem_alloc..mem_alloc + 8].copy_from_slice(&(code.memory.0 as u64).to_le_bytes()[..]);
+		let mem_total = code.vm_data_slot(codegen::VM_DATA_MEM_TOTAL);
+		memseg_mmap[mem_total..mem_total + 8].copy_from_slice(&(max_pages as u64).to_le_bytes()[..]);
 
-		for (idx, chunk) in pvf.data_chunks.iter().enumerate() {
-			let chunk_offset = offset_by(membase - memaddr, pvf.offset_map.data_chunk(idx as u32));
-			(&mut memseg_mmap[chunk_offset..chunk_offset + chunk.len()]).copy_from_slice(&chunk[..]);
+		for (chunk_offset, chunk) in &code.data_chunks {
+			memseg_mmap[*chunk_offset..*chunk_offset + chunk.len()].copy_from_slice(&chunk[..]);
 		}
 
-		let len = (pvf.code_len() | 0xfff) + 1;
-		let mut codeseg_mmap = match MmapMut::map_anon(len) {
-			Ok(mmap) => mmap,
-			Err(e) => panic!("Cannot create memory map: {:?}", e)
-		};
-		(&mut codeseg_mmap[..pvf.code_len()]).copy_from_slice(pvf.code());
-
-		for (reloc, off) in &pvf.relocs {
-			match reloc {
-				Relocation::MemoryAbsolute64 => {
-					(&mut codeseg_mmap[*off..*off + 8]).copy_from_slice(&membase.to_le_bytes()[..]);
-				},
-				Relocation::FunctionAbsoluteAddress => {
-					let offset = usize::from_le_bytes(codeseg_mmap[*off..*off + 8][..].try_into().expect("Length is constant"));
-					let addr = (&codeseg_mmap[..]).as_ptr() as usize + offset;
-					(&mut codeseg_mmap[*off..*off + 8]).copy_from_slice(&addr.to_le_bytes()[..]);
-				},
-				Relocation::LabelAbsoluteAddress(label) => {
-					let offset = *pvf.labels.get(label).expect("Unresolved label");
-					let addr = (&codeseg_mmap[..]).as_ptr() as usize + offset;
-					(&mut codeseg_mmap[*off..*off + 8]).copy_from_slice(&addr.to_le_bytes()[..]);
-				}
+		if let Some((image, len)) = &code.memory_image {
+			// SAFETY: The image replaces the start of the linear memory inside the memory segment,
+			// which is unmapped as a whole when the segment is dropped
+			let addr = unsafe { libc::mmap(membase as *mut libc::c_void, *len, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_PRIVATE | libc::MAP_FIXED, image.as_raw_fd(), 0) };
+			if addr == libc::MAP_FAILED {
+				return Err(PvfError::MemoryMapError(std::io::Error::last_os_error()));
 			}
 		}
 
-        println!("ICODE: {:02X?}", &codeseg_mmap[..pvf.code_len()]);
+		println!("DATA SEGMENT AT {:X?}", memseg_mmap.as_ptr());
 
-		let codeseg_mmap = match codeseg_mmap.make_exec() {
-			Ok(mmap) => mmap,
-			Err(e) => panic!("Cannot make mmap executable: {:?}", e)
-		};
+		let entry_sp_slot = memaddr + code.vm_data_slot(codegen::VM_DATA_ENTRY_SP);
+		let mut instance = Self { code, memseg: memseg_mmap, membase, entry_sp_slot, snapshot: Vec::new() };
 
-		println!("CODE SEGMENT AT {:X?}, DATA SEGMENT AT {:X?}", &codeseg_mmap[..].as_ptr(), &memseg_mmap[..].as_ptr());
-
-		let entry_points = pvf.exported_funcs();
-		let init_off = entry_points.get("_pvf_init").expect("Init function found");
+		let init_off = *instance.code.entry_points.get("_pvf_init").expect("Init function found");
 		println!("INIT OFFEST: {}", init_off);
-		unsafe {
+		let res = unsafe {
 			// SAFERY: Init function was generated by codegen and is known to be safe
-			let init_fn: extern "C" fn() = std::mem::transmute(((&codeseg_mmap[..]).as_ptr() as usize + init_off) as *const u8);
-			init_fn();
-		}
+			let init_fn: extern "C" fn(usize) = std::mem::transmute(instance.code.func_ptr(init_off));
+			instance.catch_traps(|| init_fn(membase))
+		};
+		// A trap in the initialization, e.g. a segment out of bounds, fails the instantiation
+		res?;
 		println!("INIT DONE");
 
-		Self { codeseg: codeseg_mmap, memseg: memseg_mmap, entry_points }
+		// The initialization cannot grow the memory, so nothing above the initial memory is
+		// written yet. The memory image is restored from its file.
+		let initialized = instance.code.membase_offset + instance.code.memory.0 as usize * 0x10000;
+		let image = instance.image_range();
+		for range in [0..image.start, image.end..initialized] {
+			take_snapshot(&mut instance.snapshot, &instance.memseg, range);
+		}
+		Ok(instance)
+	}
+
+	/// Loaded code the instance runs, which can back more instances
+	pub fn code(&self) -> &Arc<LoadedPvf> {
+		&self.code
 	}
 
+	/// Restores the memory, the globals and the tables to their state right after the
+	/// initialization, so the instance can be reused instead of instantiating the PVF again. The
+	/// memory segment is discarded and only the non-zero pages of the initial state are copied
+	/// back, so the cost is proportional to the initialized data rather than the memory in use.
+	pub fn reset(&mut self) {
+		// Anonymous mappings are shared, so the pages are backed by shmem. `MADV_DONTNEED` would
+		// only unmap them, removing frees the backing store so that they read as zero again. The
+		// memory image mapping is private, and discarding its copied pages maps the file again.
+		let image = self.image_range();
+		for (range, advice) in [(0..image.start, libc::MADV_REMOVE), (image.clone(), libc::MADV_DONTNEED), (image.end..self.memseg.len(), libc::MADV_REMOVE)] {
+			if range.is_empty() {
+				continue;
+			}
+			// SAFETY: No references into the mapping are alive while `self` is borrowed mutably
+			let res = unsafe { libc::madvise(self.memseg.as_mut_ptr().add(range.start) as *mut libc::c_void, range.len(), advice) };
+			assert_eq!(res, 0, "Memory segment pages are discarded");
+		}
+		for (offset, bytes) in &self.snapshot {
+			self.memseg[*offset..*offset + bytes.len()].copy_from_slice(bytes);
+		}
+	}
+
+	/// Linear memory, as many pages as currently allocated. Calls into the PVF borrow the
+	/// instance mutably, as the code writes to the memory, so the slice can't be held across
+	/// them:
+	///
+	/// ```compile_fail
+	/// # use pvf_executor::{RawPvf, IntelX64Compiler, PvfInstance};
+	/// # let code = wat::parse_str(r#"(module (memory 1) (func (export "f")))"#).unwrap();
+	/// # let pvf = RawPvf::from_bytes(&code).translate().unwrap().compile(&IntelX64Compiler::new());
+	/// let mut instance = PvfInstance::instantiate(&pvf).unwrap();
+	/// let memory = instance.memory();
+	/// instance.call_dynamic("f", &[]).unwrap();
+	/// assert_eq!(memory[0], 0);
+	/// ```
+	pub fn memory(&self) -> &[u8] {
+		let start = self.code.membase_offset;
+		&self.memseg[start..start + self.memory_size()]
+	}
+
+	pub fn memory_mut(&mut self) -> &mut [u8] {
+		let (start, size) = (self.code.membase_offset, self.memory_size());
+		&mut self.memseg[start..start + size]
+	}
+
+	// Size of the linear memory in bytes, as grown by the code
+	fn memory_size(&self) -> usize {
+		let mem_alloc = self.code.vm_data_slot(codegen::VM_DATA_MEM_ALLOC);
+		let pages = u64::from_le_bytes(self.memseg[mem_alloc..mem_alloc + 8].try_into().expect("Length is constant"));
+		pages as usize * 0x10000
+	}
+
+	fn memory_range(&self, offset: usize, len: usize) -> Result<Range<usize>, PvfError> {
+		let size = self.memory_size();
+		match offset.checked_add(len) {
+			Some(end) if end <= size => Ok(offset..end),
+			_ => Err(PvfError::MemoryOutOfBounds { offset, len, size }),
+		}
+	}
+
+	pub fn read_bytes(&self, offset: usize, len: usize) -> Result<&[u8], PvfError> {
+		let range = self.memory_range(offset, len)?;
+		Ok(&self.memory()[range])
+	}
+
+	pub fn write_bytes(&mut self, offset: usize, data: &[u8]) -> Result<(), PvfError> {
+		let range = self.memory_range(offset, data.len())?;
+		self.memory_mut()[range].copy_from_slice(data);
+		Ok(())
+	}
+
+	pub fn read_value<T: MemoryValue>(&self, offset: usize) -> Result<T, PvfError> {
+		Ok(T::from_le_slice(self.read_bytes(offset, T::SIZE)?))
+	}
+
+	pub fn write_value<T: MemoryValue>(&mut self, offset: usize, value: T) -> Result<(), PvfError> {
+		let range = self.memory_range(offset, T::SIZE)?;
+		value.write_le_slice(&mut self.memory_mut()[range]);
+		Ok(())
+	}
+
+	/// Linear memory exported as `name`. Like [`Self::memory`], it can't be held across calls.
+	pub fn exported_memory(&self, name: &str) -> Result<&[u8], PvfError> {
+		if !self.code.exported_memories.iter().any(|export| export == name) {
+			return Err(PvfError::ExportNotFound);
+		}
+		Ok(self.memory())
+	}
+
+	pub fn exported_memory_mut(&mut self, name: &str) -> Result<&mut [u8], PvfError> {
+		if !self.code.exported_memories.iter().any(|export| export == name) {
+			return Err(PvfError::ExportNotFound);
+		}
+		Ok(self.memory_mut())
+	}
+
+	/// Table exported as `name`
+	pub fn exported_table(&self, name: &str) -> Result<TableRef<'_>, PvfError> {
+		let index = *self.code.exported_tables.get(name).ok_or(PvfError::ExportNotFound)?;
+		let (offset, _, size) = self.code.tables[index as usize];
+		Ok(TableRef { instance: self, offset, size })
+	}
+
+	/// Value of an exported global
+	pub fn get_global(&self, name: &str) -> Result<Value, PvfError> {
+		let (index, ty, _) = *self.code.exported_globals.get(name).ok_or(PvfError::ExportNotFound)?;
+		let slot = self.code.globals_offset + index as usize * 8;
+		let bits = u64::from_le_bytes(self.memseg[slot..slot + 8].try_into().expect("Length is constant"));
+		Ok(Value::from_bits(ty, bits))
+	}
+
+	/// Sets an exported mutable global
+	pub fn set_global(&mut self, name: &str, value: Value) -> Result<(), PvfError> {
+		let (index, ty, mutable) = *self.code.exported_globals.get(name).ok_or(PvfError::ExportNotFound)?;
+		if !mutable {
+			return Err(PvfError::ImmutableGlobal(name.to_owned()));
+		}
+		if value.ty() != ty {
+			return Err(PvfError::ValidationError(format!("Global {} is {:?}, {:?} given", name, ty, value.ty())));
+		}
+		let slot = self.code.globals_offset + index as usize * 8;
+		self.memseg[slot..slot + 8].copy_from_slice(&value.to_bits().to_le_bytes());
+		Ok(())
+	}
+
+	/// ELF object describing the code to GDB, if registered. It can be written to a file to
+	/// inspect the symbols and the debug information with the usual tools.
+	pub fn gdb_jit_object(&self) -> Option<&[u8]> {
+		self.code.gdb_jit_object()
+	}
+
+	// Range of the memory segment the memory image is mapped to
+	fn image_range(&self) -> Range<usize> {
+		let len = self.code.memory_image.as_ref().map_or(0, |(_, len)| *len);
+		self.code.membase_offset..self.code.membase_offset + len
+	}
+
+	// Runs the generated code, turning the traps into errors
+	unsafe fn catch_traps<R>(&self, f: impl FnOnce() -> R) -> Result<R, PvfError> {
+		trap::catch_traps(&self.code.codeseg[..], self.entry_sp_slot, &self.code.frame_map, f).map_err(PvfError::Trap)
+	}
+
+	/// Calls the exported function `func` without checking its signature
+	///
+	/// # Safety
+	///
+	/// `P` and `R` must match the signature of the function. As the code writes to the memory
+	/// through a shared borrow, no slice of the memory may be alive during the call, and the
+	/// instance must not be called from other threads at the same time.
 	pub unsafe fn call<F, P, R>(&self, func: F, params: P) -> Result<R, PvfError>
 		where F: AsRef<str> + std::fmt::Display, P: WasmParams, R: WasmResultType
 	{
-		if let Some(offset) = self.entry_points.get(&func.to_string()) {
+		if let Some(offset) = self.code.entry_points.get(&func.to_string()) {
 			println!("CALL OFFSET {}", *offset);
-			let func_ptr = ((&self.codeseg[..]).as_ptr() as usize + *offset) as *const u8;
-			// Ok(P::invoke::<R>(func_ptr, params))
-			let res = P::invoke::<R>(func_ptr, params);
+			let func_ptr = self.code.func_ptr(*offset);
+			let res = self.catch_traps(|| P::invoke::<R>(func_ptr, self.membase, params));
 			println!("CALL DONE");
-			Ok(res)
+			res
 		} else {
 			Err(PvfError::ExportNotFound)
 		}
 	}
+
+	/// Looks up an exported function and checks its signature against the Rust types once, so
+	/// that the returned handle can be called repeatedly without the checks
+	pub fn get_typed_func<P: WasmParams, R: WasmResultType>(&self, func: &str) -> Result<TypedFunc<P, R>, PvfError> {
+		let (Some(offset), Some(signature)) = (self.code.entry_points.get(func), self.code.export_signatures.get(func)) else {
+			return Err(PvfError::ExportNotFound);
+		};
+		let (params, results) = (P::value_types(), R::value_types());
+		if params != signature.param_types || results != signature.result_types {
+			return Err(PvfError::ValidationError(format!("{} has signature {:?} -> {:?}, {:?} -> {:?} requested", func, signature.param_types, signature.result_types, params, results)));
+		}
+		Ok(TypedFunc { code: self.code.clone(), offset: *offset, _signature: PhantomData })
+	}
+
+	/// Calls an exported function with the arguments checked against its signature at run time.
+	/// The instance is borrowed mutably because the code writes to its memory.
+	pub fn call_dynamic(&mut self, func: &str, args: &[Value]) -> Result<Vec<Value>, PvfError> {
+		let (Some(offset), Some(signature)) = (self.code.entry_points.get(func), self.code.export_signatures.get(func)) else {
+			return Err(PvfError::ExportNotFound);
+		};
+		if args.len() != signature.param_types.len() {
+			return Err(PvfError::ValidationError(format!("{} expects {} argument(s), {} given", func, signature.param_types.len(), args.len())));
+		}
+		for (i, (arg, ty)) in args.iter().zip(signature.param_types.iter()).enumerate() {
+			if arg.ty() != *ty {
+				return Err(PvfError::ValidationError(format!("{} argument {} is {:?}, {:?} given", func, i, ty, arg.ty())));
+			}
+		}
+		if signature.result_types.len() > 1 {
+			return Err(PvfError::ValidationError(format!("{} has multiple results", func)));
+		}
+
+		let args = std::iter::once(self.membase as u64).chain(args.iter().map(|arg| arg.to_bits())).collect::<Vec<_>>();
+		let func_ptr = self.code.func_ptr(*offset);
+		// SAFETY: The arguments match the signature of the function. The entry trampoline takes
+		// all of them from the integer registers and the stack, and returns the result in rax.
+		let bits = unsafe { self.catch_traps(|| invoke_raw(func_ptr, &args)) }??;
+		Ok(signature.result_types.iter().map(|ty| Value::from_bits(*ty, bits)).collect())
+	}
 }
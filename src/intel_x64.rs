@@ -1,6 +1,6 @@
 use std::matches;
 
-use crate::{CodeGenerator, codegen::{self, CodeEmitter, Relocation, OffsetMap}, ir::{Ir, IrReg, IrReg::*, IrCp::*, IrOperand::*, IrCond, IrCond::*, IrLabel, IrSignature}};
+use crate::{CodeGenerator, intel_x64_peephole, intel_x64_disasm, intel_x64_asm::{Assembler, Gpr, Mem, Imm, Cond, Width}, codegen::{self, CodeEmitter, LinkTarget, Relocation, OffsetMap, InsnDecoder}, ir::{Ir, IrReg, IrReg::*, IrCp::*, IrOperand, IrOperand::*, IrCond, IrCond::*, IrLabel, IrSignature}};
 
 // Memory segment map
 //
@@ -29,19 +29,17 @@ use crate::{CodeGenerator, codegen::{self, CodeEmitter, Relocation, OffsetMap},
 //                 +-------------------+
 
 pub struct IntelX64Compiler {
-	call_targets: Vec<LinkTarget>,
-	abs_off_targets: Vec<LinkTarget>,
-	map_sra: u8,
-	map_src: u8,
-	map_srd: u8,
+	map_sra: Gpr,
+	map_src: Gpr,
+	map_srd: Gpr,
 }
 
 impl IntelX64Compiler {
 	pub fn new() -> Self {
-		Self { call_targets: Vec::new(), abs_off_targets: Vec::new(), map_sra: AX, map_src: CX, map_srd: DX }
+		Self { map_sra: Gpr::RAX, map_src: Gpr::RCX, map_srd: Gpr::RDX }
 	}
 
-	fn reg(&self, r: &IrReg) -> u8 {
+	fn reg(&self, r: &IrReg) -> Gpr {
 		match r {
 			Sra => self.map_sra,
 			Src => self.map_src,
@@ -50,289 +48,200 @@ impl IntelX64Compiler {
 	}
 }
 
-#[derive(Debug)]
-struct LinkTarget {
-	offset: usize,
-	func_index: u32,
-}
-
-const AX: u8 = 0;
-const CX: u8 = 1;
-const DX: u8 = 2;
-const BX: u8 = 3;
-const SP: u8 = 4;
-const BP: u8 = 5;
-const SI: u8 = 6;
-const DI: u8 = 7;
-const R8: u8 = 0;
-const R9: u8 = 1;
-const R10: u8 = 2;
-const R11: u8 = 3;
-const R12: u8 = 4;
-const R15: u8 = 7;
-
-const REX_B: u8 = 0x41;
-const REX_X: u8 = 0x42;
-const REX_R: u8 = 0x44;
-const REX_W: u8 = 0x48;
-
-const MOD_RM: u8 = 0x00;
-const MOD_DISP8: u8 = 0x40;
-const MOD_DISP32: u8 = 0x80;
-const MOD_REG: u8 = 0xc0;
-
-const MOD_SIB: u8 = 0x4;
-const MOD_RIPREL: u8 = 0x5;
+const ABI_PARAM_REGS: [Gpr; 6] = [Gpr::RDI, Gpr::RSI, Gpr::RDX, Gpr::RCX, Gpr::R8, Gpr::R9];
 
-const SIB1: u8 = 0x00;
-const SIB2: u8 = 0x40;
-const SIB4: u8 = 0x80;
-const SIB8: u8 = 0xc0;
+// Return address, saved rbx and saved rbp between the parameters and the locals
+const FRAME_SLOTS: i32 = 3;
 
-const OPER_SIZE_OVR: u8 = 0x66;
-const REP: u8 = 0xf3;
-
-const ABI_PARAM_REGS: [(u8, u8); 6] = [(0, DI), (0, SI), (0, DX), (0, CX), (REX_R, R8), (REX_R, R9)];
-
-const fn native_cond(cond: &IrCond) -> u8 {
+const fn native_cond(cond: &IrCond) -> Cond {
 	match cond {
-		Zero => 0x04,
-		NotZero => 0x05,
-		Equal => 0x04,
-		NotEqual => 0x05,
-		LessSigned => 0x0c,
-		LessUnsigned => 0x02,
-		GreaterSigned => 0x0f,
-		GreaterUnsigned => 0x07,
-		LessOrEqualSigned => 0x0e,
-		LessOrEqualUnsigned => 0x06,
-		GreaterOrEqualSigned => 0x0d,
-		GreaterOrEqualUnsigned => 0x03,
+		Zero => Cond::E,
+		NotZero => Cond::Ne,
+		Equal => Cond::E,
+		NotEqual => Cond::Ne,
+		LessSigned => Cond::L,
+		LessUnsigned => Cond::B,
+		GreaterSigned => Cond::G,
+		GreaterUnsigned => Cond::A,
+		LessOrEqualSigned => Cond::Le,
+		LessOrEqualUnsigned => Cond::Be,
+		GreaterOrEqualSigned => Cond::Ge,
+		GreaterOrEqualUnsigned => Cond::Ae,
 	}
 }
 
-struct JmpTarget(usize, IrLabel);
+const fn width(op: &IrOperand) -> Width {
+	match op {
+		Reg8(_) => Width::W8,
+		Reg16(_) => Width::W16,
+		Reg32(_) => Width::W32,
+		_ => Width::W64,
+	}
+}
 
 impl CodeGenerator for IntelX64Compiler {
-	fn compile_func(&mut self, code: &mut CodeEmitter, index: u32, body: Ir, signatures: &Vec<Option<IrSignature>>, offset_map: &OffsetMap) {
-		macro_rules! emit {
-			($($e:expr),*) => { { $(code.emit($e));* } }
-		}
+	fn target_arch(&self) -> &'static str {
+		"x86_64"
+	}
 
-		macro_rules! emit_with_offset {
-			($($e:expr),* ; $modrm:expr $(, $sib:expr)? ; $offset:expr) => {
-				{
-					emit!($($e),*);
-					if $offset < i8::MIN as i32 || $offset > i8::MAX as i32 {
-						emit!(MOD_DISP32 | $modrm $(, $sib)?);
-						code.emit_imm32_le($offset);
-					} else {
-						emit!(MOD_DISP8 | $modrm $(, $sib)?, $offset as u8);
-					}
-				}
-			}
-		}
+	fn compile_func(&self, code: &mut CodeEmitter, index: u32, body: Ir, signatures: &Vec<Option<IrSignature>>, offset_map: &OffsetMap) {
+		let mut asm = Assembler::new(code);
 
-		macro_rules! emit_maybe_rexw {
-			($rexw:expr, $($e:expr),*) => {
-				{
-					if $rexw {
-						emit!(REX_W);
-					}
-					emit!($($e),*)
-				}
-			}
-		}
-
-		let mut jmp_targets = Vec::new();
 		println!("S {:?}", signatures);
 		let self_signature = signatures[index as usize].as_ref().expect("Self signature available");
+		let n_params = self_signature.params;
+		let local_offset = |index: u32| {
+			if index < n_params {
+				-(index as i32 + 1) * 8
+			} else {
+				-(index as i32 + 1 + FRAME_SLOTS) * 8
+			}
+		};
 
 		for insn in body.code() {
+			asm.ir_insn(insn);
 			match insn {
-				Label(label) => code.label(label.clone()),
-				EnterFunction(n_locals) => {
-					emit!(REX_B, 0x50 | R12); // push r12
-					emit!(REX_B, 0x50 | R15); // push r15
-
-					emit!(REX_W | REX_B, 0xb8 | R15); // movabs r15, imm64
-					code.reloc(Relocation::MemoryAbsolute64);
-					code.emit_imm64_le(0);
-
-					emit!(0x50 | BX); // push rbx
-					emit!(0x50 | BP); // push rbp
-
-					emit!(REX_W, 0x89, MOD_REG | SP << 3 | BX); // mov rbx, rsp
-					emit!(REX_W, 0x89, MOD_REG | SP << 3 | BP); // mov rbp, rsp
-
-					let n_params = self_signature.params;
-					let n_total = n_locals + n_params;
-
-					if n_total > 0 {
-						// FIXME: Long offsets
-						// emit!(REX_W, 0x83, MOD_REG | 0x0 << 3 | SP, n_total as u8 * 8); // add rsp, ntot*8
-						let n_reg_params = std::cmp::min(n_params, ABI_PARAM_REGS.len() as u32);
-						let n_stack_params = n_params.saturating_sub(ABI_PARAM_REGS.len() as u32);
-
-						for i in 0..n_reg_params as usize {
-							if ABI_PARAM_REGS[i].0 > 0 {
-								// emit!(ABI_PARAM_REGS[i].0);
-								emit!(REX_B); // FIXME: hack
+				Label(label) => {
+					if let IrLabel::ExportedFunc(findex, _) = label {
+						asm.label(label.clone());
+						// System V ABI entry trampoline. Saves the callee-saved registers used by the
+						// generated code, pins the memory base passed as the first argument to r15
+						// and pushes the rest of the arguments to the stack as the internal calling
+						// convention requires. The code does not depend on the memory it runs on.
+						asm.push(Gpr::R12);
+						asm.push(Gpr::R15);
+						asm.push(Gpr::RBX);
+						asm.push(Gpr::RBP);
+						asm.mov(Width::W64, Gpr::R15, ABI_PARAM_REGS[0]);
+						// The trap handler unwinds to here, restoring the saved registers and
+						// returning to the caller
+						asm.mov(Width::W64, Mem::base(Gpr::R15, offset_map.vm_data() + codegen::VM_DATA_ENTRY_SP), Gpr::RSP);
+
+						for i in 0..n_params as usize {
+							let abi_index = i + 1;
+							if abi_index < ABI_PARAM_REGS.len() {
+								asm.push(ABI_PARAM_REGS[abi_index]);
+							} else {
+								// The on-stack argument is above the return address, four saved
+								// registers, and `i` arguments already pushed
+								asm.push(Mem::base(Gpr::RSP, (i + 5 + abi_index - ABI_PARAM_REGS.len()) as i32 * 8));
 							}
-							emit!(0x50 | ABI_PARAM_REGS[i].1); // push <abi_reg>
 						}
 
-						if n_stack_params > 0 {
-							// After the last on-stack argument, there were pushed:
-							// - return address (by `call`)
-							// - r12 (in `EnterFunction`)
-							// - r15 (in `EnterFunction`)
-							// - rbx (in `EnterFunction`)
-							// - rbp (in `EnterFunction`)
-							let mut caller_frame_off = 5i32 * 8;
+						let offset = asm.call_rel32();
+						asm.call_targets.push(LinkTarget { offset, func_index: *findex });
 
-							for _ in 0..n_stack_params {
-								emit_with_offset!(REX_W, 0x8b ; AX << 3 | BX ; caller_frame_off); // mov rax, [rbx+off]
-								emit!(0x50 | AX); // push rax
-								caller_frame_off += 8;
-							}
-						}
+						asm.pop(Gpr::RBP);
+						asm.pop(Gpr::RBX);
+						asm.pop(Gpr::R15);
+						asm.pop(Gpr::R12);
+						asm.ret(0);
 
-						if *n_locals > 0 {
-							// All the locals are guaranteed to be initialized to zero
-							emit!(0x31, MOD_REG | AX << 3 | AX); // xor eax, eax
-
-							for _ in 0..*n_locals {
-								emit!(0x50 | AX); // push rax
-							}
+						asm.label(IrLabel::AnonymousFunc(*findex));
+					} else {
+						asm.label(label.clone());
+					}
+				},
+				EnterFunction(n_locals) => {
+					// Internal calling convention: arguments are pushed to the stack by the caller
+					// in order and are removed by the callee on return. The memory base is kept
+					// in r15 all the time.
+					//
+					// Function frame layout:
+					//
+					//       +-------------------+
+					//       | Param0            | <- rbx - 8
+					//      ~~~~~~~~~~~~~~~~~~~~~~~
+					//       | ParamN            |
+					//       +-------------------+
+					//       | Return address    |
+					//       | Saved rbx         |
+					//       | Saved rbp         | <- rbp
+					//       +-------------------+
+					//       | Local0            |
+					//      ~~~~~~~~~~~~~~~~~~~~~~~
+					//       | LocalN            |
+					//       +-------------------+
+					asm.push(Gpr::RBX);
+					asm.push(Gpr::RBP);
+					asm.lea(Width::W64, Gpr::RBX, Mem::base(Gpr::RSP, (n_params as i32 + FRAME_SLOTS) * 8));
+					asm.mov(Width::W64, Gpr::RBP, Gpr::RSP);
+
+					if *n_locals > 0 {
+						// All the locals are guaranteed to be initialized to zero
+						asm.xor(Width::W32, Gpr::RAX, Gpr::RAX);
+
+						for _ in 0..*n_locals {
+							asm.push(Gpr::RAX);
 						}
 					}
 				}
 				LeaveFunction => {
-					emit!(REX_W, 0x89, MOD_REG | BX << 3 | SP); // mov rsp, rbx
-					emit!(0x58 | BP); // pop rbp
-					emit!(0x58 | BX); // pop rbx
-					emit!(REX_B, 0x58 | R15); // pop r15
-					emit!(REX_B, 0x58 | R12); // pop r12
+					asm.lea(Width::W64, Gpr::RSP, Mem::base(Gpr::RBX, -(n_params as i32 + FRAME_SLOTS) * 8));
+					asm.pop(Gpr::RBP);
+					asm.pop(Gpr::RBX);
 				}
 				EnterBlock => {
-					emit!(0x50 | BP); // push rbp
-					emit!(REX_W, 0x89, MOD_REG | SP << 3 | BP); // mov rbp, rsp
+					asm.push(Gpr::RBP);
+					asm.mov(Width::W64, Gpr::RBP, Gpr::RSP);
 				}
 				LeaveBlock => {
-					emit!(REX_W, 0x89, MOD_REG | BP << 3 | SP); // mov rsp, rbp
-					emit!(0x58 | BP); // pop rbp
+					asm.mov(Width::W64, Gpr::RSP, Gpr::RBP);
+					asm.pop(Gpr::RBP);
+				}
+				EnterInlinedFunction(frame_local) => {
+					// The inlined function may return from inside its nested blocks, so the frame
+					// pointer is kept in a local rather than relying on the rbp chain
+					asm.push(Gpr::RBP);
+					asm.mov(Width::W64, Gpr::RBP, Gpr::RSP);
+					asm.mov(Width::W64, Mem::base(Gpr::RBX, local_offset(*frame_local)), Gpr::RSP);
+				}
+				LeaveInlinedFunction(frame_local) => {
+					asm.mov(Width::W64, Gpr::RSP, Mem::base(Gpr::RBX, local_offset(*frame_local)));
+					asm.pop(Gpr::RBP);
 				}
 				Push(op) => {
 					match op {
-						Reg(r) => emit!(0x50 | self.reg(r)), // push <reg>
+						Reg(r) => asm.push(self.reg(r)),
 						_ => unreachable!()
 					}
 				},
 				Pop(op) => {
 					match op {
-						Reg(r) => emit!(0x58 | self.reg(r)), // pop <reg>
+						Reg(r) => asm.pop(self.reg(r)),
 						_ => unreachable!()
 					}
 				},
 				Move(dest, src) => {
 					match (dest, src) {
-						(Reg(rdest), Reg(rsrc)) => {
-							// mov <dreg>, <sreg>
-							emit!(REX_W, 0x89, MOD_REG | self.reg(rsrc) << 3 | self.reg(rdest));
-						},
-						(Reg(rdest), Imm32(imm)) | (Reg32(rdest), Imm32(imm)) => {
-							// mov <dreg>, <imm32>
-							emit!(0xb8 | self.reg(rdest));
-							code.emit_imm32_le(*imm);
-						},
-						(Reg(rdest), Imm64(imm)) => {
-							// mov <dreg>, <imm64>
-							if *imm > 0 && *imm < u32::MAX as i64 {
-								emit!(0xb8 | self.reg(rdest)); // movabs <rdest32>, <imm32>
-								code.emit_imm32_le(*imm as i32);
-							} else {
-								emit!(REX_W, 0xb8 | self.reg(rdest)); // movabs <rdest>, <imm64> 
-								code.emit_imm64_le(*imm);
-							}
-						},
-						(Reg(rdest), Local(index)) => {
-							// mov <dreg>, [ffp-local_off]
-							if *index < 15 {
-								emit!(REX_W, 0x8b, MOD_DISP8 | self.reg(rdest) << 3 | BX, -((*index as i8 + 1) * 8) as u8);
-							} else {
-								emit!(REX_W, 0x8b, MOD_DISP32 | self.reg(rdest) << 3 | BX);
-								code.emit_imm32_le(-(*index as i32 + 1) * 8);
-							}
-						},
-						(Reg(rdest), Global(index)) => {
-							let offset = offset_map.globals() + *index as i32 * 8;
-							emit!(REX_W | REX_B, 0x8b, MOD_DISP32 | self.reg(rdest) << 3 | R15); // mov <rdest>, [r15+<offset>]
-							code.emit_imm32_le(offset);
+						(Reg(rdest), Reg(rsrc)) => asm.mov(Width::W64, self.reg(rdest), self.reg(rsrc)),
+						// A 32-bit immediate is zero-extended to 64 bits
+						(Reg(rdest), Imm32(imm)) | (Reg32(rdest), Imm32(imm)) => asm.mov(Width::W32, self.reg(rdest), Imm(*imm as i64)),
+						(Reg(rdest), Imm64(imm)) => asm.mov(Width::W64, self.reg(rdest), Imm(*imm)),
+						(Reg(rdest), Local(index)) => asm.mov(Width::W64, self.reg(rdest), Mem::base(Gpr::RBX, local_offset(*index))),
+						(Local(index), Reg(rsrc)) => asm.mov(Width::W64, Mem::base(Gpr::RBX, local_offset(*index)), self.reg(rsrc)),
+						(Reg(rdest), Global(index)) => asm.mov(Width::W64, self.reg(rdest), Mem::base(Gpr::R15, offset_map.globals() + *index as i32 * 8)),
+						(Global(index), Reg(rsrc)) => asm.mov(Width::W64, Mem::base(Gpr::R15, offset_map.globals() + *index as i32 * 8), self.reg(rsrc)),
+						(Memory8(offset, raddr), Reg8(rsrc)) | (Memory16(offset, raddr), Reg16(rsrc)) | (Memory32(offset, raddr), Reg32(rsrc)) | (Memory64(offset, raddr), Reg(rsrc)) => {
+							asm.mov(width(src), Mem::indexed(Gpr::R15, self.reg(raddr), 1, *offset), self.reg(rsrc));
+						},
+						(Reg8(rdest), Memory8(offset, raddr)) | (Reg16(rdest), Memory16(offset, raddr)) | (Reg32(rdest), Memory32(offset, raddr)) | (Reg(rdest), Memory64(offset, raddr)) => {
+							asm.mov(width(dest), self.reg(rdest), Mem::indexed(Gpr::R15, self.reg(raddr), 1, *offset));
 						},
-						(Global(index), Reg(rsrc)) => {
-							let offset = offset_map.globals() + *index as i32 * 8;
-							emit!(REX_W | REX_B, 0x89, MOD_DISP32 | self.reg(rsrc) << 3 | R15); // mov [r15+<offset>], <rsrc>
-							code.emit_imm32_le(offset);
-						},
-						(Local(index), Reg(rsrc)) => {
-							// mov [ffp-local_off], <sreg>
-							if *index < 15 {
-								emit!(REX_W, 0x89, MOD_DISP8 | self.reg(rsrc) << 3 | BX, -((*index as i8 + 1) * 8) as u8);
-							} else {
-								emit!(REX_W, 0x8b, MOD_DISP32 | self.reg(rsrc) << 3 | BX);
-								code.emit_imm32_le(-(*index as i32 + 1) * 8);
-							}
-						},
-						// TODO: Optimize for zero offset and short offsets
-						(Memory8(offset, raddr), Reg8(rsrc)) => {
-							emit!(REX_B, 0x88, MOD_DISP32 | self.reg(rsrc) << 3 | MOD_SIB, SIB1 | self.reg(raddr) << 3 | R15); // mov [r15+<raddr>*1+<offset>], <rsrc8>
-							code.emit_imm32_le(*offset);
-						},
-						(Memory16(offset, raddr), Reg16(rsrc)) => {
-							emit!(OPER_SIZE_OVR, REX_B, 0x89, MOD_DISP32 | self.reg(rsrc) << 3 | MOD_SIB, SIB1 | self.reg(raddr) << 3 | R15); // mov [r15+<raddr>*1+<offset>], <rsrc16>
-							code.emit_imm32_le(*offset);
-						},
-						(Memory32(offset, raddr), Reg32(rsrc)) => {
-							emit!(REX_B, 0x89, MOD_DISP32 | self.reg(rsrc) << 3 | MOD_SIB, SIB1 | self.reg(raddr) << 3 | R15); // mov [r15+<raddr>*1+<offset>], <rsrc32>
-							code.emit_imm32_le(*offset);
-						},
-						(Memory64(offset, raddr), Reg(rsrc)) => {
-							emit!(REX_W | REX_B, 0x89, MOD_DISP32 | self.reg(rsrc) << 3 | MOD_SIB, SIB1 | self.reg(raddr) << 3 | R15); // mov [r15+<raddr>*1+<offset>], <rsrc>
-							code.emit_imm32_le(*offset);
-						},
-						(Reg8(rdest), Memory8(offset, raddr)) => {
-							emit!(REX_B, 0x8a, MOD_DISP32 | self.reg(rdest) << 3 | MOD_SIB, SIB1 | self.reg(raddr) << 3 | R15); // mov <rdest8>, [r15+<raddr>*1+offset]
-							code.emit_imm32_le(*offset);
-						}
-						(Reg16(rdest), Memory16(offset, raddr)) => {
-							emit!(OPER_SIZE_OVR, REX_B, 0x8b, MOD_DISP32 | self.reg(rdest) << 3 | MOD_SIB, SIB1 | self.reg(raddr) << 3 | R15); // mov <rdest16>, [r15+<raddr>*1+offset]
-							code.emit_imm32_le(*offset);
-						}
-						(Reg32(rdest), Memory32(offset, raddr)) => {
-							emit!(REX_B, 0x8b, MOD_DISP32 | self.reg(rdest) << 3 | MOD_SIB, SIB1 | self.reg(raddr) << 3 | R15); // mov <rdest32>, [r15+<raddr>*1+offset]
-							code.emit_imm32_le(*offset);
-						}
-						(Reg(rdest), Memory64(offset, raddr)) => {
-							emit!(REX_W | REX_B, 0x8b, MOD_DISP32 | self.reg(rdest) << 3 | MOD_SIB, SIB1 | self.reg(raddr) << 3 | R15); // mov <rdest64>, [r15+<raddr>*1+offset]
-							code.emit_imm32_le(*offset);
-						}
 						unk => todo!("ir Mov {:?}", unk),
 					}
 				},
 				MoveIf(cond, dest, src) => {
 					match (dest, src) {
-						(Reg(rdest), Reg(rsrc)) | (Reg32(rdest), Reg32(rsrc)) => {
-							emit_maybe_rexw!(matches!(dest, Reg(_)), 0x0f, 0x40 | native_cond(cond), MOD_REG | self.reg(rdest) << 3 | self.reg(rsrc)); // cmovcc <rdest32>, <rsrc32>
-						},
+						(Reg(rdest), Reg(rsrc)) | (Reg32(rdest), Reg32(rsrc)) => asm.cmov(width(dest), native_cond(cond), self.reg(rdest), self.reg(rsrc)),
 						_ => todo!()
 					}
 				}
 				ZeroExtend(src) => {
 					match src {
-						Reg8(rsrc) => emit!(0x0f, 0xb6, MOD_REG | self.reg(rsrc) << 3 | self.reg(rsrc)), // movzx <rsrc32>, <rsrc8>
-						Reg16(rsrc) => emit!(0x0f, 0xb7, MOD_REG | self.reg(rsrc) << 3 | self.reg(rsrc)), // movzx <rsrc32>, <rsrc16>
-						Reg32(rsrc) => emit!(0x89, MOD_REG | self.reg(rsrc) << 3 | self.reg(rsrc)), // mov <rsrc32>, <rsrc32> ; This zero-extends to 64 bits
+						Reg8(rsrc) => asm.movzx(self.reg(rsrc), self.reg(rsrc), Width::W8),
+						Reg16(rsrc) => asm.movzx(self.reg(rsrc), self.reg(rsrc), Width::W16),
+						Reg32(rsrc) => asm.mov(Width::W32, self.reg(rsrc), self.reg(rsrc)), // This zero-extends to 64 bits
 						_ => unreachable!(),
 					}
 				},
@@ -341,47 +250,40 @@ impl CodeGenerator for IntelX64Compiler {
 					// When extending to i32, should not sign-extend to upper 32 bits
 					// Not sure if matters but just to be on the safe side
 					match src {
-						Reg8(rsrc) => emit!(REX_W, 0x0f, 0xbe, MOD_REG | self.reg(rsrc) << 3 | self.reg(rsrc)), // movsx <rsrc>, <rsrc8>
-						Reg16(rsrc) => emit!(REX_W, 0x0f, 0xbf, MOD_REG | self.reg(rsrc) << 3 | self.reg(rsrc)), // movsx <rsrc>, <rsrc16>
-						Reg32(rsrc) => emit!(REX_W, 0x63, MOD_REG | self.reg(rsrc) << 3 | self.reg(rsrc)), // movsxd <rsrc>, <rsrc32>
+						Reg8(rsrc) | Reg16(rsrc) | Reg32(rsrc) => asm.movsx(Width::W64, self.reg(rsrc), self.reg(rsrc), width(src)),
 						_ => unreachable!(),
 					}
 				},
-				Add(dest, src) => {
-					match (dest, src) {
-						(Reg32(rdest), Reg32(rsrc)) => emit!(0x01, MOD_REG | self.reg(rsrc) << 3 | self.reg(rdest)), // add <dreg32>, <sreg32>
-						(Reg(rdest), Reg(rsrc)) => emit!(REX_W, 0x01, MOD_REG | self.reg(rsrc) << 3 | self.reg(rdest)), // add <dreg32>, <sreg32>
-						_ => todo!()
-					}
-				},
-				Subtract(dest, src) => {
+				Add(dest, src) | Subtract(dest, src) | And(dest, src) | Or(dest, src) | Xor(dest, src) | Compare(dest, src) => {
 					match (dest, src) {
-						(Reg32(rdest), Reg32(rsrc)) => emit!(0x29, MOD_REG | self.reg(rsrc) << 3 | self.reg(rdest)), // sub <dreg32>, <sreg32>
-						(Reg(rdest), Reg(rsrc)) => emit!(REX_W, 0x29, MOD_REG | self.reg(rsrc) << 3 | self.reg(rdest)), // sub <dreg>, <sreg>
+						(Reg32(rdest), Reg32(rsrc)) | (Reg(rdest), Reg(rsrc)) => {
+							let (w, rdest, rsrc) = (width(dest), self.reg(rdest), self.reg(rsrc));
+							match insn {
+								Add(_, _) => asm.add(w, rdest, rsrc),
+								Subtract(_, _) => asm.sub(w, rdest, rsrc),
+								And(_, _) => asm.and(w, rdest, rsrc),
+								Or(_, _) => asm.or(w, rdest, rsrc),
+								Xor(_, _) => asm.xor(w, rdest, rsrc),
+								Compare(_, _) => asm.cmp(w, rdest, rsrc),
+								_ => unreachable!()
+							}
+						},
 						_ => todo!()
 					}
 				},
 				Multiply(dest, src) => {
 					match (dest, src) {
-						(Reg32(rdest), Reg32(rsrc)) => {
-							if self.reg(rdest) != AX {
-								if self.reg(rsrc) == AX {
-									emit!(0x90 | self.reg(rdest)); // xchg <rdest32>, <rsrc32>
-								} else {
-									emit!(0x89, MOD_REG | self.reg(rdest) << 3 | AX); // mov eax, <rdest32>
-								}
-							}
-							emit!(0xf7, MOD_REG | 0x5 << 3 | self.reg(rsrc)); // imul <rsrc32>
-						},
-						(Reg(rdest), Reg(rsrc)) => {
-							if self.reg(rdest) != AX {
-								if self.reg(rsrc) == AX {
-									emit!(REX_W, 0x90 | self.reg(rdest)); // xchg <rdest>, <rsrc>
+						(Reg32(rdest), Reg32(rsrc)) | (Reg(rdest), Reg(rsrc)) => {
+							let (w, rdest, mut rsrc) = (width(dest), self.reg(rdest), self.reg(rsrc));
+							if rdest != Gpr::RAX {
+								if rsrc == Gpr::RAX {
+									asm.xchg(w, Gpr::RAX, rdest);
+									rsrc = rdest;
 								} else {
-									emit!(REX_W, 0x89, MOD_REG | self.reg(rdest) << 3 | AX); // mov rax, <rdest>
+									asm.mov(w, Gpr::RAX, rdest);
 								}
 							}
-							emit!(REX_W, 0xf7, MOD_REG | 0x5 << 3 | self.reg(rsrc)); // imul <rsrc>
+							asm.imul(w, rsrc);
 						},
 						_ => todo!(),
 					}
@@ -389,302 +291,230 @@ impl CodeGenerator for IntelX64Compiler {
 				DivideUnsigned(dest, src) | DivideSigned(dest, src) | RemainderUnsigned(dest, src) | RemainderSigned(dest, src) => {
 					match (dest, src) {
 						(Reg32(rdest), Reg32(rsrc)) | (Reg(rdest), Reg(rsrc)) => {
-							let is64 = matches!(dest, Reg(_));
+							// The dividend goes to {r|e}ax and the divisor to {r|e}cx
+							let w = width(dest);
 							match (self.reg(rdest), self.reg(rsrc)) {
-								(AX, CX) => (),
-								(CX, AX) => emit_maybe_rexw!(is64, 0x90 | CX), // xchg {r|e}ax, {r|e}cx
-								(AX, DX) => emit_maybe_rexw!(is64, 0x89, MOD_REG | DX << 3 | CX), // mov {r|e}cx, {r|e}dx
-								(DX, AX) => {
-									emit_maybe_rexw!(is64, 0x89, MOD_REG | AX << 3 | CX); // mov {r|e}cx, {r|e}ax
-									emit_maybe_rexw!(is64, 0x89, MOD_REG | DX << 3 | AX); // mov {r|e}ax, {r|e}dx
+								(Gpr::RAX, Gpr::RCX) => (),
+								(Gpr::RCX, Gpr::RAX) => asm.xchg(w, Gpr::RAX, Gpr::RCX),
+								(Gpr::RAX, Gpr::RDX) => asm.mov(w, Gpr::RCX, Gpr::RDX),
+								(Gpr::RDX, Gpr::RAX) => {
+									asm.mov(w, Gpr::RCX, Gpr::RAX);
+									asm.mov(w, Gpr::RAX, Gpr::RDX);
 								}
-								(CX, DX) => {
-									emit_maybe_rexw!(is64, 0x89, MOD_REG | CX << 3 | AX); // mov {r|e}ax, {r|e}cx
-									emit_maybe_rexw!(is64, 0x89, MOD_REG | DX << 3 | CX); // mov {r|e}cx, {r|e}dx
+								(Gpr::RCX, Gpr::RDX) => {
+									asm.mov(w, Gpr::RAX, Gpr::RCX);
+									asm.mov(w, Gpr::RCX, Gpr::RDX);
 								}
-								(DX, CX) => emit_maybe_rexw!(is64, 0x89, MOD_REG | DX << 3 | AX), // mov {r|e}ax, {r|e}dx
+								(Gpr::RDX, Gpr::RCX) => asm.mov(w, Gpr::RAX, Gpr::RDX),
 								_ => unreachable!()
 							}
 							match insn {
 								DivideSigned(_, _) | RemainderSigned(_, _) => {
-									emit_maybe_rexw!(is64, 0x99); // {cdq|cqo}
-									emit_maybe_rexw!(is64, 0xf7, MOD_REG | 0x7 << 3 | CX); // idiv {r|e}cx
+									asm.sign_extend_acc(w);
+									asm.idiv(w, Gpr::RCX);
 								},
 								DivideUnsigned(_, _) | RemainderUnsigned(_, _) => {
-									emit!(0x31, MOD_REG | DX << 3 | DX); // xor edx, edx
-									emit_maybe_rexw!(is64, 0xf7, MOD_REG | 0x6 << 3 | CX); // div {r|e}cx
+									asm.xor(Width::W32, Gpr::RDX, Gpr::RDX);
+									asm.div(w, Gpr::RCX);
 								},
 								_ => unreachable!()
 							}
 							if matches!(insn, RemainderUnsigned(_, _) | RemainderSigned(_, _)) {
-								emit_maybe_rexw!(is64, 0x89, MOD_REG | DX << 3 | AX); // mov {r|e}ax, {r|e}dx
+								asm.mov(w, Gpr::RAX, Gpr::RDX);
 							}
 						},
 						_ => todo!(),
 					}
 				}
-				Compare(dest, src) => {
-					match (dest, src) {
-						(Reg32(rdest), Reg32(rsrc)) | (Reg(rdest), Reg(rsrc)) => {
-							emit_maybe_rexw!(matches!(dest, Reg(_)), 0x39, MOD_REG | self.reg(rsrc) << 3 | self.reg(rdest)); // cmp <dreg{32|64}>, <sreg{32|64}>
-						},
-						_ => unreachable!()
-					}
-				},
 				SetIf(cond, dest) => {
 					match dest {
 						Reg(rdest) | Reg32(rdest) => {
-							emit!(0x0f, 0x90 | native_cond(cond), MOD_REG | self.reg(rdest)); // setcc <dreg8>
-							emit!(0x0f, 0xb6, MOD_REG | self.reg(rdest) << 3 | self.reg(rdest)); // movzx <dreg32>, <dreg8>
+							asm.setcc(native_cond(cond), self.reg(rdest));
+							asm.movzx(self.reg(rdest), self.reg(rdest), Width::W8);
 						},
 						_ => unreachable!()
 					}
 				}
-				And(dest, src) => {
-					match (dest, src) {
-						(Reg32(rdest), Reg32(rsrc)) => emit!(0x21, MOD_REG | self.reg(rsrc) << 3 | self.reg(rdest)), // and <dreg32>, <sreg32>
-						(Reg(rdest), Reg(rsrc)) => emit!(REX_W, 0x21, MOD_REG | self.reg(rsrc) << 3 | self.reg(rdest)), // and <dreg>, <sreg>
-						_ => todo!()
-					}
-				},
-				Or(dest, src) => {
-					match (dest, src) {
-						(Reg32(rdest), Reg32(rsrc)) => emit!(0x09, MOD_REG | self.reg(rsrc) << 3 | self.reg(rdest)), // or <dreg32>, <sreg32>
-						(Reg(rdest), Reg(rsrc)) => emit!(REX_W, 0x09, MOD_REG | self.reg(rsrc) << 3 | self.reg(rdest)), // or <dreg>, <sreg>
-						_ => todo!()
-					}
-				},
-				Xor(dest, src) => {
-					match (dest, src) {
-						(Reg32(rdest), Reg32(rsrc)) => emit!(0x31, MOD_REG | self.reg(rsrc) << 3 | self.reg(rdest)), // xor <dreg32>, <sreg32>
-						(Reg(rdest), Reg(rsrc)) => emit!(REX_W, 0x31, MOD_REG | self.reg(rsrc) << 3 | self.reg(rdest)), // xor <dreg>, <sreg>
-						_ => todo!()
-					}
-				},
 				ShiftLeft(dest, cnt) | ShiftRightUnsigned(dest, cnt) | ShiftRightSigned(dest, cnt) | RotateLeft(dest, cnt) | RotateRight(dest, cnt) => {
-					let is64 = matches!(dest, Reg(_));
 					match (dest, cnt) {
 						(Reg32(rdest), Reg32(rcnt)) | (Reg(rdest), Reg(rcnt)) => {
+							// The count must be in cl
+							let w = width(dest);
 							let nr_dest = match (self.reg(rdest), self.reg(rcnt)) {
-								(_, CX) => self.reg(rdest),
-								(CX, _) => {
-									emit_maybe_rexw!(is64, 0x87, MOD_REG | self.reg(rdest) << 3 | CX); // xchg <rdest{32|64}>, {r|e}cx
-									self.reg(rcnt)
+								(rdest, Gpr::RCX) => rdest,
+								(Gpr::RCX, rcnt) => {
+									asm.xchg(w, rcnt, Gpr::RCX);
+									rcnt
 								},
-								(_, _) => {
-									emit_maybe_rexw!(is64, 0x89, MOD_REG | self.reg(rcnt) << 3 | CX); // mov {r|e}cx, <rcnt{32|64}>
-									self.reg(rdest)
+								(rdest, rcnt) => {
+									asm.mov(w, Gpr::RCX, rcnt);
+									rdest
 								}
 							};
 
-							let (opcode, add) = match insn {
-								ShiftLeft(_, _) => (0xd3, 0x4),
-								ShiftRightUnsigned(_, _) => (0xd3, 0x5),
-								ShiftRightSigned(_, _) => (0xd3, 0x7),
-								RotateLeft(_, _) => (0xd3, 0x0),
-								RotateRight(_, _) => (0xd3, 0x1),
+							match insn {
+								ShiftLeft(_, _) => asm.shl(w, nr_dest, Gpr::RCX),
+								ShiftRightUnsigned(_, _) => asm.shr(w, nr_dest, Gpr::RCX),
+								ShiftRightSigned(_, _) => asm.sar(w, nr_dest, Gpr::RCX),
+								RotateLeft(_, _) => asm.rol(w, nr_dest, Gpr::RCX),
+								RotateRight(_, _) => asm.ror(w, nr_dest, Gpr::RCX),
 								_ => unreachable!()
-							};
-							emit_maybe_rexw!(is64, opcode, MOD_REG | add << 3 | nr_dest); // shl/shr/sar/rol/ror <rdest{32|64}>, cl
+							}
 						},
 						_ => todo!()
 					}
 				},
-				Jump(label) => {
-					// jmp near rel32 (no address just yet)
-					emit!(0xe9);
-					jmp_targets.push(JmpTarget(code.pc(), label.clone()));
-					code.emit_imm32_le(0);
-				},
-				JumpIf(cond, label) => {
-					// jcc near rel32 (no address just yet)
-					emit!(0x0f, 0x80 | native_cond(cond));
-					jmp_targets.push(JmpTarget(code.pc(), label.clone()));
-					code.emit_imm32_le(0);
-				},
+				Jump(label) => asm.jmp(label.clone()), // May be relaxed to rel8 on linking
+				JumpIf(cond, label) => asm.jcc(native_cond(cond), label.clone()), // May be relaxed to rel8 on linking
 				JumpTable(index, targets) => {
 					match index {
 						Reg32(rindex) => {
 							// FIXME: It is implicit that this operation should preserve Sra as it
 							// already contains the block return value, if any. It breaks the
 							// "scratch-all" concept and should be refactored.
-							//
-							// BEWARE: rip-relative addressing with hardcoded offset
-							emit!(REX_W, 0x8d, MOD_RM | DI << 3 | MOD_RIPREL, 0x08, 0x00, 0x00, 0x00); // lea rdi, [rip+8]
-							emit!(0xc1, MOD_REG | 0x4 << 3 | self.reg(rindex), 0x03); // shl <rindex32>, 3
-							emit!(REX_W, 0x01, MOD_REG | self.reg(rindex) << 3 | DI); // add rdi, <rindex32>
-							emit!(0xff, MOD_RM | 0x4 << 3 | DI); // jmp [rdi]
+							let rindex = self.reg(rindex);
+							asm.lea(Width::W64, Gpr::RDI, Mem::rip(0)); // Patched to point to the table below
+							let lea_end = asm.pc();
+							asm.shl(Width::W32, rindex, Imm(3));
+							asm.add(Width::W64, Gpr::RDI, rindex);
+							asm.jmp_indirect(Mem::base(Gpr::RDI, 0));
+							let table_off = (asm.pc() - lea_end) as i32;
+							asm.patch32_le(lea_end - 4, table_off);
 
 							for target in targets {
-								code.reloc(Relocation::LabelAbsoluteAddress(target.clone()));
-								code.emit_imm64_le(0);
+								asm.reloc(Relocation::LabelAbsoluteAddress(target.clone()));
+								asm.emit_imm64_le(0);
 							}
 						},
 						_ => todo!()
 					}
 				}
 				Call(label) => {
-					let (findex, signature) = match label {
-						IrLabel::AnonymousFunc(idx) | IrLabel::ExportedFunc(idx, _) | IrLabel::ImportedFunc(idx, _) => {
-							let signature = if let Some(signature) = &signatures[*idx as usize] { signature } else { unreachable!() };
-							(Some(*idx), signature) 
+					match label {
+						IrLabel::AnonymousFunc(idx) | IrLabel::ExportedFunc(idx, _) => {
+							// Arguments are already in place and are removed by the callee
+							let offset = asm.call_rel32();
+							asm.call_targets.push(LinkTarget { offset, func_index: *idx });
+							if signatures[*idx as usize].as_ref().expect("Callee signature available").results > 0 {
+								asm.push(Gpr::RAX);
+							}
 						},
-						IrLabel::Indirect(_table_index, op, signature) => {
+						IrLabel::Indirect(table_index, op, signature) => {
 							match op {
 								Reg32(op_reg) => {
-									let offset = offset_map.vm_data() + codegen::VM_DATA_TMP_0 * 8;
-									emit!(REX_W | REX_B, 0x89, MOD_DISP32 | self.reg(op_reg) << 3 | R15); // mov [r15+<offset>], <rsrc>
-									code.emit_imm32_le(offset);
-									(None, signature)
+									let op_reg = self.reg(op_reg);
+									asm.mov(Width::W32, op_reg, op_reg); // Zero-extend the index
+									asm.mov(Width::W64, Gpr::RAX, Mem::indexed(Gpr::R15, op_reg, 8, offset_map.table(*table_index)));
+									asm.call_indirect(Gpr::RAX);
 								},
 								_ => todo!()
 							}
-						}
-						_ => unreachable!(),
-					};
-					let n_params = signature.params;
-					let n_stack_params = (n_params as usize).saturating_sub(ABI_PARAM_REGS.len());
-					if n_params > 0 {
-						let mut sp_off = 8 * (n_params as i32 - 1);
-						for i in 0..std::cmp::min(n_params as usize, ABI_PARAM_REGS.len()) {
-							emit_with_offset!(REX_W | ABI_PARAM_REGS[i].0, 0x8b ; ABI_PARAM_REGS[i].1 << 3 | SP, SIB1 | SP << 3 | SP ; sp_off); // mov reg, [rsp + sp_off]
-							sp_off -= 8;
-						}
-						if n_stack_params > 0 {
-							emit!(REX_W, 0x89, MOD_REG | SP << 3 | AX); // mov rax, rsp
-							emit!(REX_W, 0x83, MOD_REG | 0x0 << 3 | AX, 0x20); // add rax, 0x20 ; offset of the number of register params minus two
-							emit!(REX_W, 0x83, MOD_REG | 0x4 << 3 | AX, 0xf0); // and rax, -16 ; align stack to 16 bytes, as per ABI requirements
-							// At this point, rax points to the aligned bottom of the ABI frame,
-							// and rsp points to the bottom of the overlapping Wasm frame. We'll
-							// store the current rsp and rbp values into the space freed up after
-							// populating registers with arguments to be able to get rid of the whole frame
-							// when the call is returned.
-							emit_with_offset!(REX_W, 0x89 ; SP << 3 | AX ; n_stack_params as i32 * 8); // mov [rax + stored_sp_off], rsp
-							emit_with_offset!(REX_W, 0x89 ; BP << 3 | AX ; (n_stack_params + 1) as i32 * 8); // mov [rax + stored_bp_off], rbp
-							emit!(REX_W, 0x89, MOD_REG | AX << 3 | BP); // mov rbp, rax
-							emit!(REX_W | REX_B, 0x89, MOD_REG | BP << 3 | R11); // mov r11, rbp
-							let frame_off = (n_stack_params as i32 - 1) * 8;
-							if frame_off > i8::MAX as i32 { // add r11, (nsp-1)*8
-								emit!(REX_W | REX_B, 0x81, MOD_REG | 0x0 << 3 | R11);
-								code.emit_imm32_le(frame_off);
-							} else {
-								emit!(REX_W | REX_B, 0x83, MOD_REG | 0x0 << 3 | R11, frame_off as u8);
+							if signature.results > 0 {
+								asm.push(Gpr::RAX);
 							}
-							// l1:
-							emit!(0x58 | AX); // pop rax
-							emit!(REX_W | REX_B, 0x89, MOD_RM | AX << 3 | R11); // mov [r11], rax
-							emit!(REX_W | REX_B, 0x83, MOD_REG | 0x5 << 3 | R11, 0x08); // sub r11, 8
-							emit!(REX_W | REX_B, 0x39, MOD_REG | BP << 3 | R11); // cmp r11, rbp
-							emit!(REX_W, 0x0f, 0x42, MOD_REG | SP << 3 | BP); // cmovb rsp, rbp
-							emit!(0x72, 0x20); // jb l3
-							emit!(REX_W, 0x39, MOD_REG | SP << 3 | BP); // cmp rbp, rsp
-							emit!(0x75, 0xea); // jne l1
-							// l2:
-							emit!(REX_W, 0x8b, MOD_DISP8 | AX << 3 | BP, 0x00); // mov rax, [rbp+0]
-							emit!(REX_W | REX_R | REX_B, 0x8b, MOD_RM | R10 << 3 | R11); // mov r10, [r11]
-							emit!(REX_W | REX_B, 0x89, MOD_RM | AX << 3 | R11); // mov [r11], rax
-							emit!(REX_W | REX_R, 0x89, MOD_DISP8 | R10 << 3 | BP, 0x00); // mov [rbp+0], r10
-							emit!(REX_W | REX_B, 0x83, MOD_REG | 0x5 << 3 | R11, 0x08); // sub r11, 8
-							emit!(REX_W, 0x83, MOD_REG | 0x0 << 3 | BP, 0x08); // add rbp, 8
-							emit!(REX_W | REX_B, 0x39, MOD_REG | BP << 3 | R11); // cmp r11, rbp
-							emit!(0x73, 0xe5); // jae l2
-							// l3:
-						} else {
-							// No stack parameters, but stack alignment is still required
-							emit!(REX_W | REX_B, 0x89, MOD_REG | SP << 3 | R12); // mov r12, rsp
-							emit!(REX_W, 0x83, MOD_REG | 0x4 << 3 | SP, 0xf0); // and rsp, -16
-
-						}
-					} else {
-						// No parameters, but stack alignment is still required
-						emit!(REX_W | REX_B, 0x89, MOD_REG | SP << 3 | R12); // mov r12, rsp
-						emit!(REX_W, 0x83, MOD_REG | 0x4 << 3 | SP, 0xf0); // and rsp, -16
-					}
-					match label {
-						IrLabel::AnonymousFunc(_) | IrLabel::ExportedFunc(_, _) => {
-							emit!(0xe8); // call near (no address yet)
-							self.call_targets.push(LinkTarget { offset: code.pc(), func_index: findex.expect("Function is always `Some` for the given label type") as u32 });
-							code.emit_imm32_le(0);
-						},
-						IrLabel::ImportedFunc(_, addr) => {
-							emit!(REX_W, 0xb8); // movabs rax, ...
-							code.emit_imm64_le(*addr as i64);
-							emit!(0xff, MOD_REG | 0x2 << 3 | AX); // call rax
 						},
-						IrLabel::Indirect(table_index, _, _) => {
-							let table_offset = offset_map.table(*table_index);
-							let stored_func_index_offset = offset_map.vm_data() + codegen::VM_DATA_TMP_0 * 8;
-							emit!(REX_W | REX_B, 0x8b, MOD_DISP32 | AX << 3 | R15); // mov rax, [r15+<offset>]
-							code.emit_imm32_le(stored_func_index_offset);
-							emit!(REX_W | REX_B, 0x8b, MOD_DISP32 | AX << 3 | MOD_SIB, SIB8 | AX << 3 | R15); // mov rax, [r15+rax*8+<offset>]
-							code.emit_imm32_le(table_offset);
-							emit!(0xff, MOD_REG | 0x2 << 3 | AX); // call rax
-						}
-						_ => unreachable!()
-					}
-					if n_params > 0 {
-						if n_stack_params > 0 {
-							// rsp points to the bottom of the ABI frame. Offsets to the stored
-							// rsp and rbp values are known
-							emit_with_offset!(REX_W, 0x8b ; BP << 3 | SP, SIB1 | SP << 3 | SP ; (n_stack_params + 1) as i32 * 8); // mov rbp, [rsp + storeb_bp_off]
-							emit_with_offset!(REX_W, 0x8b ; SP << 3 | SP, SIB1 | SP << 3 | SP ; n_stack_params as i32 * 8); // mov rsp, [rsp + storeb_sp_off]
-						} else {
-							emit!(REX_W | REX_R, 0x89, MOD_REG | R12 << 3 | SP); // mov rsp, r12
-						}
-						emit!(REX_W, 0x83, MOD_REG | 0x0 << 3 | SP, (n_params as u8) * 8); // add rsp, n_params * 8
-					} else {
-						emit!(REX_W | REX_R, 0x89, MOD_REG | R12 << 3 | SP); // mov rsp, r12
-					}
-					if signature.results > 0 {
-						emit!(0x50 | AX); // push rax
-					}
-
-				},
-				Return => {
-					emit!(0xc3); // ret near
-				}
-				Trap => {
-					emit!(0x0f, 0x0b); // ud2
-				}
-				LeadingZeroes(src) => {
-					match src {
-						Reg32(rsrc) => {
-							emit!(REP, 0x0f, 0xbd, MOD_REG | self.reg(rsrc) << 3 | self.reg(rsrc)); // lzcnt <rsrc32>, <rsrc32> ;; (encoded as rep bsr)
-						},
-						Reg(rsrc) => {
-							emit!(REP, REX_W, 0x0f, 0xbd, MOD_REG | self.reg(rsrc) << 3 | self.reg(rsrc)); // lzcnt <rsrc>, <rsrc>
+						IrLabel::ImportedFunc(idx, addr) => {
+							// System V ABI call. r12 is not used by the internal calling convention
+							// and is preserved by the export trampolines, so it is safe to clobber here.
+							let signature = signatures[*idx as usize].as_ref().expect("Import signature available");
+							let n_params = signature.params;
+							let n_stack_params = (n_params as usize).saturating_sub(ABI_PARAM_REGS.len());
+							if n_params > 0 {
+								let mut sp_off = 8 * (n_params as i32 - 1);
+								for reg in ABI_PARAM_REGS.iter().take(n_params as usize) {
+									asm.mov(Width::W64, *reg, Mem::base(Gpr::RSP, sp_off));
+									sp_off -= 8;
+								}
+								if n_stack_params > 0 {
+									asm.mov(Width::W64, Gpr::RAX, Gpr::RSP);
+									asm.add(Width::W64, Gpr::RAX, Imm(0x20)); // Offset of the number of register params minus two
+									asm.and(Width::W64, Gpr::RAX, Imm(-16)); // Align stack to 16 bytes, as per ABI requirements
+									// At this point, rax points to the aligned bottom of the ABI frame,
+									// and rsp points to the bottom of the overlapping Wasm frame. We'll
+									// store the current rsp and rbp values into the space freed up after
+									// populating registers with arguments to be able to get rid of the whole frame
+									// when the call is returned.
+									asm.mov(Width::W64, Mem::base(Gpr::RAX, n_stack_params as i32 * 8), Gpr::RSP);
+									asm.mov(Width::W64, Mem::base(Gpr::RAX, (n_stack_params + 1) as i32 * 8), Gpr::RBP);
+									asm.mov(Width::W64, Gpr::RBP, Gpr::RAX);
+									asm.mov(Width::W64, Gpr::R11, Gpr::RBP);
+									asm.add(Width::W64, Gpr::R11, Imm((n_stack_params as i64 - 1) * 8));
+
+									let l1 = asm.pc();
+									asm.pop(Gpr::RAX);
+									asm.mov(Width::W64, Mem::base(Gpr::R11, 0), Gpr::RAX);
+									asm.sub(Width::W64, Gpr::R11, Imm(8));
+									asm.cmp(Width::W64, Gpr::R11, Gpr::RBP);
+									asm.cmov(Width::W64, Cond::B, Gpr::RSP, Gpr::RBP);
+									let to_l3 = asm.jcc_short_forward(Cond::B);
+									asm.cmp(Width::W64, Gpr::RBP, Gpr::RSP);
+									asm.jcc_short(Cond::Ne, l1);
+
+									let l2 = asm.pc();
+									asm.mov(Width::W64, Gpr::RAX, Mem::base(Gpr::RBP, 0));
+									asm.mov(Width::W64, Gpr::R10, Mem::base(Gpr::R11, 0));
+									asm.mov(Width::W64, Mem::base(Gpr::R11, 0), Gpr::RAX);
+									asm.mov(Width::W64, Mem::base(Gpr::RBP, 0), Gpr::R10);
+									asm.sub(Width::W64, Gpr::R11, Imm(8));
+									asm.add(Width::W64, Gpr::RBP, Imm(8));
+									asm.cmp(Width::W64, Gpr::R11, Gpr::RBP);
+									asm.jcc_short(Cond::Ae, l2);
+
+									asm.bind(to_l3);
+								} else {
+									// No stack parameters, but stack alignment is still required
+									asm.mov(Width::W64, Gpr::R12, Gpr::RSP);
+									asm.and(Width::W64, Gpr::RSP, Imm(-16));
+								}
+							} else {
+								// No parameters, but stack alignment is still required
+								asm.mov(Width::W64, Gpr::R12, Gpr::RSP);
+								asm.and(Width::W64, Gpr::RSP, Imm(-16));
+							}
+							asm.movabs(Gpr::RAX, *addr as i64);
+							asm.call_indirect(Gpr::RAX);
+							if n_params > 0 {
+								if n_stack_params > 0 {
+									// rsp points to the bottom of the ABI frame. Offsets to the stored
+									// rsp and rbp values are known
+									asm.mov(Width::W64, Gpr::RBP, Mem::base(Gpr::RSP, (n_stack_params + 1) as i32 * 8));
+									asm.mov(Width::W64, Gpr::RSP, Mem::base(Gpr::RSP, n_stack_params as i32 * 8));
+								} else {
+									asm.mov(Width::W64, Gpr::RSP, Gpr::R12);
+								}
+								asm.add(Width::W64, Gpr::RSP, Imm(n_params as i64 * 8));
+							} else {
+								asm.mov(Width::W64, Gpr::RSP, Gpr::R12);
+							}
+							if signature.results > 0 {
+								asm.push(Gpr::RAX);
+							}
 						},
-						_ => unreachable!()
+						_ => unreachable!(),
 					}
 				},
-				TrailingZeroes(src) => {
+				Return => asm.ret(n_params as u16 * 8),
+				Trap => asm.ud2(),
+				SourceLoc(_, _) => (),
+				LeadingZeroes(src) | TrailingZeroes(src) | BitPopulationCount(src) => {
 					match src {
-						Reg32(rsrc) => {
-							emit!(REP, 0x0f, 0xbc, MOD_REG | self.reg(rsrc) << 3 | self.reg(rsrc)); // tzcnt <rsrc32>, <rsrc32> ;; (encoded as rep bsf)
-						},
-						Reg(rsrc) => {
-							emit!(REP, REX_W, 0x0f, 0xbc, MOD_REG | self.reg(rsrc) << 3 | self.reg(rsrc)); // tzcnt <rsrc>, <rsrc>
+						Reg32(rsrc) | Reg(rsrc) => {
+							let (w, rsrc) = (width(src), self.reg(rsrc));
+							match insn {
+								LeadingZeroes(_) => asm.lzcnt(w, rsrc, rsrc),
+								TrailingZeroes(_) => asm.tzcnt(w, rsrc, rsrc),
+								BitPopulationCount(_) => asm.popcnt(w, rsrc, rsrc),
+								_ => unreachable!()
+							}
 						},
 						_ => unreachable!()
 					}
-				}
-				BitPopulationCount(src) => {
-					match src {
-						Reg32(rsrc) => {
-							emit!(REP, 0x0f, 0xb8, MOD_REG | self.reg(rsrc) << 3 | self.reg(rsrc)); // popcnt <rsrc32>, <rsrc32>
-						},
-						Reg(rsrc) => {
-							emit!(REP, REX_W, 0x0f, 0xb8, MOD_REG | self.reg(rsrc) << 3 | self.reg(rsrc)); // popcnt <rsrc32>, <rsrc32>
-						},
-						_ => unreachable!(),
-					}
 				},
 				InitTablePreamble(offset) => {
 					match offset {
 						Reg(offset_reg) => {
-							emit!(REX_W | REX_B, 0x8d, MOD_DISP32 | DI << 3 | MOD_SIB, SIB8 | self.reg(offset_reg) << 3 | R15); // lea rdi, [r15+<roffset>*8+<offset>]
-							code.emit_imm32_le(offset_map.table(0)); // FIXME
-							emit!(0xfc); // cld
+							asm.lea(Width::W64, Gpr::RDI, Mem::indexed(Gpr::R15, self.reg(offset_reg), 8, offset_map.table(0))); // FIXME
+							asm.cld();
 						},
 						_ => todo!()
 					}
@@ -692,11 +522,9 @@ impl CodeGenerator for IntelX64Compiler {
 				InitTableElement(func_index_op) => {
 					match func_index_op {
 						Imm32(func_index) => {
-							emit!(REX_W, 0xb8 | AX); // movabs rax, <imm64> 
-							self.abs_off_targets.push(LinkTarget { offset: code.pc(), func_index: *func_index as u32 });
-							code.reloc(Relocation::FunctionAbsoluteAddress);
-							code.emit_imm64_le(0);
-							emit!(REX_W, 0xab); // stosq
+							let offset = asm.movabs_reloc(Gpr::RAX, Relocation::FunctionAbsoluteAddress);
+							asm.abs_off_targets.push(LinkTarget { offset, func_index: *func_index as u32 });
+							asm.stosq();
 						},
 						_ => todo!()
 					}
@@ -704,86 +532,78 @@ impl CodeGenerator for IntelX64Compiler {
 				InitTablePostamble => (),
 				InitMemoryFromChunk(chunk_idx, chunk_len, offset) => {
 					match offset {
-						Reg(offset_reg) => {
-							emit!(REX_W | REX_B, 0x8d, MOD_RM | DI << 3 | MOD_SIB, SIB1 | self.reg(offset_reg) << 3 | R15); // lea rdi, [r15+<roffset>*1]
-						},
+						Reg(offset_reg) => asm.lea(Width::W64, Gpr::RDI, Mem::indexed(Gpr::R15, self.reg(offset_reg), 1, 0)),
 						_ => todo!()
 					}
-					emit!(REX_W | REX_B, 0x8d, MOD_DISP32 | SI << 3 | R15); // lea rsi, [r15+<offset>]
-					code.emit_imm32_le(offset_map.data_chunk(*chunk_idx));
-					emit!(0xb8 | CX); // mov ecx, <imm32>
-					code.emit_imm32_le(*chunk_len as i32);
-					emit!(0xfc); // cld
-					emit!(0xf3, 0xa4); // rep movsb
+					asm.lea(Width::W64, Gpr::RSI, Mem::base(Gpr::R15, offset_map.data_chunk(*chunk_idx)));
+					asm.mov(Width::W32, Gpr::RCX, Imm(*chunk_len as i64));
+					asm.cld();
+					asm.rep_movsb();
 				},
 				MemoryGrow(pages) => {
 					match pages {
 						Reg32(rpages) => {
-							emit!(REX_W | REX_B, 0x8b, MOD_DISP32 | SI << 3 | R15); // mov rsi, [r15+<offset>]
-							code.emit_imm32_le(offset_map.vm_data() + codegen::VM_DATA_MEM_ALLOC);
-							emit!(REX_W, 0x89, MOD_REG | SI << 3 | DI); // mov rdi, rsi
-							emit!(REX_W, 0x01, MOD_REG | self.reg(rpages) << 3 | SI); // add rsi, <rpages>
-							emit!(REX_W | REX_B, 0x3b, MOD_DISP32 | SI << 3 | R15); // cmp rsi, [r15+<offset>]
-							code.emit_imm32_le(offset_map.vm_data() + codegen::VM_DATA_MEM_TOTAL);
-							emit!(0x77, 0x09); // ja fail
-							emit!(REX_W | REX_B, 0x89, MOD_DISP32 | SI << 3 | R15); // mov [r15+<offset>], rsi
-							code.emit_imm32_le(offset_map.vm_data() + codegen::VM_DATA_MEM_ALLOC);
-							emit!(0xeb, 0x05); // jmp end
-							// fail:
-							emit!(0xb8 | DI, 0xff, 0xff, 0xff, 0xff); // mov edi, -1
-							// end:
-							emit!(0x89, MOD_REG | DI << 3 | self.reg(rpages)); // mov <rpages32>, edi
+							let alloc = Mem::base(Gpr::R15, offset_map.vm_data() + codegen::VM_DATA_MEM_ALLOC);
+							asm.mov(Width::W64, Gpr::RSI, alloc);
+							asm.mov(Width::W64, Gpr::RDI, Gpr::RSI);
+							asm.add(Width::W64, Gpr::RSI, self.reg(rpages));
+							asm.cmp(Width::W64, Gpr::RSI, Mem::base(Gpr::R15, offset_map.vm_data() + codegen::VM_DATA_MEM_TOTAL));
+							let fail = asm.jcc_short_forward(Cond::A);
+							asm.mov(Width::W64, alloc, Gpr::RSI);
+							let end = asm.jmp_short_forward();
+							asm.bind(fail);
+							asm.mov(Width::W32, Gpr::RDI, Imm(-1));
+							asm.bind(end);
+							asm.mov(Width::W32, self.reg(rpages), Gpr::RDI);
 						},
 						_ => unreachable!()
 					}
 				},
 				MemorySize(dest) => {
 					match dest {
-						Reg32(rdest) => {
-							emit!(REX_W | REX_B, 0x8b, MOD_DISP32 | self.reg(rdest) << 3 | R15); // mov <rdest>, [r15+<offset>]
-							code.emit_imm32_le(offset_map.vm_data() + codegen::VM_DATA_MEM_ALLOC);
-						},
+						Reg32(rdest) => asm.mov(Width::W64, self.reg(rdest), Mem::base(Gpr::R15, offset_map.vm_data() + codegen::VM_DATA_MEM_ALLOC)),
 						_ => unreachable!()
 					}
 				}
 			}
 		}
+	}
 
-		for t in jmp_targets {
-			if let Some(label_pc) = code.labels.get(&t.1) {
-				let insn_pc = t.0 + 4;
-				let offset: isize = *label_pc as isize - insn_pc as isize;
-				code.patch32_le(t.0, offset as i32);
-			} else {
-				panic!("Unresolved label: {:?}", t.1)
-			}
+	fn finish_func(&self, code: &mut CodeEmitter) {
+		// The peephole optimizer moves the code around, so the call sites must be tracked
+		// through it. It also resolves the branches to labels.
+		let mut anchors = code.call_targets.iter().chain(code.abs_off_targets.iter()).map(|t| t.offset).collect::<Vec<_>>();
+		intel_x64_peephole::optimize(code, &mut anchors);
+		for (target, offset) in code.call_targets.iter_mut().chain(code.abs_off_targets.iter_mut()).zip(anchors) {
+			target.offset = offset;
 		}
 	}
 
-	fn link(&mut self, code: &mut CodeEmitter) {
+	fn link(&self, code: &mut CodeEmitter) {
 		let mut func_offsets: Vec<usize> = Vec::new();
 		for (label, offset) in code.labels_iter() {
-			match label {
-				IrLabel::ExportedFunc(index, _) | IrLabel::AnonymousFunc(index) => {
-					if *index as usize >= func_offsets.len() {
-						func_offsets.resize(*index as usize + 1, 0);
-					}
-					func_offsets[*index as usize] = *offset;
+			if let IrLabel::AnonymousFunc(index) = label {
+				if *index as usize >= func_offsets.len() {
+					func_offsets.resize(*index as usize + 1, 0);
 				}
-				_ => () //todo!()
+				func_offsets[*index as usize] = *offset;
 			}
 		}
 		println!("OFF {:?}", func_offsets);
-		println!("TRG {:?}", self.call_targets);
-		for target in &self.call_targets {
+		println!("TRG {:?}", code.call_targets);
+		for target in std::mem::take(&mut code.call_targets) {
 			let func_address = func_offsets[target.func_index as usize];
 			let insn_pc = target.offset + 4;
 			let offset: isize = func_address as isize - insn_pc as isize;
 			code.patch32_le(target.offset, offset as i32);
 		}
-		for target in &self.abs_off_targets {
+		for target in std::mem::take(&mut code.abs_off_targets) {
 			let func_address = func_offsets[target.func_index as usize];
 			code.patch64_le(target.offset, func_address as i64);
 		}
 	}
+
+	fn decoder(&self) -> Option<InsnDecoder> {
+		Some(intel_x64_disasm::decode)
+	}
 }
@@ -0,0 +1,611 @@
+use std::ops::{Deref, DerefMut};
+use crate::{codegen::{CodeEmitter, Relocation}, ir::IrLabel};
+
+// Typed x86-64 instruction encoder
+//
+// Instructions are built from typed operands and the encoder picks the prefixes, the ModRM and
+// SIB bytes, and the shortest displacement and immediate forms. Every instruction starts a new
+// machine instruction in the emitter, as the peephole optimizer requires.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Gpr(u8);
+
+impl Gpr {
+	pub(crate) const RAX: Gpr = Gpr(0);
+	pub(crate) const RCX: Gpr = Gpr(1);
+	pub(crate) const RDX: Gpr = Gpr(2);
+	pub(crate) const RBX: Gpr = Gpr(3);
+	pub(crate) const RSP: Gpr = Gpr(4);
+	pub(crate) const RBP: Gpr = Gpr(5);
+	pub(crate) const RSI: Gpr = Gpr(6);
+	pub(crate) const RDI: Gpr = Gpr(7);
+	pub(crate) const R8: Gpr = Gpr(8);
+	pub(crate) const R9: Gpr = Gpr(9);
+	pub(crate) const R10: Gpr = Gpr(10);
+	pub(crate) const R11: Gpr = Gpr(11);
+	pub(crate) const R12: Gpr = Gpr(12);
+	pub(crate) const R15: Gpr = Gpr(15);
+
+	const fn low(self) -> u8 {
+		self.0 & 7
+	}
+
+	const fn ext(self) -> u8 {
+		self.0 >> 3
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Width {
+	W8,
+	W16,
+	W32,
+	W64,
+}
+
+/// Memory operand `[base+index*scale+disp]`. No base means `[rip+disp]`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Mem {
+	pub(crate) base: Option<Gpr>,
+	pub(crate) index: Option<Gpr>,
+	pub(crate) scale: u8,
+	pub(crate) disp: i32,
+}
+
+impl Mem {
+	pub(crate) const fn base(base: Gpr, disp: i32) -> Self {
+		Self { base: Some(base), index: None, scale: 1, disp }
+	}
+
+	pub(crate) const fn indexed(base: Gpr, index: Gpr, scale: u8, disp: i32) -> Self {
+		Self { base: Some(base), index: Some(index), scale, disp }
+	}
+
+	pub(crate) const fn rip(disp: i32) -> Self {
+		Self { base: None, index: None, scale: 1, disp }
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Imm(pub(crate) i64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Operand {
+	Reg(Gpr),
+	Mem(Mem),
+	Imm(Imm),
+}
+
+impl From<Gpr> for Operand {
+	fn from(r: Gpr) -> Self {
+		Operand::Reg(r)
+	}
+}
+
+impl From<Mem> for Operand {
+	fn from(m: Mem) -> Self {
+		Operand::Mem(m)
+	}
+}
+
+impl From<Imm> for Operand {
+	fn from(i: Imm) -> Self {
+		Operand::Imm(i)
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Cond {
+	B = 0x2,
+	Ae = 0x3,
+	E = 0x4,
+	Ne = 0x5,
+	Be = 0x6,
+	A = 0x7,
+	L = 0xc,
+	Ge = 0xd,
+	Le = 0xe,
+	G = 0xf,
+}
+
+/// Short jump with the target not known yet, see `Assembler::bind`
+pub(crate) struct ShortJump(usize);
+
+const REX: u8 = 0x40;
+const REX_W: u8 = 0x08;
+const REX_R: u8 = 0x04;
+const REX_X: u8 = 0x02;
+const REX_B: u8 = 0x01;
+
+const OPER_SIZE_OVR: u8 = 0x66;
+const REP: u8 = 0xf3;
+
+const fn fits_i8(v: i64) -> bool {
+	v >= i8::MIN as i64 && v <= i8::MAX as i64
+}
+
+const fn fits_i32(v: i64) -> bool {
+	v >= i32::MIN as i64 && v <= i32::MAX as i64
+}
+
+// spl, bpl, sil and dil are only accessible with a REX prefix
+const fn needs_byte_rex(width: Width, r: Gpr) -> bool {
+	matches!(width, Width::W8) && r.0 >= 4 && r.0 < 8
+}
+
+pub(crate) struct Assembler<'a> {
+	code: &'a mut CodeEmitter,
+}
+
+impl Deref for Assembler<'_> {
+	type Target = CodeEmitter;
+
+	fn deref(&self) -> &CodeEmitter {
+		self.code
+	}
+}
+
+impl DerefMut for Assembler<'_> {
+	fn deref_mut(&mut self) -> &mut CodeEmitter {
+		self.code
+	}
+}
+
+impl<'a> Assembler<'a> {
+	pub(crate) fn new(code: &'a mut CodeEmitter) -> Self {
+		Self { code }
+	}
+
+	fn emit_bytes(&mut self, bytes: &[u8]) {
+		bytes.iter().for_each(|b| self.code.emit(*b));
+	}
+
+	fn emit_imm(&mut self, width: Width, imm: i64) {
+		match width {
+			Width::W8 => self.code.emit(imm as u8),
+			Width::W16 => self.emit_bytes(&(imm as i16).to_le_bytes()),
+			Width::W32 | Width::W64 => self.code.emit_imm32_le(imm as i32),
+		}
+	}
+
+	// Emits the prefixes, REX, opcode, ModRM, SIB and displacement. `reg` is either a register
+	// number or an opcode extension.
+	fn encode(&mut self, prefixes: &[u8], width: Width, opcode: &[u8], reg: u8, rm: Operand, force_rex: bool) {
+		self.code.begin_insn();
+		let mut prefixes = prefixes.to_vec();
+		if width == Width::W16 {
+			prefixes.insert(0, OPER_SIZE_OVR);
+		}
+		self.emit_bytes(&prefixes);
+
+		let mut rex = if width == Width::W64 { REX_W } else { 0 };
+		if reg >= 8 {
+			rex |= REX_R;
+		}
+		match rm {
+			Operand::Reg(r) => {
+				if r.ext() > 0 {
+					rex |= REX_B;
+				}
+			},
+			Operand::Mem(m) => {
+				if m.base.is_some_and(|b| b.ext() > 0) {
+					rex |= REX_B;
+				}
+				if m.index.is_some_and(|i| i.ext() > 0) {
+					rex |= REX_X;
+				}
+			},
+			Operand::Imm(_) => unreachable!("Immediate is not a r/m operand"),
+		}
+		if rex != 0 || force_rex {
+			self.code.emit(REX | rex);
+		}
+		self.emit_bytes(opcode);
+
+		let reg = (reg & 7) << 3;
+		match rm {
+			Operand::Reg(r) => self.code.emit(0xc0 | reg | r.low()),
+			Operand::Mem(Mem { base: None, disp, .. }) => {
+				self.code.emit(reg | 0x05);
+				self.code.emit_imm32_le(disp);
+			},
+			Operand::Mem(Mem { base: Some(base), index, scale, disp }) => {
+				// rbp and r13 as a base cannot be encoded without a displacement
+				let (mode, disp_len) = if disp == 0 && base.low() != 5 {
+					(0x00, 0)
+				} else if fits_i8(disp as i64) {
+					(0x40, 1)
+				} else {
+					(0x80, 4)
+				};
+				match index {
+					Some(index) => {
+						assert!(index != Gpr::RSP, "rsp cannot be an index");
+						let ss = match scale {
+							1 => 0x00,
+							2 => 0x40,
+							4 => 0x80,
+							8 => 0xc0,
+							_ => panic!("Invalid scale {}", scale),
+						};
+						self.code.emit(mode | reg | 0x04);
+						self.code.emit(ss | index.low() << 3 | base.low());
+					},
+					None if base.low() == 4 => {
+						// rsp and r12 as a base always need a SIB byte
+						self.code.emit(mode | reg | 0x04);
+						self.code.emit(0x24);
+					},
+					None => self.code.emit(mode | reg | base.low()),
+				}
+				match disp_len {
+					1 => self.code.emit(disp as u8),
+					4 => self.code.emit_imm32_le(disp),
+					_ => (),
+				}
+			},
+			Operand::Imm(_) => unreachable!(),
+		}
+	}
+
+	fn byte_rex(width: Width, rm: Operand, reg: Option<Gpr>) -> bool {
+		matches!(rm, Operand::Reg(r) if needs_byte_rex(width, r)) || reg.is_some_and(|r| needs_byte_rex(width, r))
+	}
+
+	// Instructions with the register number in the opcode (push, pop, mov r, imm, xchg rax)
+	fn encode_plus_reg(&mut self, width: Width, opcode: u8, r: Gpr) {
+		self.code.begin_insn();
+		if width == Width::W16 {
+			self.code.emit(OPER_SIZE_OVR);
+		}
+		let rex = if width == Width::W64 { REX_W } else { 0 } | r.ext();
+		if rex != 0 || needs_byte_rex(width, r) {
+			self.code.emit(REX | rex);
+		}
+		self.code.emit(opcode | r.low());
+	}
+
+	pub(crate) fn push(&mut self, src: impl Into<Operand>) {
+		match src.into() {
+			// Push and pop are 64-bit by default, no REX.W needed
+			Operand::Reg(r) => self.encode_plus_reg(Width::W32, 0x50, r),
+			m @ Operand::Mem(_) => self.encode(&[], Width::W32, &[0xff], 6, m, false),
+			Operand::Imm(Imm(imm)) => {
+				self.code.begin_insn();
+				if fits_i8(imm) {
+					self.emit_bytes(&[0x6a, imm as u8]);
+				} else {
+					self.code.emit(0x68);
+					self.code.emit_imm32_le(imm as i32);
+				}
+			}
+		}
+	}
+
+	pub(crate) fn pop(&mut self, dst: Gpr) {
+		self.encode_plus_reg(Width::W32, 0x58, dst);
+	}
+
+	pub(crate) fn mov(&mut self, width: Width, dst: impl Into<Operand>, src: impl Into<Operand>) {
+		let op = if width == Width::W8 { 0x88 } else { 0x89 };
+		match (dst.into(), src.into()) {
+			(dst @ (Operand::Reg(_) | Operand::Mem(_)), Operand::Reg(src)) => {
+				self.encode(&[], width, &[op], src.0, dst, Self::byte_rex(width, dst, Some(src)));
+			},
+			(Operand::Reg(dst), src @ Operand::Mem(_)) => {
+				self.encode(&[], width, &[op | 2], dst.0, src, Self::byte_rex(width, src, Some(dst)));
+			},
+			(Operand::Reg(dst), Operand::Imm(Imm(imm))) => {
+				match width {
+					Width::W8 => {
+						self.encode_plus_reg(width, 0xb0, dst);
+						self.code.emit(imm as u8);
+					},
+					Width::W64 if imm < 0 || imm > u32::MAX as i64 => {
+						if fits_i32(imm) {
+							self.encode(&[], width, &[0xc7], 0, Operand::Reg(dst), false); // Sign-extended imm32
+							self.code.emit_imm32_le(imm as i32);
+						} else {
+							self.movabs(dst, imm);
+						}
+					},
+					Width::W64 => {
+						// 32-bit moves zero-extend to 64 bits
+						self.encode_plus_reg(Width::W32, 0xb8, dst);
+						self.code.emit_imm32_le(imm as i32);
+					},
+					_ => {
+						self.encode_plus_reg(width, 0xb8, dst);
+						self.emit_imm(width, imm);
+					},
+				}
+			},
+			(dst @ Operand::Mem(_), Operand::Imm(Imm(imm))) => {
+				assert!(fits_i32(imm), "Immediate too large");
+				self.encode(&[], width, &[if width == Width::W8 { 0xc6 } else { 0xc7 }], 0, dst, false);
+				self.emit_imm(width, imm);
+			},
+			(dst, src) => panic!("Invalid operands mov {:?}, {:?}", dst, src),
+		}
+	}
+
+	/// Always uses the 64-bit immediate form. Returns the offset of the immediate, for it to be
+	/// relocated.
+	pub(crate) fn movabs(&mut self, dst: Gpr, imm: i64) -> usize {
+		self.encode_plus_reg(Width::W64, 0xb8, dst);
+		let pos = self.code.pc();
+		self.code.emit_imm64_le(imm);
+		pos
+	}
+
+	/// `movabs` with the immediate to be filled in by the relocation
+	pub(crate) fn movabs_reloc(&mut self, dst: Gpr, reloc: Relocation) -> usize {
+		self.encode_plus_reg(Width::W64, 0xb8, dst);
+		self.code.reloc(reloc);
+		let pos = self.code.pc();
+		self.code.emit_imm64_le(0);
+		pos
+	}
+
+	/// Zero-extends an 8 or 16-bit value to 32 bits (and thus to 64 bits)
+	pub(crate) fn movzx(&mut self, dst: Gpr, src: impl Into<Operand>, from: Width) {
+		let src = src.into();
+		let op = match from {
+			Width::W8 => 0xb6,
+			Width::W16 => 0xb7,
+			_ => panic!("Invalid movzx source width {:?}", from),
+		};
+		self.encode(&[], Width::W32, &[0x0f, op], dst.0, src, Self::byte_rex(from, src, None));
+	}
+
+	pub(crate) fn movsx(&mut self, width: Width, dst: Gpr, src: impl Into<Operand>, from: Width) {
+		let src = src.into();
+		match from {
+			Width::W8 => self.encode(&[], width, &[0x0f, 0xbe], dst.0, src, Self::byte_rex(from, src, None)),
+			Width::W16 => self.encode(&[], width, &[0x0f, 0xbf], dst.0, src, false),
+			Width::W32 => self.encode(&[], width, &[0x63], dst.0, src, false), // movsxd
+			Width::W64 => panic!("Invalid movsx source width"),
+		}
+	}
+
+	pub(crate) fn lea(&mut self, width: Width, dst: Gpr, src: Mem) {
+		self.encode(&[], width, &[0x8d], dst.0, Operand::Mem(src), false);
+	}
+
+	pub(crate) fn cmov(&mut self, width: Width, cond: Cond, dst: Gpr, src: impl Into<Operand>) {
+		self.encode(&[], width, &[0x0f, 0x40 | cond as u8], dst.0, src.into(), false);
+	}
+
+	/// Sets the low byte of the register only
+	pub(crate) fn setcc(&mut self, cond: Cond, dst: Gpr) {
+		self.encode(&[], Width::W8, &[0x0f, 0x90 | cond as u8], 0, Operand::Reg(dst), needs_byte_rex(Width::W8, dst));
+	}
+
+	fn alu(&mut self, ext: u8, width: Width, dst: Operand, src: Operand) {
+		let byte = width == Width::W8;
+		match (dst, src) {
+			(dst @ (Operand::Reg(_) | Operand::Mem(_)), Operand::Reg(src)) => {
+				self.encode(&[], width, &[ext << 3 | if byte { 0 } else { 1 }], src.0, dst, Self::byte_rex(width, dst, Some(src)));
+			},
+			(Operand::Reg(dst), src @ Operand::Mem(_)) => {
+				self.encode(&[], width, &[ext << 3 | if byte { 2 } else { 3 }], dst.0, src, Self::byte_rex(width, src, Some(dst)));
+			},
+			(dst @ (Operand::Reg(_) | Operand::Mem(_)), Operand::Imm(Imm(imm))) => {
+				if byte {
+					self.encode(&[], width, &[0x80], ext, dst, Self::byte_rex(width, dst, None));
+					self.code.emit(imm as u8);
+				} else if fits_i8(imm) {
+					self.encode(&[], width, &[0x83], ext, dst, false);
+					self.code.emit(imm as u8);
+				} else {
+					assert!(fits_i32(imm), "Immediate too large");
+					self.encode(&[], width, &[0x81], ext, dst, false);
+					self.emit_imm(if width == Width::W16 { Width::W16 } else { Width::W32 }, imm);
+				}
+			},
+			(dst, src) => panic!("Invalid ALU operands {:?}, {:?}", dst, src),
+		}
+	}
+
+	pub(crate) fn add(&mut self, width: Width, dst: impl Into<Operand>, src: impl Into<Operand>) {
+		self.alu(0, width, dst.into(), src.into());
+	}
+
+	pub(crate) fn or(&mut self, width: Width, dst: impl Into<Operand>, src: impl Into<Operand>) {
+		self.alu(1, width, dst.into(), src.into());
+	}
+
+	pub(crate) fn and(&mut self, width: Width, dst: impl Into<Operand>, src: impl Into<Operand>) {
+		self.alu(4, width, dst.into(), src.into());
+	}
+
+	pub(crate) fn sub(&mut self, width: Width, dst: impl Into<Operand>, src: impl Into<Operand>) {
+		self.alu(5, width, dst.into(), src.into());
+	}
+
+	pub(crate) fn xor(&mut self, width: Width, dst: impl Into<Operand>, src: impl Into<Operand>) {
+		self.alu(6, width, dst.into(), src.into());
+	}
+
+	pub(crate) fn cmp(&mut self, width: Width, dst: impl Into<Operand>, src: impl Into<Operand>) {
+		self.alu(7, width, dst.into(), src.into());
+	}
+
+	pub(crate) fn xchg(&mut self, width: Width, a: Gpr, b: Gpr) {
+		match (a, b) {
+			(Gpr::RAX, r) | (r, Gpr::RAX) if width != Width::W8 => self.encode_plus_reg(width, 0x90, r),
+			_ => self.encode(&[], width, &[if width == Width::W8 { 0x86 } else { 0x87 }], a.0, Operand::Reg(b), Self::byte_rex(width, Operand::Reg(b), Some(a))),
+		}
+	}
+
+	// Group 3 with the accumulator as the implicit operand
+	fn group3(&mut self, ext: u8, width: Width, src: Operand) {
+		self.encode(&[], width, &[if width == Width::W8 { 0xf6 } else { 0xf7 }], ext, src, Self::byte_rex(width, src, None));
+	}
+
+	/// rdx:rax = rax * src, signed
+	pub(crate) fn imul(&mut self, width: Width, src: impl Into<Operand>) {
+		self.group3(5, width, src.into());
+	}
+
+	/// rax = rdx:rax / src, rdx = rdx:rax % src, unsigned
+	pub(crate) fn div(&mut self, width: Width, src: impl Into<Operand>) {
+		self.group3(6, width, src.into());
+	}
+
+	/// rax = rdx:rax / src, rdx = rdx:rax % src, signed
+	pub(crate) fn idiv(&mut self, width: Width, src: impl Into<Operand>) {
+		self.group3(7, width, src.into());
+	}
+
+	/// Sign-extends the accumulator into rdx (`cdq` or `cqo`)
+	pub(crate) fn sign_extend_acc(&mut self, width: Width) {
+		self.code.begin_insn();
+		match width {
+			Width::W32 => self.code.emit(0x99),
+			Width::W64 => self.emit_bytes(&[REX | REX_W, 0x99]),
+			_ => panic!("Invalid width {:?}", width),
+		}
+	}
+
+	// Group 2, the count is either cl or an immediate
+	fn shift(&mut self, ext: u8, width: Width, dst: Operand, count: Operand) {
+		let byte = width == Width::W8;
+		match count {
+			Operand::Reg(Gpr::RCX) => self.encode(&[], width, &[if byte { 0xd2 } else { 0xd3 }], ext, dst, Self::byte_rex(width, dst, None)),
+			Operand::Imm(Imm(imm)) => {
+				self.encode(&[], width, &[if byte { 0xc0 } else { 0xc1 }], ext, dst, Self::byte_rex(width, dst, None));
+				self.code.emit(imm as u8);
+			},
+			_ => panic!("Shift count must be cl or an immediate"),
+		}
+	}
+
+	pub(crate) fn rol(&mut self, width: Width, dst: impl Into<Operand>, count: impl Into<Operand>) {
+		self.shift(0, width, dst.into(), count.into());
+	}
+
+	pub(crate) fn ror(&mut self, width: Width, dst: impl Into<Operand>, count: impl Into<Operand>) {
+		self.shift(1, width, dst.into(), count.into());
+	}
+
+	pub(crate) fn shl(&mut self, width: Width, dst: impl Into<Operand>, count: impl Into<Operand>) {
+		self.shift(4, width, dst.into(), count.into());
+	}
+
+	pub(crate) fn shr(&mut self, width: Width, dst: impl Into<Operand>, count: impl Into<Operand>) {
+		self.shift(5, width, dst.into(), count.into());
+	}
+
+	pub(crate) fn sar(&mut self, width: Width, dst: impl Into<Operand>, count: impl Into<Operand>) {
+		self.shift(7, width, dst.into(), count.into());
+	}
+
+	pub(crate) fn lzcnt(&mut self, width: Width, dst: Gpr, src: impl Into<Operand>) {
+		self.encode(&[REP], width, &[0x0f, 0xbd], dst.0, src.into(), false);
+	}
+
+	pub(crate) fn tzcnt(&mut self, width: Width, dst: Gpr, src: impl Into<Operand>) {
+		self.encode(&[REP], width, &[0x0f, 0xbc], dst.0, src.into(), false);
+	}
+
+	pub(crate) fn popcnt(&mut self, width: Width, dst: Gpr, src: impl Into<Operand>) {
+		self.encode(&[REP], width, &[0x0f, 0xb8], dst.0, src.into(), false);
+	}
+
+	/// Near jump to a label, resolved on linking
+	pub(crate) fn jmp(&mut self, label: IrLabel) {
+		self.code.begin_insn();
+		self.code.emit(0xe9);
+		self.code.branch(label);
+		self.code.emit_imm32_le(0);
+	}
+
+	/// Near conditional jump to a label, resolved on linking
+	pub(crate) fn jcc(&mut self, cond: Cond, label: IrLabel) {
+		self.code.begin_insn();
+		self.emit_bytes(&[0x0f, 0x80 | cond as u8]);
+		self.code.branch(label);
+		self.code.emit_imm32_le(0);
+	}
+
+	/// Short conditional jump to an already emitted offset
+	pub(crate) fn jcc_short(&mut self, cond: Cond, target: usize) {
+		self.code.begin_insn();
+		let rel = target as isize - (self.code.pc() + 2) as isize;
+		assert!(fits_i8(rel as i64), "Short jump out of range");
+		self.emit_bytes(&[0x70 | cond as u8, rel as u8]);
+	}
+
+	/// Short conditional jump forward, to the offset given to `bind` later
+	pub(crate) fn jcc_short_forward(&mut self, cond: Cond) -> ShortJump {
+		self.code.begin_insn();
+		self.emit_bytes(&[0x70 | cond as u8, 0]);
+		ShortJump(self.code.pc() - 1)
+	}
+
+	/// Short unconditional jump forward, to the offset given to `bind` later
+	pub(crate) fn jmp_short_forward(&mut self) -> ShortJump {
+		self.code.begin_insn();
+		self.emit_bytes(&[0xeb, 0]);
+		ShortJump(self.code.pc() - 1)
+	}
+
+	/// Resolves a forward short jump to the current offset
+	pub(crate) fn bind(&mut self, jump: ShortJump) {
+		let rel = self.code.pc() - (jump.0 + 1);
+		assert!(rel <= i8::MAX as usize, "Short jump out of range");
+		self.code.code[jump.0] = rel as u8;
+	}
+
+	pub(crate) fn jmp_indirect(&mut self, target: impl Into<Operand>) {
+		self.encode(&[], Width::W32, &[0xff], 4, target.into(), false);
+	}
+
+	/// Near call with the displacement to be patched. Returns the offset of the displacement.
+	pub(crate) fn call_rel32(&mut self) -> usize {
+		self.code.begin_insn();
+		self.code.emit(0xe8);
+		let pos = self.code.pc();
+		self.code.emit_imm32_le(0);
+		pos
+	}
+
+	pub(crate) fn call_indirect(&mut self, target: impl Into<Operand>) {
+		self.encode(&[], Width::W32, &[0xff], 2, target.into(), false);
+	}
+
+	/// Returns and removes `pop` bytes of arguments from the stack
+	pub(crate) fn ret(&mut self, pop: u16) {
+		self.code.begin_insn();
+		if pop > 0 {
+			self.code.emit(0xc2);
+			self.emit_bytes(&pop.to_le_bytes());
+		} else {
+			self.code.emit(0xc3);
+		}
+	}
+
+	pub(crate) fn ud2(&mut self) {
+		self.code.begin_insn();
+		self.emit_bytes(&[0x0f, 0x0b]);
+	}
+
+	pub(crate) fn cld(&mut self) {
+		self.code.begin_insn();
+		self.code.emit(0xfc);
+	}
+
+	/// Stores rax to [rdi] and advances rdi
+	pub(crate) fn stosq(&mut self) {
+		self.code.begin_insn();
+		self.emit_bytes(&[REX | REX_W, 0xab]);
+	}
+
+	/// Copies rcx bytes from [rsi] to [rdi]
+	pub(crate) fn rep_movsb(&mut self) {
+		self.code.begin_insn();
+		self.emit_bytes(&[REP, 0xa4]);
+	}
+}
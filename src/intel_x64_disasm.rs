@@ -0,0 +1,322 @@
+// x86-64 instruction decoder
+//
+// Covers the instructions the x86-64 code generator emits and prints them in Intel syntax.
+// Anything else is reported as undecodable, so that the caller may dump it as raw bytes.
+
+const REG64: [&str; 16] = ["rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15"];
+const REG32: [&str; 16] = ["eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi", "r8d", "r9d", "r10d", "r11d", "r12d", "r13d", "r14d", "r15d"];
+const REG16: [&str; 16] = ["ax", "cx", "dx", "bx", "sp", "bp", "si", "di", "r8w", "r9w", "r10w", "r11w", "r12w", "r13w", "r14w", "r15w"];
+const REG8: [&str; 16] = ["al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil", "r8b", "r9b", "r10b", "r11b", "r12b", "r13b", "r14b", "r15b"];
+const REG8_LEGACY: [&str; 4] = ["ah", "ch", "dh", "bh"];
+
+const COND: [&str; 16] = ["o", "no", "b", "ae", "e", "ne", "be", "a", "s", "ns", "p", "np", "l", "ge", "le", "g"];
+const GROUP1: [&str; 8] = ["add", "or", "adc", "sbb", "and", "sub", "xor", "cmp"];
+const GROUP2: [&str; 8] = ["rol", "ror", "rcl", "rcr", "shl", "shr", "sal", "sar"];
+const GROUP3: [&str; 8] = ["test", "test", "not", "neg", "mul", "imul", "div", "idiv"];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Size {
+	B8,
+	B16,
+	B32,
+	B64,
+}
+
+struct Decoder<'a> {
+	code: &'a [u8],
+	pos: usize,
+	rex: u8,
+}
+
+enum Rm {
+	Reg(usize),
+	Mem(String),
+}
+
+impl Decoder<'_> {
+	fn byte(&mut self) -> Option<u8> {
+		let b = *self.code.get(self.pos)?;
+		self.pos += 1;
+		Some(b)
+	}
+
+	fn imm8(&mut self) -> Option<i64> {
+		self.byte().map(|b| b as i8 as i64)
+	}
+
+	fn imm16(&mut self) -> Option<i64> {
+		let bytes = self.code.get(self.pos..self.pos + 2)?;
+		self.pos += 2;
+		Some(i16::from_le_bytes(bytes.try_into().unwrap()) as i64)
+	}
+
+	fn imm32(&mut self) -> Option<i64> {
+		let bytes = self.code.get(self.pos..self.pos + 4)?;
+		self.pos += 4;
+		Some(i32::from_le_bytes(bytes.try_into().unwrap()) as i64)
+	}
+
+	fn imm64(&mut self) -> Option<i64> {
+		let bytes = self.code.get(self.pos..self.pos + 8)?;
+		self.pos += 8;
+		Some(i64::from_le_bytes(bytes.try_into().unwrap()))
+	}
+
+	fn reg_name(&self, reg: usize, size: Size) -> &'static str {
+		match size {
+			Size::B64 => REG64[reg],
+			Size::B32 => REG32[reg],
+			Size::B16 => REG16[reg],
+			Size::B8 if self.rex == 0 && (4..8).contains(&reg) => REG8_LEGACY[reg - 4],
+			Size::B8 => REG8[reg],
+		}
+	}
+
+	// Returns the reg field and the r/m operand
+	fn modrm(&mut self) -> Option<(usize, Rm)> {
+		let modrm = self.byte()?;
+		let (mode, reg, rm) = (modrm >> 6, (modrm >> 3 & 7) as usize, (modrm & 7) as usize);
+		let reg = reg | ((self.rex as usize & 0x04) << 1);
+		if mode == 3 {
+			return Some((reg, Rm::Reg(rm | ((self.rex as usize & 0x01) << 3))));
+		}
+
+		let (base, index) = if rm == 4 {
+			let sib = self.byte()?;
+			let (scale, index, base) = (1 << (sib >> 6), (sib >> 3 & 7) as usize, (sib & 7) as usize);
+			let index = index | ((self.rex as usize & 0x02) << 2);
+			let base = if base == 5 && mode == 0 { None } else { Some(base | ((self.rex as usize & 0x01) << 3)) };
+			(base, if index == 4 { None } else { Some((index, scale)) })
+		} else if rm == 5 && mode == 0 {
+			let disp = self.imm32()?;
+			return Some((reg, Rm::Mem(format!("[rip{}]", fmt_disp(disp)))));
+		} else {
+			(Some(rm | ((self.rex as usize & 0x01) << 3)), None)
+		};
+
+		let disp = match mode {
+			1 => self.imm8()?,
+			2 => self.imm32()?,
+			_ if base.is_none() => self.imm32()?,
+			_ => 0,
+		};
+		let mut s = String::from("[");
+		if let Some(base) = base {
+			s += REG64[base];
+		}
+		if let Some((index, scale)) = index {
+			if base.is_some() {
+				s += " + ";
+			}
+			if scale > 1 {
+				s += &format!("{}*", scale);
+			}
+			s += REG64[index];
+		}
+		s += &fmt_disp(disp);
+		s += "]";
+		Some((reg, Rm::Mem(s)))
+	}
+
+	fn rm_name(&self, rm: &Rm, size: Size) -> String {
+		match rm {
+			Rm::Reg(r) => self.reg_name(*r, size).to_string(),
+			Rm::Mem(m) => format!("{} ptr {}", match size {
+				Size::B8 => "byte",
+				Size::B16 => "word",
+				Size::B32 => "dword",
+				Size::B64 => "qword",
+			}, m),
+		}
+	}
+}
+
+fn fmt_disp(disp: i64) -> String {
+	match disp {
+		0 => String::new(),
+		d if d < 0 => format!(" - {}", -d),
+		d => format!(" + {}", d),
+	}
+}
+
+/// Decodes one instruction located at `pc`. Returns its length and text, or `None` if the
+/// instruction is not known to the decoder.
+pub(crate) fn decode(code: &[u8], pc: usize) -> Option<(usize, String)> {
+	let mut d = Decoder { code, pos: 0, rex: 0 };
+	let (mut opsize16, mut rep) = (false, false);
+	let mut op = loop {
+		match d.byte()? {
+			0x66 => opsize16 = true,
+			0xf3 => rep = true,
+			b => break b,
+		}
+	};
+	if op & 0xf0 == 0x40 {
+		d.rex = op;
+		op = d.byte()?;
+	}
+	let size = if d.rex & 0x08 != 0 { Size::B64 } else if opsize16 { Size::B16 } else { Size::B32 };
+	let rex_b = (d.rex as usize & 0x01) << 3;
+
+	let text = match op {
+		0x00..=0x3f if op & 0x07 < 4 => {
+			let byte = op & 1 == 0;
+			let size = if byte { Size::B8 } else { size };
+			let (reg, rm) = d.modrm()?;
+			let (reg, rm) = (d.reg_name(reg, size), d.rm_name(&rm, size));
+			if op & 2 == 0 {
+				format!("{} {}, {}", GROUP1[op as usize >> 3], rm, reg)
+			} else {
+				format!("{} {}, {}", GROUP1[op as usize >> 3], reg, rm)
+			}
+		},
+		0x50..=0x57 => format!("push {}", REG64[(op & 7) as usize | rex_b]),
+		0x58..=0x5f => format!("pop {}", REG64[(op & 7) as usize | rex_b]),
+		0x63 => {
+			let (reg, rm) = d.modrm()?;
+			format!("movsxd {}, {}", d.reg_name(reg, size), d.rm_name(&rm, Size::B32))
+		},
+		0x68 => format!("push {}", d.imm32()?),
+		0x6a => format!("push {}", d.imm8()?),
+		0x70..=0x7f => {
+			let rel = d.imm8()?;
+			format!("j{} 0x{:x}", COND[(op & 0x0f) as usize], (pc + d.pos) as i64 + rel)
+		},
+		0x80 | 0x81 | 0x83 => {
+			let size = if op == 0x80 { Size::B8 } else { size };
+			let (ext, rm) = d.modrm()?;
+			let imm = match op {
+				0x81 if size == Size::B16 => d.imm16()?,
+				0x81 => d.imm32()?,
+				_ => d.imm8()?,
+			};
+			format!("{} {}, {}", GROUP1[ext & 7], d.rm_name(&rm, size), imm)
+		},
+		0x86..=0x8b => {
+			let size = if op & 1 == 0 { Size::B8 } else { size };
+			let (reg, rm) = d.modrm()?;
+			let (reg, rm) = (d.reg_name(reg, size), d.rm_name(&rm, size));
+			match op {
+				0x86 | 0x87 => format!("xchg {}, {}", reg, rm),
+				0x88 | 0x89 => format!("mov {}, {}", rm, reg),
+				_ => format!("mov {}, {}", reg, rm),
+			}
+		},
+		0x8d => {
+			let (reg, rm) = d.modrm()?;
+			let Rm::Mem(mem) = rm else { return None };
+			format!("lea {}, {}", d.reg_name(reg, size), mem)
+		},
+		0x90 if rex_b == 0 => "nop".to_string(),
+		0x90..=0x97 => format!("xchg {}, {}", d.reg_name(0, size), d.reg_name((op & 7) as usize | rex_b, size)),
+		0x99 => match size {
+			Size::B64 => "cqo",
+			Size::B16 => "cwd",
+			_ => "cdq",
+		}.to_string(),
+		0xa4 if rep => "rep movsb".to_string(),
+		0xa4 => "movsb".to_string(),
+		0xab if rep => return None,
+		0xab => match size {
+			Size::B64 => "stosq",
+			Size::B16 => "stosw",
+			_ => "stosd",
+		}.to_string(),
+		0xb0..=0xb7 => format!("mov {}, {}", d.reg_name((op & 7) as usize | rex_b, Size::B8), d.byte()?),
+		0xb8..=0xbf => {
+			let reg = d.reg_name((op & 7) as usize | rex_b, size);
+			match size {
+				Size::B64 => format!("movabs {}, {}", reg, d.imm64()?),
+				Size::B16 => format!("mov {}, {}", reg, d.imm16()? as u16),
+				_ => format!("mov {}, {}", reg, d.imm32()? as u32),
+			}
+		},
+		0xc0 | 0xc1 | 0xd2 | 0xd3 => {
+			let size = if op & 1 == 0 { Size::B8 } else { size };
+			let (ext, rm) = d.modrm()?;
+			let rm = d.rm_name(&rm, size);
+			if op < 0xd0 {
+				format!("{} {}, {}", GROUP2[ext & 7], rm, d.byte()?)
+			} else {
+				format!("{} {}, cl", GROUP2[ext & 7], rm)
+			}
+		},
+		0xc2 => format!("ret {}", d.imm16()? as u16),
+		0xc3 => "ret".to_string(),
+		0xc6 | 0xc7 => {
+			let size = if op == 0xc6 { Size::B8 } else { size };
+			let (_, rm) = d.modrm()?;
+			let imm = match size {
+				Size::B8 => d.imm8()?,
+				Size::B16 => d.imm16()?,
+				_ => d.imm32()?,
+			};
+			format!("mov {}, {}", d.rm_name(&rm, size), imm)
+		},
+		0xe8 | 0xe9 => {
+			let rel = d.imm32()?;
+			format!("{} 0x{:x}", if op == 0xe8 { "call" } else { "jmp" }, (pc + d.pos) as i64 + rel)
+		},
+		0xeb => {
+			let rel = d.imm8()?;
+			format!("jmp 0x{:x}", (pc + d.pos) as i64 + rel)
+		},
+		0xf6 | 0xf7 => {
+			let size = if op == 0xf6 { Size::B8 } else { size };
+			let (ext, rm) = d.modrm()?;
+			let rm = d.rm_name(&rm, size);
+			match ext & 7 {
+				0 | 1 => format!("test {}, {}", rm, if size == Size::B8 { d.imm8()? } else { d.imm32()? }),
+				ext => format!("{} {}", GROUP3[ext], rm),
+			}
+		},
+		0xfc => "cld".to_string(),
+		0xff => {
+			let (ext, rm) = d.modrm()?;
+			match ext & 7 {
+				2 => format!("call {}", d.rm_name(&rm, Size::B64)),
+				4 => format!("jmp {}", d.rm_name(&rm, Size::B64)),
+				6 => format!("push {}", d.rm_name(&rm, Size::B64)),
+				_ => return None,
+			}
+		},
+		0x0f => {
+			let op = d.byte()?;
+			match op {
+				0x0b => "ud2".to_string(),
+				0x40..=0x4f => {
+					let (reg, rm) = d.modrm()?;
+					format!("cmov{} {}, {}", COND[(op & 0x0f) as usize], d.reg_name(reg, size), d.rm_name(&rm, size))
+				},
+				0x80..=0x8f => {
+					let rel = d.imm32()?;
+					format!("j{} 0x{:x}", COND[(op & 0x0f) as usize], (pc + d.pos) as i64 + rel)
+				},
+				0x90..=0x9f => {
+					let (_, rm) = d.modrm()?;
+					format!("set{} {}", COND[(op & 0x0f) as usize], d.rm_name(&rm, Size::B8))
+				},
+				0xb6 | 0xb7 | 0xbe | 0xbf => {
+					let (reg, rm) = d.modrm()?;
+					let from = if op & 1 == 0 { Size::B8 } else { Size::B16 };
+					format!("{} {}, {}", if op < 0xb8 { "movzx" } else { "movsx" }, d.reg_name(reg, size), d.rm_name(&rm, from))
+				},
+				0xb8 | 0xbc | 0xbd => {
+					let mnemonic = match (op, rep) {
+						(0xb8, true) => "popcnt",
+						(0xbc, true) => "tzcnt",
+						(0xbd, true) => "lzcnt",
+						(0xbc, false) => "bsf",
+						(0xbd, false) => "bsr",
+						_ => return None,
+					};
+					let (reg, rm) = d.modrm()?;
+					format!("{} {}, {}", mnemonic, d.reg_name(reg, size), d.rm_name(&rm, size))
+				},
+				_ => return None,
+			}
+		},
+		_ => return None,
+	};
+	Some((d.pos, text))
+}
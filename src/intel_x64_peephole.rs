@@ -0,0 +1,366 @@
+use std::collections::{HashMap, HashSet};
+use crate::{codegen::CodeEmitter, ir::IrLabel};
+
+// Machine-level peephole optimizer for the x86-64 code
+//
+// Runs over the instruction stream recorded by `CodeEmitter` before the code is linked. The
+// assembler already picks the shortest displacement and immediate encodings, but it cannot know
+// the branch distances, so the branches to labels are emitted with rel32 displacements. Here
+// redundant instructions are removed, and the branches are relaxed and resolved.
+//
+// Instructions containing relocations or link-time patched values are never touched. The
+// compiler also emits a few sequences with hardcoded short jumps and rip-relative addresses;
+// everything those span is left as is, too.
+
+const REX_W: u8 = 0x48;
+
+const OP_JMP_REL8: u8 = 0xeb;
+const OP_JMP_REL32: u8 = 0xe9;
+const OP_JCC_REL8: u8 = 0x70;
+const OP_JCC_REL32: u8 = 0x80; // Preceded by 0x0f
+
+const SP: u8 = 4;
+const BX: u8 = 3;
+const BP: u8 = 5;
+
+#[derive(Debug)]
+struct Branch {
+	// Condition code for conditional jumps
+	cond: Option<u8>,
+	label: IrLabel,
+	long: bool,
+}
+
+#[derive(Debug)]
+struct Insn {
+	start: usize,
+	bytes: Vec<u8>,
+	fixed: bool,
+	labeled: bool,
+	removed: bool,
+	branch: Option<Branch>,
+}
+
+impl Insn {
+	fn len(&self) -> usize {
+		match &self.branch {
+			Some(Branch { long: false, .. }) => 2,
+			Some(Branch { cond: None, long: true, .. }) => 5,
+			Some(Branch { cond: Some(_), long: true, .. }) => 6,
+			None => self.bytes.len(),
+		}
+	}
+
+	fn is(&self, bytes: &[u8]) -> bool {
+		!self.removed && self.branch.is_none() && self.bytes == bytes
+	}
+}
+
+/// Optimizes the emitted code in place, resolving all the branches. `anchors` are the offsets
+/// the code generator keeps for itself; they are updated to point to the same bytes in the
+/// optimized code.
+pub(crate) fn optimize(code: &mut CodeEmitter, anchors: &mut [usize]) {
+	let mut insns = split(code, anchors);
+
+	fuse_conditional_jumps(&mut insns, &code.labels);
+	remove_redundant_moves(&mut insns);
+	remove_redundant_block_exits(&mut insns);
+
+	let live = insns.iter().enumerate().filter(|(_, i)| !i.removed).map(|(n, _)| n).collect::<Vec<_>>();
+	let live_starts = live.iter().map(|n| insns[*n].start).collect::<Vec<_>>();
+	let target_of = |offset: usize| live_starts.partition_point(|start| *start < offset);
+	let branch_targets = live.iter().map(|n| match &insns[*n].branch {
+		Some(branch) => target_of(*code.labels.get(&branch.label).unwrap_or_else(|| panic!("Unresolved label: {:?}", branch.label))),
+		None => 0,
+	}).collect::<Vec<_>>();
+
+	// Branch relaxation. All the branches start short and are made long until every
+	// displacement fits. Branches only grow, so the process terminates.
+	let mut new_starts;
+	loop {
+		new_starts = Vec::with_capacity(live.len() + 1);
+		let mut pc = 0;
+		for n in live.iter() {
+			new_starts.push(pc);
+			pc += insns[*n].len();
+		}
+		new_starts.push(pc);
+
+		let mut changed = false;
+		for (k, n) in live.iter().enumerate() {
+			if let Some(branch) = insns[*n].branch.as_mut() {
+				let disp = new_starts[branch_targets[k]] as isize - (new_starts[k] + 2) as isize;
+				if !branch.long && i8::try_from(disp).is_err() {
+					branch.long = true;
+					changed = true;
+				}
+			}
+		}
+		if !changed {
+			break;
+		}
+	}
+
+	let mut new_code = Vec::with_capacity(new_starts[live.len()]);
+	for (k, n) in live.iter().enumerate() {
+		let insn = &insns[*n];
+		match &insn.branch {
+			Some(branch) => {
+				let end = new_starts[k] + insn.len();
+				let disp = new_starts[branch_targets[k]] as isize - end as isize;
+				match (branch.cond, branch.long) {
+					(None, false) => new_code.extend([OP_JMP_REL8, disp as i8 as u8]),
+					(Some(cond), false) => new_code.extend([OP_JCC_REL8 | cond, disp as i8 as u8]),
+					(None, true) => new_code.push(OP_JMP_REL32),
+					(Some(cond), true) => new_code.extend([0x0f, OP_JCC_REL32 | cond]),
+				}
+				if branch.long {
+					new_code.extend((disp as i32).to_le_bytes());
+				}
+			},
+			None => new_code.extend(&insn.bytes),
+		}
+	}
+
+	// Offsets pointing to the beginning of an instruction follow the instruction (or the next
+	// surviving one if it has been removed), offsets inside an instruction can only be in fixed
+	// ones, which are copied verbatim
+	let remap = |offset: usize| {
+		let k = target_of(offset);
+		if live_starts.get(k) == Some(&offset) {
+			return new_starts[k];
+		}
+		let n = insns.partition_point(|i| i.start <= offset) - 1;
+		assert!(insns[n].fixed && !insns[n].removed, "Offset inside a fixed instruction");
+		new_starts[live.partition_point(|l| *l < n)] + offset - insns[n].start
+	};
+
+	for offset in code.labels.values_mut() {
+		*offset = new_starts[target_of(*offset)];
+	}
+	for (offset, _) in code.ir_map.iter_mut() {
+		*offset = new_starts[target_of(*offset)];
+	}
+	for (offset, _, _) in code.source_map.iter_mut() {
+		*offset = new_starts[target_of(*offset)];
+	}
+	for (offset, _) in code.call_sites.iter_mut() {
+		*offset = new_starts[target_of(*offset)];
+	}
+	for (_, offset) in code.relocs.iter_mut() {
+		*offset = remap(*offset);
+	}
+	for offset in anchors.iter_mut() {
+		*offset = remap(*offset);
+	}
+	code.insns = new_starts[..live.len()].to_vec();
+	code.branches.clear();
+	code.code = new_code;
+}
+
+fn split(code: &CodeEmitter, anchors: &[usize]) -> Vec<Insn> {
+	let mut starts = code.insns.clone();
+	if starts.first() != Some(&0) && !code.code.is_empty() {
+		starts.insert(0, 0);
+	}
+	let branches = code.branches.iter().cloned().collect::<HashMap<_, _>>();
+	let labeled = code.labels.values().collect::<HashSet<_>>();
+
+	let mut insns = starts.iter().enumerate().map(|(n, start)| {
+		let end = starts.get(n + 1).copied().unwrap_or(code.code.len());
+		let bytes = code.code[*start..end].to_vec();
+		let branch = branches.get(start).map(|label| Branch {
+			cond: if bytes[0] == OP_JMP_REL32 { None } else { Some(bytes[1] & 0x0f) },
+			label: label.clone(),
+			long: false,
+		});
+		Insn { start: *start, bytes, fixed: false, labeled: labeled.contains(start), removed: false, branch }
+	}).collect::<Vec<_>>();
+
+	let containing = |offset: usize| insns.partition_point(|i| i.start <= offset) - 1;
+	let mut fixed = code.relocs.iter().map(|(_, offset)| containing(*offset)).chain(anchors.iter().map(|offset| containing(*offset))).collect::<Vec<_>>();
+
+	// Hardcoded relative offsets, nothing between the instruction and its target may change
+	for (n, insn) in insns.iter().enumerate() {
+		if insn.branch.is_some() {
+			continue;
+		}
+		let end = insn.start + insn.bytes.len();
+		let disp = match insn.bytes[..] {
+			[op, rel8] if op == OP_JMP_REL8 || op & 0xf0 == OP_JCC_REL8 => Some(rel8 as i8 as isize),
+			_ => rip_relative_disp(&insn.bytes).map(|disp| disp as isize),
+		};
+		if let Some(disp) = disp {
+			let target = (end as isize + disp) as usize;
+			let (from, to) = (std::cmp::min(end, target), std::cmp::max(end, target));
+			fixed.push(n);
+			fixed.extend((containing(from)..insns.len()).take_while(|k| insns[*k].start < to));
+		}
+	}
+
+	for n in fixed {
+		insns[n].fixed = true;
+		if let Some(branch) = insns[n].branch.as_mut() {
+			branch.long = true;
+		}
+	}
+
+	insns
+}
+
+// Position of the ModRM byte for the opcodes the compiler emits with one
+fn modrm_pos(bytes: &[u8]) -> Option<usize> {
+	let mut pos = bytes.iter().take_while(|b| matches!(b, 0x66 | 0xf2 | 0xf3)).count();
+	if bytes.get(pos).is_some_and(|b| b & 0xf0 == 0x40) {
+		pos += 1;
+	}
+	match bytes.get(pos)? {
+		0x0f => match bytes.get(pos + 1)? {
+			0x40..=0x4f | 0x90..=0x9f | 0xaf | 0xb6 | 0xb7 | 0xb8 | 0xbc | 0xbd | 0xbe | 0xbf => Some(pos + 2),
+			_ => None,
+		},
+		0x01 | 0x03 | 0x09 | 0x0b | 0x21 | 0x23 | 0x29 | 0x2b | 0x31 | 0x33 | 0x39 | 0x3b | 0x63 |
+		0x81 | 0x83 | 0x85 | 0x88..=0x8b | 0x8d | 0xc1 | 0xc7 | 0xd3 | 0xf7 | 0xff => Some(pos + 1),
+		_ => None,
+	}
+}
+
+fn rip_relative_disp(bytes: &[u8]) -> Option<i32> {
+	let pos = modrm_pos(bytes)?;
+	if bytes.get(pos)? & 0xc7 == 0x05 {
+		Some(i32::from_le_bytes(bytes.get(pos + 1..pos + 5)?.try_into().ok()?))
+	} else {
+		None
+	}
+}
+
+fn next_live(insns: &[Insn], mut n: usize) -> Option<usize> {
+	n += 1;
+	while insns.get(n)?.removed {
+		n += 1;
+	}
+	Some(n)
+}
+
+// setcc <r8> ; movzx <r32>, <r8> ; and <r>, <r> ; jz/jnz <label>
+//   =>
+// jncc/jcc <label>
+//
+// The flags `setcc` reads are still there when the jump is reached. The register must be dead
+// after the jump, it is checked to be overwritten before it's read both after the jump and at
+// the label.
+fn fuse_conditional_jumps(insns: &mut [Insn], labels: &HashMap<IrLabel, usize>) {
+	for n in 0..insns.len() {
+		let [0x0f, setcc, modrm] = insns[n].bytes[..] else { continue };
+		if insns[n].fixed || insns[n].removed || setcc & 0xf0 != 0x90 || modrm >> 6 != 0b11 {
+			continue;
+		}
+		let cond = setcc & 0x0f;
+		let reg = modrm & 7;
+		let rr = 0xc0 | reg << 3 | reg;
+
+		let Some(movzx) = next_live(insns, n) else { continue };
+		let Some(and) = next_live(insns, movzx) else { continue };
+		let Some(jcc) = next_live(insns, and) else { continue };
+		if !insns[movzx].is(&[0x0f, 0xb6, rr]) || !(insns[and].is(&[0x21, rr]) || insns[and].is(&[REX_W, 0x21, rr])) {
+			continue;
+		}
+		if [movzx, and, jcc].iter().any(|k| insns[*k].labeled || insns[*k].fixed) {
+			continue;
+		}
+		let Some(target) = insns[jcc].branch.as_ref().and_then(|branch| labels.get(&branch.label)) else { continue };
+		let target = insns.partition_point(|i| i.start < *target);
+		if !is_dead_from(insns, next_live(insns, jcc), reg) || !is_dead_from(insns, Some(target), reg) {
+			continue;
+		}
+		let Some(branch) = insns[jcc].branch.as_mut() else { continue };
+		branch.cond = match branch.cond {
+			Some(0x4) => Some(cond ^ 1), // jz
+			Some(0x5) => Some(cond),     // jnz
+			_ => continue,
+		};
+		for k in [n, movzx, and] {
+			insns[k].removed = true;
+		}
+	}
+}
+
+// Whether the low register `reg` is overwritten before it's read, from the instruction `n` on.
+// Only the instructions the code generator starts a Wasm instruction or leaves a block with are
+// known, anything else counts as a read.
+fn is_dead_from(insns: &[Insn], mut n: Option<usize>, reg: u8) -> bool {
+	while let Some(k) = n {
+		let insn = &insns[k];
+		if insn.removed {
+			n = next_live(insns, k);
+			continue;
+		}
+		if insn.branch.is_some() {
+			return false;
+		}
+		match insn.bytes[..] {
+			// pop <r> ; mov <r32>, <imm32> ; movabs <r>, <imm64>
+			[op] if op == 0x58 | reg => return true,
+			[op, _, _, _, _] if op == 0xb8 | reg => return true,
+			[REX_W, op, _, _, _, _, _, _, _, _] if op == 0xb8 | reg => return true,
+			// mov rsp, rbp ; pop rbp
+			[REX_W, 0x89, 0xec] | [0x5d] => n = next_live(insns, k),
+			// mov <r>, <r/m> not addressing through <r>
+			[0x8b, modrm, ref rest @ ..] | [REX_W, 0x8b, modrm, ref rest @ ..] if modrm >> 3 & 7 == reg => {
+				let rm = modrm & 7;
+				return match modrm >> 6 {
+					0b11 => rm != reg,
+					// SIB byte
+					_ if rm == SP => rest.first().is_some_and(|sib| sib & 7 != reg && sib >> 3 & 7 != reg),
+					0b00 if rm == BP => true, // rip-relative
+					_ => rm != reg,
+				};
+			},
+			_ => return false,
+		}
+	}
+	false
+}
+
+// mov <a>, <a>
+// mov <a>, <b> ; mov <b>, <a>
+fn remove_redundant_moves(insns: &mut [Insn]) {
+	for n in 0..insns.len() {
+		let [REX_W, 0x89, modrm] = insns[n].bytes[..] else { continue };
+		if insns[n].fixed || insns[n].removed || modrm >> 6 != 0b11 {
+			continue;
+		}
+		let (src, dest) = (modrm >> 3 & 7, modrm & 7);
+		if src == dest {
+			insns[n].removed = true;
+			continue;
+		}
+		if let Some(next) = next_live(insns, n) {
+			if insns[next].is(&[REX_W, 0x89, 0xc0 | dest << 3 | src]) && !insns[next].labeled && !insns[next].fixed {
+				insns[next].removed = true;
+			}
+		}
+	}
+}
+
+// Leaving a block (mov rsp, rbp ; pop rbp) right before leaving the function or the inlined
+// function is useless as both restore rsp and rbp from the frame anyway. Whoever jumps into the
+// removed instructions ends up in the same state, so the labels do not matter here.
+fn remove_redundant_block_exits(insns: &mut [Insn]) {
+	let is_frame_exit = |insn: &Insn| {
+		// lea rsp, [rbx+<disp>] (function exit) or mov rsp, [rbx+<disp>] (inlined function exit)
+		!insn.fixed && insn.branch.is_none() &&
+		matches!(insn.bytes[..], [REX_W, 0x8d | 0x8b, modrm, ..] if modrm & 0x3f == SP << 3 | BX && modrm >> 6 != 0b11)
+	};
+	for n in (0..insns.len()).rev() {
+		if insns[n].fixed || !insns[n].is(&[REX_W, 0x89, 0xc0 | BP << 3 | SP]) {
+			continue;
+		}
+		let Some(pop) = next_live(insns, n) else { continue };
+		let Some(exit) = next_live(insns, pop) else { continue };
+		if !insns[pop].fixed && insns[pop].is(&[0x58 | BP]) && is_frame_exit(&insns[exit]) {
+			insns[n].removed = true;
+			insns[pop].removed = true;
+		}
+	}
+}
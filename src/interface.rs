@@ -0,0 +1,69 @@
+use crate::{ValueType, ir::IrSignature};
+
+/// Wasm value types of the parameters and the results of a function
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct FuncSignature {
+	pub params: Vec<ValueType>,
+	pub results: Vec<ValueType>,
+}
+
+impl From<&IrSignature> for FuncSignature {
+	fn from(signature: &IrSignature) -> Self {
+		Self { params: signature.param_types.clone(), results: signature.result_types.clone() }
+	}
+}
+
+/// Size limits of a memory, in pages, or of a table, in elements, as declared by the module
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+	pub initial: u32,
+	pub maximum: Option<u32>,
+}
+
+/// Type of an imported or exported item
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExternType {
+	Func(FuncSignature),
+	Global { ty: ValueType, mutable: bool },
+	Memory(Limits),
+	Table(Limits),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModuleImport {
+	pub module: String,
+	pub name: String,
+	pub ty: ExternType,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModuleExport {
+	pub name: String,
+	/// Index of the item in its index space, e.g. the function index of a function
+	pub index: u32,
+	pub ty: ExternType,
+}
+
+/// Everything the module imports and exports, and the memory and tables it defines, known
+/// without executing it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ModuleInterface {
+	pub imports: Vec<ModuleImport>,
+	pub exports: Vec<ModuleExport>,
+	pub memory: Option<Limits>,
+	pub tables: Vec<Limits>,
+}
+
+impl ModuleInterface {
+	pub fn export(&self, name: &str) -> Option<&ModuleExport> {
+		self.exports.iter().find(|export| export.name == name)
+	}
+
+	/// Signature of the exported function `name`
+	pub fn func_export(&self, name: &str) -> Option<&FuncSignature> {
+		match self.export(name) {
+			Some(ModuleExport { ty: ExternType::Func(signature), .. }) => Some(signature),
+			_ => None,
+		}
+	}
+}
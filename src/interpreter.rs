@@ -0,0 +1,527 @@
+use std::{collections::HashMap, rc::Rc};
+use crate::{PvfError, Backtrace, instance::invoke_raw, WasmFrame, ir::{IrNames, IrPvf, IrFunc, IrTable, IrCp, IrCp::*, IrOperand, IrOperand::*, IrReg, IrReg::*, IrCond, IrCond::*, IrLabel, IrSignature}};
+
+// Reference interpreter
+//
+// Executes the IR directly, with the machine model the code generators implement: three
+// 64-bit registers, flags set by `Compare` and `And`, and a value stack with the function
+// frame and block pointers laid out exactly like the native stack. It is not meant to be fast;
+// it is used to check the code generators against, including the ones for the architectures
+// the host cannot execute.
+//
+// Unlike the native code, the interpreter checks everything the Wasm spec requires to trap,
+// including the memory accesses beyond the current memory size.
+
+// Value stack size, in 64-bit slots
+const STACK_SLOTS: usize = 0x10000;
+
+const PAGE_SIZE: usize = 0x10000;
+
+fn trap() -> PvfError {
+	PvfError::Trap(Backtrace::default())
+}
+
+struct InterpretedFunc {
+	code: Vec<IrCp>,
+	labels: HashMap<IrLabel, usize>,
+}
+
+enum Func {
+	Import(*const u8),
+	Function(Rc<InterpretedFunc>),
+}
+
+// Flags are kept as the operands of the last comparison. `And` compares its result to zero,
+// which gives the same flags as the native `and`.
+#[derive(Clone, Copy)]
+struct Flags {
+	a: u64,
+	b: u64,
+	is64: bool,
+}
+
+pub struct IrInterpreter {
+	funcs: Vec<Option<Func>>,
+	signatures: Vec<Option<IrSignature>>,
+	exports: HashMap<String, u32>,
+	data_chunks: Vec<Vec<u8>>,
+	tables: Vec<Vec<Option<u32>>>,
+	table_cursor: usize,
+	globals: Vec<u64>,
+	memory: Vec<u8>,
+	memory_pages: u32,
+	memory_max_pages: u32,
+	stack: Vec<u64>,
+	sp: usize,
+	fp: usize,
+	bp: usize,
+	regs: [u64; 3],
+	flags: Flags,
+	names: IrNames,
+}
+
+impl IrInterpreter {
+	/// Sets up the instance state and runs the initialization function, like
+	/// `PvfInstance::instantiate` does
+	pub fn instantiate(pvf: &IrPvf) -> Result<Self, PvfError> {
+		let mut exports = HashMap::new();
+		let funcs = pvf.funcs.iter().map(|maybe_func| maybe_func.as_ref().map(|func| match func {
+			IrFunc::Import(addr) => Func::Import(*addr),
+			IrFunc::Function(ir) => {
+				let code = ir.code().to_vec();
+				let mut labels = HashMap::new();
+				for (pc, cp) in code.iter().enumerate() {
+					if let Label(label) = cp {
+						if let IrLabel::ExportedFunc(index, name) = label {
+							exports.insert(name.clone(), *index);
+						}
+						labels.insert(label.clone(), pc);
+					}
+				}
+				Func::Function(Rc::new(InterpretedFunc { code, labels }))
+			},
+		})).collect();
+
+		let tables = pvf.tables.iter().map(|table| match table {
+			IrTable::Table(max_size) => vec![None; *max_size as usize],
+			IrTable::Import(_) => todo!("Imported tables"),
+		}).collect();
+
+		let mut memory = vec![0; pvf.memory.0 as usize * PAGE_SIZE];
+		for (offset, bytes) in &pvf.memory_image {
+			memory[*offset as usize..*offset as usize + bytes.len()].copy_from_slice(bytes);
+		}
+
+		let mut interpreter = Self {
+			funcs,
+			signatures: pvf.signatures.clone(),
+			exports,
+			data_chunks: pvf.data_chunks.iter().map(|chunk| chunk.data.clone()).collect(),
+			tables,
+			table_cursor: 0,
+			globals: Vec::new(),
+			memory,
+			memory_pages: pvf.memory.0,
+			memory_max_pages: pvf.memory.1,
+			stack: vec![0; STACK_SLOTS],
+			sp: STACK_SLOTS,
+			fp: STACK_SLOTS,
+			bp: STACK_SLOTS,
+			regs: [0; 3],
+			flags: Flags { a: 0, b: 0, is64: false },
+			names: pvf.names().clone(),
+		};
+		interpreter.call("_pvf_init", &[])?;
+		Ok(interpreter)
+	}
+
+	/// Calls an exported function. Returns the raw 64-bit result register if the function has
+	/// a result.
+	pub fn call(&mut self, func: &str, args: &[u64]) -> Result<Option<u64>, PvfError> {
+		let index = *self.exports.get(func).ok_or(PvfError::ExportNotFound)?;
+		let signature = self.signatures[index as usize].clone().expect("Export signature available");
+		if args.len() != signature.params as usize {
+			return Err(PvfError::ValidationError(format!("{} expects {} argument(s), {} given", func, signature.params, args.len())));
+		}
+
+		self.sp = STACK_SLOTS;
+		for arg in args {
+			self.push(*arg)?;
+		}
+		self.push(0)?; // Return address
+		self.run(index)?;
+		Ok((signature.results > 0).then_some(self.regs[Sra as usize]))
+	}
+
+	pub fn memory(&self) -> &[u8] {
+		&self.memory
+	}
+
+	fn push(&mut self, value: u64) -> Result<(), PvfError> {
+		if self.sp == 0 {
+			return Err(trap());
+		}
+		self.sp -= 1;
+		self.stack[self.sp] = value;
+		Ok(())
+	}
+
+	fn pop(&mut self) -> u64 {
+		self.sp += 1;
+		self.stack[self.sp - 1]
+	}
+
+	fn local_slot(&self, n_params: u32, index: u32) -> usize {
+		if index < n_params {
+			self.fp - (index as usize + 1)
+		} else {
+			self.fp - (index as usize + 4)
+		}
+	}
+
+	fn memory_range(&self, offset: i32, addr: u64, size: usize) -> Result<std::ops::Range<usize>, PvfError> {
+		let start = addr as u32 as usize + offset as u32 as usize;
+		if start + size > self.memory.len() {
+			Err(trap())
+		} else {
+			Ok(start..start + size)
+		}
+	}
+
+	fn read(&self, op: &IrOperand, n_params: u32) -> Result<u64, PvfError> {
+		let load = |offset: &i32, r: &IrReg, size: usize| -> Result<u64, PvfError> {
+			let mut bytes = [0u8; 8];
+			bytes[..size].copy_from_slice(&self.memory[self.memory_range(*offset, self.regs[*r as usize], size)?]);
+			Ok(u64::from_le_bytes(bytes))
+		};
+		Ok(match op {
+			Reg(r) => self.regs[*r as usize],
+			Reg8(r) => self.regs[*r as usize] & 0xff,
+			Reg16(r) => self.regs[*r as usize] & 0xffff,
+			Reg32(r) => self.regs[*r as usize] & 0xffff_ffff,
+			Memory8(offset, r) => load(offset, r, 1)?,
+			Memory16(offset, r) => load(offset, r, 2)?,
+			Memory32(offset, r) => load(offset, r, 4)?,
+			Memory64(offset, r) => load(offset, r, 8)?,
+			// Loading a 32-bit immediate zero-extends it
+			Imm32(imm) => *imm as u32 as u64,
+			Imm64(imm) => *imm as u64,
+			Local(index) => self.stack[self.local_slot(n_params, *index)],
+			Global(index) => self.globals.get(*index as usize).copied().unwrap_or(0),
+		})
+	}
+
+	fn write(&mut self, op: &IrOperand, value: u64, n_params: u32) -> Result<(), PvfError> {
+		let mut store = |offset: &i32, r: &IrReg, size: usize| -> Result<(), PvfError> {
+			let range = self.memory_range(*offset, self.regs[*r as usize], size)?;
+			self.memory[range].copy_from_slice(&value.to_le_bytes()[..size]);
+			Ok(())
+		};
+		match op {
+			Reg(r) => self.regs[*r as usize] = value,
+			// Byte and word writes keep the upper part of the register, 32-bit writes clear it
+			Reg8(r) => self.regs[*r as usize] = self.regs[*r as usize] & !0xff | value & 0xff,
+			Reg16(r) => self.regs[*r as usize] = self.regs[*r as usize] & !0xffff | value & 0xffff,
+			Reg32(r) => self.regs[*r as usize] = value & 0xffff_ffff,
+			Memory8(offset, r) => store(offset, r, 1)?,
+			Memory16(offset, r) => store(offset, r, 2)?,
+			Memory32(offset, r) => store(offset, r, 4)?,
+			Memory64(offset, r) => store(offset, r, 8)?,
+			Local(index) => {
+				let slot = self.local_slot(n_params, *index);
+				self.stack[slot] = value;
+			},
+			Global(index) => {
+				if *index as usize >= self.globals.len() {
+					self.globals.resize(*index as usize + 1, 0);
+				}
+				self.globals[*index as usize] = value;
+			},
+			Imm32(_) | Imm64(_) => unreachable!(),
+		}
+		Ok(())
+	}
+
+	fn cond(&self, cond: &IrCond) -> bool {
+		let Flags { a, b, is64 } = self.flags;
+		let (ua, ub, sa, sb) = if is64 {
+			(a, b, a as i64, b as i64)
+		} else {
+			(a as u32 as u64, b as u32 as u64, a as i32 as i64, b as i32 as i64)
+		};
+		match cond {
+			Zero | Equal => ua == ub,
+			NotZero | NotEqual => ua != ub,
+			LessSigned => sa < sb,
+			LessUnsigned => ua < ub,
+			GreaterSigned => sa > sb,
+			GreaterUnsigned => ua > ub,
+			LessOrEqualSigned => sa <= sb,
+			LessOrEqualUnsigned => ua <= ub,
+			GreaterOrEqualSigned => sa >= sb,
+			GreaterOrEqualUnsigned => ua >= ub,
+		}
+	}
+
+	fn call_func(&mut self, index: u32) -> Result<(), PvfError> {
+		let signature = self.signatures[index as usize].clone().expect("Callee signature available");
+		match self.funcs[index as usize].as_ref().expect("Callee exists") {
+			Func::Import(addr) => {
+				let addr = *addr;
+				let args = self.stack[self.sp..self.sp + signature.params as usize].iter().rev().copied().collect::<Vec<_>>();
+				self.sp += signature.params as usize;
+				// SAFETY: The import resolver provides the functions with the signatures the
+				// module declares
+				self.regs[Sra as usize] = unsafe { invoke_raw(addr, &args) }?;
+			},
+			Func::Function(_) => {
+				self.push(0)?; // Return address
+				self.run(index)?;
+			},
+		}
+		if signature.results > 0 {
+			self.push(self.regs[Sra as usize])?;
+		}
+		Ok(())
+	}
+
+	// Runs a function. A trap gets the frame of the function appended to its backtrace.
+	fn run(&mut self, index: u32) -> Result<(), PvfError> {
+		let (mut loc, mut call_site) = (None, None);
+		self.run_body(index, &mut loc, &mut call_site).map_err(|err| match err {
+			PvfError::Trap(mut backtrace) => {
+				// Code inlined from another function gets a frame of that function, followed by
+				// the frame of the call site
+				let (func_index, wasm_offset) = match loc {
+					Some((func_index, wasm_offset)) => (func_index, Some(wasm_offset)),
+					None => (index, None),
+				};
+				backtrace.frames.push(WasmFrame { func_index, func_name: self.names.func(func_index).map(str::to_owned), wasm_offset });
+				if let Some((func_index, wasm_offset)) = call_site {
+					backtrace.frames.push(WasmFrame { func_index, func_name: self.names.func(func_index).map(str::to_owned), wasm_offset: Some(wasm_offset) });
+				}
+				PvfError::Trap(backtrace)
+			},
+			err => err,
+		})
+	}
+
+	// `loc` is kept updated with the location of the last instruction executed, and `call_site`
+	// with the location of the call the instruction is inlined at, if any
+	fn run_body(&mut self, index: u32, loc: &mut Option<(u32, u32)>, call_site: &mut Option<(u32, u32)>) -> Result<(), PvfError> {
+		let Some(Func::Function(func)) = &self.funcs[index as usize] else { unreachable!() };
+		let func = func.clone();
+		let n_params = self.signatures[index as usize].as_ref().expect("Function signature available").params;
+		let label_pc = |label: &IrLabel| *func.labels.get(label).unwrap_or_else(|| panic!("Unresolved label: {:?}", label));
+		let mut pc = 0;
+
+		macro_rules! binop {
+			($dest:expr, $src:expr, |$a:ident, $b:ident, $is64:ident| $e:expr) => {
+				{
+					let $is64 = matches!($dest, Reg(_));
+					let $a = self.read($dest, n_params)?;
+					let $b = self.read($src, n_params)?;
+					let res: u64 = $e;
+					self.write($dest, res, n_params)?;
+					res
+				}
+			}
+		}
+
+		macro_rules! unop {
+			($src:expr, |$a:ident, $is64:ident| $e:expr) => {
+				{
+					let $is64 = matches!($src, Reg(_));
+					let $a = self.read($src, n_params)?;
+					let res: u64 = $e;
+					self.write($src, res, n_params)?;
+				}
+			}
+		}
+
+		loop {
+			let cp = func.code.get(pc).expect("Function ends with a return");
+			pc += 1;
+			match cp {
+				Label(_) => (),
+				SourceLoc(func_index, wasm_offset) => {
+					// Inlined functions make no calls, so a location in the caller ends the inlined code
+					if call_site.is_some_and(|(caller, _)| caller == *func_index) {
+						*call_site = None;
+					}
+					*loc = Some((*func_index, *wasm_offset));
+				},
+				EnterFunction(n_locals) => {
+					self.push(self.fp as u64)?;
+					self.push(self.bp as u64)?;
+					self.fp = self.sp + n_params as usize + 3;
+					self.bp = self.sp;
+					for _ in 0..*n_locals {
+						self.push(0)?;
+					}
+				},
+				LeaveFunction => {
+					self.sp = self.fp - (n_params as usize + 3);
+					self.bp = self.pop() as usize;
+					self.fp = self.pop() as usize;
+				},
+				EnterBlock => {
+					self.push(self.bp as u64)?;
+					self.bp = self.sp;
+				},
+				LeaveBlock => {
+					self.sp = self.bp;
+					self.bp = self.pop() as usize;
+				},
+				EnterInlinedFunction(frame_local) => {
+					*call_site = *loc;
+					self.push(self.bp as u64)?;
+					self.bp = self.sp;
+					self.write(&Local(*frame_local), self.sp as u64, n_params)?;
+				},
+				LeaveInlinedFunction(frame_local) => {
+					self.sp = self.read(&Local(*frame_local), n_params)? as usize;
+					self.bp = self.pop() as usize;
+				},
+				Push(op) => {
+					let value = self.read(op, n_params)?;
+					self.push(value)?;
+				},
+				Pop(op) => {
+					let value = self.pop();
+					self.write(op, value, n_params)?;
+				},
+				Move(dest, src) => {
+					let value = self.read(src, n_params)?;
+					self.write(dest, value, n_params)?;
+				},
+				MoveIf(cond, dest, src) => {
+					// Like `cmov`, a 32-bit destination is zero-extended even if the condition
+					// does not hold
+					let value = if self.cond(cond) { self.read(src, n_params)? } else { self.read(dest, n_params)? };
+					self.write(dest, value, n_params)?;
+				},
+				ZeroExtend(src) => {
+					let value = self.read(src, n_params)?;
+					self.write(&Reg(*reg_of(src)), value, n_params)?;
+				},
+				SignExtend(src) => {
+					let value = match src {
+						Reg8(r) => self.regs[*r as usize] as i8 as i64,
+						Reg16(r) => self.regs[*r as usize] as i16 as i64,
+						Reg32(r) => self.regs[*r as usize] as i32 as i64,
+						_ => unreachable!(),
+					};
+					self.write(&Reg(*reg_of(src)), value as u64, n_params)?;
+				},
+				Compare(dest, src) => {
+					self.flags = Flags { a: self.read(dest, n_params)?, b: self.read(src, n_params)?, is64: matches!(dest, Reg(_)) };
+				},
+				SetIf(cond, dest) => {
+					let value = self.cond(cond) as u64;
+					self.write(dest, value, n_params)?;
+				},
+				Add(dest, src) => { binop!(dest, src, |a, b, _is64| a.wrapping_add(b)); },
+				Subtract(dest, src) => { binop!(dest, src, |a, b, _is64| a.wrapping_sub(b)); },
+				Multiply(dest, src) => { binop!(dest, src, |a, b, _is64| a.wrapping_mul(b)); },
+				DivideUnsigned(dest, src) => {
+					binop!(dest, src, |a, b, is64| match (is64, b) {
+						(_, 0) => return Err(trap()),
+						(true, _) => a / b,
+						(false, _) => (a as u32 / b as u32) as u64,
+					});
+				},
+				DivideSigned(dest, src) => {
+					binop!(dest, src, |a, b, is64| if is64 {
+						(a as i64).checked_div(b as i64).ok_or_else(trap)? as u64
+					} else {
+						(a as i32).checked_div(b as i32).ok_or_else(trap)? as u64
+					});
+				},
+				RemainderUnsigned(dest, src) => {
+					binop!(dest, src, |a, b, is64| match (is64, b) {
+						(_, 0) => return Err(trap()),
+						(true, _) => a % b,
+						(false, _) => (a as u32 % b as u32) as u64,
+					});
+				},
+				RemainderSigned(dest, src) => {
+					binop!(dest, src, |a, b, is64| match (is64, b) {
+						(_, 0) => return Err(trap()),
+						(true, _) => (a as i64).wrapping_rem(b as i64) as u64,
+						(false, _) => (a as i32).wrapping_rem(b as i32) as u64,
+					});
+				},
+				And(dest, src) => {
+					let is64 = matches!(dest, Reg(_));
+					let res = binop!(dest, src, |a, b, _is64| a & b);
+					self.flags = Flags { a: res, b: 0, is64 };
+				},
+				Or(dest, src) => { binop!(dest, src, |a, b, _is64| a | b); },
+				Xor(dest, src) => { binop!(dest, src, |a, b, _is64| a ^ b); },
+				ShiftLeft(dest, src) => {
+					binop!(dest, src, |a, b, is64| if is64 { a.wrapping_shl(b as u32) } else { (a as u32).wrapping_shl(b as u32) as u64 });
+				},
+				ShiftRightUnsigned(dest, src) => {
+					binop!(dest, src, |a, b, is64| if is64 { a.wrapping_shr(b as u32) } else { (a as u32).wrapping_shr(b as u32) as u64 });
+				},
+				ShiftRightSigned(dest, src) => {
+					binop!(dest, src, |a, b, is64| if is64 { (a as i64).wrapping_shr(b as u32) as u64 } else { (a as i32).wrapping_shr(b as u32) as u32 as u64 });
+				},
+				RotateLeft(dest, src) => {
+					binop!(dest, src, |a, b, is64| if is64 { a.rotate_left(b as u32) } else { (a as u32).rotate_left(b as u32) as u64 });
+				},
+				RotateRight(dest, src) => {
+					binop!(dest, src, |a, b, is64| if is64 { a.rotate_right(b as u32) } else { (a as u32).rotate_right(b as u32) as u64 });
+				},
+				LeadingZeroes(src) => unop!(src, |a, is64| if is64 { a.leading_zeros() } else { (a as u32).leading_zeros() } as u64),
+				TrailingZeroes(src) => unop!(src, |a, is64| if is64 { a.trailing_zeros() } else { (a as u32).trailing_zeros() } as u64),
+				BitPopulationCount(src) => unop!(src, |a, _is64| a.count_ones() as u64),
+				Jump(label) => pc = label_pc(label),
+				JumpIf(cond, label) => {
+					if self.cond(cond) {
+						pc = label_pc(label);
+					}
+				},
+				JumpTable(index, targets) => {
+					let index = self.read(index, n_params)? as usize;
+					pc = label_pc(targets.get(index).ok_or_else(trap)?);
+				},
+				Call(label) => {
+					match label {
+						IrLabel::AnonymousFunc(idx) | IrLabel::ExportedFunc(idx, _) | IrLabel::ImportedFunc(idx, _) => self.call_func(*idx)?,
+						IrLabel::Indirect(table_index, op, _) => {
+							let entry = self.read(op, n_params)? as usize;
+							let callee = self.tables[*table_index as usize].get(entry).copied().flatten().ok_or_else(trap)?;
+							self.call_func(callee)?;
+						},
+						_ => unreachable!(),
+					}
+				},
+				Return => {
+					self.pop(); // Return address
+					self.sp += n_params as usize;
+					return Ok(());
+				},
+				Trap => return Err(trap()),
+				InitTablePreamble(offset) => {
+					self.table_cursor = self.read(offset, n_params)? as usize;
+				},
+				InitTableElement(func_index) => {
+					let func_index = self.read(func_index, n_params)? as u32;
+					// FIXME: Only the first table is initialized, like in the code generators
+					let slot = self.tables[0].get_mut(self.table_cursor).ok_or_else(trap)?;
+					*slot = Some(func_index);
+					self.table_cursor += 1;
+				},
+				InitTablePostamble => (),
+				InitMemoryFromChunk(chunk_idx, chunk_len, offset) => {
+					let offset = self.read(offset, n_params)?;
+					let range = self.memory_range(0, offset, *chunk_len as usize)?;
+					self.memory[range].copy_from_slice(&self.data_chunks[*chunk_idx as usize][..*chunk_len as usize]);
+				},
+				MemoryGrow(pages) => {
+					let old_pages = self.memory_pages;
+					let new_pages = old_pages as u64 + self.read(pages, n_params)?;
+					if new_pages > self.memory_max_pages as u64 {
+						self.write(pages, u32::MAX as u64, n_params)?;
+					} else {
+						self.memory_pages = new_pages as u32;
+						self.memory.resize(new_pages as usize * PAGE_SIZE, 0);
+						self.write(pages, old_pages as u64, n_params)?;
+					}
+				},
+				MemorySize(dest) => {
+					self.write(dest, self.memory_pages as u64, n_params)?;
+				},
+			}
+		}
+	}
+}
+
+fn reg_of(op: &IrOperand) -> &IrReg {
+	match op {
+		Reg(r) | Reg8(r) | Reg16(r) | Reg32(r) => r,
+		_ => unreachable!(),
+	}
+}
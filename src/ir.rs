@@ -1,4 +1,6 @@
-use crate::{CodeGenerator, codegen::CodeEmitter, PreparedPvf};
+use std::{collections::HashMap, sync::{Mutex, atomic::{AtomicUsize, Ordering}}};
+use wasmparser::FuncType;
+use crate::{CodeGenerator, PvfError, ValueType, interface::ModuleInterface, codegen::CodeEmitter, PreparedPvf, cfg::{PassManager, PushPopFolding, DeadCodeElimination}};
 
 #[derive(Debug, Copy, Clone, PartialEq)]
 #[derive(Eq)]
@@ -71,13 +73,50 @@ pub enum IrCp {
     MemorySize(IrOperand),
     Return,
     Trap,
+    EnterInlinedFunction(u32),
+    LeaveInlinedFunction(u32),
+    // Function index and Wasm bytecode offset of the instruction the following code originates
+    // from. Emits no code.
+    SourceLoc(u32, u32),
+}
+
+impl IrCp {
+    pub(crate) fn for_each_operand_mut(&mut self, mut f: impl FnMut(&mut IrOperand)) {
+        match self {
+            IrCp::InitTablePreamble(op) | IrCp::InitTableElement(op) | IrCp::InitMemoryFromChunk(_, _, op) |
+            IrCp::Push(op) | IrCp::Pop(op) | IrCp::ZeroExtend(op) | IrCp::SignExtend(op) | IrCp::SetIf(_, op) |
+            IrCp::LeadingZeroes(op) | IrCp::TrailingZeroes(op) | IrCp::BitPopulationCount(op) |
+            IrCp::JumpTable(op, _) | IrCp::MemoryGrow(op) | IrCp::MemorySize(op) => f(op),
+            IrCp::Move(a, b) | IrCp::MoveIf(_, a, b) | IrCp::Compare(a, b) | IrCp::Add(a, b) | IrCp::Subtract(a, b) |
+            IrCp::Multiply(a, b) | IrCp::DivideUnsigned(a, b) | IrCp::DivideSigned(a, b) | IrCp::RemainderUnsigned(a, b) |
+            IrCp::RemainderSigned(a, b) | IrCp::And(a, b) | IrCp::Or(a, b) | IrCp::Xor(a, b) | IrCp::ShiftLeft(a, b) |
+            IrCp::ShiftRightUnsigned(a, b) | IrCp::ShiftRightSigned(a, b) | IrCp::RotateLeft(a, b) | IrCp::RotateRight(a, b) => {
+                f(a);
+                f(b);
+            },
+            IrCp::Call(IrLabel::Indirect(_, op, _)) => f(op),
+            IrCp::Label(_) | IrCp::EnterFunction(_) | IrCp::LeaveFunction | IrCp::EnterBlock | IrCp::LeaveBlock |
+            IrCp::InitTablePostamble | IrCp::Jump(_) | IrCp::JumpIf(_, _) | IrCp::Call(_) | IrCp::Return | IrCp::Trap |
+            IrCp::EnterInlinedFunction(_) | IrCp::LeaveInlinedFunction(_) | IrCp::SourceLoc(_, _) => (),
+        }
+    }
+
+    /// Visits the function-local labels defined or referenced by the instruction
+    pub(crate) fn for_each_local_label_mut(&mut self, mut f: impl FnMut(&mut IrLabel)) {
+        match self {
+            IrCp::Label(label) | IrCp::Jump(label) | IrCp::JumpIf(_, label) => f(label),
+            IrCp::JumpTable(_, labels) => labels.iter_mut().for_each(f),
+            _ => (),
+        }
+    }
 }
 
 #[derive(Eq, PartialEq, Hash, Debug, Clone)]
 pub enum IrLabel {
     ExportedFunc(u32, String),
     AnonymousFunc(u32),
-    ImportedFunc(u32, *const u8),
+    // Imported function and its address, kept as an integer as it's only copied to the code
+    ImportedFunc(u32, usize),
     BranchTarget(u64),
     LocalLabel(u32),
     Indirect(u32, IrOperand, IrSignature),
@@ -99,7 +138,27 @@ pub enum IrCond {
     GreaterOrEqualUnsigned,
 }
 
-#[derive(Clone)]
+impl IrCond {
+    /// The condition that holds exactly when this one does not
+    pub fn inverse(&self) -> IrCond {
+        match self {
+            IrCond::Zero => IrCond::NotZero,
+            IrCond::NotZero => IrCond::Zero,
+            IrCond::Equal => IrCond::NotEqual,
+            IrCond::NotEqual => IrCond::Equal,
+            IrCond::LessSigned => IrCond::GreaterOrEqualSigned,
+            IrCond::LessUnsigned => IrCond::GreaterOrEqualUnsigned,
+            IrCond::GreaterSigned => IrCond::LessOrEqualSigned,
+            IrCond::GreaterUnsigned => IrCond::LessOrEqualUnsigned,
+            IrCond::LessOrEqualSigned => IrCond::GreaterSigned,
+            IrCond::LessOrEqualUnsigned => IrCond::GreaterUnsigned,
+            IrCond::GreaterOrEqualSigned => IrCond::LessSigned,
+            IrCond::GreaterOrEqualUnsigned => IrCond::LessUnsigned,
+        }
+    }
+}
+
+#[derive(Clone, Default)]
 pub struct Ir(Vec<IrCp>);
 
 impl Ir {
@@ -107,14 +166,26 @@ impl Ir {
         Self(Vec::new())
     }
 
+    pub(crate) fn from_code(code: Vec<IrCp>) -> Self {
+        Self(code)
+    }
+
     pub fn code(&self) -> &[IrCp] {
         &self.0
     }
 
+    pub(crate) fn into_code(self) -> Vec<IrCp> {
+        self.0
+    }
+
     pub fn append(&mut self, other: &mut Ir) {
     	self.0.append(&mut other.0);
     }
 
+    pub fn source_loc(&mut self, func_index: u32, offset: u32) {
+        self.0.push(IrCp::SourceLoc(func_index, offset));
+    }
+
     pub fn label(&mut self, l: IrLabel) {
         self.0.push(IrCp::Label(l));
     }
@@ -286,17 +357,25 @@ impl Ir {
     pub fn r#return(&mut self) {
     	self.0.push(IrCp::Return);
     }
+
+    pub fn enter_inlined_function(&mut self, frame_local: u32) {
+        self.0.push(IrCp::EnterInlinedFunction(frame_local));
+    }
+
+    pub fn leave_inlined_function(&mut self, frame_local: u32) {
+        self.0.push(IrCp::LeaveInlinedFunction(frame_local));
+    }
 }
 
 #[derive(Debug, Clone)]
-enum IrFunc {
+pub(crate) enum IrFunc {
 	Import(*const u8),
 	Function(Ir),
 }
 
 #[derive(Debug, Clone)]
 pub enum IrTable {
-    Import(*const u8),
+    Import(usize),
     Table(u32),
 }
 
@@ -304,7 +383,7 @@ pub enum IrTable {
 // throughout the code to avoid confusion with the data segment of the OS process.
 #[derive(Debug, Clone)]
 pub struct IrDataChunk {
-    data: Vec<u8>
+    pub(crate) data: Vec<u8>
 }
 
 impl IrDataChunk {
@@ -313,15 +392,88 @@ impl IrDataChunk {
     }
 }
 
-#[derive(Debug)]
+/// Function and local names from the `name` custom section
+#[derive(Debug, Clone, Default)]
+pub struct IrNames {
+    pub(crate) funcs: HashMap<u32, String>,
+    pub(crate) locals: HashMap<u32, HashMap<u32, String>>,
+}
+
+impl IrNames {
+    pub fn func(&self, func_index: u32) -> Option<&str> {
+        self.funcs.get(&func_index).map(|s| s.as_str())
+    }
+
+    pub fn local(&self, func_index: u32, local_index: u32) -> Option<&str> {
+        self.locals.get(&func_index)?.get(&local_index).map(|s| s.as_str())
+    }
+
+    /// Label text with the function name appended, if the label is a function entry
+    pub fn label(&self, label: &IrLabel) -> String {
+        match label {
+            IrLabel::AnonymousFunc(index) | IrLabel::ExportedFunc(index, _) | IrLabel::ImportedFunc(index, _) => match self.func(*index) {
+                Some(name) => format!("{:?} <{}>", label, name),
+                None => format!("{:?}", label),
+            },
+            _ => format!("{:?}", label),
+        }
+    }
+}
+
 pub struct IrPvf {
 	hints: IrHints,
-    funcs: Vec<Option<IrFunc>>,
+    pub(crate) funcs: Vec<Option<IrFunc>>,
     // init_index: usize,
-    signatures: Vec<Option<IrSignature>>,
-    memory: (u32, u32),
-    tables: Vec<IrTable>,
-    data_chunks: Vec<IrDataChunk>,
+    pub(crate) signatures: Vec<Option<IrSignature>>,
+    pub(crate) memory: (u32, u32),
+    pub(crate) tables: Vec<IrTable>,
+    pub(crate) data_chunks: Vec<IrDataChunk>,
+    // Initial contents of the linear memory, if the data segment offsets are constant, as the
+    // offset and the bytes of every segment. Later segments overwrite earlier ones, the rest of
+    // the memory is zero.
+    pub(crate) memory_image: Vec<(u32, Vec<u8>)>,
+    pub(crate) names: IrNames,
+    pub(crate) interface: ModuleInterface,
+}
+
+impl std::fmt::Debug for IrPvf {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "memory: {:?}, tables: {:?}, data chunks: {}, {:?}", self.memory, self.tables, self.data_chunks.len(), self.hints)?;
+        for (index, maybe_ir) in self.funcs.iter().enumerate() {
+            let Some(func) = maybe_ir else { continue };
+            let name = self.names.func(index as u32).map(|name| format!(" <{}>", name)).unwrap_or_default();
+            writeln!(f, "func {}{}: {:?}", index, name, self.signatures[index])?;
+            match func {
+                IrFunc::Import(addr) => writeln!(f, "\timport {:?}", addr)?,
+                IrFunc::Function(ir) => {
+                    // Inlined callees keep their locals in an area past the function's own ones,
+                    // and the names of those locals are not known here
+                    let inlined = ir.code().iter().filter_map(|cp| match cp {
+                        IrCp::EnterInlinedFunction(frame_local) => Some(*frame_local),
+                        _ => None,
+                    }).min().unwrap_or(u32::MAX);
+                    for cp in ir.code() {
+                        // Named locals are annotated
+                        let mut locals = Vec::new();
+                        cp.clone().for_each_operand_mut(|op| if let IrOperand::Local(local) = op {
+                            if *local >= inlined {
+                                return;
+                            }
+                            if let Some(name) = self.names.local(index as u32, *local) {
+                                locals.push(format!("{} = {}", local, name));
+                            }
+                        });
+                        if locals.is_empty() {
+                            writeln!(f, "\t{:?}", cp)?;
+                        } else {
+                            writeln!(f, "\t{:?} ; {}", cp, locals.join(", "))?;
+                        }
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
 }
 
 impl std::fmt::Debug for Ir {
@@ -334,13 +486,23 @@ impl std::fmt::Debug for Ir {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 #[derive(Eq)]
 #[derive(PartialEq)]
 #[derive(Hash)]
 pub struct IrSignature {
     pub(crate) params: u32,
     pub(crate) results: u32,
+    pub(crate) param_types: Vec<ValueType>,
+    pub(crate) result_types: Vec<ValueType>,
+}
+
+impl IrSignature {
+    pub(crate) fn from_func_type(func_type: &FuncType) -> Result<Self, PvfError> {
+        let param_types = func_type.params().iter().map(|ty| ValueType::try_from(*ty)).collect::<Result<Vec<_>, _>>()?;
+        let result_types = func_type.results().iter().map(|ty| ValueType::try_from(*ty)).collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { params: param_types.len() as u32, results: result_types.len() as u32, param_types, result_types })
+    }
 }
 
 #[derive(Debug, Clone, Default)]
@@ -350,9 +512,75 @@ pub struct IrHints {
 	pub(crate) has_tables: bool,
 }
 
+const INLINE_MAX_SIZE: usize = 32;
+
+// Produces the code replacing a call to `callee`, with the callee locals moved to the caller
+// frame starting from the local `base`. Returns the code and the number of caller locals used.
+fn inline_body(callee: &Ir, signature: &IrSignature, base: u32, next_label: &mut u32) -> (Vec<IrCp>, u32) {
+    use IrOperand::*;
+    use IrReg::*;
+
+    let IrCp::EnterFunction(n_locals) = callee.0[1] else { unreachable!() };
+    let frame_local = base;
+    let locals_base = base + 1;
+    let exit_label = IrLabel::LocalLabel(*next_label);
+    *next_label += 1;
+    let mut label_map = std::collections::HashMap::new();
+
+    let mut code = Vec::new();
+    // Arguments are on the stack, the last one on top
+    for i in (0..signature.params).rev() {
+        code.push(IrCp::Pop(Reg(Sra)));
+        code.push(IrCp::Move(Local(locals_base + i), Reg(Sra)));
+    }
+    // Locals must be zeroed on every call, not only once in the caller prologue
+    if n_locals > 0 {
+        code.push(IrCp::Move(Reg(Sra), Imm32(0)));
+        for i in 0..n_locals {
+            code.push(IrCp::Move(Local(locals_base + signature.params + i), Reg(Sra)));
+        }
+    }
+    code.push(IrCp::EnterInlinedFunction(frame_local));
+
+    for cp in callee.0[2..].iter() {
+        let mut cp = cp.clone();
+        cp.for_each_operand_mut(|op| {
+            if let Local(index) = op {
+                *index += locals_base;
+            }
+        });
+        cp.for_each_local_label_mut(|label| {
+            if matches!(label, IrLabel::LocalLabel(_) | IrLabel::BranchTarget(_)) {
+                *label = label_map.entry(label.clone()).or_insert_with(|| {
+                    *next_label += 1;
+                    IrLabel::LocalLabel(*next_label - 1)
+                }).clone();
+            }
+        });
+        code.push(match cp {
+            IrCp::LeaveFunction => IrCp::LeaveInlinedFunction(frame_local),
+            IrCp::Return => IrCp::Jump(exit_label.clone()),
+            cp => cp,
+        });
+    }
+
+    if matches!(code.last(), Some(IrCp::Jump(label)) if *label == exit_label) {
+        code.pop();
+    }
+    code.push(IrCp::Label(exit_label));
+    if signature.results > 0 {
+        code.push(IrCp::Push(Reg(Sra)));
+    }
+
+    (code, 1 + signature.params + n_locals)
+}
+
 impl IrPvf {
     pub(crate) fn new() -> Self {
-        Self { hints: IrHints::default(), funcs: Vec::new(), signatures: Vec::new(), memory: (0, 0), tables: Vec::new(), data_chunks: Vec::new() }
+        Self {
+            hints: IrHints::default(), funcs: Vec::new(), signatures: Vec::new(), memory: (0, 0), tables: Vec::new(), data_chunks: Vec::new(), memory_image: Vec::new(),
+            names: IrNames::default(), interface: ModuleInterface::default(),
+        }
     }
 
     fn ensure_func_vec_size(&mut self, index: u32) {
@@ -388,55 +616,173 @@ impl IrPvf {
         self.memory = (min, max);
     }
 
+    pub(crate) fn set_memory_image(&mut self, image: Vec<(u32, Vec<u8>)>) {
+        self.memory_image = image;
+    }
+
+    pub(crate) fn set_names(&mut self, names: IrNames) {
+        self.names = names;
+    }
+
+    pub(crate) fn set_interface(&mut self, interface: ModuleInterface) {
+        self.interface = interface;
+    }
+
+    /// Imports, exports, memory and tables of the module
+    pub fn interface(&self) -> &ModuleInterface {
+        &self.interface
+    }
+
+    pub fn names(&self) -> &IrNames {
+        &self.names
+    }
+
     pub(crate) fn set_hints(&mut self, hints: IrHints) {
     	self.hints = hints;
     }
 
     pub fn optimize(&mut self) {
+        self.inline_functions(INLINE_MAX_SIZE);
+        let mut passes = PassManager::new();
+        passes.add_pass(DeadCodeElimination);
+        passes.add_pass(PushPopFolding);
+        self.run_passes(&mut passes);
+        println!("OPT IR: {:?}", self);
+    }
+
+    /// Inlines calls to leaf functions whose body is not longer than `max_size` instructions
+    pub fn inline_functions(&mut self, max_size: usize) {
+        let mut next_label = 0;
+        for maybe_ir in self.funcs.iter() {
+            if let Some(IrFunc::Function(ir)) = maybe_ir {
+                for cp in ir.code() {
+                    if let IrCp::Label(IrLabel::LocalLabel(index)) = cp {
+                        next_label = std::cmp::max(next_label, index + 1);
+                    }
+                }
+            }
+        }
+
+        let inlinable = self.funcs.iter().map(|maybe_ir| match maybe_ir {
+            Some(IrFunc::Function(ir)) if ir.code().iter().filter(|cp| !matches!(cp, IrCp::SourceLoc(_, _))).count() <= max_size &&
+                ir.code().iter().all(|cp| !matches!(cp, IrCp::Call(_))) => {
+                match ir.code() {
+                    [IrCp::Label(_), IrCp::EnterFunction(_), ..] => Some(ir.clone()),
+                    _ => None,
+                }
+            },
+            _ => None,
+        }).collect::<Vec<_>>();
+
+        for (caller_idx, maybe_ir) in self.funcs.iter_mut().enumerate() {
+            let Some(IrFunc::Function(ref mut ir)) = maybe_ir else { continue };
+            let Some(&IrCp::EnterFunction(n_locals)) = ir.0.get(1) else { continue };
+            let n_params = self.signatures[caller_idx].as_ref().expect("Function signature exists").params;
+            // Inlined bodies never overlap in time, so they all share the same area in the
+            // caller's frame. The first local of the area keeps the inlined frame pointer.
+            let base = n_params + n_locals;
+            let mut area_size = 0;
+
+            let mut code = Vec::with_capacity(ir.0.len());
+            for cp in std::mem::take(&mut ir.0) {
+                let callee_idx = match &cp {
+                    IrCp::Call(IrLabel::AnonymousFunc(idx) | IrLabel::ExportedFunc(idx, _)) => *idx as usize,
+                    _ => {
+                        code.push(cp);
+                        continue;
+                    }
+                };
+                let Some(callee) = &inlinable[callee_idx] else {
+                    code.push(cp);
+                    continue;
+                };
+                let signature = self.signatures[callee_idx].as_ref().expect("Function signature exists");
+                let mut inlined = inline_body(callee, signature, base, &mut next_label);
+                area_size = std::cmp::max(area_size, inlined.1);
+                code.append(&mut inlined.0);
+            }
+
+            if area_size > 0 {
+                code[1] = IrCp::EnterFunction(n_locals + area_size);
+            }
+            ir.0 = code;
+        }
+    }
+
+    pub fn run_passes(&mut self, passes: &mut PassManager) {
         for maybe_ir in self.funcs.iter_mut() {
             if let Some(IrFunc::Function(ref mut ir)) = maybe_ir {
-                if ir.0.len() == 0 {
+                if ir.0.is_empty() {
                     continue;
                 }
-                let mut opt = Vec::new();
-                let mut pc = 0;
-                while pc < ir.0.len() - 1 {
-                    match (&ir.0[pc], &ir.0[pc + 1]) {
-                        (IrCp::Push(IrOperand::Reg(push_reg)), IrCp::Pop(IrOperand::Reg(pop_reg))) => {
-                            if push_reg != pop_reg {
-                                opt.push(IrCp::Move(IrOperand::Reg(*pop_reg), IrOperand::Reg(*push_reg)));
-                            }
-                            pc += 2;
-                        },
-                        _ => {
-                            opt.push(ir.0[pc].clone());
-                            pc += 1;
-                        }
-                    }
-                }
-                opt.push(ir.0[pc].clone());
-                ir.0 = opt;
+                *ir = passes.run(std::mem::take(ir));
             }
         }
-        println!("OPT IR: {:?}", self);
     }
 
-    pub fn compile(self, codegen: &mut dyn CodeGenerator) -> PreparedPvf {
-        let mut code = CodeEmitter::new();
+    /// Compiles the functions on as many threads as the host has cores
+    pub fn compile(self, codegen: &dyn CodeGenerator) -> PreparedPvf {
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        self.compile_with_threads(codegen, threads)
+    }
+
+    /// Compiles the functions on up to `threads` threads. Every function is compiled into its own
+    /// buffer, and the buffers are concatenated in function index order before linking, so the
+    /// code is the same regardless of the number of threads.
+    pub fn compile_with_threads(self, codegen: &dyn CodeGenerator, threads: usize) -> PreparedPvf {
         let offset_map = codegen.build_offset_map(&self.tables, &self.data_chunks);
 
-        for (func_idx, maybe_ir) in self.funcs.into_iter().enumerate() {
-            if let Some(IrFunc::Function(ir)) = maybe_ir {
-                codegen.compile_func(&mut code, func_idx as u32, ir, &self.signatures, &offset_map);
+        let compile_func = |func_idx: u32, ir: Ir| {
+            let mut code = CodeEmitter::new();
+            // The entry code of the function is attributed to its first instruction
+            if let Some(loc) = ir.code().iter().find(|cp| matches!(cp, IrCp::SourceLoc(_, _))) {
+                code.ir_insn(loc);
             }
+            codegen.compile_func(&mut code, func_idx, ir, &self.signatures, &offset_map);
+            codegen.finish_func(&mut code);
+            code
+        };
+
+        let jobs = self.funcs.into_iter().enumerate().filter_map(|(func_idx, maybe_ir)| match maybe_ir {
+            Some(IrFunc::Function(ir)) => Some((func_idx as u32, ir)),
+            _ => None,
+        }).collect::<Vec<_>>();
+
+        let funcs = if threads <= 1 || jobs.len() <= 1 {
+            jobs.into_iter().map(|(func_idx, ir)| compile_func(func_idx, ir)).collect::<Vec<_>>()
+        } else {
+            // Threads take the next function from the queue until it's exhausted
+            let jobs = jobs.into_iter().map(|job| Mutex::new(Some(job))).collect::<Vec<_>>();
+            let results = jobs.iter().map(|_| Mutex::new(None)).collect::<Vec<_>>();
+            let next_job = AtomicUsize::new(0);
+            std::thread::scope(|scope| {
+                for _ in 0..threads.min(jobs.len()) {
+                    scope.spawn(|| loop {
+                        let n = next_job.fetch_add(1, Ordering::Relaxed);
+                        let Some(job) = jobs.get(n) else { break };
+                        let (func_idx, ir) = job.lock().unwrap().take().expect("Function is compiled once");
+                        *results[n].lock().unwrap() = Some(compile_func(func_idx, ir));
+                    });
+                }
+            });
+            results.into_iter().map(|result| result.into_inner().unwrap().expect("Function is compiled")).collect()
+        };
+
+        let mut code = CodeEmitter::new();
+        for func in funcs {
+            code.append(func);
         }
         codegen.link(&mut code);
 
         println!("CODE: {:02X?}", code.code);
 
         PreparedPvf {
-            code: code.code, labels: code.labels, relocs: code.relocs, memory: self.memory, tables_pages: offset_map.get_tables_pages(),
-            data_chunks: self.data_chunks.into_iter().map(|s| s.data).collect(), offset_map,
+            code: code.code, labels: code.labels, relocs: code.relocs, ir_map: code.ir_map, source_map: code.source_map, call_sites: code.call_sites, names: self.names, interface: self.interface, signatures: self.signatures, decoder: codegen.decoder(), target_arch: codegen.target_arch(), memory: self.memory, tables_pages: offset_map.get_tables_pages(),
+            table_sizes: self.tables.iter().map(|table| match table {
+                IrTable::Table(size) => *size,
+                IrTable::Import(_) => todo!("Imported tables"),
+            }).collect(),
+            data_chunks: self.data_chunks.into_iter().map(|s| s.data).collect(), memory_image: self.memory_image, offset_map,
          }
     }
 }
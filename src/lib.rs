@@ -1,17 +1,39 @@
 mod error;
 mod raw;
 mod ir;
+mod cfg;
 mod codegen;
 mod intel_x64;
+mod intel_x64_asm;
+mod intel_x64_disasm;
+mod intel_x64_peephole;
+mod aarch64;
+mod interpreter;
 mod prepared_pvf;
+mod loaded_pvf;
 mod instance;
+mod trap;
+mod perf;
+mod gdb_jit;
+mod value;
+mod interface;
+mod config;
 #[cfg(test)]
 mod test;
 
 pub use error::PvfError;
 pub use raw::RawPvf;
-pub use ir::IrPvf;
+pub use ir::{IrPvf, IrNames};
+pub use cfg::{Cfg, BasicBlock, Loop, IrPass, PassManager};
 pub use intel_x64::IntelX64Compiler;
+pub use aarch64::Aarch64Compiler;
+pub use interpreter::IrInterpreter;
 pub use codegen::CodeGenerator;
 pub use prepared_pvf::PreparedPvf;
-pub use instance::PvfInstance;
+pub use loaded_pvf::LoadedPvf;
+pub use instance::{PvfInstance, TypedFunc, TableRef};
+pub use trap::{Backtrace, WasmFrame};
+pub use perf::ProfilingConfig;
+pub use value::{Value, ValueType, MemoryValue};
+pub use config::{ExecutorConfig, ExecutorLimit};
+pub use interface::{ModuleInterface, ModuleImport, ModuleExport, ExternType, FuncSignature, Limits};
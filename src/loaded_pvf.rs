@@ -0,0 +1,163 @@
+use std::{collections::HashMap, fs::File, os::{fd::FromRawFd, unix::fs::FileExt}};
+use memmap::{MmapMut, Mmap};
+use crate::{PreparedPvf, PvfError, ValueType, ExternType, ir::{IrLabel, IrSignature}, codegen::Relocation, trap::FrameMap, perf::{self, ProfilingConfig}, gdb_jit::{self, GdbJitRegistration}};
+
+/// Code of a PVF loaded into a read-only executable mapping. The code does not depend on the
+/// memory it runs on, the memory base is passed to the entry trampolines, so a single loaded PVF
+/// backs any number of instances, on any number of threads.
+pub struct LoadedPvf {
+	pub(crate) codeseg: Mmap,
+	pub(crate) entry_points: HashMap<String, usize>,
+	pub(crate) export_signatures: HashMap<String, IrSignature>,
+	pub(crate) frame_map: FrameMap,
+	// Index, type and mutability of the exported globals
+	pub(crate) exported_globals: HashMap<String, (u32, ValueType, bool)>,
+	pub(crate) exported_memories: Vec<String>,
+	// Index of the exported tables
+	pub(crate) exported_tables: HashMap<String, u32>,
+	// Function index by the code offset of the function body, to map the table elements back
+	pub(crate) func_offsets: HashMap<usize, u32>,
+	gdb_registration: Option<GdbJitRegistration>,
+	// Layout of the memory segment of the instances. The memory base, the VM data and the data
+	// chunks are at the given offsets from the start of the segment, the linear memory up to its
+	// maximum follows the memory base.
+	pub(crate) memory: (u32, u32),
+	pub(crate) membase_offset: usize,
+	pub(crate) vm_data_offset: usize,
+	pub(crate) globals_offset: usize,
+	// Offset, declared initial size and size in elements of every table
+	pub(crate) tables: Vec<(usize, u32, u32)>,
+	pub(crate) data_chunks: Vec<(usize, Vec<u8>)>,
+	// Initial contents of the linear memory, mapped copy-on-write at the memory base of every
+	// instance, and the length of the mapping
+	pub(crate) memory_image: Option<(File, usize)>,
+}
+
+impl LoadedPvf {
+	pub fn load(pvf: &PreparedPvf) -> Result<Self, PvfError> {
+		Self::load_with_profiling(pvf, ProfilingConfig::default())
+	}
+
+	/// Loads the PVF code, making its symbols available to the profilers enabled in `profiling`
+	pub fn load_with_profiling(pvf: &PreparedPvf, profiling: ProfilingConfig) -> Result<Self, PvfError> {
+		if pvf.target_arch != std::env::consts::ARCH {
+			return Err(PvfError::TargetMismatch(pvf.target_arch));
+		}
+		let membase_offset = (2 + pvf.tables_pages as usize + pvf.data_segments_pages() as usize) * 0x10000;
+		let vm_data_offset = offset_by(membase_offset, pvf.offset_map.vm_data());
+		let globals_offset = offset_by(membase_offset, pvf.offset_map.globals());
+		let exported_globals = pvf.interface.exports.iter().filter_map(|export| match export.ty {
+			ExternType::Global { ty, mutable } => Some((export.name.clone(), (export.index, ty, mutable))),
+			_ => None,
+		}).collect();
+		let exported_memories = pvf.interface.exports.iter().filter(|export| matches!(export.ty, ExternType::Memory(_))).map(|export| export.name.clone()).collect();
+		let exported_tables = pvf.interface.exports.iter().filter(|export| matches!(export.ty, ExternType::Table(_))).map(|export| (export.name.clone(), export.index)).collect();
+		let tables = pvf.table_sizes.iter().zip(&pvf.interface.tables).enumerate()
+			.map(|(index, (size, limits))| (offset_by(membase_offset, pvf.offset_map.table(index as u32)), limits.initial, *size)).collect();
+		let func_offsets = pvf.labels.iter().filter_map(|(label, offset)| match label {
+			IrLabel::AnonymousFunc(index) => Some((*offset, *index)),
+			_ => None,
+		}).collect();
+		let data_chunks = pvf.data_chunks.iter().enumerate().map(|(idx, chunk)| (offset_by(membase_offset, pvf.offset_map.data_chunk(idx as u32)), chunk.clone())).collect();
+
+		let memory_image = if pvf.memory_image.is_empty() {
+			None
+		} else {
+			Some(create_memory_image(&pvf.memory_image).map_err(PvfError::FilesystemError)?)
+		};
+
+		let len = (pvf.code_len() | 0xfff) + 1;
+		let mut codeseg_mmap = MmapMut::map_anon(len).map_err(PvfError::MemoryMapError)?;
+		codeseg_mmap[..pvf.code_len()].copy_from_slice(pvf.code());
+
+		// Only the code addresses are relocated, they are the same for every instance
+		for (reloc, off) in &pvf.relocs {
+			match reloc {
+				Relocation::FunctionAbsoluteAddress => {
+					let offset = usize::from_le_bytes(codeseg_mmap[*off..*off + 8][..].try_into().expect("Length is constant"));
+					let addr = codeseg_mmap.as_ptr() as usize + offset;
+					codeseg_mmap[*off..*off + 8].copy_from_slice(&addr.to_le_bytes()[..]);
+				},
+				Relocation::LabelAbsoluteAddress(label) => {
+					let offset = *pvf.labels.get(label).expect("Unresolved label");
+					let addr = codeseg_mmap.as_ptr() as usize + offset;
+					codeseg_mmap[*off..*off + 8].copy_from_slice(&addr.to_le_bytes()[..]);
+				}
+			}
+		}
+
+		// Instruction caches are not coherent with data caches on AArch64
+		#[cfg(target_arch = "aarch64")]
+		unsafe {
+			extern "C" {
+				fn __clear_cache(start: *mut u8, end: *mut u8);
+			}
+			let start = codeseg_mmap.as_mut_ptr();
+			__clear_cache(start, start.add(pvf.code_len()));
+		}
+
+		let codeseg_mmap = codeseg_mmap.make_exec().map_err(PvfError::MemoryMapError)?;
+
+		println!("CODE SEGMENT AT {:X?}", &codeseg_mmap[..].as_ptr());
+
+		if profiling.perf_map || profiling.jitdump {
+			let code_addr = codeseg_mmap.as_ptr() as usize;
+			let symbols = pvf.symbols();
+			if profiling.perf_map {
+				perf::write_perf_map(code_addr, &symbols).map_err(PvfError::FilesystemError)?;
+			}
+			if profiling.jitdump {
+				perf::write_jitdump(code_addr, &codeseg_mmap[..pvf.code_len()], &symbols).map_err(PvfError::FilesystemError)?;
+			}
+		}
+		let gdb_registration = profiling.gdb_jit.then(|| GdbJitRegistration::register(gdb_jit::build_object(pvf, codeseg_mmap.as_ptr() as usize)));
+
+		Ok(Self {
+			codeseg: codeseg_mmap, entry_points: pvf.exported_funcs(), export_signatures: pvf.export_signatures(),
+			frame_map: FrameMap::new(&pvf.labels, &pvf.params(), &pvf.source_map, &pvf.call_sites, &pvf.names), exported_globals, exported_memories, exported_tables, func_offsets,
+			gdb_registration, memory: pvf.memory, membase_offset, vm_data_offset, globals_offset, tables, data_chunks, memory_image,
+		})
+	}
+
+	/// ELF object describing the code to GDB, if registered. It can be written to a file to
+	/// inspect the symbols and the debug information with the usual tools.
+	pub fn gdb_jit_object(&self) -> Option<&[u8]> {
+		self.gdb_registration.as_ref().map(GdbJitRegistration::object)
+	}
+
+	// Address of the entry trampoline of the exported function at `offset`
+	pub(crate) fn func_ptr(&self, offset: usize) -> *const u8 {
+		(self.codeseg.as_ptr() as usize + offset) as *const u8
+	}
+
+	pub(crate) fn vm_data_slot(&self, offset: i32) -> usize {
+		self.vm_data_offset + offset as usize
+	}
+}
+
+// Writes the memory image segments at their offsets to an anonymous file, sized to whole pages
+// so that it can be mapped. The gaps are holes of the file, which take no memory.
+fn create_memory_image(image: &[(u32, Vec<u8>)]) -> std::io::Result<(File, usize)> {
+	let fd = unsafe { libc::memfd_create(c"pvf-memory-image".as_ptr(), libc::MFD_CLOEXEC) };
+	if fd < 0 {
+		return Err(std::io::Error::last_os_error());
+	}
+	// SAFETY: The descriptor was just created and is owned by nothing else
+	let file = unsafe { File::from_raw_fd(fd) };
+	let end = image.iter().map(|(offset, bytes)| *offset as usize + bytes.len()).max().unwrap_or(0);
+	let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+	let len = end.div_ceil(page_size) * page_size;
+	file.set_len(len as u64)?;
+	for (offset, bytes) in image {
+		file.write_all_at(bytes, *offset as u64)?;
+	}
+	Ok((file, len))
+}
+
+fn offset_by(base: usize, offset: i32) -> usize {
+	if offset.is_negative() {
+		base - offset.unsigned_abs() as usize
+	} else {
+		base + offset as usize
+	}
+}
@@ -0,0 +1,131 @@
+use std::{fs::{File, OpenOptions}, io::Write, path::PathBuf, sync::Mutex};
+
+// Linux perf integration
+//
+// perf cannot symbolize the code in anonymous mappings by itself. Two formats let it find the
+// JIT code symbols:
+//
+// - The perf map, `/tmp/perf-<pid>.map`, a text file with a line per symbol: start address and
+//   size in hex, and the name. `perf report` picks it up directly.
+// - The jitdump, `jit-<pid>.dump`, a binary log of the code loads including the code bytes, so
+//   the code can be annotated even after it's unmapped. perf learns about the file from an
+//   executable mapping of it, and `perf inject --jit` turns the records into ELF objects. The
+//   record timestamps must come from the clock perf records with, `perf record -k mono`.
+//
+// The jitdump file is created in `$JITDUMPDIR`, or in `/tmp` if it's not set.
+
+/// Profiler and debugger integrations enabled on instantiation
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProfilingConfig {
+	/// Append the function symbols to the perf map
+	pub perf_map: bool,
+	/// Record the code loads to the jitdump
+	pub jitdump: bool,
+	/// Register the code with GDB through its JIT interface for the lifetime of the instance.
+	/// GDB only sees the code if the crate is built with the `gdb-jit` feature.
+	pub gdb_jit: bool,
+}
+
+const JITDUMP_MAGIC: u32 = 0x4a695444;
+const JITDUMP_VERSION: u32 = 1;
+const JITDUMP_HEADER_SIZE: u32 = 40;
+const JIT_CODE_LOAD: u32 = 0;
+
+#[cfg(target_arch = "x86_64")]
+const ELF_MACHINE: u32 = 62; // EM_X86_64
+#[cfg(target_arch = "aarch64")]
+const ELF_MACHINE: u32 = 183; // EM_AARCH64
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const ELF_MACHINE: u32 = 0;
+
+struct JitDump {
+	file: File,
+	// The mapping perf learns the file name from, kept for the lifetime of the process
+	_marker: *mut libc::c_void,
+	code_index: u64,
+}
+
+// SAFETY: The marker mapping is never accessed
+unsafe impl Send for JitDump {}
+
+static JITDUMP: Mutex<Option<JitDump>> = Mutex::new(None);
+
+fn timestamp() -> u64 {
+	let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+	unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+	ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+pub(crate) fn perf_map_path() -> PathBuf {
+	PathBuf::from(format!("/tmp/perf-{}.map", std::process::id()))
+}
+
+pub(crate) fn jitdump_path() -> PathBuf {
+	let dir = std::env::var_os("JITDUMPDIR").map_or_else(|| PathBuf::from("/tmp"), PathBuf::from);
+	dir.join(format!("jit-{}.dump", std::process::id()))
+}
+
+/// Appends the symbols of the code at `code_addr` to the perf map
+pub(crate) fn write_perf_map(code_addr: usize, symbols: &[(usize, usize, String)]) -> std::io::Result<()> {
+	let mut map = String::new();
+	for (offset, size, name) in symbols {
+		map.push_str(&format!("{:x} {:x} {}\n", code_addr + offset, size, name));
+	}
+	// A single write keeps the lines of concurrent instantiations from interleaving
+	OpenOptions::new().create(true).append(true).open(perf_map_path())?.write_all(map.as_bytes())
+}
+
+fn open_jitdump() -> std::io::Result<JitDump> {
+	let mut file = OpenOptions::new().create(true).truncate(true).read(true).write(true).open(jitdump_path())?;
+
+	let mut header = Vec::with_capacity(JITDUMP_HEADER_SIZE as usize);
+	header.extend_from_slice(&JITDUMP_MAGIC.to_ne_bytes());
+	header.extend_from_slice(&JITDUMP_VERSION.to_ne_bytes());
+	header.extend_from_slice(&JITDUMP_HEADER_SIZE.to_ne_bytes());
+	header.extend_from_slice(&ELF_MACHINE.to_ne_bytes());
+	header.extend_from_slice(&0u32.to_ne_bytes()); // Padding
+	header.extend_from_slice(&std::process::id().to_ne_bytes());
+	header.extend_from_slice(&timestamp().to_ne_bytes());
+	header.extend_from_slice(&0u64.to_ne_bytes()); // Flags
+	file.write_all(&header)?;
+
+	let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+	let marker = unsafe {
+		use std::os::fd::AsRawFd;
+		libc::mmap(std::ptr::null_mut(), page_size, libc::PROT_READ | libc::PROT_EXEC, libc::MAP_PRIVATE, file.as_raw_fd(), 0)
+	};
+	if marker == libc::MAP_FAILED {
+		return Err(std::io::Error::last_os_error());
+	}
+	Ok(JitDump { file, _marker: marker, code_index: 0 })
+}
+
+/// Records the code at `code_addr` to the jitdump, a code load per symbol
+pub(crate) fn write_jitdump(code_addr: usize, code: &[u8], symbols: &[(usize, usize, String)]) -> std::io::Result<()> {
+	let mut jitdump = JITDUMP.lock().unwrap_or_else(|e| e.into_inner());
+	if jitdump.is_none() {
+		*jitdump = Some(open_jitdump()?);
+	}
+	let jitdump = jitdump.as_mut().expect("Jitdump is open");
+
+	let pid = std::process::id();
+	let tid = unsafe { libc::syscall(libc::SYS_gettid) } as u32;
+	let mut records = Vec::new();
+	for (offset, size, name) in symbols {
+		let total_size = 16 + 40 + name.len() + 1 + size;
+		records.extend_from_slice(&JIT_CODE_LOAD.to_ne_bytes());
+		records.extend_from_slice(&(total_size as u32).to_ne_bytes());
+		records.extend_from_slice(&timestamp().to_ne_bytes());
+		records.extend_from_slice(&pid.to_ne_bytes());
+		records.extend_from_slice(&tid.to_ne_bytes());
+		records.extend_from_slice(&((code_addr + offset) as u64).to_ne_bytes()); // vma
+		records.extend_from_slice(&((code_addr + offset) as u64).to_ne_bytes()); // code_addr
+		records.extend_from_slice(&(*size as u64).to_ne_bytes());
+		records.extend_from_slice(&jitdump.code_index.to_ne_bytes());
+		records.extend_from_slice(name.as_bytes());
+		records.push(0);
+		records.extend_from_slice(&code[*offset..offset + size]);
+		jitdump.code_index += 1;
+	}
+	jitdump.file.write_all(&records)
+}
@@ -1,13 +1,24 @@
-use crate::{ir::IrLabel, codegen::{Relocation, OffsetMap}};
-use std::collections::HashMap;
+use crate::{interface::ModuleInterface, ir::{IrLabel, IrCp, IrNames, IrSignature}, codegen::{Relocation, OffsetMap, InsnDecoder}};
+use std::{collections::HashMap, fmt::Write};
 
 pub struct PreparedPvf {
 	pub(crate) code: Vec<u8>,
 	pub(crate) labels: HashMap<IrLabel, usize>,
 	pub(crate) relocs: Vec<(Relocation, usize)>,
+	pub(crate) ir_map: Vec<(usize, IrCp)>,
+	pub(crate) source_map: Vec<(usize, u32, u32)>,
+	pub(crate) call_sites: Vec<(usize, Option<(u32, u32)>)>,
+	pub(crate) names: IrNames,
+	pub(crate) interface: ModuleInterface,
+	pub(crate) signatures: Vec<Option<IrSignature>>,
+	pub(crate) decoder: Option<InsnDecoder>,
+	pub(crate) target_arch: &'static str,
 	pub(crate) memory: (u32, u32),
 	pub(crate) tables_pages: u32,
+	// Number of elements of every table
+	pub(crate) table_sizes: Vec<u32>,
 	pub(crate) data_chunks: Vec<Vec<u8>>,
+	pub(crate) memory_image: Vec<(u32, Vec<u8>)>,
 	pub(crate) offset_map: OffsetMap,
 }
 
@@ -30,6 +41,112 @@ impl PreparedPvf {
 		res
 	}
 
+	// Signatures of the exported functions, by export name
+	pub(crate) fn export_signatures(&self) -> HashMap<String, IrSignature> {
+		self.labels.keys().filter_map(|label| match label {
+			IrLabel::ExportedFunc(index, name) => Some((name.clone(), self.signatures[*index as usize].clone().expect("Export signature available"))),
+			_ => None,
+		}).collect()
+	}
+
+	// Number of parameters of every function, by index
+	pub(crate) fn params(&self) -> Vec<u32> {
+		self.signatures.iter().map(|signature| signature.as_ref().map_or(0, |signature| signature.params)).collect()
+	}
+
+	/// Architecture the code was compiled for, as in `std::env::consts::ARCH`
+	pub fn target_arch(&self) -> &'static str {
+		self.target_arch
+	}
+
+	/// Function and local names from the `name` section of the module
+	pub fn names(&self) -> &IrNames {
+		&self.names
+	}
+
+	/// Imports, exports, memory and tables of the module
+	pub fn interface(&self) -> &ModuleInterface {
+		&self.interface
+	}
+
+	/// Source map of the code. Every entry is a native code offset, and the function index and the
+	/// Wasm bytecode offset of the instruction the code starting there originates from. Entries
+	/// are ordered by native offset.
+	pub fn source_map(&self) -> &[(usize, u32, u32)] {
+		&self.source_map
+	}
+
+	/// Function index and Wasm bytecode offset of the instruction the native code at `offset`
+	/// originates from. Code inlined from another function is attributed to that function.
+	pub fn wasm_location(&self, offset: usize) -> Option<(u32, u32)> {
+		let n = self.source_map.partition_point(|(pc, _, _)| *pc <= offset);
+		self.source_map[..n].last().map(|(_, func_index, wasm_offset)| (*func_index, *wasm_offset))
+	}
+
+	/// Disassembles the code, interleaved with the labels, the relocation sites and the IR
+	/// instructions the code originates from. Code the code generator cannot decode is dumped
+	/// as raw bytes.
+	pub fn disassemble(&self) -> String {
+		let mut labels = self.labels.iter().map(|(label, offset)| (*offset, self.names.label(label))).collect::<Vec<_>>();
+		labels.sort();
+		let mut relocs: HashMap<usize, Vec<&Relocation>> = HashMap::new();
+		for (reloc, offset) in &self.relocs {
+			relocs.entry(*offset).or_default().push(reloc);
+		}
+
+		let mut out = String::new();
+		let (mut next_label, mut next_cp) = (0, 0);
+		let mut pc = 0;
+		while pc < self.code.len() {
+			while next_label < labels.len() && labels[next_label].0 <= pc {
+				writeln!(out, "{}:", labels[next_label].1).unwrap();
+				next_label += 1;
+			}
+			while next_cp < self.ir_map.len() && self.ir_map[next_cp].0 <= pc {
+				writeln!(out, "\t; {:?}", self.ir_map[next_cp].1).unwrap();
+				next_cp += 1;
+			}
+
+			// Jump tables are embedded in the code
+			let is_data = relocs.get(&pc).is_some_and(|r| r.iter().any(|r| matches!(r, Relocation::LabelAbsoluteAddress(_))));
+			let (len, text) = match self.decoder {
+				_ if is_data && pc + 8 <= self.code.len() => (8, format!(".quad 0x{:x}", u64::from_le_bytes(self.code[pc..pc + 8].try_into().unwrap()))),
+				Some(decode) => decode(&self.code[pc..], pc).unwrap_or_else(|| (1, format!(".byte 0x{:02x}", self.code[pc]))),
+				None => (1, format!(".byte 0x{:02x}", self.code[pc])),
+			};
+
+			let bytes = self.code[pc..pc + len].iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ");
+			let sites = (pc..pc + len).filter_map(|offset| relocs.get(&offset)).flatten().map(|r| format!("{:?}", r)).collect::<Vec<_>>();
+			write!(out, "\t{:08x}  {:<30} {}", pc, bytes, text).unwrap();
+			if !sites.is_empty() {
+				write!(out, "  ; reloc {}", sites.join(", ")).unwrap();
+			}
+			out.push('\n');
+			pc += len;
+		}
+		out
+	}
+
+	/// Code offset, size and symbol name of every function body and entry trampoline, ordered
+	/// by offset
+	pub(crate) fn symbols(&self) -> Vec<(usize, usize, String)> {
+		let mut starts = self.labels.iter().filter_map(|(label, offset)| {
+			let name = match label {
+				IrLabel::AnonymousFunc(index) => match self.names.func(*index) {
+					Some(name) => format!("wasm[{}]::{}", index, name),
+					None => format!("wasm[{}]", index),
+				},
+				IrLabel::ExportedFunc(index, export) => format!("trampoline[{}]::{}", index, export),
+				_ => return None,
+			};
+			Some((*offset, name))
+		}).collect::<Vec<_>>();
+		starts.sort();
+
+		let ends = starts.iter().skip(1).map(|(offset, _)| *offset).chain(std::iter::once(self.code.len())).collect::<Vec<_>>();
+		starts.into_iter().zip(ends).filter(|((start, _), end)| end > start).map(|((start, name), end)| (start, end - start, name)).collect()
+	}
+
 	pub fn data_segments_pages(&self) -> u32 {
 		self.data_chunks.iter().map(|s| ((s.len() | 0xffff + 1) >> 16) as u32).sum()
 	}
@@ -1,8 +1,9 @@
-use crate::{PvfError, IrPvf};
-use crate::ir::{Ir, IrLabel, IrOperand::*, IrReg::*, IrCond::*, IrSignature, IrHints};
+use crate::{PvfError, IrPvf, ValueType, ExecutorConfig, ExecutorLimit};
+use crate::interface::{ModuleInterface, ModuleImport, ModuleExport, ExternType, FuncSignature, Limits};
+use crate::ir::{Ir, IrLabel, IrOperand::*, IrReg::*, IrCond, IrCond::*, IrSignature, IrHints, IrNames};
 // use std::assert_matches::assert_matches;
 use std::collections::HashMap;
-use wasmparser::{Parser, ExternalKind, Type, Payload, Operator as Op, BlockType, Import, Encoding, TypeRef, TableInit, FuncType, OperatorsReader, ElementKind, ElementItems, DataKind};
+use wasmparser::{Parser, ExternalKind, Type, Payload, Operator as Op, BlockType, Import, Encoding, TypeRef, TableInit, FuncType, OperatorsReader, ElementKind, ElementItems, DataKind, Name, NameSectionReader};
 
 enum ControlFrameType {
 	Func,
@@ -15,6 +16,16 @@ struct ControlFrame {
 	cftype: ControlFrameType,
 	block_index: u64,
 	has_retval: bool,
+	// Code following an unconditional branch is not reachable until the end of the frame
+	unreachable: bool,
+	// Some reachable branch targets the end of this frame
+	branched_to: bool,
+}
+
+impl ControlFrame {
+	fn new(cftype: ControlFrameType, block_index: u64, has_retval: bool) -> Self {
+		Self { cftype, block_index, has_retval, unreachable: false, branched_to: false }
+	}
 }
 
 type ImportResolver = fn(&str, &str, &Type) -> Result<*const u8, PvfError>;
@@ -45,26 +56,40 @@ fn parse_const_expr(mut reader: OperatorsReader, globals: &Vec<GlobalRef>) -> Re
 	Err(PvfError::ValidationError("Constant expression must end with `end` opcode".to_owned()))
 }
 
+// Offset of a data segment given by a constant expression
+fn const_offset(mut reader: OperatorsReader) -> Option<u32> {
+	match (reader.read(), reader.read()) {
+		(Ok(Op::I32Const { value }), Ok(Op::End)) => Some(value as u32),
+		_ => None,
+	}
+}
+
 pub struct RawPvf {
 	wasm_code: Vec<u8>,
 	block_index: u64,
 	import_resolver: Option<ImportResolver>,
+	config: ExecutorConfig,
 }
 
 impl RawPvf {
 	pub fn from_bytes(bytes: &[u8]) -> Self {
-		Self { wasm_code: Vec::from(&bytes[..]), block_index: 0, import_resolver: None }
+		Self { wasm_code: Vec::from(&bytes[..]), block_index: 0, import_resolver: None, config: ExecutorConfig::default() }
 	}
 
 	pub fn from_file<P: AsRef<std::path::Path>>(path: P) -> Result<Self, PvfError> {
 		let wasm_code = std::fs::read(path).map_err(PvfError::FilesystemError)?;
-		Ok(Self { wasm_code, block_index: 0, import_resolver: None })
+		Ok(Self { wasm_code, block_index: 0, import_resolver: None, config: ExecutorConfig::default() })
 	}
 
 	pub fn set_import_resolver(&mut self, resolver: ImportResolver) {
 		self.import_resolver = Some(resolver);
 	}
 
+	/// Sets the resource limits the PVF is checked against on translation
+	pub fn set_executor_config(&mut self, config: ExecutorConfig) {
+		self.config = config;
+	}
+
 	pub fn translate(mut self) -> Result<IrPvf, PvfError> {
 		let mut types = Vec::new();
 		// let mut imports;
@@ -83,6 +108,8 @@ impl RawPvf {
 		let mut globals = Vec::new();
 		let mut hints = IrHints::default();
 		let mut data_chunk_cnt = 0;
+		let mut interface = ModuleInterface::default();
+		let mut global_types = Vec::new();
 
 		for payload in Parser::new(0).parse_all(&self.wasm_code) {
 			match payload? {
@@ -98,7 +125,8 @@ impl RawPvf {
 								if let Some(resolver) = self.import_resolver {
 									let funcref = resolver(import.module, import.name, &types[ti as usize]).map_err(|_| PvfError::UnresolvedImport(import.module.to_owned() + "::" + import.name))?;
 									let Type::Func(functype) = &types[ti as usize];
-									let signature = IrSignature { params: functype.params().len() as u32, results: functype.results().len() as u32 };
+									let signature = IrSignature::from_func_type(functype)?;
+									interface.imports.push(ModuleImport { module: import.module.to_owned(), name: import.name.to_owned(), ty: ExternType::Func(FuncSignature::from(&signature)) });
 									ir_pvf.add_func_import(findex, funcref, signature);
 									func_imports.push(funcref);
 									functypes.push(ti);
@@ -108,15 +136,21 @@ impl RawPvf {
 									panic!("Import is requested but no import resolver was specified");
 								}
 							},
-							TypeRef::Global(_) => {
+							TypeRef::Global(ty) => {
+								let ty = ExternType::Global { ty: ValueType::try_from(ty.content_type)?, mutable: ty.mutable };
+								interface.imports.push(ModuleImport { module: import.module.to_owned(), name: import.name.to_owned(), ty: ty.clone() });
+								global_types.push(ty);
 								globals.push(GlobalRef::Imported)
 							},
 							_ => todo!()
 						}
 					}
+					// A module may have no function section
+					ExecutorLimit::Functions.check(functypes.len() as u64, self.config.max_functions as u64)?;
 				},
 				Payload::FunctionSection(reader) => {
 					functypes.extend(reader.into_iter().flatten());
+					ExecutorLimit::Functions.check(functypes.len() as u64, self.config.max_functions as u64)?;
 					println!("FUNCTYPES {:?}", functypes);
 					let init_index = functypes.len();
 					init_ir.label(IrLabel::ExportedFunc(init_index as u32, "_pvf_init".to_owned()));
@@ -127,22 +161,34 @@ impl RawPvf {
 					let mem = reader.into_iter().next().expect("Memory section contains a single memory entry").expect("Memory section parsed successfully");
 					assert!(!mem.memory64);
 					assert!(!mem.shared);
+					ExecutorLimit::MemoryPages.check(mem.initial, self.config.max_memory_pages as u64)?;
 					mem_initial = mem.initial as u32;
-					mem_max = if let Some(max) = mem.maximum { max as u32 } else { mem_initial + 128 };
+					mem_max = mem.maximum.map_or(self.config.max_memory_pages, |max| max.min(self.config.max_memory_pages as u64) as u32);
+					interface.memory = Some(Limits { initial: mem_initial, maximum: mem.maximum.map(|max| max as u32) });
 					ir_pvf.set_memory(mem_initial, mem_max);
 				}
 				Payload::ExportSection(reader) => {
 					for export in reader.into_iter() {
 						let export = export.unwrap();
-						if export.kind == ExternalKind::Func {
-							func_export.insert(export.index, export.name);
-						}
+						let ty = match export.kind {
+							ExternalKind::Func => {
+								func_export.insert(export.index, export.name);
+								let Type::Func(functype) = &types[functypes[export.index as usize] as usize];
+								ExternType::Func(FuncSignature::from(&IrSignature::from_func_type(functype)?))
+							},
+							ExternalKind::Global => global_types[export.index as usize].clone(),
+							ExternalKind::Memory => ExternType::Memory(interface.memory.expect("Exported memory is defined")),
+							ExternalKind::Table => ExternType::Table(interface.tables[export.index as usize]),
+							ExternalKind::Tag => continue,
+						};
+						interface.exports.push(ModuleExport { name: export.name.to_owned(), index: export.index, ty });
 					}
 				},
 				Payload::GlobalSection(reader) => {
 					hints.has_globals = true;
 					for global in reader.into_iter() {
 						let global = global.unwrap();
+						global_types.push(ExternType::Global { ty: ValueType::try_from(global.ty.content_type)?, mutable: global.ty.mutable });
 						let global_init_ir = parse_const_expr(global.init_expr.get_operators_reader(), &globals )?;
 						init_ir.append(&mut global_init_ir.clone());
 						init_ir.pop(Reg(Sra));
@@ -151,14 +197,38 @@ impl RawPvf {
 					}
 				}
 				Payload::CodeSectionEntry(fbody) => {
-					let locals_reader = fbody.get_locals_reader()?;
-					let n_locals = locals_reader.into_iter().flatten().fold(0, |a, (n, _)| a + n);
+					let typeidx = functypes[findex as usize];
+					let Type::Func(ftype) = &types[typeidx as usize];
+					// Checked on every declaration, so that huge counts can't overflow the sum
+					let mut n_locals = ftype.params().len() as u64;
+					ExecutorLimit::Locals.check(n_locals, self.config.max_locals as u64)?;
+					for locals in fbody.get_locals_reader()? {
+						let (n, _) = locals?;
+						n_locals = n_locals.saturating_add(n as u64);
+						ExecutorLimit::Locals.check(n_locals, self.config.max_locals as u64)?;
+					}
+					let n_locals = (n_locals - ftype.params().len() as u64) as u32;
 
 					let mut reader = fbody.get_operators_reader()?;
 					let mut ir = Ir::new();
 					let mut cstack = Vec::new();
-					let typeidx = functypes[findex as usize];
-					let Type::Func(ftype) = &types[typeidx as usize];
+
+					// Condition of the flags set by the last comparison, if the comparison result
+					// goes directly to the following `br_if` or `select` and is not materialized
+					let mut fused_cond: Option<IrCond> = None;
+
+					macro_rules! fuse_or_set_if {
+						($cond:expr, $dest:expr) => {
+							{
+								if matches!(reader.clone().read(), Ok(Op::BrIf { .. } | Op::Select)) {
+									fused_cond = Some($cond);
+								} else {
+									ir.set_if($cond, Reg32($dest));
+									ir.push(Reg($dest));
+								}
+							}
+						};
+					}
 
 					macro_rules! impl_compare {
 						($cond:expr, $reg:ident, $dest:expr, $src:expr) => {
@@ -166,8 +236,7 @@ impl RawPvf {
 								ir.pop(Reg($src));
 								ir.pop(Reg($dest));
 								ir.compare($reg($dest), $reg($src));
-								ir.set_if($cond, $reg($dest));
-								ir.push(Reg($dest));
+								fuse_or_set_if!($cond, $dest);
 							}
 						};
 					}
@@ -205,7 +274,7 @@ impl RawPvf {
 						};
 					}
 
-					cstack.push(ControlFrame { cftype: ControlFrameType::Func, block_index: 0, has_retval: ftype.results().len() > 0 });
+					cstack.push(ControlFrame::new(ControlFrameType::Func, 0, ftype.results().len() > 0));
 
 					ir.label(
 						if let Some(export) = func_export.get(&findex) {
@@ -217,7 +286,25 @@ impl RawPvf {
 					ir.enter_function(n_locals);
 
 					while !reader.eof() {
+						let offset = reader.original_position() as u32;
 						let op = reader.read()?;
+
+						if cstack.last().is_some_and(|f| f.unreachable) {
+							match op {
+								// Frames opened in unreachable code are dead as a whole. They are
+								// tracked to match their `end` but never emit any code.
+								Op::Block { .. } | Op::Loop { .. } | Op::If { .. } => {
+									let mut frame = ControlFrame::new(ControlFrameType::Block, 0, false);
+									frame.unreachable = true;
+									cstack.push(frame);
+									continue;
+								},
+								Op::End | Op::Else => (),
+								_ => continue,
+							}
+						}
+
+						ir.source_loc(findex, offset);
 						match op {
 							Op::I32Const { value: v } => {
 								ir.r#move(Reg(Sra), Imm32(v));
@@ -254,14 +341,12 @@ impl RawPvf {
 							Op::I32Eqz => {
 								ir.pop(Reg(Sra));
 								ir.and(Reg32(Sra), Reg32(Sra));
-								ir.set_if(Zero, Reg32(Sra));
-								ir.push(Reg(Sra));
+								fuse_or_set_if!(Zero, Sra);
 							}
 							Op::I64Eqz => {
 								ir.pop(Reg(Sra));
 								ir.and(Reg(Sra), Reg(Sra));
-								ir.set_if(Zero, Reg32(Sra));
-								ir.push(Reg(Sra));
+								fuse_or_set_if!(Zero, Sra);
 							}
 							Op::I32And => impl_comm_binary!(Reg32, Sra, Srd, and),
 							Op::I32Or => impl_comm_binary!(Reg32, Sra, Srd, or),
@@ -276,7 +361,7 @@ impl RawPvf {
 									BlockType::Type(_) => true,
 									BlockType::FuncType(_) => todo!(),
 								};
-								cstack.push(ControlFrame { cftype: ControlFrameType::Block, block_index: self.block_index, has_retval });
+								cstack.push(ControlFrame::new(ControlFrameType::Block, self.block_index, has_retval));
 								ir.enter_block();
 							},
 							Op::Loop { blockty } => {
@@ -286,20 +371,25 @@ impl RawPvf {
 									BlockType::Type(_) => true,
 									BlockType::FuncType(_) => todo!(),
 								};
-								cstack.push(ControlFrame { cftype: ControlFrameType::Loop, block_index: self.block_index, has_retval });
+								cstack.push(ControlFrame::new(ControlFrameType::Loop, self.block_index, has_retval));
 								ir.enter_block();
 								ir.label(IrLabel::BranchTarget(self.block_index));
 							},
 							Op::Br { relative_depth } | Op::BrIf { relative_depth } => {
-								let target_frame = &cstack[cstack.len() - relative_depth as usize - 1];
+								let target_depth = cstack.len() - relative_depth as usize - 1;
+								cstack[target_depth].branched_to = true;
+								let target_frame = &cstack[target_depth];
 								let mut else_label = 0;
 
 								if matches!(op, Op::BrIf { .. }) {
-									ir.pop(Reg(Sra));
-									ir.and(Reg32(Sra), Reg32(Sra));
+									let cond = fused_cond.take().unwrap_or_else(|| {
+										ir.pop(Reg(Sra));
+										ir.and(Reg32(Sra), Reg32(Sra));
+										NotZero
+									});
 									else_label = local_label_index;
 									local_label_index += 1;
-									ir.jump_if(Zero, IrLabel::LocalLabel(else_label));
+									ir.jump_if(cond.inverse(), IrLabel::LocalLabel(else_label));
 								}
 
 								if target_frame.has_retval {
@@ -312,12 +402,18 @@ impl RawPvf {
 
 								if matches!(op, Op::BrIf { .. }) {
 									ir.label(IrLabel::LocalLabel(else_label));
+								} else {
+									cstack.last_mut().expect("Control stack is not empty").unreachable = true;
 								}
 							},
 							Op::BrTable { targets } => {
-								let default_frame = &cstack[cstack.len() - targets.default() as usize - 1];
 								let mut br_targets = targets.targets().collect::<Result<Vec<_>, _>>()?;
 								br_targets.push(targets.default());
+								for target in br_targets.iter() {
+									let target_depth = cstack.len() - *target as usize - 1;
+									cstack[target_depth].branched_to = true;
+								}
+								let default_frame = &cstack[cstack.len() - targets.default() as usize - 1];
 								ir.pop(Reg(Srd)); // Branch target index
 								ir.r#move(Reg32(Sra), Imm32(br_targets.len() as i32 - 1));
 								ir.compare(Reg32(Srd), Reg32(Sra));
@@ -343,19 +439,34 @@ impl RawPvf {
 									}
 									ir.jump(IrLabel::BranchTarget(frame.block_index));
 								}
+								cstack.last_mut().expect("Control stack is not empty").unreachable = true;
 							},
 							Op::End => {
 								if let Some(frame) = cstack.pop() {
+									if cstack.last().is_some_and(|f| f.unreachable) {
+										// The whole frame is dead
+										continue;
+									}
 									match frame.cftype {
 										ControlFrameType::Func => {
-											if ftype.results().len() > 0 {
+											if frame.unreachable && !frame.branched_to {
+												continue;
+											}
+											if ftype.results().len() > 0 && !frame.unreachable {
 												ir.pop(Reg(Sra));
 											}
 											ir.leave_function();
 											ir.r#return();
 										},
 										ControlFrameType::Block | ControlFrameType::Loop => {
-											if frame.has_retval {
+											// Loop branch targets are at the beginning of the loop, so
+											// its end is only reachable by falling through
+											let end_reachable = !frame.unreachable || (frame.branched_to && matches!(frame.cftype, ControlFrameType::Block));
+											if !end_reachable {
+												cstack.last_mut().expect("Control stack is not empty").unreachable = true;
+												continue;
+											}
+											if frame.has_retval && !frame.unreachable {
 												ir.pop(Reg(Sra));
 											}
 											if matches!(frame.cftype, ControlFrameType::Block) {
@@ -374,7 +485,7 @@ impl RawPvf {
 							},
 							Op::Call { function_index } => {
 								ir.call(if function_index < nimports {
-									IrLabel::ImportedFunc(function_index, func_imports[function_index as usize])
+									IrLabel::ImportedFunc(function_index, func_imports[function_index as usize] as usize)
 								} else {
 									IrLabel::AnonymousFunc(function_index)
 								});
@@ -491,7 +602,10 @@ impl RawPvf {
 								ir.pop(Reg(Srd));
 								ir.r#move(Memory32(memarg.offset as i32, Srd), Reg32(Sra));
 							},
-							Op::Unreachable => ir.trap(),
+							Op::Unreachable => {
+								ir.trap();
+								cstack.last_mut().expect("Control stack is not empty").unreachable = true;
+							},
 							Op::Nop => (),
 							Op::If { blockty } => todo!(),
 							Op::Else => todo!(),
@@ -501,23 +615,28 @@ impl RawPvf {
 								}
 								ir.leave_function();
 								ir.r#return();
+								cstack.last_mut().expect("Control stack is not empty").unreachable = true;
 							},
 							Op::CallIndirect { type_index, table_index, table_byte } => {
 								assert_eq!(table_byte, 0); // Reference types are not supported yet
 								ir.pop(Reg(Sra));
 								let Type::Func(functype) = &types[type_index as usize];
-								let signature = IrSignature { params: functype.params().len() as u32, results: functype.results().len() as u32 };
+								let signature = IrSignature::from_func_type(functype)?;
 								ir.call(IrLabel::Indirect(table_index, Reg32(Sra), signature));
 							},
 							Op::Drop => {
 								ir.pop(Reg(Sra));
 							},
 							Op::Select => {
-								ir.pop(Reg(Sra));
-								ir.and(Reg(Sra), Reg(Sra));
+								// Pops and moves leave the flags intact
+								let cond = fused_cond.take().unwrap_or_else(|| {
+									ir.pop(Reg(Sra));
+									ir.and(Reg(Sra), Reg(Sra));
+									NotZero
+								});
 								ir.pop(Reg(Sra));
 								ir.pop(Reg(Srd));
-								ir.move_if(NotZero, Reg(Sra), Reg(Srd));
+								ir.move_if(cond, Reg(Sra), Reg(Srd));
 								ir.push(Reg(Sra));
 							},
 							Op::MemorySize { mem: _, mem_byte } => {
@@ -572,7 +691,7 @@ impl RawPvf {
 					}
 
 					let Type::Func(signature) = &types[functypes[findex as usize] as usize];
-					let signature = IrSignature { params: signature.params().len() as u32, results: signature.results().len() as u32 };
+					let signature = IrSignature::from_func_type(signature)?;
 					ir_pvf.add_func(findex, ir, signature);
 					findex += 1;
 				},
@@ -588,8 +707,10 @@ impl RawPvf {
 						if !matches!(table.init, TableInit::RefNull) {
 							todo!("Table initialization mode {:?}", table.init);
 						}
-						let table_size = if let Some(maximum) = table.ty.maximum { maximum } else { table.ty.initial };
+						ExecutorLimit::TableElements.check(table.ty.initial as u64, self.config.max_table_elements as u64)?;
+						let table_size = table.ty.maximum.map_or(table.ty.initial, |maximum| maximum.min(self.config.max_table_elements));
 						ir_pvf.add_table(table_size);
+						interface.tables.push(Limits { initial: table.ty.initial, maximum: table.ty.maximum });
 					}
 				},
 				Payload::TagSection(_) => todo!(),
@@ -617,25 +738,49 @@ impl RawPvf {
 					}
 				},
 				Payload::DataSection(reader) => {
-					for data in reader.into_iter() {
-						let data = data.unwrap();
-						if let DataKind::Active { memory_index, offset_expr } = data.kind {
-							assert_eq!(memory_index, 0); // WASM MVP only supports single memory
-
-							ir_pvf.add_data_chunk(data.data);
-
-							let mut init_offset_ir = parse_const_expr(offset_expr.get_operators_reader(), &globals)?;
-							init_ir.append(&mut init_offset_ir);
-							init_ir.pop(Reg(Sra));
-							init_ir.init_memory_from_chunk(data_chunk_cnt, data.data.len() as u32, Reg(Sra));
-							data_chunk_cnt += 1;
-						} else {
-							todo!("Passive data segment");
+					let segments = reader.into_iter().collect::<Result<Vec<_>, _>>()?;
+					let data_bytes = segments.iter().map(|data| data.data.len() as u64).sum();
+					ExecutorLimit::DataBytes.check(data_bytes, self.config.max_data_bytes as u64)?;
+
+					// If every segment is placed at a constant offset, the initial memory contents
+					// are known before execution and the segments are not copied at runtime
+					let offsets = segments.iter().map(|data| match &data.kind {
+						DataKind::Active { memory_index: 0, offset_expr } => const_offset(offset_expr.get_operators_reader()),
+						_ => None,
+					}).collect::<Option<Vec<_>>>();
+					if let Some(offsets) = offsets {
+						let mut image = Vec::new();
+						for (data, offset) in segments.iter().zip(offsets) {
+							if offset as usize + data.data.len() > mem_initial as usize * 0x10000 {
+								return Err(PvfError::ValidationError(format!("Data segment at 0x{:x} of {} byte(s) is out of the initial memory", offset, data.data.len())));
+							}
+							if !data.data.is_empty() {
+								image.push((offset, data.data.to_vec()));
+							}
+						}
+						ir_pvf.set_memory_image(image);
+					} else {
+						for data in segments {
+							if let DataKind::Active { memory_index, offset_expr } = data.kind {
+								assert_eq!(memory_index, 0); // WASM MVP only supports single memory
+
+								ir_pvf.add_data_chunk(data.data);
+
+								let mut init_offset_ir = parse_const_expr(offset_expr.get_operators_reader(), &globals)?;
+								init_ir.append(&mut init_offset_ir);
+								init_ir.pop(Reg(Sra));
+								init_ir.init_memory_from_chunk(data_chunk_cnt, data.data.len() as u32, Reg(Sra));
+								data_chunk_cnt += 1;
+							} else {
+								todo!("Passive data segment");
+							}
 						}
 					}
 				},
 				Payload::DataCountSection { count, range } => todo!(),
-				Payload::CodeSectionStart { count, range, size } => (), // FIXME
+				Payload::CodeSectionStart { count, range, size } => {
+					ExecutorLimit::CodeSize.check(size as u64, self.config.max_code_size as u64)?;
+				},
 				Payload::ModuleSection { parser, range } => todo!(),
 				Payload::InstanceSection(_) => todo!(),
 				Payload::CoreTypeSection(_) => todo!(),
@@ -647,6 +792,31 @@ impl RawPvf {
 				Payload::ComponentStartSection { start, range } => todo!(),
 				Payload::ComponentImportSection(_) => todo!(),
 				Payload::ComponentExportSection(_) => todo!(),
+				Payload::CustomSection(reader) if reader.name() == "name" => {
+					// A malformed name section does not invalidate the module, the names read so
+					// far are kept
+					let mut names = IrNames::default();
+					for subsection in NameSectionReader::new(reader.data(), reader.data_offset()) {
+						match subsection {
+							Ok(Name::Function(map)) => {
+								for naming in map.into_iter().map_while(Result::ok) {
+									names.funcs.insert(naming.index, naming.name.to_string());
+								}
+							},
+							Ok(Name::Local(map)) => {
+								for func in map.into_iter().map_while(Result::ok) {
+									let locals = names.locals.entry(func.index).or_default();
+									for naming in func.names.into_iter().map_while(Result::ok) {
+										locals.insert(naming.index, naming.name.to_string());
+									}
+								}
+							},
+							Ok(_) => (),
+							Err(_) => break,
+						}
+					}
+					ir_pvf.set_names(names);
+				},
 				Payload::CustomSection(_) => (),
 				Payload::UnknownSection { id, contents, range } => todo!(),
 				Payload::End(_) => (), // FIXME
@@ -655,7 +825,8 @@ impl RawPvf {
 
 		init_ir.leave_function();
 		init_ir.r#return();
-		ir_pvf.add_func(findex, init_ir, IrSignature { params: 0, results: 0 });
+		ir_pvf.add_func(findex, init_ir, IrSignature::default());
+		ir_pvf.set_interface(interface);
 
 		println!("IR: {:?}", ir_pvf);
 		Ok(ir_pvf)
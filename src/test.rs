@@ -1,4 +1,4 @@
-use crate::{RawPvf, IntelX64Compiler, PvfInstance, instance::{WasmResultType, WasmParams}, PvfError};
+use crate::{RawPvf, IrPvf, IntelX64Compiler, Aarch64Compiler, IrInterpreter, PvfInstance, LoadedPvf, instance::{WasmResultType, WasmParams}, PvfError, ir::{IrCp, IrLabel}, perf, gdb_jit, ProfilingConfig, Value, ValueType, ExternType, FuncSignature, Limits, ExecutorConfig, ExecutorLimit};
 
 fn wat(code: &str) -> Vec<u8> {
 	wat::parse_str(code).unwrap()
@@ -8,9 +8,9 @@ fn test<P: WasmParams, R: WasmResultType>(code: Vec<u8>, params: P) -> R {
 	let raw: RawPvf = RawPvf::from_bytes(&code);
 	let mut ir = raw.translate().unwrap();
 	ir.optimize();
-	let mut codegen = IntelX64Compiler::new();
-	let pvf = ir.compile(&mut codegen);
-	let instance = PvfInstance::instantiate(&pvf);
+	let codegen = IntelX64Compiler::new();
+	let pvf = ir.compile(&codegen);
+	let instance = PvfInstance::instantiate(&pvf).unwrap();
 	unsafe { instance.call::<_, _, R>("test", params) }.unwrap()
 }
 
@@ -23,23 +23,30 @@ extern "C" fn add2(x: i32) -> i32 {
 	x + 2
 }
 
-fn test_with_imports<P: WasmParams, R: WasmResultType>(code: Vec<u8>, params: P) -> R {
-	let mut raw = RawPvf::from_bytes(&code);
-	raw.set_import_resolver(|module, name, _ty| {
-		if module == "env" {
-			match name {
-				"add2" => Ok(add2 as *const u8),
-				_ => Err(PvfError::UnresolvedImport(name.to_owned())),
-			}
-		} else {
-			Err(PvfError::UnresolvedImport(name.to_owned()))
+fn resolve_env(module: &str, name: &str, _ty: &wasmparser::Type) -> Result<*const u8, PvfError> {
+	if module == "env" {
+		match name {
+			"add2" => Ok(add2 as *const u8),
+			_ => Err(PvfError::UnresolvedImport(name.to_owned())),
 		}
-	});
+	} else {
+		Err(PvfError::UnresolvedImport(name.to_owned()))
+	}
+}
+
+fn translate_with_imports(code: &[u8]) -> IrPvf {
+	let mut raw = RawPvf::from_bytes(code);
+	raw.set_import_resolver(resolve_env);
 	let mut ir = raw.translate().unwrap();
 	ir.optimize();
-	let mut codegen = IntelX64Compiler::new();
-	let pvf = ir.compile(&mut codegen);
-	let instance = PvfInstance::instantiate(&pvf);
+	ir
+}
+
+fn test_with_imports<P: WasmParams, R: WasmResultType>(code: Vec<u8>, params: P) -> R {
+	let ir = translate_with_imports(&code);
+	let codegen = IntelX64Compiler::new();
+	let pvf = ir.compile(&codegen);
+	let instance = PvfInstance::instantiate(&pvf).unwrap();
 	unsafe { instance.call::<_, _, R>("test", params) }.unwrap()
 }
 
@@ -417,6 +424,24 @@ fn locals() {
 	);
 }
 
+#[test]
+fn far_locals() {
+	// Locals from index 15 on are out of reach of a disp8 displacement
+	assert_eq!(
+		test::<_, i32>(wat(r#"
+			(module
+				(func (export "test") (result i32) (local i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32)
+					(local.set 19 (i32.const 40))
+					(local.set 15 (i32.const 2))
+					(i32.add (local.get 19) (local.get 15))
+				)
+			)"#),
+			()
+		),
+		42
+	);
+}
+
 #[test]
 fn globals() {
 	assert_eq!(
@@ -692,3 +717,1336 @@ fn memory_init() {
 		42
 	);
 }
+
+struct CfgStats {
+	blocks: usize,
+	loops: Vec<(usize, Vec<usize>)>,
+}
+
+impl crate::IrPass for std::rc::Rc<std::cell::RefCell<Vec<CfgStats>>> {
+	fn name(&self) -> &'static str {
+		"cfg-stats"
+	}
+
+	fn run(&mut self, cfg: &mut crate::Cfg) {
+		for b in 0..cfg.blocks().len() {
+			if cfg.is_reachable(b) {
+				assert!(cfg.dominates(0, b));
+			}
+			for succ in cfg.block(b).successors() {
+				assert!(cfg.block(*succ).predecessors().contains(&b));
+			}
+		}
+		let loops = cfg.loops().iter().map(|l| {
+			for latch in l.latches() {
+				assert!(cfg.dominates(l.header(), *latch));
+			}
+			(l.header(), l.body().to_vec())
+		}).collect();
+		self.borrow_mut().push(CfgStats { blocks: cfg.blocks().len(), loops });
+	}
+}
+
+#[test]
+fn cfg() {
+	let code = wat(r#"
+		(module
+			(func (export "test") (param i32) (result i32) (local i32)
+				(loop
+					(local.set 1 (i32.add (local.get 1) (i32.const 10)))
+					(local.set 0 (i32.sub (local.get 0) (i32.const 1)))
+					(br_if 0 (local.get 0))
+				)
+				local.get 1
+			)
+		)"#);
+	let mut ir = RawPvf::from_bytes(&code).translate().unwrap();
+	let stats = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+	let mut passes = crate::PassManager::new();
+	passes.add_pass(stats.clone());
+	ir.run_passes(&mut passes);
+
+	// The exported function and the init function
+	let stats = stats.borrow();
+	assert_eq!(stats.len(), 2);
+	// Entry, loop body ending with `br_if`, `br` to the loop header, and the function exit
+	assert_eq!(stats[0].blocks, 4);
+	assert_eq!(stats[0].loops, vec![(1, vec![1, 2])]);
+	assert!(stats[1].loops.is_empty());
+
+	let codegen = IntelX64Compiler::new();
+	let pvf = ir.compile(&codegen);
+	let instance = PvfInstance::instantiate(&pvf).unwrap();
+	assert_eq!(unsafe { instance.call::<_, _, i32>("test", (4,)) }.unwrap(), 40);
+}
+
+fn code_len(code: Vec<u8>) -> usize {
+	let mut ir = RawPvf::from_bytes(&code).translate().unwrap();
+	ir.optimize();
+	ir.compile(&IntelX64Compiler::new()).code_len()
+}
+
+#[test]
+fn dead_code() {
+	assert_eq!(test::<_, i32>(wat(r#"(module (func (export "test") (result i32) (i32.const 42) (return) (i32.const 1) (i32.add)))"#), ()), 42);
+	assert_eq!(
+		test::<_, i32>(wat(r#"
+			(module
+				(func (export "test") (result i32)
+					(block (result i32)
+						(block
+							(br 1 (i32.const 42))
+							(drop (i32.const 1))
+							(block (unreachable))
+						)
+						(i32.const 2)
+					)
+				)
+			)"#),
+			()
+		),
+		42
+	);
+	assert_eq!(
+		test::<_, i32>(wat(r#"
+			(module
+				(func (export "test") (param i32) (result i32)
+					(block
+						(block
+							(block
+								(br_table 0 1 2 (local.get 0))
+								(unreachable)
+							)
+							(return (i32.const 40))
+						)
+						(return (i32.const 41))
+					)
+					(i32.const 42)
+				)
+			)"#),
+			(2,)
+		),
+		42
+	);
+
+	// The unreachable tail is neither translated nor emitted
+	assert_eq!(
+		code_len(wat(r#"(module (func (export "test") (result i32) (i32.const 42) (return) (i32.const 1) (i32.add) (block (drop (i32.const 2))) (unreachable)))"#)),
+		code_len(wat(r#"(module (func (export "test") (result i32) (i32.const 42) (return)))"#)),
+	);
+	assert_eq!(
+		code_len(wat(r#"(module (func (export "test") (result i32) (loop (br 0)) (i32.const 42)))"#)),
+		code_len(wat(r#"(module (func (export "test") (result i32) (loop (br 0)) (unreachable)))"#)),
+	);
+
+	let mut ir = crate::ir::Ir::new();
+	ir.label(IrLabel::AnonymousFunc(0));
+	ir.jump(IrLabel::LocalLabel(1));
+	ir.trap();
+	ir.label(IrLabel::LocalLabel(0));
+	ir.jump(IrLabel::LocalLabel(1));
+	ir.label(IrLabel::LocalLabel(1));
+	ir.r#return();
+	let mut passes = crate::PassManager::new();
+	passes.add_pass(crate::cfg::DeadCodeElimination);
+	let ir = passes.run(ir);
+	assert!(matches!(ir.code(), [IrCp::Label(_), IrCp::Jump(_), IrCp::Label(_), IrCp::Return]));
+}
+
+struct CountOps(std::rc::Rc<std::cell::Cell<usize>>, fn(&IrCp) -> bool);
+
+impl crate::IrPass for CountOps {
+	fn name(&self) -> &'static str {
+		"count-ops"
+	}
+
+	fn run(&mut self, cfg: &mut crate::Cfg) {
+		let n = cfg.blocks().iter().flat_map(|b| b.code()).filter(|cp| self.1(cp)).count();
+		self.0.set(self.0.get() + n);
+	}
+}
+
+#[test]
+fn inline() {
+	let code = wat(r#"
+		(module
+			(func $acc (param i32) (result i32) (local i32)
+				(local.set 1 (i32.add (local.get 1) (local.get 0)))
+				(local.get 1)
+			)
+			(func $early (param i32) (result i32)
+				(block
+					(block
+						(br_if 1 (local.get 0))
+						(return (i32.const 42))
+					)
+				)
+				(i32.const 1)
+			)
+			(func (export "test") (result i32) (local i32 i32 i64 i64 i64 i64 i64 i64 i64 i64 i64 i64 i64 i64 i64 i64)
+				(local.set 1 (i32.const 3))
+				(loop
+					(local.set 0 (i32.add (local.get 0) (call $acc (i32.const 14))))
+					(local.set 1 (i32.sub (local.get 1) (i32.const 1)))
+					(br_if 0 (local.get 1))
+				)
+				(i32.mul (call $early (i32.const 0)) (call $early (i32.const 1)))
+				(i32.sub (local.get 0))
+				(i32.add (i32.const 42))
+			)
+		)"#);
+	let mut ir = RawPvf::from_bytes(&code).translate().unwrap();
+	ir.optimize();
+	let calls = std::rc::Rc::new(std::cell::Cell::new(0));
+	let mut passes = crate::PassManager::new();
+	passes.add_pass(CountOps(calls.clone(), |cp| matches!(cp, IrCp::Call(_))));
+	ir.run_passes(&mut passes);
+	assert_eq!(calls.get(), 0);
+
+	let pvf = ir.compile(&IntelX64Compiler::new());
+	let instance = PvfInstance::instantiate(&pvf).unwrap();
+	assert_eq!(unsafe { instance.call::<_, _, i32>("test", ()) }.unwrap(), 42);
+}
+
+#[test]
+fn calling_convention() {
+	// Stack arguments passed by the host through the export trampoline
+	assert_eq!(
+		test::<_, i32>(wat(r#"
+			(module
+				(func (export "test") (param i32 i32 i32 i32 i32 i32 i32 i32) (result i32)
+					(i32.sub (i32.add (local.get 0) (i32.mul (local.get 6) (local.get 7))) (i32.add (local.get 1) (local.get 5)))
+				)
+			)"#),
+			(20, 1, 0, 0, 0, 4, 3, 9)
+		),
+		42
+	);
+	// Exported function called from Wasm, directly and through a table
+	assert_eq!(
+		test::<_, i32>(wat(r#"
+			(module
+				(type $sub_t (func (param i32 i32) (result i32)))
+				(func $sub (export "sub") (param i32 i32) (result i32) (local i32)
+					(local.set 2 (i32.sub (local.get 0) (local.get 1)))
+					(block (br_if 0 (i32.eqz (local.get 2))) (return (local.get 2)))
+					(unreachable)
+				)
+				(func (export "test") (result i32)
+					(call $sub (i32.const 50) (i32.const 6))
+					(call_indirect (type $sub_t) (i32.const 5) (i32.const 3) (i32.const 0))
+					i32.sub
+				)
+				(table 1 1 funcref)
+				(elem (i32.const 0) $sub)
+			)"#),
+			()
+		),
+		42
+	);
+}
+
+#[test]
+fn peephole() {
+	use crate::{codegen::CodeEmitter, intel_x64_asm::{*, Width::*}};
+
+	// Short branches and fused compare-and-branch
+	let code = wat(r#"
+		(module
+			(func (export "test") (result i32) (local i32 i32)
+				(loop
+					(local.set 1 (i32.add (local.get 1) (local.get 0)))
+					(local.set 0 (i32.add (local.get 0) (i32.const 1)))
+					(br_if 0 (i32.lt_s (local.get 0) (i32.const 100)))
+				)
+				(i32.sub (local.get 1) (i32.const 4908))
+			)
+		)"#);
+	let mut ir = RawPvf::from_bytes(&code).translate().unwrap();
+	ir.optimize();
+	let pvf = ir.compile(&IntelX64Compiler::new());
+	assert!(!pvf.code().windows(2).any(|w| w[0] == 0x0f && w[1] & 0xf0 == 0x90), "setcc not fused into the jump");
+	assert!(!pvf.code().windows(2).any(|w| w[0] == 0x0f && w[1] & 0xf0 == 0x80), "Long jump in a short function");
+	assert_eq!(test::<_, i32>(code, ()), 42);
+
+	// The compare result is only fused into the jump if it's dead on both paths
+	let fused = |fallthrough: fn(&mut Assembler)| {
+		let mut code = CodeEmitter::new();
+		let mut asm = Assembler::new(&mut code);
+		asm.setcc(Cond::E, Gpr::RAX);
+		asm.movzx(Gpr::RAX, Gpr::RAX, W8);
+		asm.and(W32, Gpr::RAX, Gpr::RAX);
+		asm.jcc(Cond::Ne, IrLabel::LocalLabel(0));
+		fallthrough(&mut asm);
+		asm.label(IrLabel::LocalLabel(0));
+		asm.mov(W32, Gpr::RAX, Imm(1));
+		crate::intel_x64_peephole::optimize(&mut code, &mut []);
+		!code.code.windows(2).any(|w| w[0] == 0x0f && w[1] & 0xf0 == 0x90)
+	};
+	assert!(fused(|asm| asm.mov(W32, Gpr::RAX, Imm(0))));
+	assert!(!fused(|asm| asm.push(Gpr::RAX)));
+
+	// Branches over long code are relaxed to rel32
+	let body = "(global.set $g (i32.add (global.get $g) (i32.const 1)))".repeat(32);
+	assert_eq!(
+		test::<_, i32>(wat(&format!(r#"
+			(module
+				(global $g (mut i32) (i32.const 0))
+				(func (export "test") (result i32) (local i32)
+					(loop
+						{body}
+						(local.set 0 (i32.add (local.get 0) (i32.const 1)))
+						(br_if 0 (i32.ne (local.get 0) (i32.const 2)))
+					)
+					(i32.sub (global.get $g) (i32.const 22))
+				)
+			)"#)),
+			()
+		),
+		42
+	);
+}
+
+#[test]
+fn fused_compare() {
+	let code = wat(r#"
+		(module
+			(func $max (param i64 i64) (result i64)
+				(select (local.get 0) (local.get 1) (i64.gt_u (local.get 0) (local.get 1)))
+			)
+			(func (export "test") (result i32) (local i32 i32)
+				(loop
+					(local.set 1 (i32.add (local.get 1) (local.get 0)))
+					(local.set 0 (i32.add (local.get 0) (i32.const 1)))
+					(br_if 0 (i32.lt_s (local.get 0) (i32.const 10)))
+				)
+				(block
+					(br_if 0 (i32.eqz (local.get 1)))
+					(local.set 1 (i32.sub (local.get 1) (i32.wrap_i64 (call $max (i64.const 3) (i64.const -1)))))
+				)
+				(i32.sub (local.get 1) (i32.const 4))
+			)
+		)"#);
+	let mut ir = RawPvf::from_bytes(&code).translate().unwrap();
+	let set_ifs = std::rc::Rc::new(std::cell::Cell::new(0));
+	let mut passes = crate::PassManager::new();
+	passes.add_pass(CountOps(set_ifs.clone(), |cp| matches!(cp, IrCp::SetIf(_, _))));
+	ir.run_passes(&mut passes);
+	assert_eq!(set_ifs.get(), 0);
+	ir.optimize();
+	let pvf = ir.compile(&IntelX64Compiler::new());
+	let instance = PvfInstance::instantiate(&pvf).unwrap();
+	// -1 is the maximum as unsigned, so it is 45 - (-1) - 4
+	assert_eq!(unsafe { instance.call::<_, _, i32>("test", ()) }.unwrap(), 42);
+}
+
+#[test]
+fn aarch64_encoding() {
+	use crate::aarch64::*;
+	// Reference encodings produced by `llvm-mc -triple=aarch64 -show-encoding`
+	let cases = [
+		(ret(), 0xd65f03c0), // ret
+		(nop(), 0xd503201f), // nop
+		(mov(true, 0, 1), 0xaa0103e0), // mov x0, x1
+		(mov(false, 0, 1), 0x2a0103e0), // mov w0, w1
+		(add(true, 0, 0, 1), 0x8b010000), // add x0, x0, x1
+		(add(false, 2, 2, 1), 0x0b010042), // add w2, w2, w1
+		(sub(true, 0, 0, 1), 0xcb010000), // sub x0, x0, x1
+		(add_uxtw(16, 28, 0), 0x8b204390), // add x16, x28, w0, uxtw
+		(add_lsl(9, 9, 0, 3), 0x8b000d29), // add x9, x9, x0, lsl #3
+		(add_imm(true, 27, 31, 0), 0x910003fb), // add x27, sp, #0
+		(add_imm(true, 19, 27, 40), 0x9100a373), // add x19, x27, #40
+		(sub_imm(true, 27, 19, 40), 0xd100a27b), // sub x27, x19, #40
+		(sub_imm_lsl12(31, 31, 64), 0xd14103ff), // sub sp, sp, #64, lsl #12
+		(add_imm_lsl12(31, 31, 64), 0x914103ff), // add sp, sp, #64, lsl #12
+		(add_sp(31, 31, 16), 0x8b3063ff), // add sp, sp, x16
+		(cmp(false, 0, 1), 0x6b01001f), // cmp w0, w1
+		(cmp(true, 0, 1), 0xeb01001f), // cmp x0, x1
+		(cmp_imm(true, 0, 1), 0xf100041f), // cmp x0, #1
+		(cmn_imm(false, 1, 1), 0x3100043f), // cmn w1, #1
+		(ands(false, 0, 0, 1), 0x6a010000), // ands w0, w0, w1
+		(orr(true, 0, 0, 1), 0xaa010000), // orr x0, x0, x1
+		(eor(false, 0, 0, 1), 0x4a010000), // eor w0, w0, w1
+		(neg(true, 16, 1), 0xcb0103f0), // neg x16, x1
+		(mul(false, 0, 0, 1), 0x1b017c00), // mul w0, w0, w1
+		(msub(true, 0, 16, 1, 0), 0x9b018200), // msub x0, x16, x1, x0
+		(udiv(true, 0, 0, 1), 0x9ac10800), // udiv x0, x0, x1
+		(sdiv(false, 0, 0, 1), 0x1ac10c00), // sdiv w0, w0, w1
+		(lslv(false, 0, 0, 1), 0x1ac12000), // lsl w0, w0, w1
+		(lsrv(true, 0, 0, 1), 0x9ac12400), // lsr x0, x0, x1
+		(asrv(false, 0, 0, 1), 0x1ac12800), // asr w0, w0, w1
+		(rorv(true, 0, 0, 16), 0x9ad02c00), // ror x0, x0, x16
+		(clz(false, 0, 0), 0x5ac01000), // clz w0, w0
+		(rbit(true, 0, 0), 0xdac00000), // rbit x0, x0
+		(csel(true, 0, 2, 0, 0xb), 0x9a80b040), // csel x0, x2, x0, lt
+		(cset(0, 0x0), 0x1a9f17e0), // cset w0, eq
+		(cset(1, 0x8), 0x1a9f97e1), // cset w1, hi
+		(sxtb(0, 0), 0x93401c00), // sxtb x0, w0
+		(sxth(1, 1), 0x93403c21), // sxth x1, w1
+		(sxtw(0, 0), 0x93407c00), // sxtw x0, w0
+		(uxtb(0, 0), 0x53001c00), // uxtb w0, w0
+		(uxth(2, 2), 0x53003c42), // uxth w2, w2
+		(movz(false, 0, 42, 0), 0x52800540), // movz w0, #42
+		(movz(true, 0, 0xbeef, 1), 0xd2b7dde0), // movz x0, #0xbeef, lsl #16
+		(movk(true, 0, 0x1234, 3), 0xf2e24680), // movk x0, #0x1234, lsl #48
+		(movn(false, 0, 0, 0), 0x12800000), // movn w0, #0
+		(ldr_imm(SIZE64, 0, 16, 0), 0xf9400200), // ldr x0, [x16]
+		(ldr_reg(SIZE8, 0, 16, 17), 0x38716a00), // ldrb w0, [x16, x17]
+		(str_reg(SIZE16, 1, 16, 17), 0x78316a01), // strh w1, [x16, x17]
+		(str_imm(SIZE32, 0, 16, 0), 0xb9000200), // str w0, [x16]
+		(ldr_reg(SIZE64, 0, 28, 17), 0xf8716b80), // ldr x0, [x28, x17]
+		(ldr_uxtw3(16, 17, 0), 0xf8605a30), // ldr x16, [x17, w0, uxtw #3]
+		(ldur(0, 19, -8), 0xf85f8260), // ldur x0, [x19, #-8]
+		(stur(1, 19, -40), 0xf81d8261), // stur x1, [x19, #-40]
+		(str_pre(SIZE64, 0, 27, -8), 0xf81f8f60), // str x0, [x27, #-8]!
+		(ldr_post(SIZE64, 0, 27, 8), 0xf8408760), // ldr x0, [x27], #8
+		(str_post(SIZE8, 12, 9, 1), 0x3800152c), // strb w12, [x9], #1
+		(ldr_post(SIZE8, 12, 10, 1), 0x3840154c), // ldrb w12, [x10], #1
+		(str_post(SIZE64, 16, 9, 8), 0xf8008530), // str x16, [x9], #8
+		(stp_pre(29, 30, 31, -48), 0xa9bd7bfd), // stp x29, x30, [sp, #-48]!
+		(stp(27, 28, 31, 16), 0xa90173fb), // stp x27, x28, [sp, #16]
+		(ldp(27, 28, 31, 16), 0xa94173fb), // ldp x27, x28, [sp, #16]
+		(ldp_post(29, 30, 31, 48), 0xa8c37bfd), // ldp x29, x30, [sp], #48
+		(ldr_imm(SIZE64, 19, 31, 32), 0xf94013f3), // ldr x19, [sp, #32]
+		(str_imm(SIZE64, 19, 31, 32), 0xf90013f3), // str x19, [sp, #32]
+		(ldr_literal(16, 8), 0x58000050), // ldr x16, #8
+		(b(12), 0x14000003), // b #12
+		(b(-16), 0x17fffffc), // b #-16
+		(bl(0x100), 0x94000040), // bl #0x100
+		(b_cond(0x1, 16), 0x54000081), // b.ne #16
+		(b_cond(0x7, 8), 0x54000047), // b.vc #8
+		(cbz(true, 11, 20), 0xb40000ab), // cbz x11, #20
+		(cbnz(false, 1, 8), 0x35000041), // cbnz w1, #8
+		(br(16), 0xd61f0200), // br x16
+		(blr(16), 0xd63f0200), // blr x16
+		(adr(16, 12), 0x10000070), // adr x16, #12
+		(udf(), 0x00000000), // udf #0
+		(fmov_to_simd(true, 16, 0), 0x9e670010), // fmov d16, x0
+		(fmov_to_simd(false, 16, 0), 0x1e270010), // fmov s16, w0
+		(fmov_from_simd(0, 16), 0x1e260200), // fmov w0, s16
+		(cnt8b(16, 16), 0x0e205a10), // cnt v16.8b, v16.8b
+		(addv8b(16, 16), 0x0e31ba10), // addv b16, v16.8b
+	];
+	for (encoded, expected) in cases {
+		assert_eq!(encoded, expected, "{:08x} != {:08x}", encoded, expected);
+	}
+}
+
+#[test]
+fn intel_x64_encoding() {
+	use crate::{codegen::CodeEmitter, intel_x64_asm::{*, Width::*}};
+	const RAX: Gpr = Gpr::RAX; const RCX: Gpr = Gpr::RCX; const RDX: Gpr = Gpr::RDX; const RBX: Gpr = Gpr::RBX;
+	const RSP: Gpr = Gpr::RSP; const RBP: Gpr = Gpr::RBP; const RSI: Gpr = Gpr::RSI; const RDI: Gpr = Gpr::RDI;
+	const R8: Gpr = Gpr::R8; const R10: Gpr = Gpr::R10; const R11: Gpr = Gpr::R11; const R12: Gpr = Gpr::R12;
+	const R15: Gpr = Gpr::R15;
+
+	fn enc(f: impl FnOnce(&mut Assembler)) -> Vec<u8> {
+		let mut code = CodeEmitter::new();
+		f(&mut Assembler::new(&mut code));
+		code.code
+	}
+
+	// Reference encodings produced by `llvm-mc -triple=x86_64 -show-encoding`
+	let cases = [
+		(enc(|a| a.mov(W64, RAX, RCX)), vec![0x48, 0x89, 0xc8]), // mov rax, rcx
+		(enc(|a| a.mov(W32, R8, R15)), vec![0x45, 0x89, 0xf8]), // mov r8d, r15d
+		(enc(|a| a.mov(W64, Mem::base(RBX, -8), RAX)), vec![0x48, 0x89, 0x43, 0xf8]), // mov qword ptr [rbx - 8], rax
+		(enc(|a| a.mov(W64, RDX, Mem::base(RBX, -200))), vec![0x48, 0x8b, 0x93, 0x38, 0xff, 0xff, 0xff]), // mov rdx, qword ptr [rbx - 200]
+		(enc(|a| a.mov(W8, Mem::indexed(R15, RCX, 1, 0), RSI)), vec![0x41, 0x88, 0x34, 0x0f]), // mov byte ptr [r15 + rcx], sil
+		(enc(|a| a.mov(W8, RDX, Mem::indexed(R15, RAX, 1, 16))), vec![0x41, 0x8a, 0x54, 0x07, 0x10]), // mov dl, byte ptr [r15 + rax + 16]
+		(enc(|a| a.mov(W16, Mem::indexed(R15, RAX, 1, 0), RCX)), vec![0x66, 0x41, 0x89, 0x0c, 0x07]), // mov word ptr [r15 + rax], cx
+		(enc(|a| a.mov(W32, RAX, Mem::indexed(R15, RCX, 1, -0x10000))), vec![0x41, 0x8b, 0x84, 0x0f, 0x00, 0x00, 0xff, 0xff]), // mov eax, dword ptr [r15 + rcx*1 - 0x10000]
+		(enc(|a| a.mov(W64, RAX, Mem::indexed(R15, RDX, 8, 0x20000))), vec![0x49, 0x8b, 0x84, 0xd7, 0x00, 0x00, 0x02, 0x00]), // mov rax, qword ptr [r15 + rdx*8 + 0x20000]
+		(enc(|a| a.mov(W64, R10, Mem::base(R11, 0))), vec![0x4d, 0x8b, 0x13]), // mov r10, qword ptr [r11]
+		(enc(|a| a.mov(W64, Mem::base(RBP, 0), R10)), vec![0x4c, 0x89, 0x55, 0x00]), // mov qword ptr [rbp], r10
+		(enc(|a| a.mov(W64, RAX, Mem::base(R12, 8))), vec![0x49, 0x8b, 0x44, 0x24, 0x08]), // mov rax, qword ptr [r12 + 8]
+		(enc(|a| a.mov(W64, RAX, Mem::base(RSP, 0))), vec![0x48, 0x8b, 0x04, 0x24]), // mov rax, qword ptr [rsp]
+		(enc(|a| a.mov(W32, RAX, Imm(42))), vec![0xb8, 0x2a, 0x00, 0x00, 0x00]), // mov eax, 42
+		(enc(|a| a.mov(W32, RCX, Imm(0xffffffff))), vec![0xb9, 0xff, 0xff, 0xff, 0xff]), // mov ecx, 0xffffffff
+		(enc(|a| a.mov(W64, RAX, Imm(-1))), vec![0x48, 0xc7, 0xc0, 0xff, 0xff, 0xff, 0xff]), // mov rax, -1
+		(enc(|a| a.mov(W64, RDX, Imm(0x123456789a))), vec![0x48, 0xba, 0x9a, 0x78, 0x56, 0x34, 0x12, 0x00, 0x00, 0x00]), // movabs rdx, 0x123456789a
+		(enc(|a| a.mov(W16, RCX, Imm(0x1234))), vec![0x66, 0xb9, 0x34, 0x12]), // mov cx, 0x1234
+		(enc(|a| a.mov(W8, RBX, Imm(7))), vec![0xb3, 0x07]), // mov bl, 7
+		(enc(|a| a.mov(W64, Mem::base(RSP, 16), Imm(1))), vec![0x48, 0xc7, 0x44, 0x24, 0x10, 0x01, 0x00, 0x00, 0x00]), // mov qword ptr [rsp + 16], 1
+		(enc(|a| a.push(RBX)), vec![0x53]), // push rbx
+		(enc(|a| a.push(R12)), vec![0x41, 0x54]), // push r12
+		(enc(|a| a.push(Mem::base(RSP, 24))), vec![0xff, 0x74, 0x24, 0x18]), // push qword ptr [rsp + 24]
+		(enc(|a| a.push(Imm(127))), vec![0x6a, 0x7f]), // push 127
+		(enc(|a| a.pop(RBP)), vec![0x5d]), // pop rbp
+		(enc(|a| a.pop(R15)), vec![0x41, 0x5f]), // pop r15
+		(enc(|a| a.lea(W64, RBX, Mem::base(RSP, 40))), vec![0x48, 0x8d, 0x5c, 0x24, 0x28]), // lea rbx, [rsp + 40]
+		(enc(|a| a.lea(W64, RSP, Mem::base(RBX, -40))), vec![0x48, 0x8d, 0x63, 0xd8]), // lea rsp, [rbx - 40]
+		(enc(|a| a.lea(W64, RDI, Mem::rip(8))), vec![0x48, 0x8d, 0x3d, 0x08, 0x00, 0x00, 0x00]), // lea rdi, [rip + 8]
+		(enc(|a| a.lea(W64, RDI, Mem::indexed(R15, RAX, 8, -0x20000))), vec![0x49, 0x8d, 0xbc, 0xc7, 0x00, 0x00, 0xfe, 0xff]), // lea rdi, [r15 + rax*8 - 0x20000]
+		(enc(|a| a.movzx(RAX, RAX, W8)), vec![0x0f, 0xb6, 0xc0]), // movzx eax, al
+		(enc(|a| a.movzx(RCX, RSI, W8)), vec![0x40, 0x0f, 0xb6, 0xce]), // movzx ecx, sil
+		(enc(|a| a.movzx(RDX, Mem::indexed(R15, RCX, 1, 0), W16)), vec![0x41, 0x0f, 0xb7, 0x14, 0x0f]), // movzx edx, word ptr [r15 + rcx]
+		(enc(|a| a.movsx(W64, RAX, RAX, W8)), vec![0x48, 0x0f, 0xbe, 0xc0]), // movsx rax, al
+		(enc(|a| a.movsx(W64, RCX, RCX, W16)), vec![0x48, 0x0f, 0xbf, 0xc9]), // movsx rcx, cx
+		(enc(|a| a.movsx(W64, RDX, RDX, W32)), vec![0x48, 0x63, 0xd2]), // movsxd rdx, edx
+		(enc(|a| a.cmov(W32, Cond::L, RAX, RCX)), vec![0x0f, 0x4c, 0xc1]), // cmovl eax, ecx
+		(enc(|a| a.cmov(W64, Cond::Ae, RSP, RBP)), vec![0x48, 0x0f, 0x43, 0xe5]), // cmovae rsp, rbp
+		(enc(|a| a.setcc(Cond::E, RAX)), vec![0x0f, 0x94, 0xc0]), // sete al
+		(enc(|a| a.setcc(Cond::G, RDI)), vec![0x40, 0x0f, 0x9f, 0xc7]), // setg dil
+		(enc(|a| a.add(W64, RAX, RCX)), vec![0x48, 0x01, 0xc8]), // add rax, rcx
+		(enc(|a| a.add(W64, R11, Imm(8))), vec![0x49, 0x83, 0xc3, 0x08]), // add r11, 8
+		(enc(|a| a.add(W64, RSP, Imm(128))), vec![0x48, 0x81, 0xc4, 0x80, 0x00, 0x00, 0x00]), // add rsp, 128
+		(enc(|a| a.sub(W64, R11, Imm(8))), vec![0x49, 0x83, 0xeb, 0x08]), // sub r11, 8
+		(enc(|a| a.and(W64, RSP, Imm(-16))), vec![0x48, 0x83, 0xe4, 0xf0]), // and rsp, -16
+		(enc(|a| a.or(W32, RCX, RDX)), vec![0x09, 0xd1]), // or ecx, edx
+		(enc(|a| a.xor(W32, RAX, RAX)), vec![0x31, 0xc0]), // xor eax, eax
+		(enc(|a| a.cmp(W64, R11, RBP)), vec![0x49, 0x39, 0xeb]), // cmp r11, rbp
+		(enc(|a| a.cmp(W64, RSI, Mem::base(R15, 264))), vec![0x49, 0x3b, 0xb7, 0x08, 0x01, 0x00, 0x00]), // cmp rsi, qword ptr [r15 + 264]
+		(enc(|a| a.cmp(W32, Mem::base(RBX, -8), Imm(0x12345))), vec![0x81, 0x7b, 0xf8, 0x45, 0x23, 0x01, 0x00]), // cmp dword ptr [rbx - 8], 0x12345
+		(enc(|a| a.xchg(W64, RAX, RCX)), vec![0x48, 0x91]), // xchg rax, rcx
+		(enc(|a| a.xchg(W32, RDX, RCX)), vec![0x87, 0xd1]), // xchg edx, ecx
+		(enc(|a| a.imul(W32, RCX)), vec![0xf7, 0xe9]), // imul ecx
+		(enc(|a| a.div(W64, RCX)), vec![0x48, 0xf7, 0xf1]), // div rcx
+		(enc(|a| a.idiv(W32, RCX)), vec![0xf7, 0xf9]), // idiv ecx
+		(enc(|a| a.sign_extend_acc(W32)), vec![0x99]), // cdq
+		(enc(|a| a.sign_extend_acc(W64)), vec![0x48, 0x99]), // cqo
+		(enc(|a| a.shl(W32, RAX, RCX)), vec![0xd3, 0xe0]), // shl eax, cl
+		(enc(|a| a.shr(W64, RDX, RCX)), vec![0x48, 0xd3, 0xea]), // shr rdx, cl
+		(enc(|a| a.sar(W32, RAX, Imm(3))), vec![0xc1, 0xf8, 0x03]), // sar eax, 3
+		(enc(|a| a.rol(W64, RAX, RCX)), vec![0x48, 0xd3, 0xc0]), // rol rax, cl
+		(enc(|a| a.ror(W32, RCX, RCX)), vec![0xd3, 0xc9]), // ror ecx, cl
+		(enc(|a| a.lzcnt(W32, RAX, RAX)), vec![0xf3, 0x0f, 0xbd, 0xc0]), // lzcnt eax, eax
+		(enc(|a| a.tzcnt(W64, RCX, RCX)), vec![0xf3, 0x48, 0x0f, 0xbc, 0xc9]), // tzcnt rcx, rcx
+		(enc(|a| a.popcnt(W32, RDX, RDX)), vec![0xf3, 0x0f, 0xb8, 0xd2]), // popcnt edx, edx
+		(enc(|a| a.call_indirect(RAX)), vec![0xff, 0xd0]), // call rax
+		(enc(|a| a.jmp_indirect(Mem::base(RDI, 0))), vec![0xff, 0x27]), // jmp qword ptr [rdi]
+		(enc(|a| a.ret(0)), vec![0xc3]), // ret
+		(enc(|a| a.ret(16)), vec![0xc2, 0x10, 0x00]), // ret 16
+		(enc(|a| a.ud2()), vec![0x0f, 0x0b]), // ud2
+		(enc(|a| a.cld()), vec![0xfc]), // cld
+		(enc(|a| a.stosq()), vec![0x48, 0xab]), // stosq
+		(enc(|a| a.rep_movsb()), vec![0xf3, 0xa4]), // rep movsb
+	];
+	for (n, (got, expected)) in cases.iter().enumerate() {
+		assert_eq!(got, expected, "case {}", n);
+	}
+}
+
+const CROSS_CHECK: &str = r#"
+	(module
+		(import "env" "add2" (func $add2 (param i32) (result i32)))
+		(type $unop (func (param i32) (result i32)))
+		(memory 1 3)
+		(data (i32.const 16) "\fe\ff\2a\00")
+		(global $g (mut i64) (i64.const 5))
+		(table 2 funcref)
+		(elem (i32.const 0) $double $triple)
+		(func $double (param i32) (result i32) (i32.shl (local.get 0) (i32.const 1)))
+		(func $triple (param i32) (result i32) (i32.mul (local.get 0) (i32.const 3)))
+		(func $mix (param i64 i64) (result i64)
+			(i64.xor (i64.rotl (local.get 0) (i64.const 13)) (i64.mul (local.get 1) (i64.const 0x9e3779b97f4a7c15)))
+		)
+		(func (export "test") (param i32 i64) (result i64) (local $acc i64) (local $i i32)
+			(local.set $acc (i64.extend_i32_s (i32.div_s (local.get 0) (i32.const 7))))
+			(local.set $acc (call $mix (local.get $acc) (i64.extend_i32_u (i32.rem_u (local.get 0) (i32.const 13)))))
+			(local.set $acc (call $mix (local.get $acc) (i64.div_u (local.get 1) (i64.const 3))))
+			(local.set $acc (call $mix (local.get $acc) (i64.rem_s (local.get 1) (i64.const -5))))
+			(local.set $acc (call $mix (local.get $acc) (i64.extend_i32_u (i32.rotr (local.get 0) (i32.const 5)))))
+			(local.set $acc (call $mix (local.get $acc) (i64.extend_i32_u (i32.clz (local.get 0)))))
+			(local.set $acc (call $mix (local.get $acc) (i64.ctz (local.get 1))))
+			(local.set $acc (call $mix (local.get $acc) (i64.popcnt (local.get 1))))
+			(local.set $acc (call $mix (local.get $acc) (i64.shr_s (local.get 1) (i64.extend_i32_u (local.get 0)))))
+			(local.set $acc (call $mix (local.get $acc) (select (i64.const 1) (i64.const 2) (i64.lt_s (local.get 1) (i64.const 0)))))
+			(local.set $acc (call $mix (local.get $acc) (i64.extend_i32_u (i32.ge_u (local.get 0) (i32.const 100)))))
+
+			(i32.store16 (i32.const 32) (local.get 0))
+			(local.set $acc (call $mix (local.get $acc) (i64.load16_s (i32.const 32))))
+			(local.set $acc (call $mix (local.get $acc) (i64.load8_u offset=1 (i32.const 16))))
+			(local.set $acc (call $mix (local.get $acc) (i64.extend_i32_s (i32.load8_s (i32.const 16)))))
+			(i64.store offset=8 (i32.const 40) (local.get $acc))
+			(local.set $acc (call $mix (local.get $acc) (i64.load32_u (i32.const 52))))
+			(local.set $acc (call $mix (local.get $acc) (i64.extend_i32_u (memory.grow (i32.const 1)))))
+			(local.set $acc (call $mix (local.get $acc) (i64.extend_i32_u (memory.size))))
+
+			(global.set $g (i64.add (global.get $g) (local.get $acc)))
+			(local.set $acc (call $mix (local.get $acc) (global.get $g)))
+			(local.set $acc (call $mix (local.get $acc) (i64.extend_i32_u (call_indirect (type $unop) (local.get 0) (i32.and (local.get 0) (i32.const 1))))))
+			(local.set $acc (call $mix (local.get $acc) (i64.extend_i32_u (call $add2 (local.get 0)))))
+
+			(loop $l
+				(block $b2
+					(block $b1
+						(block $b0
+							(br_table $b0 $b1 $b2 (i32.rem_u (local.get $i) (i32.const 3)))
+						)
+						(local.set $acc (i64.add (local.get $acc) (i64.const 1)))
+						(br $b2)
+					)
+					(local.set $acc (i64.sub (local.get $acc) (i64.const 7)))
+				)
+				(local.set $i (i32.add (local.get $i) (i32.const 1)))
+				(br_if $l (i32.lt_u (local.get $i) (i32.const 10)))
+			)
+			(local.get $acc)
+		)
+	)"#;
+
+#[test]
+fn interpreter() {
+	let code = wat(CROSS_CHECK);
+	let pvf = translate_with_imports(&code).compile(&IntelX64Compiler::new());
+	let instance = PvfInstance::instantiate(&pvf).unwrap();
+	let mut interpreter = IrInterpreter::instantiate(&translate_with_imports(&code)).unwrap();
+
+	// The instances keep their state between the calls, so the memory and the globals are
+	// compared too
+	for (a, b) in [(0, 0), (-1, -1), (123, 0x123456789), (i32::MIN, i64::MIN), (77, -5)] {
+		let native = unsafe { instance.call::<_, _, i64>("test", (a, b)) }.unwrap();
+		let interpreted = interpreter.call("test", &[a as u32 as u64, b as u64]).unwrap();
+		assert_eq!(interpreted, Some(native as u64), "test({}, {})", a, b);
+	}
+
+	let code = wat(r#"
+		(module
+			(func (export "div") (param i32 i32) (result i32) (i32.div_s (local.get 0) (local.get 1)))
+			(func (export "trap") (unreachable))
+		)"#);
+	let mut interpreter = IrInterpreter::instantiate(&RawPvf::from_bytes(&code).translate().unwrap()).unwrap();
+	assert_eq!(interpreter.call("div", &[-42i32 as u32 as u64, 2]).unwrap(), Some(-21i32 as u32 as u64));
+	assert!(matches!(interpreter.call("div", &[1, 0]), Err(PvfError::Trap(_))));
+	assert!(matches!(interpreter.call("div", &[i32::MIN as u32 as u64, -1i32 as u32 as u64]), Err(PvfError::Trap(_))));
+	assert!(matches!(interpreter.call("trap", &[]), Err(PvfError::Trap(_))));
+	assert!(matches!(interpreter.call("missing", &[]), Err(PvfError::ExportNotFound)));
+}
+
+#[test]
+fn aarch64_codegen() {
+	let code = wat(CROSS_CHECK);
+	let pvf = translate_with_imports(&code).compile(&Aarch64Compiler::new());
+	let words = pvf.code().chunks(4).map(|w| u32::from_le_bytes(w.try_into().unwrap())).collect::<Vec<_>>();
+	assert_eq!(pvf.code_len() % 4, 0);
+
+	// Every exported function starts with the entry trampoline saving the frame registers
+	for offset in pvf.exported_funcs().values() {
+		assert_eq!(words[offset / 4], crate::aarch64::stp_pre(29, 30, 31, -48));
+	}
+	// All the calls and the branches are resolved
+	assert!(!words.contains(&crate::aarch64::bl(0)));
+	assert!(!words.contains(&crate::aarch64::b(0)));
+	assert!(!words.iter().any(|w| w & 0xff00_0010 == 0x5400_0000 && w & 0x00ff_ffe0 == 0));
+	// Every function checks the value stack room before building its frame
+	let checks = words.windows(3).filter(|w| w == &[crate::aarch64::cmp(true, 16, 17), crate::aarch64::b_cond(0x2, 8), crate::aarch64::udf()]).count();
+	assert_eq!(checks, pvf.labels.keys().filter(|label| matches!(label, IrLabel::AnonymousFunc(_))).count());
+
+	// The code only runs natively on AArch64 hosts, where it must agree with the interpreter
+	#[cfg(target_arch = "aarch64")]
+	{
+		let instance = PvfInstance::instantiate(&pvf).unwrap();
+		let mut interpreter = IrInterpreter::instantiate(&translate_with_imports(&code)).unwrap();
+		for (a, b) in [(0i32, 0i64), (-1, -1), (123, 0x123456789)] {
+			let native = unsafe { instance.call::<_, _, i64>("test", (a, b)) }.unwrap();
+			assert_eq!(interpreter.call("test", &[a as u32 as u64, b as u64]).unwrap(), Some(native as u64));
+		}
+	}
+
+	// Frame offsets out of reach of an imm12 go through a scratch register
+	let code = wat(&format!("(module (func (param {}) (result i32) (local.get 599)))", "i32 ".repeat(600)));
+	let pvf = RawPvf::from_bytes(&code).translate().unwrap().compile(&Aarch64Compiler::new());
+	let words = pvf.code().chunks(4).map(|w| u32::from_le_bytes(w.try_into().unwrap())).collect::<Vec<_>>();
+	assert!(words.contains(&crate::aarch64::add_sp(19, 27, 16)));
+
+	// The code is only loaded on the architecture it's compiled for
+	assert_eq!(pvf.target_arch(), "aarch64");
+	#[cfg(not(target_arch = "aarch64"))]
+	assert!(matches!(LoadedPvf::load(&pvf), Err(PvfError::TargetMismatch("aarch64"))));
+}
+
+#[test]
+fn intel_x64_decoding() {
+	// Reference texts produced by `llvm-mc --disassemble -output-asm-variant=1`, the branches are
+	// decoded at offset 0x100
+	let cases = [
+		(&[0x48, 0x89, 0xc8][..], "mov rax, rcx"),
+		(&[0x45, 0x89, 0xf8][..], "mov r8d, r15d"),
+		(&[0x48, 0x89, 0x43, 0xf8][..], "mov qword ptr [rbx - 8], rax"),
+		(&[0x48, 0x8b, 0x93, 0x38, 0xff, 0xff, 0xff][..], "mov rdx, qword ptr [rbx - 200]"),
+		(&[0x41, 0x88, 0x34, 0x0f][..], "mov byte ptr [r15 + rcx], sil"),
+		(&[0x41, 0x8a, 0x54, 0x07, 0x10][..], "mov dl, byte ptr [r15 + rax + 16]"),
+		(&[0x66, 0x41, 0x89, 0x0c, 0x07][..], "mov word ptr [r15 + rax], cx"),
+		(&[0x41, 0x8b, 0x84, 0x0f, 0x00, 0x00, 0xff, 0xff][..], "mov eax, dword ptr [r15 + rcx - 65536]"),
+		(&[0x49, 0x8b, 0x84, 0xd7, 0x00, 0x00, 0x02, 0x00][..], "mov rax, qword ptr [r15 + 8*rdx + 131072]"),
+		(&[0x4d, 0x8b, 0x13][..], "mov r10, qword ptr [r11]"),
+		(&[0x4c, 0x89, 0x55, 0x00][..], "mov qword ptr [rbp], r10"),
+		(&[0x49, 0x8b, 0x44, 0x24, 0x08][..], "mov rax, qword ptr [r12 + 8]"),
+		(&[0x49, 0x8b, 0x45, 0x00][..], "mov rax, qword ptr [r13]"),
+		(&[0xb8, 0x2a, 0x00, 0x00, 0x00][..], "mov eax, 42"),
+		(&[0xb9, 0xff, 0xff, 0xff, 0xff][..], "mov ecx, 4294967295"),
+		(&[0x48, 0xc7, 0xc0, 0xff, 0xff, 0xff, 0xff][..], "mov rax, -1"),
+		(&[0x48, 0xba, 0x9a, 0x78, 0x56, 0x34, 0x12, 0x00, 0x00, 0x00][..], "movabs rdx, 78187493530"),
+		(&[0x66, 0xb9, 0x34, 0x12][..], "mov cx, 4660"),
+		(&[0xb3, 0x07][..], "mov bl, 7"),
+		(&[0x48, 0xc7, 0x44, 0x24, 0x10, 0x01, 0x00, 0x00, 0x00][..], "mov qword ptr [rsp + 16], 1"),
+		(&[0x53][..], "push rbx"),
+		(&[0x41, 0x54][..], "push r12"),
+		(&[0xff, 0x74, 0x24, 0x18][..], "push qword ptr [rsp + 24]"),
+		(&[0x6a, 0x7f][..], "push 127"),
+		(&[0x5d][..], "pop rbp"),
+		(&[0x41, 0x5f][..], "pop r15"),
+		(&[0x48, 0x8d, 0x5c, 0x24, 0x28][..], "lea rbx, [rsp + 40]"),
+		(&[0x48, 0x8d, 0x63, 0xd8][..], "lea rsp, [rbx - 40]"),
+		(&[0x48, 0x8d, 0x3d, 0x08, 0x00, 0x00, 0x00][..], "lea rdi, [rip + 8]"),
+		(&[0x49, 0x8d, 0xbc, 0xc7, 0x00, 0x00, 0xfe, 0xff][..], "lea rdi, [r15 + 8*rax - 131072]"),
+		(&[0x0f, 0xb6, 0xc0][..], "movzx eax, al"),
+		(&[0x40, 0x0f, 0xb6, 0xce][..], "movzx ecx, sil"),
+		(&[0x41, 0x0f, 0xb7, 0x14, 0x0f][..], "movzx edx, word ptr [r15 + rcx]"),
+		(&[0x48, 0x0f, 0xbe, 0xc0][..], "movsx rax, al"),
+		(&[0x48, 0x0f, 0xbf, 0xc9][..], "movsx rcx, cx"),
+		(&[0x48, 0x63, 0xd2][..], "movsxd rdx, edx"),
+		(&[0x0f, 0x4c, 0xc1][..], "cmovl eax, ecx"),
+		(&[0x48, 0x0f, 0x43, 0xe5][..], "cmovae rsp, rbp"),
+		(&[0x0f, 0x94, 0xc0][..], "sete al"),
+		(&[0x40, 0x0f, 0x9f, 0xc7][..], "setg dil"),
+		(&[0x48, 0x01, 0xc8][..], "add rax, rcx"),
+		(&[0x49, 0x83, 0xc3, 0x08][..], "add r11, 8"),
+		(&[0x48, 0x81, 0xc4, 0x80, 0x00, 0x00, 0x00][..], "add rsp, 128"),
+		(&[0x49, 0x83, 0xeb, 0x08][..], "sub r11, 8"),
+		(&[0x48, 0x83, 0xe4, 0xf0][..], "and rsp, -16"),
+		(&[0x09, 0xd1][..], "or ecx, edx"),
+		(&[0x31, 0xc0][..], "xor eax, eax"),
+		(&[0x49, 0x39, 0xeb][..], "cmp r11, rbp"),
+		(&[0x49, 0x3b, 0xb7, 0x08, 0x01, 0x00, 0x00][..], "cmp rsi, qword ptr [r15 + 264]"),
+		(&[0x81, 0x7b, 0xf8, 0x45, 0x23, 0x01, 0x00][..], "cmp dword ptr [rbx - 8], 74565"),
+		(&[0x48, 0x91][..], "xchg rax, rcx"),
+		(&[0x87, 0xd1][..], "xchg edx, ecx"),
+		(&[0xf7, 0xe9][..], "imul ecx"),
+		(&[0x48, 0xf7, 0xf1][..], "div rcx"),
+		(&[0xf7, 0xf9][..], "idiv ecx"),
+		(&[0x99][..], "cdq"),
+		(&[0x48, 0x99][..], "cqo"),
+		(&[0xd3, 0xe0][..], "shl eax, cl"),
+		(&[0x48, 0xd3, 0xea][..], "shr rdx, cl"),
+		(&[0xc1, 0xf8, 0x03][..], "sar eax, 3"),
+		(&[0x48, 0xd3, 0xc0][..], "rol rax, cl"),
+		(&[0xd3, 0xc9][..], "ror ecx, cl"),
+		(&[0xf3, 0x0f, 0xbd, 0xc0][..], "lzcnt eax, eax"),
+		(&[0xf3, 0x48, 0x0f, 0xbc, 0xc9][..], "tzcnt rcx, rcx"),
+		(&[0xf3, 0x0f, 0xb8, 0xd2][..], "popcnt edx, edx"),
+		(&[0xff, 0xd0][..], "call rax"),
+		(&[0xff, 0x27][..], "jmp qword ptr [rdi]"),
+		(&[0xc3][..], "ret"),
+		(&[0xc2, 0x10, 0x00][..], "ret 16"),
+		(&[0x0f, 0x0b][..], "ud2"),
+		(&[0xfc][..], "cld"),
+		(&[0x48, 0xab][..], "stosq"),
+		(&[0xf3, 0xa4][..], "rep movsb"),
+		(&[0x75, 0xea][..], "jne 0xec"),
+		(&[0x0f, 0x84, 0x10, 0x00, 0x00, 0x00][..], "je 0x116"),
+		(&[0xe8, 0x00, 0xff, 0xff, 0xff][..], "call 0x5"),
+	];
+	for (bytes, expected) in cases {
+		assert_eq!(crate::intel_x64_disasm::decode(bytes, 0x100), Some((bytes.len(), expected.to_string())));
+	}
+	assert_eq!(crate::intel_x64_disasm::decode(&[0x0f, 0xff], 0), None);
+}
+
+#[test]
+fn disassemble() {
+	let raw = RawPvf::from_bytes(&wat(r#"
+		(module
+			(table 1 funcref)
+			(elem (i32.const 0) $add)
+			(func $add (export "add") (param i32 i32) (result i32)
+				(i32.add (local.get 0) (local.get 1)))
+			(func (export "test") (param i32) (result i32)
+				(block (block (br_table 0 1 (local.get 0))) (return (i32.const 1)))
+				(call_indirect (param i32 i32) (result i32) (local.get 0) (i32.const 2) (i32.const 0))))
+	"#));
+	let pvf = raw.translate().unwrap().compile(&IntelX64Compiler::new());
+	let text = pvf.disassemble();
+	assert!(text.contains("ExportedFunc(0, \"add\") <add>:\n"));
+	assert!(text.contains("push r12\n"));
+	assert!(text.contains("mov r15, rdi\n"));
+	assert!(text.contains("; EnterFunction(0)\n"));
+	assert!(text.contains("add eax, edx\n"));
+	assert!(text.contains("jmp qword ptr [rdi]\n"));
+	assert!(text.contains(".quad 0x0  ; reloc LabelAbsoluteAddress("));
+	assert!(text.contains("call rax\n"));
+	assert!(!text.contains(".byte"));
+}
+
+#[test]
+fn source_map() {
+	let code = wat(r#"(module (func (export "test") (param i32) (result i32) (i32.add (local.get 0) (i32.const 1)) (unreachable)))"#);
+	let mut ir = RawPvf::from_bytes(&code).translate().unwrap();
+	ir.optimize();
+	let pvf = ir.compile(&IntelX64Compiler::new());
+
+	let map = pvf.source_map();
+	assert!(map.windows(2).all(|w| w[0].0 <= w[1].0));
+	assert!(map.iter().all(|(pc, func, _)| *pc < pvf.code_len() && *func == 0));
+	let mut opcodes = map.iter().map(|(_, _, offset)| code[*offset as usize]).collect::<Vec<_>>();
+	opcodes.dedup();
+	assert_eq!(opcodes, [0x20, 0x41, 0x6a, 0x00]); // local.get, i32.const, i32.add, unreachable
+
+	// The trampoline belongs to the function, the trap to the `unreachable`
+	assert_eq!(pvf.wasm_location(0).map(|(_, offset)| code[offset as usize]), Some(0x20));
+	let ud2 = pvf.code().windows(2).position(|w| w == [0x0f, 0x0b]).unwrap();
+	assert_eq!(pvf.wasm_location(ud2).map(|(func, offset)| (func, code[offset as usize])), Some((0, 0x00)));
+}
+
+#[test]
+fn name_section() {
+	let raw = RawPvf::from_bytes(&wat(r#"
+		(module
+			(func $square (param $x i32) (result i32) (local $tmp i32)
+				(local.set $tmp (i32.mul (local.get $x) (local.get $x)))
+				(local.get $tmp))
+			(func (export "test") (param i32) (result i32) (call $square (local.get 0))))
+	"#));
+	let ir = raw.translate().unwrap();
+	assert_eq!(ir.names().func(0), Some("square"));
+	assert_eq!(ir.names().func(1), None);
+	assert_eq!(ir.names().local(0, 0), Some("x"));
+	assert_eq!(ir.names().local(0, 1), Some("tmp"));
+	assert!(format!("{:?}", ir).contains("func 0 <square>:"));
+	assert!(format!("{:?}", ir).contains("Move(Local(1), Reg(Sra)) ; 1 = tmp"));
+
+	let pvf = ir.compile(&IntelX64Compiler::new());
+	assert_eq!(pvf.names().func(0), Some("square"));
+	assert!(pvf.disassemble().contains("AnonymousFunc(0) <square>:\n"));
+
+	// Locals of inlined callees are not annotated with the caller's names
+	let mut ir = RawPvf::from_bytes(&wat(r#"
+		(module
+			(func $square (param $x i32) (result i32) (i32.mul (local.get $x) (local.get $x)))
+			(func (export "test") (param $n i32) (result i32) (local $acc i32)
+				(local.set $acc (call $square (local.get $n)))
+				(local.get $acc)))
+	"#)).translate().unwrap();
+	ir.inline_functions(32);
+	ir.names.locals.get_mut(&1).unwrap().insert(3, "stale".to_string());
+	let dump = format!("{:?}", ir);
+	assert!(dump.contains("Move(Local(3), Reg(Sra))"));
+	assert!(dump.contains("; 1 = acc"));
+	assert!(!dump.contains("stale"));
+}
+
+#[test]
+fn trap_backtrace() {
+	let code = wat(r#"
+		(module
+			(func $inner (param i32) (result i32) (i32.div_u (i32.const 100) (local.get 0)))
+			(func $middle (param i32 i32) (result i32) (i32.add (local.get 0) (call $inner (local.get 1))))
+			(func $test (export "test") (param i32) (result i32) (call $middle (i32.const 7) (local.get 0)))
+			(func (export "unreachable") (drop (call $test (i32.const 1))) (unreachable))
+		)"#);
+	let pvf = RawPvf::from_bytes(&code).translate().unwrap().compile(&IntelX64Compiler::new());
+	let instance = PvfInstance::instantiate(&pvf).unwrap();
+
+	let frames = |err: PvfError| match err {
+		PvfError::Trap(backtrace) => backtrace.frames.into_iter().map(|frame| (frame.func_name, code[frame.wasm_offset.unwrap() as usize])).collect::<Vec<_>>(),
+		err => panic!("Unexpected error: {:?}", err),
+	};
+	let expected = vec![(Some("inner".to_owned()), 0x6e), (Some("middle".to_owned()), 0x10), (Some("test".to_owned()), 0x10)];
+
+	let err = unsafe { instance.call::<_, _, i32>("test", 0) }.unwrap_err();
+	assert!(err.to_string().contains("#1: func 1 <middle> @ 0x"));
+	assert_eq!(frames(err), expected);
+	// The instance is still usable after a trap
+	assert_eq!(unsafe { instance.call::<_, _, i32>("test", 5) }.unwrap(), 27);
+	assert_eq!(frames(unsafe { instance.call::<_, _, ()>("unreachable", ()) }.unwrap_err()), [(None, 0x00)]);
+
+	let mut interpreter = IrInterpreter::instantiate(&RawPvf::from_bytes(&code).translate().unwrap()).unwrap();
+	assert_eq!(frames(interpreter.call("test", &[0]).unwrap_err()), expected);
+
+	// $inner is inlined into $middle, which keeps its own frame at the call site
+	let mut ir = RawPvf::from_bytes(&code).translate().unwrap();
+	ir.optimize();
+	let mut interpreter = IrInterpreter::instantiate(&ir).unwrap();
+	assert_eq!(frames(interpreter.call("test", &[0]).unwrap_err()), expected);
+	let pvf = ir.compile(&IntelX64Compiler::new());
+	assert!(pvf.disassemble().contains("EnterInlinedFunction"));
+	let instance = PvfInstance::instantiate(&pvf).unwrap();
+	assert_eq!(frames(unsafe { instance.call::<_, _, i32>("test", 0) }.unwrap_err()), expected);
+}
+
+#[test]
+fn init_trap() {
+	// The element segment is far out of the table, so the initialization faults
+	let code = wat(r#"
+		(module
+			(table 1 funcref)
+			(elem (i32.const 0x7fff0000) $f)
+			(func $f)
+		)"#);
+	let pvf = RawPvf::from_bytes(&code).translate().unwrap().compile(&IntelX64Compiler::new());
+	assert!(matches!(PvfInstance::instantiate(&pvf), Err(PvfError::Trap(_))));
+}
+
+#[test]
+fn perf_profiling() {
+	let code = wat(r#"
+		(module
+			(func $double (param i32) (result i32) (i32.mul (local.get 0) (i32.const 2)))
+			(func (export "test") (param i32) (result i32) (call $double (local.get 0)))
+		)"#);
+	let pvf = RawPvf::from_bytes(&code).translate().unwrap().compile(&IntelX64Compiler::new());
+	let instance = PvfInstance::instantiate_with_profiling(&pvf, ProfilingConfig { perf_map: true, jitdump: true, ..Default::default() }).unwrap();
+	assert_eq!(unsafe { instance.call::<_, _, i32>("test", 21) }.unwrap(), 42);
+
+	let symbols = pvf.symbols();
+	assert_eq!(symbols.iter().map(|(_, _, name)| name.as_str()).collect::<Vec<_>>(), ["wasm[0]::double", "trampoline[1]::test", "wasm[1]", "trampoline[2]::_pvf_init", "wasm[2]"]);
+	assert_eq!(symbols.iter().map(|(_, size, _)| size).sum::<usize>(), pvf.code_len());
+
+	// Other tests may append to the same files
+	let map = std::fs::read_to_string(perf::perf_map_path()).unwrap();
+	let line = map.lines().find(|line| line.ends_with(" wasm[0]::double")).unwrap();
+	let size = usize::from_str_radix(line.split(' ').nth(1).unwrap(), 16).unwrap();
+	assert_eq!(size, symbols[0].1);
+
+	let dump = std::fs::read(perf::jitdump_path()).unwrap();
+	assert_eq!(u32::from_ne_bytes(dump[0..4].try_into().unwrap()), 0x4a695444);
+	// The trampolines are relocated, the function bodies are recorded as they are compiled
+	let name = b"wasm[0]::double\0";
+	let at = dump.windows(name.len()).position(|w| w == name).unwrap();
+	let (offset, size, _) = &symbols[0];
+	assert_eq!(&dump[at + name.len()..at + name.len() + size], &pvf.code()[*offset..offset + size]);
+}
+
+#[test]
+fn gdb_jit() {
+	let code = wat(r#"
+		(module
+			(func $double (param i32) (result i32) (i32.mul (local.get 0) (i32.const 2)))
+			(func (export "test") (param i32) (result i32)
+				(block (br_if 0 (i32.eqz (local.get 0))) (return (call $double (local.get 0))))
+				(i32.const 5))
+		)"#);
+	let pvf = RawPvf::from_bytes(&code).translate().unwrap().compile(&IntelX64Compiler::new());
+	let instance = PvfInstance::instantiate_with_profiling(&pvf, ProfilingConfig { gdb_jit: true, ..Default::default() }).unwrap();
+	assert_eq!(unsafe { instance.call::<_, _, i32>("test", 4) }.unwrap(), 8);
+
+	let object = instance.gdb_jit_object().unwrap().to_vec();
+	assert_eq!(&object[..4], b"\x7fELF");
+	let contains = |bytes: &[u8]| object.windows(bytes.len()).any(|w| w == bytes);
+	assert!(contains(b"\0wasm[0]::double\0trampoline[1]::test\0wasm[1]\0"));
+	assert!(contains(b"\0.debug_frame\0.debug_line\0"));
+	// The CFA of the one-parameter functions is rbx - 8 past the prologue
+	assert!(contains(&[0x12, 3, 1]));
+
+	let code_addr = gdb_jit::text_addr(&object).unwrap();
+	assert!(gdb_jit::is_registered(code_addr));
+	drop(instance);
+	assert!(!gdb_jit::is_registered(code_addr));
+}
+
+#[test]
+fn parallel_compilation() {
+	let code = wat(CROSS_CHECK);
+	let compile = |codegen: &dyn crate::CodeGenerator, threads: usize| {
+		let mut ir = translate_with_imports(&code);
+		ir.optimize();
+		ir.compile_with_threads(codegen, threads)
+	};
+	for codegen in [&IntelX64Compiler::new() as &dyn crate::CodeGenerator, &Aarch64Compiler::new()] {
+		let serial = compile(codegen, 1);
+		for threads in [2, 3, 8] {
+			let parallel = compile(codegen, threads);
+			assert_eq!(parallel.code(), serial.code(), "{} threads", threads);
+			assert_eq!(parallel.labels, serial.labels);
+			assert_eq!(format!("{:?}", parallel.relocs), format!("{:?}", serial.relocs));
+			assert_eq!(parallel.source_map(), serial.source_map());
+		}
+	}
+
+	let pvf = compile(&IntelX64Compiler::new(), 4);
+	let instance = PvfInstance::instantiate(&pvf).unwrap();
+	let mut interpreter = IrInterpreter::instantiate(&translate_with_imports(&code)).unwrap();
+	for (a, b) in [(0i32, 0i64), (-1, -1), (123, 0x123456789)] {
+		let native = unsafe { instance.call::<_, _, i64>("test", (a, b)) }.unwrap();
+		assert_eq!(interpreter.call("test", &[a as u32 as u64, b as u64]).unwrap(), Some(native as u64));
+	}
+}
+
+#[test]
+fn call_dynamic() {
+	let code = wat(r#"
+		(module
+			(func (export "mix") (param i32 i64 i32) (result i64)
+				(i64.add (i64.extend_i32_s (local.get 0)) (i64.mul (local.get 1) (i64.extend_i32_u (local.get 2)))))
+			(func (export "neg") (param i32) (result i32) (i32.sub (i32.const 0) (local.get 0)))
+			(func (export "div") (param i32 i32) (result i32) (i32.div_s (local.get 0) (local.get 1)))
+			(func (export "nothing"))
+		)"#);
+	let pvf = RawPvf::from_bytes(&code).translate().unwrap().compile(&IntelX64Compiler::new());
+	let mut instance = PvfInstance::instantiate(&pvf).unwrap();
+
+	assert_eq!(instance.call_dynamic("mix", &[Value::I32(-5), Value::I64(0x100000000), Value::I32(3)]).unwrap(), [Value::I64(0x2fffffffb)]);
+	assert_eq!(instance.call_dynamic("neg", &[Value::I32(i32::MIN + 1)]).unwrap(), [Value::I32(i32::MAX)]);
+	assert_eq!(instance.call_dynamic("nothing", &[]).unwrap(), []);
+
+	let validation_error = |res: Result<Vec<Value>, PvfError>| match res {
+		Err(PvfError::ValidationError(msg)) => msg,
+		res => panic!("Unexpected result: {:?}", res),
+	};
+	assert_eq!(validation_error(instance.call_dynamic("div", &[Value::I32(1)])), "div expects 2 argument(s), 1 given");
+	assert_eq!(validation_error(instance.call_dynamic("mix", &[Value::I32(1), Value::I32(2), Value::I32(3)])), "mix argument 1 is I64, I32 given");
+	assert!(matches!(instance.call_dynamic("missing", &[]), Err(PvfError::ExportNotFound)));
+	assert!(matches!(instance.call_dynamic("div", &[Value::I32(1), Value::I32(0)]), Err(PvfError::Trap(_))));
+	assert_eq!(instance.call_dynamic("div", &[Value::I32(-9), Value::I32(2)]).unwrap(), [Value::I32(-4)]);
+
+	// Exports with too many parameters for a native call fail instead of aborting
+	let code = wat(&format!(r#"(module (func (export "wide") (param {}) (result i32) (local.get 12)))"#, "i32 ".repeat(13)));
+	let pvf = RawPvf::from_bytes(&code).translate().unwrap().compile(&IntelX64Compiler::new());
+	let mut instance = PvfInstance::instantiate(&pvf).unwrap();
+	assert!(matches!(instance.call_dynamic("wide", &[Value::I32(1); 13]), Err(PvfError::ValidationError(_))));
+}
+
+#[test]
+fn typed_func() {
+	let code = wat(r#"
+		(module
+			(func (export "mix") (param i32 i64) (result i64) (i64.add (i64.extend_i32_s (local.get 0)) (local.get 1)))
+			(func (export "div") (param i32 i32) (result i32) (i32.div_u (local.get 0) (local.get 1)))
+			(func (export "nothing"))
+		)"#);
+	let pvf = RawPvf::from_bytes(&code).translate().unwrap().compile(&IntelX64Compiler::new());
+	let mut instance = PvfInstance::instantiate(&pvf).unwrap();
+
+	let mix = instance.get_typed_func::<(i32, i64), i64>("mix").unwrap();
+	let div = instance.get_typed_func::<(u32, u32), u32>("div").unwrap();
+	assert_eq!(mix.call(&mut instance, (-1, 0x100000000)).unwrap(), 0xffffffff);
+	assert_eq!(mix.call(&mut instance, (2, 3)).unwrap(), 5);
+	assert_eq!(div.call(&mut instance, (u32::MAX, 2)).unwrap(), u32::MAX / 2);
+	assert!(matches!(div.call(&mut instance, (1, 0)), Err(PvfError::Trap(_))));
+	instance.get_typed_func::<(), ()>("nothing").unwrap().call(&mut instance, ()).unwrap();
+
+	// Handles work on any instance of the same loaded code only
+	let mut other = PvfInstance::new(instance.code().clone()).unwrap();
+	assert_eq!(mix.call(&mut other, (1, 1)).unwrap(), 2);
+	let mut foreign = PvfInstance::instantiate(&pvf).unwrap();
+	assert!(std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| mix.call(&mut foreign, (1, 1)))).is_err());
+
+	assert!(matches!(instance.get_typed_func::<(i32, i32), i64>("mix"), Err(PvfError::ValidationError(_))));
+	assert!(matches!(instance.get_typed_func::<(i32, i64), ()>("mix"), Err(PvfError::ValidationError(_))));
+	assert!(matches!(instance.get_typed_func::<i32, i32>("div"), Err(PvfError::ValidationError(_))));
+	assert!(matches!(instance.get_typed_func::<(), ()>("missing"), Err(PvfError::ExportNotFound)));
+}
+
+#[test]
+fn module_interface() {
+	let code = wat(r#"
+		(module
+			(import "env" "add2" (func $add2 (param i32) (result i32)))
+			(import "env" "base" (global i64))
+			(memory (export "mem") 2 16)
+			(table (export "tab") 3 funcref)
+			(global $counter (export "counter") (mut i32) (i32.const 0))
+			(func (export "test") (param i32 i64) (result i64) (i64.add (i64.extend_i32_u (call $add2 (local.get 0))) (local.get 1)))
+			(func (export "nothing"))
+		)"#);
+	let ir = translate_with_imports(&code);
+	let interface = ir.interface().clone();
+
+	assert_eq!(interface.imports.len(), 2);
+	assert_eq!((interface.imports[0].module.as_str(), interface.imports[0].name.as_str()), ("env", "add2"));
+	assert_eq!(interface.imports[0].ty, ExternType::Func(FuncSignature { params: vec![ValueType::I32], results: vec![ValueType::I32] }));
+	assert_eq!(interface.imports[1].ty, ExternType::Global { ty: ValueType::I64, mutable: false });
+	assert_eq!(interface.memory, Some(Limits { initial: 2, maximum: Some(16) }));
+	assert_eq!(interface.tables, [Limits { initial: 3, maximum: None }]);
+
+	let exports = interface.exports.iter().map(|export| (export.name.as_str(), export.index, export.ty.clone())).collect::<Vec<_>>();
+	assert_eq!(exports, [
+		("mem", 0, ExternType::Memory(Limits { initial: 2, maximum: Some(16) })),
+		("tab", 0, ExternType::Table(Limits { initial: 3, maximum: None })),
+		("counter", 1, ExternType::Global { ty: ValueType::I32, mutable: true }),
+		("test", 1, ExternType::Func(FuncSignature { params: vec![ValueType::I32, ValueType::I64], results: vec![ValueType::I64] })),
+		("nothing", 2, ExternType::Func(FuncSignature::default())),
+	]);
+	assert_eq!(interface.func_export("nothing"), Some(&FuncSignature::default()));
+	assert_eq!(interface.func_export("mem"), None);
+
+	// The interface is kept through compilation
+	let pvf = ir.compile(&IntelX64Compiler::new());
+	assert_eq!(pvf.interface(), &interface);
+}
+
+#[test]
+fn instance_reset() {
+	let code = wat(r#"
+		(module
+			(memory 1 4)
+			(data (i32.const 0x100) "\2a\00\00\00")
+			(global $counter (mut i32) (i32.const 10))
+			(func (export "bump") (result i32)
+				(global.set $counter (i32.add (global.get $counter) (i32.const 1)))
+				(i32.store (i32.const 0x100) (i32.add (i32.load (i32.const 0x100)) (i32.const 1)))
+				(i32.store (i32.const 0x8000) (i32.const 7))
+				(global.get $counter))
+			(func (export "state") (result i32)
+				(i32.add (i32.add (global.get $counter) (i32.load (i32.const 0x100))) (i32.load (i32.const 0x8000))))
+			(func (export "grow") (result i32)
+				(drop (memory.grow (i32.const 2)))
+				(i32.store (i32.const 0x20000) (i32.const 1))
+				(memory.size))
+			(func (export "size") (result i32) (memory.size))
+			(func (export "trap") (global.set $counter (i32.const 100)) (unreachable))
+		)"#);
+	let pvf = RawPvf::from_bytes(&code).translate().unwrap().compile(&IntelX64Compiler::new());
+	let mut instance = PvfInstance::instantiate(&pvf).unwrap();
+
+	let call = |instance: &PvfInstance, func| unsafe { instance.call::<_, _, i32>(func, ()) }.unwrap();
+	assert_eq!(call(&instance, "state"), 52);
+	for _ in 0..3 {
+		assert_eq!(call(&instance, "bump"), 11);
+		assert_eq!(call(&instance, "bump"), 12);
+		assert_eq!(call(&instance, "state"), 12 + 44 + 7);
+		assert_eq!(call(&instance, "grow"), 3);
+		assert!(unsafe { instance.call::<_, _, ()>("trap", ()) }.is_err());
+		instance.reset();
+		assert_eq!(call(&instance, "state"), 52);
+		assert_eq!(call(&instance, "size"), 1);
+	}
+}
+
+#[test]
+fn shared_code() {
+	let code = wat(r#"
+		(module
+			(memory 1)
+			(global $sum (mut i64) (i64.const 0))
+			(func (export "add") (param i64) (result i64)
+				(i64.store (i32.const 0x40) (i64.add (i64.load (i32.const 0x40)) (local.get 0)))
+				(global.set $sum (i64.add (global.get $sum) (local.get 0)))
+				(i64.add (global.get $sum) (i64.load (i32.const 0x40))))
+		)"#);
+	let pvf = RawPvf::from_bytes(&code).translate().unwrap().compile(&IntelX64Compiler::new());
+	let loaded = std::sync::Arc::new(LoadedPvf::load(&pvf).unwrap());
+
+	let results = std::thread::scope(|scope| {
+		let threads = (1..=4i64).map(|n| {
+			let loaded = loaded.clone();
+			scope.spawn(move || {
+				let mut instance = PvfInstance::new(loaded).unwrap();
+				let add = instance.get_typed_func::<i64, i64>("add").unwrap();
+				(0..1000).fold(0, |_, _| add.call(&mut instance, n).unwrap())
+			})
+		}).collect::<Vec<_>>();
+		threads.into_iter().map(|thread| thread.join().unwrap()).collect::<Vec<_>>()
+	});
+	// Every instance has its own memory and globals
+	assert_eq!(results, [2000, 4000, 6000, 8000]);
+
+	let a = PvfInstance::new(loaded.clone()).unwrap();
+	let b = PvfInstance::new(loaded.clone()).unwrap();
+	assert!(std::sync::Arc::ptr_eq(a.code(), b.code()));
+	assert_eq!(unsafe { a.call::<_, _, i64>("add", 5i64) }.unwrap(), 10);
+	assert_eq!(unsafe { b.call::<_, _, i64>("add", 7i64) }.unwrap(), 14);
+	assert_eq!(unsafe { a.call::<_, _, i64>("add", 1i64) }.unwrap(), 12);
+}
+
+#[test]
+fn memory_image() {
+	let code = wat(r#"
+		(module
+			(memory 64)
+			(data (i32.const 0x10) "\01\02\03\04")
+			(data (i32.const 0x12) "\aa\bb")
+			(data (i32.const 0x3ffff0) "\ff\ff\ff\7f")
+			(func (export "load") (param i32) (result i32) (i32.load (local.get 0)))
+			(func (export "store") (param i32 i32) (i32.store (local.get 0) (local.get 1)))
+		)"#);
+	let ir = RawPvf::from_bytes(&code).translate().unwrap();
+	// Only the segments are kept, not the gaps between them
+	assert_eq!(ir.memory_image.iter().map(|(offset, bytes)| (*offset, bytes.len())).collect::<Vec<_>>(), [(0x10, 4), (0x12, 2), (0x3ffff0, 4)]);
+	let mut interpreter = IrInterpreter::instantiate(&ir).unwrap();
+	assert_eq!(interpreter.call("load", &[0x10]).unwrap(), Some(0xbbaa0201));
+
+	let pvf = ir.compile(&IntelX64Compiler::new());
+	// The segments are not copied at runtime
+	assert!(pvf.data_chunks.is_empty());
+	let loaded = std::sync::Arc::new(LoadedPvf::load(&pvf).unwrap());
+	let mut a = PvfInstance::new(loaded.clone()).unwrap();
+	let mut b = PvfInstance::new(loaded).unwrap();
+
+	let load = |instance: &mut PvfInstance, addr: i32| instance.get_typed_func::<i32, i32>("load").unwrap().call(instance, addr).unwrap();
+	let store = |instance: &mut PvfInstance, addr: i32, value: i32| instance.get_typed_func::<(i32, i32), ()>("store").unwrap().call(instance, (addr, value)).unwrap();
+	assert_eq!(load(&mut a, 0x10), 0xbbaa0201u32 as i32);
+	assert_eq!(load(&mut a, 0x3ffff0), i32::MAX);
+	assert_eq!(load(&mut a, 0x3ffff4), 0);
+
+	// The image is copy-on-write, writes are private to the instance
+	store(&mut a, 0x10, 7);
+	store(&mut a, 0x3ffff4, 8);
+	assert_eq!((load(&mut a, 0x10), load(&mut a, 0x3ffff4)), (7, 8));
+	assert_eq!((load(&mut b, 0x10), load(&mut b, 0x3ffff4)), (0xbbaa0201u32 as i32, 0));
+	a.reset();
+	assert_eq!((load(&mut a, 0x10), load(&mut a, 0x3ffff4)), (0xbbaa0201u32 as i32, 0));
+
+	let code = wat(r#"(module (memory 1) (data (i32.const 0xfffe) "\01\02\03"))"#);
+	assert!(matches!(RawPvf::from_bytes(&code).translate(), Err(PvfError::ValidationError(_))));
+}
+
+#[test]
+fn host_memory_access() {
+	let code = wat(r#"
+		(module
+			(memory 1 3)
+			(data (i32.const 0x20) "hello")
+			(func (export "sum") (param i32 i32) (result i64)
+				(local $sum i64)
+				(block $done
+					(loop $next
+						(br_if $done (i32.eqz (local.get 1)))
+						(local.set $sum (i64.add (local.get $sum) (i64.load32_u (local.get 0))))
+						(local.set 0 (i32.add (local.get 0) (i32.const 4)))
+						(local.set 1 (i32.sub (local.get 1) (i32.const 1)))
+						(br $next)))
+				(i64.store (i32.const 0x10000) (local.get $sum))
+				(local.get $sum))
+			(func (export "grow") (result i32) (memory.grow (i32.const 1)))
+		)"#);
+	let pvf = RawPvf::from_bytes(&code).translate().unwrap().compile(&IntelX64Compiler::new());
+	let mut instance = PvfInstance::instantiate(&pvf).unwrap();
+
+	assert_eq!(instance.memory().len(), 0x10000);
+	assert_eq!(instance.read_bytes(0x20, 5).unwrap(), b"hello");
+	instance.memory_mut()[0x20] = b'j';
+	assert_eq!(instance.read_bytes(0x20, 5).unwrap(), b"jello");
+
+	for (i, value) in [1u32, 20, 300, u32::MAX].into_iter().enumerate() {
+		instance.write_value(0x100 + i * 4, value).unwrap();
+	}
+	assert_eq!(instance.read_value::<u16>(0x104).unwrap(), 20);
+	assert_eq!(unsafe { instance.call::<_, _, i64>("sum", (0x100, 4)) }.unwrap(), 321 + u32::MAX as i64);
+
+	// Accesses are bounded by the allocated pages, not by the maximum
+	let out_of_bounds = |res: Result<(), PvfError>| matches!(res, Err(PvfError::MemoryOutOfBounds { size: 0x10000, .. }));
+	assert!(out_of_bounds(instance.read_value::<i64>(0xfffc).map(|_| ())));
+	assert!(out_of_bounds(instance.write_bytes(0x10000, &[1])));
+	assert!(out_of_bounds(instance.read_bytes(usize::MAX, 2).map(|_| ())));
+	assert_eq!(unsafe { instance.call::<_, _, i32>("grow", ()) }.unwrap(), 1);
+	assert_eq!(instance.memory().len(), 0x20000);
+	assert_eq!(instance.read_value::<i64>(0x10000).unwrap(), 321 + u32::MAX as i64);
+	instance.write_bytes(0x1fffe, &[1, 2]).unwrap();
+	assert!(matches!(instance.write_bytes(0x1ffff, &[1, 2]), Err(PvfError::MemoryOutOfBounds { offset: 0x1ffff, len: 2, size: 0x20000 })));
+}
+
+#[test]
+fn exported_globals() {
+	let code = wat(r#"
+		(module
+			(global $hidden (mut i32) (i32.const 5))
+			(global (export "__heap_base") i32 (i32.const 0x12340))
+			(global $counter (export "counter") (mut i64) (i64.const -3))
+			(func (export "bump") (result i64)
+				(global.set $counter (i64.add (global.get $counter) (i64.extend_i32_u (global.get $hidden))))
+				(global.get $counter))
+		)"#);
+	let pvf = RawPvf::from_bytes(&code).translate().unwrap().compile(&IntelX64Compiler::new());
+	let mut instance = PvfInstance::instantiate(&pvf).unwrap();
+
+	assert_eq!(instance.get_global("__heap_base").unwrap(), Value::I32(0x12340));
+	assert_eq!(instance.get_global("counter").unwrap(), Value::I64(-3));
+	assert_eq!(unsafe { instance.call::<_, _, i64>("bump", ()) }.unwrap(), 2);
+	assert_eq!(instance.get_global("counter").unwrap(), Value::I64(2));
+	instance.set_global("counter", Value::I64(i64::MIN)).unwrap();
+	assert_eq!(unsafe { instance.call::<_, _, i64>("bump", ()) }.unwrap(), i64::MIN + 5);
+
+	assert!(matches!(instance.set_global("__heap_base", Value::I32(0)), Err(PvfError::ImmutableGlobal(name)) if name == "__heap_base"));
+	assert!(matches!(instance.set_global("counter", Value::I32(0)), Err(PvfError::ValidationError(_))));
+	assert!(matches!(instance.get_global("hidden"), Err(PvfError::ExportNotFound)));
+	assert!(matches!(instance.get_global("bump"), Err(PvfError::ExportNotFound)));
+
+	instance.reset();
+	assert_eq!(instance.get_global("counter").unwrap(), Value::I64(-3));
+}
+
+#[test]
+fn exported_memory_and_table() {
+	let code = wat(r#"
+		(module
+			(memory (export "memory") 1)
+			(table (export "__indirect_function_table") 4 funcref)
+			(elem (i32.const 1) $double $square)
+			(func $double (param i32) (result i32) (i32.add (local.get 0) (local.get 0)))
+			(func $square (param i32) (result i32) (i32.mul (local.get 0) (local.get 0)))
+			(func (export "apply") (param i32 i32) (result i32)
+				(i32.store (i32.const 0) (call_indirect (param i32) (result i32) (local.get 1) (local.get 0)))
+				(i32.load (i32.const 0)))
+		)"#);
+	let pvf = RawPvf::from_bytes(&code).translate().unwrap().compile(&IntelX64Compiler::new());
+	let mut instance = PvfInstance::instantiate(&pvf).unwrap();
+
+	let table = instance.exported_table("__indirect_function_table").unwrap();
+	assert_eq!(table.size(), 4);
+	assert_eq!((0..4).map(|index| table.get(index).unwrap()).collect::<Vec<_>>(), [None, Some(0), Some(1), None]);
+	assert!(matches!(table.get(4), Err(PvfError::TableOutOfBounds { index: 4, size: 4 })));
+	assert!(matches!(instance.exported_table("memory"), Err(PvfError::ExportNotFound)));
+
+	assert_eq!(unsafe { instance.call::<_, _, i32>("apply", (2, 9)) }.unwrap(), 81);
+	assert_eq!(&instance.exported_memory("memory").unwrap()[..4], 81u32.to_le_bytes());
+	instance.exported_memory_mut("memory").unwrap()[4] = 1;
+	assert_eq!(instance.read_value::<u8>(4).unwrap(), 1);
+	assert!(matches!(instance.exported_memory("__indirect_function_table"), Err(PvfError::ExportNotFound)));
+	assert!(matches!(instance.exported_memory_mut("mem"), Err(PvfError::ExportNotFound)));
+}
+
+#[test]
+fn executor_limits() {
+	let code = wat(r#"
+		(module
+			(memory 2)
+			(table (export "table") 3 10 funcref)
+			(data (i32.const 0) "0123456789")
+			(func $f (param i32) (result i32) (local i64 i64) (local.get 0))
+			(func (export "grow") (param i32) (result i32) (memory.grow (local.get 0)))
+		)"#);
+	let translate = |config: ExecutorConfig| {
+		let mut raw = RawPvf::from_bytes(&code);
+		raw.set_executor_config(config);
+		raw.translate()
+	};
+	let limit = |res: Result<IrPvf, PvfError>| match res {
+		Err(PvfError::LimitExceeded { limit, value, max }) => (limit, value, max),
+		res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+	};
+	let config = ExecutorConfig::default();
+	assert_eq!(limit(translate(ExecutorConfig { max_memory_pages: 1, ..config })), (ExecutorLimit::MemoryPages, 2, 1));
+	assert_eq!(limit(translate(ExecutorConfig { max_table_elements: 2, ..config })), (ExecutorLimit::TableElements, 3, 2));
+	assert_eq!(limit(translate(ExecutorConfig { max_functions: 1, ..config })), (ExecutorLimit::Functions, 2, 1));
+	assert_eq!(limit(translate(ExecutorConfig { max_locals: 2, ..config })), (ExecutorLimit::Locals, 3, 2));
+	// Two declarations of 0xffffffff locals, which overflow a 32-bit sum
+	let huge_locals = [
+		0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+		0x01, 0x04, 0x01, 0x60, 0x00, 0x00,
+		0x03, 0x02, 0x01, 0x00,
+		0x0a, 0x10, 0x01, 0x0e, 0x02, 0xff, 0xff, 0xff, 0xff, 0x0f, 0x7f, 0xff, 0xff, 0xff, 0xff, 0x0f, 0x7e, 0x0b,
+	];
+	assert_eq!(limit(RawPvf::from_bytes(&huge_locals).translate()), (ExecutorLimit::Locals, 0xffffffff, 50_000));
+	assert_eq!(limit(translate(ExecutorConfig { max_code_size: 10, ..config })).0, ExecutorLimit::CodeSize);
+	assert_eq!(limit(translate(ExecutorConfig { max_data_bytes: 9, ..config })), (ExecutorLimit::DataBytes, 10, 9));
+
+	// Imported functions count even without a function section
+	let mut raw = RawPvf::from_bytes(&wat(r#"(module (import "env" "add2" (func (param i32) (result i32))) (import "env" "add2" (func (param i32) (result i32))))"#));
+	raw.set_import_resolver(resolve_env);
+	raw.set_executor_config(ExecutorConfig { max_functions: 1, ..config });
+	assert_eq!(limit(raw.translate()), (ExecutorLimit::Functions, 2, 1));
+
+	// Memory declared without a maximum grows up to the limit, tables are lowered to it
+	let ir = translate(ExecutorConfig { max_memory_pages: 4, max_table_elements: 5, ..config }).unwrap();
+	assert_eq!(ir.memory, (2, 4));
+	let pvf = ir.compile(&IntelX64Compiler::new());
+	let instance = PvfInstance::instantiate(&pvf).unwrap();
+	assert_eq!(unsafe { instance.call::<_, _, i32>("grow", 3) }.unwrap(), -1);
+	assert_eq!(unsafe { instance.call::<_, _, i32>("grow", 2) }.unwrap(), 2);
+	assert_eq!(instance.memory().len(), 4 * 0x10000);
+	assert_eq!(instance.exported_table("table").unwrap().size(), 5);
+
+	// Instantiation can be limited further
+	let instance = PvfInstance::instantiate_with_config(&pvf, &ExecutorConfig { max_memory_pages: 3, ..config }).unwrap();
+	assert_eq!(unsafe { instance.call::<_, _, i32>("grow", 2) }.unwrap(), -1);
+	assert_eq!(unsafe { instance.call::<_, _, i32>("grow", 1) }.unwrap(), 2);
+	assert!(matches!(PvfInstance::instantiate_with_config(&pvf, &ExecutorConfig { max_memory_pages: 1, ..config }),
+		Err(PvfError::LimitExceeded { limit: ExecutorLimit::MemoryPages, value: 2, max: 1 })));
+	// Tables are checked by their initial size, as on translation, and keep their translated size
+	let instance = PvfInstance::instantiate_with_config(&pvf, &ExecutorConfig { max_table_elements: 4, ..config }).unwrap();
+	assert_eq!(instance.exported_table("table").unwrap().size(), 5);
+	assert!(matches!(PvfInstance::instantiate_with_config(&pvf, &ExecutorConfig { max_table_elements: 2, ..config }),
+		Err(PvfError::LimitExceeded { limit: ExecutorLimit::TableElements, value: 3, max: 2 })));
+}
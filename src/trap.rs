@@ -0,0 +1,243 @@
+use std::{cell::Cell, collections::HashMap, fmt};
+use crate::ir::{IrLabel, IrNames};
+
+// Native trap handling
+//
+// Traps in the generated code are hardware exceptions: `ud2` raises SIGILL, integer division
+// by zero and overflow raise SIGFPE, and faulting memory accesses raise SIGSEGV or SIGBUS. While
+// a PVF function is being called, the signal handler checks whether the faulting instruction
+// belongs to its code. If it does, the handler walks the function frame chain to collect the
+// return addresses, and then resumes execution as if the entry trampoline returned, with the
+// callee-saved registers it saved restored. Other signals are passed on to the handler
+// installed before.
+//
+// The frame chain is the one `EnterFunction` builds: rbx points right above the parameters of
+// the current function, and below them are the return address and the caller's saved rbx.
+//
+// Only the x86-64 code is supported for now. On other targets, a trap is not caught.
+
+// Maximum number of frames captured
+const MAX_FRAMES: usize = 64;
+
+/// A Wasm function frame of a trap backtrace
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WasmFrame {
+	pub func_index: u32,
+	/// Function name from the `name` section, if present
+	pub func_name: Option<String>,
+	/// Offset of the instruction in the Wasm bytecode, for the innermost frame the trapping
+	/// instruction, for the others the call
+	pub wasm_offset: Option<u32>,
+}
+
+/// Wasm-level backtrace captured on a trap, innermost frame first
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Backtrace {
+	pub frames: Vec<WasmFrame>,
+}
+
+impl fmt::Display for Backtrace {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		for (i, frame) in self.frames.iter().enumerate() {
+			write!(f, "\n  #{}: func {}", i, frame.func_index)?;
+			if let Some(name) = &frame.func_name {
+				write!(f, " <{}>", name)?;
+			}
+			if let Some(offset) = frame.wasm_offset {
+				write!(f, " @ {:#x}", offset)?;
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Maps native code offsets to Wasm functions
+pub(crate) struct FrameMap {
+	// Start offsets of the function bodies with their index and number of parameters, and of
+	// the entry trampolines, which have none, in ascending order
+	entries: Vec<(usize, Option<(u32, u32)>)>,
+	source_map: Vec<(usize, u32, u32)>,
+	call_sites: Vec<(usize, Option<(u32, u32)>)>,
+	names: IrNames,
+}
+
+impl FrameMap {
+	pub(crate) fn new(labels: &HashMap<IrLabel, usize>, params: &[u32], source_map: &[(usize, u32, u32)], call_sites: &[(usize, Option<(u32, u32)>)], names: &IrNames) -> Self {
+		let mut entries = labels.iter().filter_map(|(label, offset)| match label {
+			IrLabel::AnonymousFunc(index) => Some((*offset, Some((*index, params[*index as usize])))),
+			IrLabel::ExportedFunc(_, _) => Some((*offset, None)),
+			_ => None,
+		}).collect::<Vec<_>>();
+		entries.sort();
+		Self { entries, source_map: source_map.to_vec(), call_sites: call_sites.to_vec(), names: names.clone() }
+	}
+
+	// Index and number of parameters of the function the code at `offset` belongs to
+	fn func_at(&self, offset: usize) -> Option<(u32, u32)> {
+		let n = self.entries.partition_point(|(start, _)| *start <= offset);
+		self.entries[..n].last().and_then(|(_, func)| *func)
+	}
+
+	fn backtrace(&self, raw: &RawBacktrace) -> Backtrace {
+		let mut frames = Vec::new();
+		for (i, offset) in raw.offsets[..raw.len].iter().enumerate() {
+			// Return addresses point after the call, the call itself is the instruction before
+			let offset = if i == 0 { *offset } else { offset - 1 };
+			let Some((label_func, _)) = self.func_at(offset) else { continue };
+			let n = self.source_map.partition_point(|(pc, _, _)| *pc <= offset);
+			let (func_index, wasm_offset) = match self.source_map[..n].last() {
+				Some((_, func_index, wasm_offset)) => (*func_index, Some(*wasm_offset)),
+				None => (label_func, None),
+			};
+			frames.push(self.frame(func_index, wasm_offset));
+			// Code inlined from another function gets a frame of that function, followed by the
+			// frame of the call site
+			let n = self.call_sites.partition_point(|(pc, _)| *pc <= offset);
+			if let Some((_, Some((func_index, wasm_offset)))) = self.call_sites[..n].last() {
+				frames.push(self.frame(*func_index, Some(*wasm_offset)));
+			}
+		}
+		Backtrace { frames }
+	}
+
+	fn frame(&self, func_index: u32, wasm_offset: Option<u32>) -> WasmFrame {
+		WasmFrame { func_index, func_name: self.names.func(func_index).map(str::to_owned), wasm_offset }
+	}
+}
+
+#[derive(Clone, Copy)]
+struct RawBacktrace {
+	offsets: [usize; MAX_FRAMES],
+	len: usize,
+}
+
+#[derive(Clone, Copy)]
+struct ActiveCall {
+	code: (usize, usize),
+	entry_sp_slot: usize,
+	frame_map: *const FrameMap,
+}
+
+thread_local! {
+	static ACTIVE_CALL: Cell<Option<ActiveCall>> = const { Cell::new(None) };
+	static TRAPPED: Cell<Option<RawBacktrace>> = const { Cell::new(None) };
+}
+
+/// Runs `f`, which calls into `code`, catching the traps. `entry_sp_slot` is the address of the
+/// VM data slot the entry trampolines store their stack pointer to.
+///
+/// # Safety
+///
+/// `code` must be the code `frame_map` was built for, and `f` must only enter it through the
+/// entry trampolines.
+pub(crate) unsafe fn catch_traps<R>(code: &[u8], entry_sp_slot: usize, frame_map: &FrameMap, f: impl FnOnce() -> R) -> Result<R, Backtrace> {
+	#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+	native::install_handlers();
+
+	let start = code.as_ptr() as usize;
+	let call = ActiveCall { code: (start, start + code.len()), entry_sp_slot, frame_map };
+	let outer = ACTIVE_CALL.with(|active| active.replace(Some(call)));
+	let res = f();
+	ACTIVE_CALL.with(|active| active.set(outer));
+
+	match TRAPPED.with(|trapped| trapped.take()) {
+		Some(raw) => Err(frame_map.backtrace(&raw)),
+		None => Ok(res),
+	}
+}
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+mod native {
+	use std::{sync::OnceLock, ffi::{c_int, c_void}};
+	use super::*;
+
+	const SIGNALS: [c_int; 4] = [libc::SIGILL, libc::SIGFPE, libc::SIGSEGV, libc::SIGBUS];
+
+	struct PreviousActions([libc::sigaction; SIGNALS.len()]);
+
+	// SAFETY: The actions are only read, they are plain data besides the handler addresses
+	unsafe impl Send for PreviousActions {}
+	unsafe impl Sync for PreviousActions {}
+
+	static PREVIOUS_ACTIONS: OnceLock<PreviousActions> = OnceLock::new();
+
+	pub(super) fn install_handlers() {
+		PREVIOUS_ACTIONS.get_or_init(|| unsafe {
+			let mut previous = [std::mem::zeroed::<libc::sigaction>(); SIGNALS.len()];
+			for (signum, previous) in SIGNALS.iter().zip(previous.iter_mut()) {
+				let mut action: libc::sigaction = std::mem::zeroed();
+				action.sa_sigaction = handle_signal as *const () as usize;
+				action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+				libc::sigemptyset(&mut action.sa_mask);
+				if libc::sigaction(*signum, &action, previous) != 0 {
+					panic!("Cannot install the trap handler: {}", std::io::Error::last_os_error());
+				}
+			}
+			PreviousActions(previous)
+		});
+	}
+
+	unsafe extern "C" fn handle_signal(signum: c_int, info: *mut libc::siginfo_t, context: *mut c_void) {
+		let gregs = &mut (*(context as *mut libc::ucontext_t)).uc_mcontext.gregs;
+		let pc = gregs[libc::REG_RIP as usize] as usize;
+
+		let call = ACTIVE_CALL.with(Cell::get).filter(|call| (call.code.0..call.code.1).contains(&pc));
+		let Some(call) = call else {
+			chain_previous(signum, info, context);
+			return;
+		};
+
+		let entry_sp = *(call.entry_sp_slot as *const usize);
+		let raw = walk_frames(&*call.frame_map, call.code, pc, gregs[libc::REG_RBX as usize] as usize, gregs[libc::REG_RSP as usize] as usize, entry_sp);
+		TRAPPED.with(|trapped| trapped.set(Some(raw)));
+
+		// Return from the entry trampoline: pop rbp, rbx, r15, r12 and the return address
+		let saved = entry_sp as *const i64;
+		gregs[libc::REG_RBP as usize] = *saved;
+		gregs[libc::REG_RBX as usize] = *saved.add(1);
+		gregs[libc::REG_R15 as usize] = *saved.add(2);
+		gregs[libc::REG_R12 as usize] = *saved.add(3);
+		gregs[libc::REG_RIP as usize] = *saved.add(4);
+		gregs[libc::REG_RSP as usize] = (entry_sp + 5 * 8) as i64;
+		gregs[libc::REG_RAX as usize] = 0;
+	}
+
+	// Not a trap, hands the signal to the action installed before ours, which stays installed
+	unsafe fn chain_previous(signum: c_int, info: *mut libc::siginfo_t, context: *mut c_void) {
+		let previous = &PREVIOUS_ACTIONS.get().expect("Handlers are installed").0;
+		let index = SIGNALS.iter().position(|s| *s == signum).expect("Handled signal");
+		let previous = &previous[index];
+		if previous.sa_flags & libc::SA_SIGINFO != 0 {
+			let handler: unsafe extern "C" fn(c_int, *mut libc::siginfo_t, *mut c_void) = std::mem::transmute(previous.sa_sigaction);
+			handler(signum, info, context);
+		} else if previous.sa_sigaction == libc::SIG_DFL {
+			// The default action terminates the process, there is nothing to keep our handler for.
+			// The signal is blocked while handled, so it is delivered on return.
+			libc::signal(signum, libc::SIG_DFL);
+			libc::raise(signum);
+		} else if previous.sa_sigaction != libc::SIG_IGN {
+			let handler: unsafe extern "C" fn(c_int) = std::mem::transmute(previous.sa_sigaction);
+			handler(signum);
+		}
+	}
+
+	// Collects the code offsets of the trapping instruction and of the return addresses up to
+	// the entry trampoline. Only the stack between the trapping frame and the trampoline is read.
+	unsafe fn walk_frames(frame_map: &FrameMap, code: (usize, usize), pc: usize, mut fp: usize, sp: usize, entry_sp: usize) -> RawBacktrace {
+		let mut raw = RawBacktrace { offsets: [0; MAX_FRAMES], len: 0 };
+		let mut pc = pc;
+		while raw.len < MAX_FRAMES && (code.0..code.1).contains(&pc) {
+			let Some((_, n_params)) = frame_map.func_at(pc - code.0) else { break };
+			raw.offsets[raw.len] = pc - code.0;
+			raw.len += 1;
+
+			let Some(ret_slot) = fp.checked_sub((n_params as usize + 1) * 8) else { break };
+			if ret_slot < sp + 8 || ret_slot >= entry_sp {
+				break;
+			}
+			pc = *(ret_slot as *const usize);
+			fp = *((ret_slot - 8) as *const usize);
+		}
+		raw
+	}
+}
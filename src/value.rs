@@ -0,0 +1,92 @@
+use wasmparser::ValType;
+use crate::PvfError;
+
+/// Wasm value type of a function parameter or result
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ValueType {
+	I32,
+	I64,
+	F32,
+	F64,
+}
+
+impl TryFrom<ValType> for ValueType {
+	type Error = PvfError;
+
+	fn try_from(ty: ValType) -> Result<Self, PvfError> {
+		Ok(match ty {
+			ValType::I32 => ValueType::I32,
+			ValType::I64 => ValueType::I64,
+			ValType::F32 => ValueType::F32,
+			ValType::F64 => ValueType::F64,
+			ty => return Err(PvfError::ValidationError(format!("Unsupported value type {:?}", ty))),
+		})
+	}
+}
+
+/// Wasm value passed to or returned from a function
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+	I32(i32),
+	I64(i64),
+	F32(f32),
+	F64(f64),
+}
+
+impl Value {
+	pub fn ty(&self) -> ValueType {
+		match self {
+			Value::I32(_) => ValueType::I32,
+			Value::I64(_) => ValueType::I64,
+			Value::F32(_) => ValueType::F32,
+			Value::F64(_) => ValueType::F64,
+		}
+	}
+
+	// The value as it's passed in a 64-bit register, 32-bit values are zero-extended
+	pub(crate) fn to_bits(self) -> u64 {
+		match self {
+			Value::I32(v) => v as u32 as u64,
+			Value::I64(v) => v as u64,
+			Value::F32(v) => v.to_bits() as u64,
+			Value::F64(v) => v.to_bits(),
+		}
+	}
+
+	// The value of type `ty` from a 64-bit register, 32-bit values are in the lower half
+	pub(crate) fn from_bits(ty: ValueType, bits: u64) -> Self {
+		match ty {
+			ValueType::I32 => Value::I32(bits as u32 as i32),
+			ValueType::I64 => Value::I64(bits as i64),
+			ValueType::F32 => Value::F32(f32::from_bits(bits as u32)),
+			ValueType::F64 => Value::F64(f64::from_bits(bits)),
+		}
+	}
+}
+
+/// Value stored in the linear memory in little-endian byte order
+pub trait MemoryValue: Copy {
+	const SIZE: usize;
+	fn from_le_slice(bytes: &[u8]) -> Self;
+	fn write_le_slice(self, bytes: &mut [u8]);
+}
+
+macro_rules! impl_memory_value {
+	($($t:ty)*) => {
+		$(
+			impl MemoryValue for $t {
+				const SIZE: usize = std::mem::size_of::<$t>();
+
+				fn from_le_slice(bytes: &[u8]) -> Self {
+					<$t>::from_le_bytes(bytes.try_into().expect("Slice has the size of the value"))
+				}
+
+				fn write_le_slice(self, bytes: &mut [u8]) {
+					bytes.copy_from_slice(&self.to_le_bytes());
+				}
+			}
+		)*
+	};
+}
+
+impl_memory_value!(u8 i8 u16 i16 u32 i32 u64 i64 f32 f64);